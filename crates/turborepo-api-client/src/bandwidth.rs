@@ -0,0 +1,167 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::time::{sleep_until, Instant};
+
+/// Maximum artifact transfer rates, in bytes per second. `None` doesn't
+/// limit that direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    // Shared by every transfer in that direction, across clones of the client
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    // Applied to each transfer on its own
+    pub per_transfer_upload: Option<u64>,
+    pub per_transfer_download: Option<u64>,
+}
+
+// Paces transfers to a fixed rate by reserving a slot of time for every
+// chunk. Idle time isn't saved up, so there are no bursts over the rate.
+#[derive(Debug)]
+struct RateLimiter {
+    bytes_per_second: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Returns when `bytes` can be sent
+    fn reserve(&self, bytes: usize) -> Instant {
+        let mut next_free = self.next_free.lock().expect("rate limiter lock poisoned");
+        let start = (*next_free).max(Instant::now());
+        *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        start
+    }
+}
+
+/// Applies `BandwidthLimits` to the transfers of an `APIClient`.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimiter {
+    limits: BandwidthLimits,
+    upload: Option<Arc<RateLimiter>>,
+    download: Option<Arc<RateLimiter>>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Self {
+            limits,
+            upload: limits.upload.map(|rate| Arc::new(RateLimiter::new(rate))),
+            download: limits.download.map(|rate| Arc::new(RateLimiter::new(rate))),
+        }
+    }
+
+    pub fn limits(&self) -> &BandwidthLimits {
+        &self.limits
+    }
+
+    /// Starts an upload, limited by both the global and per-transfer limits.
+    pub fn upload(&self) -> Transfer {
+        Transfer {
+            global: self.upload.clone(),
+            own: self.limits.per_transfer_upload.map(RateLimiter::new),
+        }
+    }
+
+    /// Starts a download, limited by both the global and per-transfer limits.
+    pub fn download(&self) -> Transfer {
+        Transfer {
+            global: self.download.clone(),
+            own: self.limits.per_transfer_download.map(RateLimiter::new),
+        }
+    }
+}
+
+/// A single upload or download.
+#[derive(Debug)]
+pub struct Transfer {
+    global: Option<Arc<RateLimiter>>,
+    own: Option<RateLimiter>,
+}
+
+impl Transfer {
+    pub fn is_limited(&self) -> bool {
+        self.global.is_some() || self.own.is_some()
+    }
+
+    /// Waits until `bytes` more can be transferred.
+    pub async fn consume(&self, bytes: usize) {
+        let ready = [self.global.as_deref(), self.own.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|limiter| limiter.reserve(bytes))
+            .max();
+        if let Some(ready) = ready {
+            sleep_until(ready).await;
+        }
+    }
+
+    /// Limits the rate `stream` is read at.
+    pub fn throttle<E>(
+        self,
+        stream: impl Stream<Item = Result<Bytes, E>>,
+    ) -> impl Stream<Item = Result<Bytes, E>> {
+        let transfer = Arc::new(self);
+        stream.then(move |chunk| {
+            let transfer = transfer.clone();
+            async move {
+                if let Ok(chunk) = &chunk {
+                    transfer.consume(chunk.len()).await;
+                }
+                chunk
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_limits() {
+        let limiter = BandwidthLimiter::new(BandwidthLimits {
+            download: Some(1000),
+            per_transfer_download: Some(500),
+            ..Default::default()
+        });
+        assert!(!limiter.upload().is_limited());
+
+        // A single transfer is held to its own limit
+        let start = Instant::now();
+        let transfer = limiter.download();
+        for _ in 0..3 {
+            transfer.consume(250).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // Concurrent transfers share the global limit
+        let limiter = BandwidthLimiter::new(BandwidthLimits {
+            download: Some(1000),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let (first, second) = (limiter.download(), limiter.download());
+        futures::join!(
+            async {
+                first.consume(500).await;
+                first.consume(500).await;
+            },
+            async {
+                second.consume(500).await;
+                second.consume(500).await;
+            }
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+}
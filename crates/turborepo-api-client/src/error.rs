@@ -13,8 +13,20 @@ pub enum Error {
     TooManyFailures(#[from] Box<reqwest::Error>),
     #[error("Unable to set up TLS.")]
     TlsError(#[source] reqwest::Error),
+    #[error("custom root certificates are not supported, turbo was built without TLS")]
+    TlsUnavailable,
+    #[error("invalid proxy {0}: {1}")]
+    InvalidProxy(String, #[source] reqwest::Error),
+    #[error("unable to read certificate {0}: {1}")]
+    CertificateReadError(String, #[source] std::io::Error),
+    #[error("invalid certificate {0}: {1}")]
+    InvalidCertificate(String, #[source] reqwest::Error),
+    #[error("no certificates found in {0}")]
+    NoCertificates(String),
     #[error("Error parsing header: {0}")]
     InvalidHeader(#[from] ToStrError),
+    #[error("Error reading artifact: {0}")]
+    ArtifactReadError(#[from] std::io::Error),
     #[error("Error parsing URL: {0}")]
     InvalidUrl(#[from] url::ParseError),
     #[error("unknown caching status: {0}")]
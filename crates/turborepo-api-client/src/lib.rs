@@ -9,6 +9,8 @@ use lazy_static::lazy_static;
 use regex::Regex;
 pub use reqwest::Response;
 use reqwest::{Method, RequestBuilder, StatusCode};
+use tokio_util::io::ReaderStream;
+use turbopath::AbsoluteSystemPath;
 use turborepo_ci::{is_ci, Vendor};
 use turborepo_vercel_api::{
     APIError, CachingStatus, CachingStatusResponse, PreflightResponse, SpacesResponse, Team,
@@ -16,10 +18,17 @@ use turborepo_vercel_api::{
 };
 use url::Url;
 
-pub use crate::error::{Error, Result};
+pub use crate::{
+    bandwidth::{BandwidthLimiter, BandwidthLimits, Transfer},
+    error::{Error, Result},
+    network::NetworkOpts,
+    retry::{RetryOn, RetryPolicy},
+};
 
 pub mod analytics;
+mod bandwidth;
 mod error;
+mod network;
 mod retry;
 pub mod spaces;
 
@@ -45,7 +54,7 @@ pub trait Client {
     async fn put_artifact(
         &self,
         hash: &str,
-        artifact_body: &[u8],
+        artifact_path: &AbsoluteSystemPath,
         duration: u64,
         tag: Option<&str>,
         token: &str,
@@ -58,6 +67,16 @@ pub trait Client {
         team_id: Option<&str>,
         team_slug: Option<&str>,
     ) -> Result<Option<Response>>;
+    // Fetches the artifact from byte `start` on. Servers that don't support
+    // ranges respond with the whole artifact and a 200 rather than a 206.
+    async fn fetch_artifact_range(
+        &self,
+        hash: &str,
+        token: &str,
+        team_id: Option<&str>,
+        team_slug: Option<&str>,
+        start: u64,
+    ) -> Result<Option<Response>>;
     async fn artifact_exists(
         &self,
         hash: &str,
@@ -89,6 +108,10 @@ pub struct APIClient {
     base_url: String,
     user_agent: String,
     use_preflight: bool,
+    // Kept to rebuild `client` with new `NetworkOpts`
+    timeout: u64,
+    retry_policy: RetryPolicy,
+    bandwidth_limiter: BandwidthLimiter,
 }
 
 #[derive(Clone)]
@@ -108,7 +131,7 @@ impl Client for APIClient {
             .header("User-Agent", self.user_agent.clone())
             .header("Authorization", format!("Bearer {}", token))
             .header("Content-Type", "application/json");
-        let response = retry::make_retryable_request(request_builder)
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -123,7 +146,7 @@ impl Client for APIClient {
             .header("Content-Type", "application/json")
             .header("Authorization", format!("Bearer {}", token));
 
-        let response = retry::make_retryable_request(request_builder)
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -169,7 +192,7 @@ impl Client for APIClient {
 
         let request_builder = Self::add_team_params(request_builder, team_id, team_slug);
 
-        let response = retry::make_retryable_request(request_builder)
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -190,7 +213,7 @@ impl Client for APIClient {
             .header("Content-Type", "application/json")
             .header("Authorization", format!("Bearer {}", token));
 
-        let response = retry::make_retryable_request(request_builder)
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -204,7 +227,7 @@ impl Client for APIClient {
             .query(&[("token", token), ("tokenName", token_name)])
             .header("User-Agent", self.user_agent.clone());
 
-        let response = retry::make_retryable_request(request_builder)
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -219,7 +242,7 @@ impl Client for APIClient {
     async fn put_artifact(
         &self,
         hash: &str,
-        artifact_body: &[u8],
+        artifact_path: &AbsoluteSystemPath,
         duration: u64,
         tag: Option<&str>,
         token: &str,
@@ -241,25 +264,43 @@ impl Client for APIClient {
             request_url = preflight_response.location.to_string();
         }
 
-        let mut request_builder = self
-            .client
-            .put(&request_url)
-            .header("Content-Type", "application/octet-stream")
-            .header("x-artifact-duration", duration.to_string())
-            .header("User-Agent", self.user_agent.clone())
-            .body(artifact_body.to_vec());
+        // The artifact is streamed from disk, so each attempt reopens it
+        let build_request = || {
+            let artifact = artifact_path.open()?;
+            let content_length = artifact.metadata()?.len();
+            let transfer = self.bandwidth_limiter.upload();
+            let body = if transfer.is_limited() {
+                let stream = ReaderStream::new(tokio::fs::File::from_std(artifact));
+                reqwest::Body::wrap_stream(transfer.throttle(stream))
+            } else {
+                tokio::fs::File::from_std(artifact).into()
+            };
 
-        if allow_auth {
-            request_builder = request_builder.header("Authorization", format!("Bearer {}", token));
-        }
+            let mut request_builder = self
+                .client
+                .put(&request_url)
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-Length", content_length)
+                .header("x-artifact-duration", duration.to_string())
+                .header("User-Agent", self.user_agent.clone())
+                .body(body);
+
+            if allow_auth {
+                request_builder =
+                    request_builder.header("Authorization", format!("Bearer {}", token));
+            }
 
-        request_builder = Self::add_ci_header(request_builder);
+            request_builder = Self::add_ci_header(request_builder);
 
-        if let Some(tag) = tag {
-            request_builder = request_builder.header("x-artifact-tag", tag);
-        }
+            if let Some(tag) = tag {
+                request_builder = request_builder.header("x-artifact-tag", tag);
+            }
 
-        let response = retry::make_retryable_request(request_builder).await?;
+            Ok(request_builder)
+        };
+
+        let response =
+            retry::make_rebuilt_retryable_request(build_request, &self.retry_policy).await?;
 
         if response.status() == StatusCode::FORBIDDEN {
             return Err(Self::handle_403(response).await);
@@ -313,6 +354,18 @@ impl Client for APIClient {
             .await
     }
 
+    async fn fetch_artifact_range(
+        &self,
+        hash: &str,
+        token: &str,
+        team_id: Option<&str>,
+        team_slug: Option<&str>,
+        start: u64,
+    ) -> Result<Option<Response>> {
+        self.request_artifact(hash, token, team_id, team_slug, Method::GET, Some(start))
+            .await
+    }
+
     async fn artifact_exists(
         &self,
         hash: &str,
@@ -332,36 +385,8 @@ impl Client for APIClient {
         team_slug: Option<&str>,
         method: Method,
     ) -> Result<Option<Response>> {
-        let mut request_url = self.make_url(&format!("/v8/artifacts/{}", hash));
-        let mut allow_auth = true;
-
-        if self.use_preflight {
-            let preflight_response = self
-                .do_preflight(token, &request_url, "GET", "Authorization, User-Agent")
-                .await?;
-
-            allow_auth = preflight_response.allow_authorization_header;
-            request_url = preflight_response.location.to_string();
-        };
-
-        let mut request_builder = self
-            .client
-            .request(method, request_url)
-            .header("User-Agent", self.user_agent.clone());
-
-        if allow_auth {
-            request_builder = request_builder.header("Authorization", format!("Bearer {}", token));
-        }
-
-        request_builder = Self::add_team_params(request_builder, team_id, team_slug);
-
-        let response = retry::make_retryable_request(request_builder).await?;
-
-        match response.status() {
-            StatusCode::FORBIDDEN => Err(Self::handle_403(response).await),
-            StatusCode::NOT_FOUND => Ok(None),
-            _ => Ok(Some(response.error_for_status()?)),
-        }
+        self.request_artifact(hash, token, team_id, team_slug, method, None)
+            .await
     }
 
     async fn do_preflight(
@@ -379,7 +404,7 @@ impl Client for APIClient {
             .header("Access-Control-Request-Headers", request_headers)
             .header("Authorization", format!("Bearer {}", token));
 
-        let response = retry::make_retryable_request(request_builder).await?;
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy).await?;
 
         let headers = response.headers();
         let location = if let Some(location) = headers.get("Location") {
@@ -420,15 +445,7 @@ impl APIClient {
         version: &str,
         use_preflight: bool,
     ) -> Result<Self> {
-        let client_build = if timeout != 0 {
-            reqwest::Client::builder()
-                .timeout(std::time::Duration::from_secs(timeout))
-                .build()
-        } else {
-            reqwest::Client::builder().build()
-        };
-
-        let client = client_build.map_err(Error::TlsError)?;
+        let client = Self::build_client(timeout, &NetworkOpts::default())?;
 
         let user_agent = format!(
             "turbo {} {} {} {}",
@@ -442,9 +459,101 @@ impl APIClient {
             base_url: base_url.as_ref().to_string(),
             user_agent,
             use_preflight,
+            timeout,
+            retry_policy: RetryPolicy::default(),
+            bandwidth_limiter: BandwidthLimiter::default(),
         })
     }
 
+    fn build_client(timeout: u64, network_opts: &NetworkOpts) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if timeout != 0 {
+            builder = builder.timeout(std::time::Duration::from_secs(timeout));
+        }
+
+        network_opts
+            .apply(builder)?
+            .build()
+            .map_err(Error::TlsError)
+    }
+
+    /// Sets the proxy and root certificates used to reach the API. Fails if
+    /// the proxy url or a certificate is invalid.
+    pub fn with_network_opts(mut self, network_opts: &NetworkOpts) -> Result<Self> {
+        self.client = Self::build_client(self.timeout, network_opts)?;
+        Ok(self)
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Limits the rate artifacts are uploaded and downloaded at. The global
+    /// limits are shared with clones of the client made after this.
+    pub fn with_bandwidth_limits(mut self, limits: BandwidthLimits) -> Self {
+        self.bandwidth_limiter = BandwidthLimiter::new(limits);
+        self
+    }
+
+    pub fn bandwidth_limiter(&self) -> &BandwidthLimiter {
+        &self.bandwidth_limiter
+    }
+
+    async fn request_artifact(
+        &self,
+        hash: &str,
+        token: &str,
+        team_id: Option<&str>,
+        team_slug: Option<&str>,
+        method: Method,
+        range_start: Option<u64>,
+    ) -> Result<Option<Response>> {
+        let mut request_url = self.make_url(&format!("/v8/artifacts/{}", hash));
+        let mut allow_auth = true;
+
+        if self.use_preflight {
+            let preflight_response = self
+                .do_preflight(
+                    token,
+                    &request_url,
+                    "GET",
+                    "Authorization, User-Agent, Range",
+                )
+                .await?;
+
+            allow_auth = preflight_response.allow_authorization_header;
+            request_url = preflight_response.location.to_string();
+        };
+
+        let mut request_builder = self
+            .client
+            .request(method, request_url)
+            .header("User-Agent", self.user_agent.clone());
+
+        if allow_auth {
+            request_builder = request_builder.header("Authorization", format!("Bearer {}", token));
+        }
+
+        request_builder = Self::add_team_params(request_builder, team_id, team_slug);
+
+        if let Some(start) = range_start {
+            request_builder = request_builder.header("Range", format!("bytes={}-", start));
+        }
+
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy).await?;
+
+        match response.status() {
+            StatusCode::FORBIDDEN => Err(Self::handle_403(response).await),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(response.error_for_status()?)),
+        }
+    }
+
     /// Create a new request builder with the preflight check done,
     /// team parameters added, CI header, and a content type of json.
     pub(crate) async fn create_request_builder(
@@ -519,7 +628,7 @@ mod test {
     use anyhow::Result;
     use turborepo_vercel_api_mock::start_test_server;
 
-    use crate::{APIClient, Client};
+    use crate::{APIClient, Client, Error, NetworkOpts};
 
     #[tokio::test]
     async fn test_do_preflight() -> Result<()> {
@@ -578,4 +687,43 @@ mod test {
         handle.abort();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_network_opts() -> Result<()> {
+        let port = port_scanner::request_open_port().unwrap();
+        let handle = tokio::spawn(start_test_server(port));
+
+        // The API is only reachable through the proxy, which the mock server
+        // stands in for
+        let client = APIClient::new("http://cache.invalid", 200, "2.0.0", true)?
+            .with_network_opts(&NetworkOpts {
+                proxy: Some(format!("http://localhost:{}", port)),
+                ..Default::default()
+            })?;
+        let response = client.get_user("token").await?;
+        assert_eq!(
+            response.user.id,
+            turborepo_vercel_api_mock::EXPECTED_USER_ID
+        );
+
+        // Unless the host is excluded
+        let client = APIClient::new("http://cache.invalid", 200, "2.0.0", true)?
+            .with_network_opts(&NetworkOpts {
+                proxy: Some(format!("http://localhost:{}", port)),
+                no_proxy: Some("cache.invalid".to_string()),
+                ..Default::default()
+            })?;
+        assert!(client.get_user("token").await.is_err());
+
+        let result = APIClient::new("http://cache.invalid", 200, "2.0.0", true)?.with_network_opts(
+            &NetworkOpts {
+                proxy: Some("http://in the mood for love".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(Error::InvalidProxy(..))));
+
+        handle.abort();
+        Ok(())
+    }
 }
@@ -1,15 +1,111 @@
+use std::time::Duration;
+
 use reqwest::{RequestBuilder, Response, StatusCode};
 use tokio::time::sleep;
 
 use crate::Error;
 
-const MIN_SLEEP_TIME_SECS: u64 = 2;
-const MAX_SLEEP_TIME_SECS: u64 = 10;
-const RETRY_MAX: u32 = 2;
+/// Which failures are worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryOn {
+    // 429 responses
+    pub rate_limited: bool,
+    // 5xx responses, other than 501
+    pub server_errors: bool,
+    // Failing to connect or to send the request
+    pub connection_errors: bool,
+    pub timeouts: bool,
+    // Responses cut off partway through their body. Artifact downloads resume
+    // where they stopped rather than starting over.
+    pub interrupted_transfers: bool,
+}
+
+impl Default for RetryOn {
+    fn default() -> Self {
+        Self {
+            rate_limited: true,
+            server_errors: true,
+            connection_errors: true,
+            timeouts: true,
+            interrupted_transfers: true,
+        }
+    }
+}
+
+/// How requests to the API are retried. Requests are made up to
+/// `max_attempts` times, waiting an exponentially increasing backoff between
+/// `min_backoff` and `max_backoff` before each retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 2,
+            min_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(10),
+            retry_on: RetryOn::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retries.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// How long to wait before the retry following attempt `attempt`,
+    /// counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.min_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+
+    /// Whether there are attempts left after attempt `attempt`, counting
+    /// from 1.
+    pub fn can_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    pub fn should_retry_status(&self, status: StatusCode) -> bool {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return self.retry_on.rate_limited;
+        }
+
+        status.is_server_error()
+            && status != StatusCode::NOT_IMPLEMENTED
+            && self.retry_on.server_errors
+    }
+
+    pub fn should_retry_error(&self, error: &reqwest::Error) -> bool {
+        if let Some(status) = error.status() {
+            return self.should_retry_status(status);
+        }
+        if error.is_timeout() {
+            return self.retry_on.timeouts;
+        }
+        if error.is_body() || error.is_decode() {
+            return self.retry_on.interrupted_transfers;
+        }
+
+        (error.is_connect() || error.is_request()) && self.retry_on.connection_errors
+    }
+}
 
-/// Retries a request until `RETRY_MAX` is reached, the `should_retry_request`
-/// function returns false, or the future succeeds. Uses an exponential backoff
-/// with a base of 2 to delay between retries.
+/// Retries a request until the policy's `max_attempts` is reached, the
+/// policy says the failure isn't worth retrying, or the request succeeds.
+/// Retryable statuses are retried as well, and the last response is returned
+/// if every attempt gets one.
 ///
 /// # Arguments
 ///
@@ -19,39 +115,109 @@ const RETRY_MAX: u32 = 2;
 /// returns: Result<Response, Error>
 pub(crate) async fn make_retryable_request(
     request_builder: RequestBuilder,
+    policy: &RetryPolicy,
+) -> Result<Response, Error> {
+    make_rebuilt_retryable_request(
+        || Ok(request_builder.try_clone().expect("cannot clone request")),
+        policy,
+    )
+    .await
+}
+
+/// Like `make_retryable_request`, but calls `build_request` for every attempt
+/// rather than cloning a single request, so that streamed bodies can be used.
+pub(crate) async fn make_rebuilt_retryable_request(
+    build_request: impl Fn() -> Result<RequestBuilder, Error>,
+    policy: &RetryPolicy,
 ) -> Result<Response, Error> {
-    let mut last_error = None;
-    for retry_count in 0..RETRY_MAX {
-        let builder = request_builder.try_clone().expect("cannot clone request");
+    let mut attempt = 1;
+    loop {
+        let builder = build_request()?;
         match builder.send().await {
-            Ok(value) => return Ok(value),
-            Err(err) => {
-                if !should_retry_request(&err) {
-                    return Err(err.into());
-                }
-                last_error = Some(err);
+            Ok(response)
+                if policy.can_retry(attempt) && policy.should_retry_status(response.status()) => {}
+            Ok(response) => return Ok(response),
+            Err(err) if !policy.should_retry_error(&err) => return Err(err.into()),
+            Err(err) if !policy.can_retry(attempt) => {
+                return Err(Error::TooManyFailures(Box::new(err)))
             }
+            Err(_) => {}
         }
 
-        let sleep_period = (2_u64)
-            .pow(retry_count)
-            .clamp(MIN_SLEEP_TIME_SECS, MAX_SLEEP_TIME_SECS);
-        sleep(std::time::Duration::from_secs(sleep_period)).await;
+        sleep(policy.backoff(attempt)).await;
+        attempt += 1;
     }
-
-    Err(Error::TooManyFailures(Box::new(last_error.unwrap())))
 }
 
-fn should_retry_request(error: &reqwest::Error) -> bool {
-    if let Some(status) = error.status() {
-        if status == StatusCode::TOO_MANY_REQUESTS {
-            return true;
-        }
+#[cfg(test)]
+mod test {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
 
-        if status.as_u16() >= 500 && status.as_u16() != 501 {
-            return true;
-        }
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let backoffs: Vec<_> = (1..=5).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            backoffs,
+            [500, 1000, 2000, 3000, 3000].map(Duration::from_millis)
+        );
+        assert!(policy.can_retry(4));
+        assert!(!policy.can_retry(5));
+        assert!(!RetryPolicy::none().can_retry(1));
     }
 
-    false
+    #[test]
+    fn test_should_retry_status() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(policy.should_retry_status(StatusCode::BAD_GATEWAY));
+        assert!(!policy.should_retry_status(StatusCode::NOT_IMPLEMENTED));
+        assert!(!policy.should_retry_status(StatusCode::NOT_FOUND));
+
+        let policy = RetryPolicy {
+            retry_on: RetryOn {
+                server_errors: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(!policy.should_retry_status(StatusCode::BAD_GATEWAY));
+        assert!(policy.should_retry_status(StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    #[tokio::test]
+    async fn test_connection_reset_is_retried() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            // Resets the first connection, and answers the second
+            let (socket, _) = listener.accept().await.unwrap();
+            socket.set_linger(Some(Duration::ZERO)).unwrap();
+            drop(socket);
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let policy = RetryPolicy {
+            min_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let request = reqwest::Client::new().get(format!("http://{}", addr));
+        let response = make_retryable_request(request, &policy).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        server.await.unwrap();
+
+        Ok(())
+    }
 }
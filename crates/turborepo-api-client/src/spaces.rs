@@ -151,7 +151,7 @@ impl APIClient {
             .await?
             .json(&payload);
 
-        let response = retry::make_retryable_request(request_builder)
+        let response = retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -174,7 +174,7 @@ impl APIClient {
             .await?
             .json(&task);
 
-        retry::make_retryable_request(request_builder)
+        retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
@@ -198,7 +198,7 @@ impl APIClient {
             .await?
             .json(&payload);
 
-        retry::make_retryable_request(request_builder)
+        retry::make_retryable_request(request_builder, &self.retry_policy)
             .await?
             .error_for_status()?;
 
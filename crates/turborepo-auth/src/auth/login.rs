@@ -213,7 +213,7 @@ mod tests {
         async fn put_artifact(
             &self,
             _hash: &str,
-            _artifact_body: &[u8],
+            _artifact_path: &turbopath::AbsoluteSystemPath,
             _duration: u64,
             _tag: Option<&str>,
             _token: &str,
@@ -232,6 +232,16 @@ mod tests {
         ) -> turborepo_api_client::Result<Option<Response>> {
             unimplemented!("fetch_artifact")
         }
+        async fn fetch_artifact_range(
+            &self,
+            _hash: &str,
+            _token: &str,
+            _team_id: Option<&str>,
+            _team_slug: Option<&str>,
+            _start: u64,
+        ) -> turborepo_api_client::Result<Option<Response>> {
+            unimplemented!("fetch_artifact_range")
+        }
         async fn artifact_exists(
             &self,
             _hash: &str,
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+use turborepo_cache::{fs::FSCache, CacheOpts};
+
+// Puts the same artifact with more and more zstd workers. Every frame
+// is split between the workers, so on a machine with enough cores the
+// throughput goes up with the number of workers.
+fn bench_compression_workers(c: &mut Criterion) {
+    let mut g = c.benchmark_group("turborepo-cache");
+    g.sample_size(10);
+    g.measurement_time(Duration::from_secs(10));
+
+    let repo_root = tempfile::tempdir().unwrap();
+    let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path()).unwrap();
+
+    let file = AnchoredSystemPathBuf::from_raw("large-file").unwrap();
+    // Only partly compressible, so that compressing it takes a while
+    let contents: Vec<u8> = (0..64 * 1024 * 1024u64)
+        .map(|i| ((i * i) >> 7) as u8)
+        .collect();
+    repo_root_path
+        .resolve(&file)
+        .create_with_contents(&contents)
+        .unwrap();
+    g.throughput(Throughput::Bytes(contents.len() as u64));
+
+    for workers in [0, 1, 2, 4, 8] {
+        g.bench_with_input(
+            BenchmarkId::new("compress_zstd", workers),
+            &workers,
+            |b, &workers| {
+                let opts = CacheOpts {
+                    fs_cache_compression_workers: workers,
+                    ..Default::default()
+                };
+                let cache = FSCache::new(&opts, repo_root_path, None).unwrap();
+                b.iter(|| {
+                    cache
+                        .put(repo_root_path, "the-hash", &[file.clone()], 0)
+                        .unwrap()
+                })
+            },
+        );
+    }
+    g.finish();
+}
+
+criterion_group!(benches, bench_compression_workers);
+criterion_main!(benches);
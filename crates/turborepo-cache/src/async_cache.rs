@@ -5,14 +5,34 @@ use tokio::{
     sync::{mpsc, Semaphore},
     task::JoinHandle,
 };
+use tracing::debug;
 use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
 use turborepo_analytics::AnalyticsSender;
 use turborepo_api_client::{APIAuth, APIClient};
 
-use crate::{multiplexer::CacheMultiplexer, CacheError, CacheHitMetadata, CacheOpts};
+use crate::{
+    fs::{validate_tags, ArtifactTags},
+    multiplexer::CacheMultiplexer,
+    remote::RemoteArtifactMetadata,
+    CacheError, CacheHitMetadata, CacheOpts, CachePolicy, FlushSummary, OutputGlobs,
+    OutputValidation,
+};
+
+/// How `AsyncCache::put_with_options` writes an artifact.
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    // Defaults to `CacheOpts::cache_policy`
+    pub policy: Option<CachePolicy>,
+    pub tags: ArtifactTags,
+    // The task's logs, stored with the artifact so that they can be replayed
+    // with `AsyncCache::fetch_logs`. Only kept by the filesystem cache.
+    pub logs: Option<Vec<u8>>,
+}
 
 pub struct AsyncCache {
     real_cache: Arc<CacheMultiplexer>,
+    policy: CachePolicy,
+    strict_output_validation: bool,
     writer_sender: mpsc::Sender<WorkerRequest>,
     writer_thread: JoinHandle<()>,
 }
@@ -23,6 +43,9 @@ enum WorkerRequest {
         key: String,
         duration: u64,
         files: Vec<AnchoredSystemPathBuf>,
+        policy: CachePolicy,
+        tags: ArtifactTags,
+        logs: Option<Vec<u8>>,
     },
     #[cfg(test)]
     Flush(tokio::sync::oneshot::Sender<()>),
@@ -60,11 +83,24 @@ impl AsyncCache {
                         key,
                         duration,
                         files,
+                        policy,
+                        tags,
+                        logs,
                     } => {
                         let permit = semaphore.clone().acquire_owned().await.unwrap();
                         let real_cache = real_cache.clone();
                         workers.push(tokio::spawn(async move {
-                            let _ = real_cache.put(&anchor, &key, &files, duration).await;
+                            let _ = real_cache
+                                .put(
+                                    &anchor,
+                                    &key,
+                                    &files,
+                                    duration,
+                                    &policy,
+                                    &tags,
+                                    logs.as_deref(),
+                                )
+                                .await;
                             // Release permit once we're done with the write
                             drop(permit);
                         }))
@@ -88,6 +124,8 @@ impl AsyncCache {
 
         Ok(AsyncCache {
             real_cache,
+            policy: opts.cache_policy.clone(),
+            strict_output_validation: opts.strict_output_validation,
             writer_sender,
             writer_thread,
         })
@@ -100,6 +138,40 @@ impl AsyncCache {
         files: Vec<AnchoredSystemPathBuf>,
         duration: u64,
     ) -> Result<(), CacheError> {
+        self.put_with_options(anchor, key, files, duration, PutOptions::default())
+            .await
+    }
+
+    /// Like `put`, but writes to the layers of `policy` rather than those of
+    /// `CacheOpts::cache_policy`, e.g. for a task that shouldn't be shared.
+    pub async fn put_with_policy(
+        &self,
+        anchor: AbsoluteSystemPathBuf,
+        key: String,
+        files: Vec<AnchoredSystemPathBuf>,
+        duration: u64,
+        policy: CachePolicy,
+    ) -> Result<(), CacheError> {
+        let options = PutOptions {
+            policy: Some(policy),
+            ..Default::default()
+        };
+        self.put_with_options(anchor, key, files, duration, options)
+            .await
+    }
+
+    /// Like `put`, with a policy and tags for the artifact. Tags are checked
+    /// here so that invalid ones are reported to the caller, rather than
+    /// failing the write in the background.
+    pub async fn put_with_options(
+        &self,
+        anchor: AbsoluteSystemPathBuf,
+        key: String,
+        files: Vec<AnchoredSystemPathBuf>,
+        duration: u64,
+        options: PutOptions,
+    ) -> Result<(), CacheError> {
+        validate_tags(&options.tags)?;
         if self
             .writer_sender
             .send(WorkerRequest::WriteRequest {
@@ -107,6 +179,9 @@ impl AsyncCache {
                 key,
                 duration,
                 files,
+                policy: options.policy.unwrap_or_else(|| self.policy.clone()),
+                tags: options.tags,
+                logs: options.logs,
             })
             .await
             .is_err()
@@ -118,7 +193,15 @@ impl AsyncCache {
     }
 
     pub async fn exists(&self, key: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
-        self.real_cache.exists(key).await
+        self.real_cache.exists(key, &self.policy).await
+    }
+
+    pub async fn exists_with_policy(
+        &self,
+        key: &str,
+        policy: &CachePolicy,
+    ) -> Result<Option<CacheHitMetadata>, CacheError> {
+        self.real_cache.exists(key, policy).await
     }
 
     pub async fn fetch(
@@ -126,7 +209,81 @@ impl AsyncCache {
         anchor: &AbsoluteSystemPath,
         key: &str,
     ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
-        self.real_cache.fetch(anchor, key).await
+        self.real_cache.fetch(anchor, key, &self.policy).await
+    }
+
+    /// Like `fetch`, but reads the layers of `policy` in its order.
+    pub async fn fetch_with_policy(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+        policy: &CachePolicy,
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        self.real_cache.fetch(anchor, key, policy).await
+    }
+
+    /// Returns the logs stored with an artifact by `put_with_options`,
+    /// without restoring its files.
+    pub async fn fetch_logs(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        self.real_cache.fetch_logs(key).await
+    }
+
+    /// Like `fetch`, and then checks the restored paths against the task's
+    /// declared outputs, to catch artifacts written by an older version of
+    /// the task. With `CacheOpts::strict_output_validation` a mismatch is an
+    /// error, otherwise it's returned alongside the hit.
+    pub async fn fetch_with_outputs(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+        outputs: &OutputGlobs,
+    ) -> Result<
+        Option<(
+            CacheHitMetadata,
+            Vec<AnchoredSystemPathBuf>,
+            OutputValidation,
+        )>,
+        CacheError,
+    > {
+        let Some((hit, files)) = self.fetch(anchor, key).await? else {
+            return Ok(None);
+        };
+
+        let validation = outputs.validate(&files)?;
+        if !validation.is_valid() {
+            if self.strict_output_validation {
+                return Err(validation.into_error(key));
+            }
+            debug!(
+                "restored outputs of {} don't match the task's outputs: {} unexpected, {} missing",
+                key,
+                validation.unexpected.len(),
+                validation.missing.len()
+            );
+        }
+
+        Ok(Some((hit, files, validation)))
+    }
+
+    pub async fn remote_metadata(
+        &self,
+        key: &str,
+    ) -> Result<Option<RemoteArtifactMetadata>, CacheError> {
+        self.real_cache.remote_metadata(key).await
+    }
+
+    /// Starts copying the artifacts for `hashes` into the local cache in the
+    /// background, see `CacheMultiplexer::prefetch`.
+    pub fn prefetch(&self, hashes: Vec<String>) -> JoinHandle<()> {
+        let real_cache = self.real_cache.clone();
+        tokio::spawn(async move { real_cache.prefetch(&hashes).await })
+    }
+
+    /// Uploads the puts queued while the remote cache was unreachable, see
+    /// `CacheMultiplexer::flush_pending`. Only does anything with
+    /// `CacheOpts::remote_cache_upload_queue` set.
+    pub async fn flush_pending(&self) -> Result<FlushSummary, CacheError> {
+        self.real_cache.flush_pending().await
     }
 
     // Used for testing to ensure that the workers resolve
@@ -161,7 +318,8 @@ mod tests {
 
     use crate::{
         test_cases::{get_test_cases, TestCase},
-        AsyncCache, CacheHitMetadata, CacheOpts, CacheSource, RemoteCacheOpts,
+        AsyncCache, CacheError, CacheHitMetadata, CacheOpts, CacheSource, OutputGlobs,
+        RemoteCacheOpts,
     };
 
     #[tokio::test]
@@ -180,6 +338,114 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_memory_cache() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = turbopath::AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("As Tears Go By")?;
+
+        let opts = CacheOpts {
+            skip_remote: true,
+            workers: 10,
+            memory_cache_max_bytes: Some(1024 * 1024),
+            ..Default::default()
+        };
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let async_cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+
+        async_cache
+            .put(
+                repo_root_path.clone(),
+                "the-hash".to_string(),
+                vec![file.clone()],
+                10,
+            )
+            .await?;
+        async_cache.wait().await;
+
+        // Hits come from memory once the filesystem cache is gone
+        repo_root_path
+            .join_components(&["node_modules", ".cache", "turbo"])
+            .remove_dir_all()?;
+        repo_root_path.resolve(&file).remove_file()?;
+        let hit = CacheHitMetadata {
+            source: CacheSource::Local,
+            time_saved: 10,
+        };
+        assert_eq!(async_cache.exists("the-hash").await?, Some(hit));
+        assert_eq!(
+            async_cache.fetch(&repo_root_path, "the-hash").await?,
+            Some((hit, vec![file.clone()]))
+        );
+        assert_eq!(
+            repo_root_path.resolve(&file).read_to_string()?,
+            "As Tears Go By"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_outputs() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let files = ["dist/index.js", "dist/index.d.ts"]
+            .map(|path| turbopath::AnchoredSystemPathBuf::from_raw(path).unwrap());
+        for file in &files {
+            repo_root_path.resolve(file).ensure_dir()?;
+            repo_root_path
+                .resolve(file)
+                .create_with_contents("Ashes of Time")?;
+        }
+        // The task no longer outputs type declarations
+        let outputs = OutputGlobs {
+            inclusions: vec!["dist/**/*.js".to_string()],
+            exclusions: vec![],
+        };
+
+        for strict_output_validation in [false, true] {
+            let opts = CacheOpts {
+                skip_remote: true,
+                workers: 10,
+                strict_output_validation,
+                ..Default::default()
+            };
+            let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+            let async_cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+            async_cache
+                .put(
+                    repo_root_path.clone(),
+                    "the-hash".to_string(),
+                    files.to_vec(),
+                    10,
+                )
+                .await?;
+            async_cache.wait().await;
+
+            let result = async_cache
+                .fetch_with_outputs(&repo_root_path, "the-hash", &outputs)
+                .await;
+            if strict_output_validation {
+                assert_matches!(result, Err(CacheError::InvalidOutputs(..)));
+            } else {
+                let (_, _, validation) = result?.unwrap();
+                assert_eq!(validation.unexpected, vec![files[1].clone()]);
+                assert!(validation.missing.is_empty());
+            }
+            assert_eq!(
+                async_cache
+                    .fetch_with_outputs(&repo_root_path, "missing", &outputs)
+                    .await?,
+                None
+            );
+        }
+
+        Ok(())
+    }
+
     async fn round_trip_test_without_fs(test_case: &TestCase, port: u16) -> Result<()> {
         let repo_root = tempdir()?;
         let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
@@ -196,6 +462,7 @@ mod tests {
                 team_id: "my-team".to_string(),
                 signature: false,
             }),
+            ..Default::default()
         };
 
         let api_client = APIClient::new(format!("http://localhost:{}", port), 200, "2.0.0", true)?;
@@ -269,6 +536,7 @@ mod tests {
                 team_id: "my-team".to_string(),
                 signature: false,
             }),
+            ..Default::default()
         };
 
         // Initialize client with invalid API url to ensure that we don't hit the
@@ -352,6 +620,7 @@ mod tests {
                 team_id: "my-team".to_string(),
                 signature: false,
             }),
+            ..Default::default()
         };
 
         let api_client = APIClient::new(format!("http://localhost:{}", port), 200, "2.0.0", true)?;
@@ -420,6 +689,17 @@ mod tests {
             })
         );
 
+        // Prefetching copies the artifact back into the fs cache
+        async_cache.prefetch(vec![hash.clone()]).await?;
+        assert!(fs_cache_path.exists());
+        assert_eq!(
+            async_cache.exists(&hash).await?,
+            Some(CacheHitMetadata {
+                source: CacheSource::Local,
+                time_saved: test_case.duration
+            })
+        );
+
         Ok(())
     }
 }
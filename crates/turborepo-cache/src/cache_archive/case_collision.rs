@@ -0,0 +1,191 @@
+use std::{backtrace::Backtrace, collections::HashMap, io::Read};
+
+use tar::Entry;
+use turbopath::AbsoluteSystemPath;
+
+use crate::CacheError;
+
+// Tracks the paths restored so far by their case-folded form, so that an
+// entry that would overwrite an earlier one with a differently-cased path is
+// caught before it is written. Only enabled when restoring onto a
+// case-insensitive filesystem.
+pub struct CaseCollisions {
+    seen: Option<HashMap<String, String>>,
+}
+
+impl CaseCollisions {
+    pub fn new(anchor: &AbsoluteSystemPath) -> Self {
+        if is_case_insensitive(anchor) {
+            Self::enabled()
+        } else {
+            Self { seen: None }
+        }
+    }
+
+    pub fn enabled() -> Self {
+        Self {
+            seen: Some(HashMap::new()),
+        }
+    }
+
+    pub fn check(&mut self, entry: &Entry<impl Read>) -> Result<(), CacheError> {
+        let Some(seen) = &mut self.seen else {
+            return Ok(());
+        };
+
+        let path = entry.path()?;
+        let path = path.to_string_lossy();
+        // Entries are archived with trailing slashes on directories
+        let path = path.trim_end_matches('/');
+        match seen.get(&path.to_lowercase()) {
+            Some(existing) if existing != path => Err(CacheError::CaseCollision(
+                existing.clone(),
+                path.to_string(),
+                Backtrace::capture(),
+            )),
+            Some(_) => Ok(()),
+            None => {
+                seen.insert(path.to_lowercase(), path.to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+// Case sensitivity is a property of the filesystem (or on Windows, of each
+// directory), so we check it where we're restoring to: if `anchor` or one of
+// its ancestors can be found under a differently-cased name, it's the same
+// directory on a case-insensitive filesystem.
+fn is_case_insensitive(anchor: &AbsoluteSystemPath) -> bool {
+    for path in anchor.ancestors() {
+        let (Some(parent), Some(name)) = (path.parent(), path.as_path().file_name()) else {
+            break;
+        };
+        let swapped = swap_case(name);
+        if swapped == name {
+            continue;
+        }
+
+        let Ok(metadata) = path.symlink_metadata() else {
+            return false;
+        };
+        return match parent.join_component(&swapped).symlink_metadata() {
+            Ok(swapped_metadata) => same_file(&metadata, &swapped_metadata),
+            Err(_) => false,
+        };
+    }
+
+    false
+}
+
+fn swap_case(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_uppercase() {
+                c.to_lowercase().next().unwrap_or(c)
+            } else {
+                c.to_uppercase().next().unwrap_or(c)
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+// Windows doesn't give us a file id from `Metadata`. Directories are only
+// case-sensitive there when explicitly configured to be, so finding the
+// swapped name is enough.
+#[cfg(windows)]
+fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::assert_matches::assert_matches;
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn archive(entries: &[(&str, tar::EntryType)]) -> Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_size(0);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, std::io::empty())?;
+        }
+
+        Ok(builder.into_inner()?)
+    }
+
+    fn check_all(archive: &[u8]) -> Result<(), CacheError> {
+        let mut case_collisions = CaseCollisions::enabled();
+        for entry in tar::Archive::new(archive).entries()? {
+            case_collisions.check(&entry?)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_collisions() -> Result<()> {
+        let regular = tar::EntryType::Regular;
+        let directory = tar::EntryType::Directory;
+
+        let colliding = archive(&[("Foo.js", regular), ("foo.js", regular)])?;
+        assert_matches!(
+            check_all(&colliding),
+            Err(CacheError::CaseCollision(first, second, _))
+                if first == "Foo.js" && second == "foo.js"
+        );
+
+        let colliding_directories = archive(&[
+            ("dist/", directory),
+            ("dist/a.js", regular),
+            ("Dist/", directory),
+        ])?;
+        assert_matches!(
+            check_all(&colliding_directories),
+            Err(CacheError::CaseCollision(..))
+        );
+
+        // Repeated entries for the same path are fine
+        let repeated = archive(&[
+            ("dist/", directory),
+            ("dist/", directory),
+            ("Dist.js", regular),
+        ])?;
+        check_all(&repeated)?;
+
+        // Nothing is checked on case-sensitive filesystems
+        let mut case_collisions = CaseCollisions { seen: None };
+        for entry in tar::Archive::new(&colliding[..]).entries()? {
+            case_collisions.check(&entry?)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_case_insensitive() -> Result<()> {
+        let dir = tempdir()?;
+        let anchor = AbsoluteSystemPath::from_std_path(dir.path())?.join_component("Probe");
+        anchor.create_dir_all()?;
+
+        // The defaults for each platform's usual filesystem
+        #[cfg(target_os = "linux")]
+        assert!(!is_case_insensitive(&anchor));
+        #[cfg(any(target_os = "macos", windows))]
+        assert!(is_case_insensitive(&anchor));
+
+        Ok(())
+    }
+}
@@ -1,18 +1,98 @@
 use std::{
     backtrace::Backtrace,
+    collections::HashMap,
     fs,
     fs::OpenOptions,
+    io,
     io::{BufWriter, Read, Write},
     path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::UNIX_EPOCH,
 };
 
 use tar::{EntryType, Header};
-use turbopath::{AbsoluteSystemPath, AnchoredSystemPath};
+use turbopath::{AbsoluteSystemPath, AnchoredSystemPath, AnchoredSystemPathBuf};
 
-use crate::CacheError;
+use crate::{
+    cache_archive::{
+        encryption::EncryptingWriter,
+        index::{digest_bytes, ArchiveEntry, ArchiveEntryKind, CountingWriter, DigestingReader},
+        seekable::SeekableZstdWriter,
+        sparse::{data_regions, set_sparse_header, SparseReader},
+        xattrs::{pax_header, read_xattrs},
+        CompressionAlgorithm, EncryptionKey, Progress, ProgressReporter, LOGS_ENTRY,
+    },
+    CacheError,
+};
+
+// A writer in the chain an archive is written through. Finishing it writes
+// what it still holds back, e.g. the end of a compressed stream, and then
+// finishes the writer below it. Archives that aren't finished are incomplete
+// rather than ending wherever the writers happened to be dropped.
+pub(crate) trait FinishWrite: Write {
+    fn finish_write(self: Box<Self>) -> io::Result<()>;
+}
+
+type ArchiveWriter<'a> = Box<dyn FinishWrite + 'a>;
+
+// The end of the chain, which only needs flushing
+struct OutputWriter<W: Write>(W);
+
+impl<W: Write> Write for OutputWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> FinishWrite for OutputWriter<W> {
+    fn finish_write(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'a> FinishWrite for EncryptingWriter<ArchiveWriter<'a>> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.finish_write()
+    }
+}
+
+impl<'a> FinishWrite for SeekableZstdWriter<ArchiveWriter<'a>> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.finish_write()
+    }
+}
+
+impl<'a> FinishWrite for lz4_flex::frame::FrameEncoder<ArchiveWriter<'a>> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.finish_write()
+    }
+}
+
+impl<'a> FinishWrite for zstd::Encoder<'static, ArchiveWriter<'a>> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.finish_write()
+    }
+}
 
 pub struct CacheWriter<'a> {
-    builder: tar::Builder<Box<dyn Write + 'a>>,
+    builder: tar::Builder<CountingWriter<ArchiveWriter<'a>>>,
+    bytes_written: Arc<AtomicU64>,
+    index: Vec<ArchiveEntry>,
+    mtimes: bool,
+    dedupe: bool,
+    sparse: bool,
+    xattrs: bool,
+    // The regular files added so far by size, with their digests, so that
+    // only files of the same size as an earlier one are hashed up front
+    originals: HashMap<u64, Vec<(String, AnchoredSystemPathBuf)>>,
+    progress: ProgressReporter<'a>,
 }
 
 impl<'a> CacheWriter<'a> {
@@ -26,48 +106,173 @@ impl<'a> CacheWriter<'a> {
         Ok(self.builder.append_data(header, path, body)?)
     }
 
-    pub fn finish(mut self) -> Result<(), CacheError> {
-        Ok(self.builder.finish()?)
+    /// Writes the end of the archive and finishes each writer it goes
+    /// through in turn. Errors from any of them are returned, and leave the
+    /// archive incomplete.
+    pub fn finish(self) -> Result<(), CacheError> {
+        let writer = self.builder.into_inner()?.into_inner();
+        Ok(writer.finish_write()?)
+    }
+
+    // The entries added so far, in the order they appear in the archive.
+    pub fn index(&self) -> &[ArchiveEntry] {
+        &self.index
+    }
+
+    fn new(writer: ArchiveWriter<'a>) -> Self {
+        let (writer, bytes_written) = CountingWriter::new(writer);
+        CacheWriter {
+            builder: tar::Builder::new(writer),
+            bytes_written,
+            index: Vec::new(),
+            mtimes: false,
+            dedupe: false,
+            sparse: false,
+            xattrs: false,
+            originals: HashMap::new(),
+            progress: ProgressReporter::default(),
+        }
+    }
+
+    /// Records the mtimes of regular files, to the second. Otherwise every
+    /// mtime is 0 so that archives of the same files are identical.
+    pub fn with_mtimes(mut self, mtimes: bool) -> Self {
+        self.mtimes = mtimes;
+        self
+    }
+
+    /// Stores files with the same contents as an earlier file of the archive
+    /// as a hardlink entry pointing at it, rather than storing them again.
+    /// They're restored as copies. Versions of turbo without support for
+    /// these entries fail to restore such archives.
+    pub fn with_dedupe(mut self, dedupe: bool) -> Self {
+        self.dedupe = dedupe;
+        self
+    }
+
+    /// Stores files with holes as GNU sparse entries, which only hold the
+    /// regions with data, and restores them with the same holes. Versions
+    /// of turbo without support for these entries fail to restore such
+    /// archives.
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Records the extended attributes of each file, e.g. the code signature
+    /// or quarantine flag of a macOS app, in a PAX header before its entry.
+    /// They're only restored by `CacheReader::with_xattrs`.
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Calls `progress` after each file is added. Totals are left for the
+    /// caller to fill in.
+    pub fn with_progress(mut self, progress: &'a dyn Fn(Progress)) -> Self {
+        self.progress = ProgressReporter::new(Some(progress));
+        self
     }
 
     pub fn from_writer(writer: impl Write + 'a, use_compression: bool) -> Result<Self, CacheError> {
+        let writer: ArchiveWriter = Box::new(OutputWriter(writer));
         if use_compression {
-            let zw = zstd::Encoder::new(writer, 0)?.auto_finish();
-            Ok(CacheWriter {
-                builder: tar::Builder::new(Box::new(zw)),
-            })
+            let zw = zstd::Encoder::new(writer, 0)?;
+            Ok(CacheWriter::new(Box::new(zw)))
         } else {
-            Ok(CacheWriter {
-                builder: tar::Builder::new(Box::new(writer)),
-            })
+            Ok(CacheWriter::new(writer))
         }
     }
 
     // Makes a new CacheArchive at the specified path
     // Wires up the chain of writers:
-    // tar::Builder -> zstd::Encoder (optional) -> BufWriter -> File
-    pub fn create(path: &AbsoluteSystemPath) -> Result<Self, CacheError> {
+    // tar::Builder -> SeekableZstdWriter | lz4 FrameEncoder (optional) ->
+    // EncryptingWriter (optional) -> BufWriter -> File
+    //
+    // The compression algorithm is determined by the extension of `path`.
+    // zstd archives are written in the seekable format so that they can be
+    // partially restored.
+    // `compression_level` and `compression_workers` are only used by zstd,
+    // where a level of 0 is the default level and 0 workers compresses on the
+    // calling thread.
+    pub fn create(
+        path: &AbsoluteSystemPath,
+        compression_level: i32,
+        compression_workers: u32,
+    ) -> Result<Self, CacheError> {
+        Self::create_with_key(path, compression_level, compression_workers, None)
+    }
+
+    /// Like `create`, but encrypts the archive after compressing it if `key`
+    /// is given. Encrypted archives can only be read with the same key.
+    pub fn create_with_key(
+        path: &AbsoluteSystemPath,
+        compression_level: i32,
+        compression_workers: u32,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Self, CacheError> {
+        let writer = Self::archive_writer(path, compression_level, compression_workers, key)?;
+        Ok(CacheWriter::new(writer))
+    }
+
+    /// Writes `tar` to a new archive at `path` as it is, compressed and
+    /// encrypted the way `create_with_key` would. The tar isn't checked.
+    pub(crate) fn copy_tar(
+        path: &AbsoluteSystemPath,
+        compression_level: i32,
+        compression_workers: u32,
+        key: Option<&EncryptionKey>,
+        tar: &mut impl Read,
+    ) -> Result<(), CacheError> {
+        let mut writer = Self::archive_writer(path, compression_level, compression_workers, key)?;
+        io::copy(tar, &mut writer)?;
+        Ok(writer.finish_write()?)
+    }
+
+    fn archive_writer(
+        path: &AbsoluteSystemPath,
+        compression_level: i32,
+        compression_workers: u32,
+        key: Option<&EncryptionKey>,
+    ) -> Result<ArchiveWriter<'static>, CacheError> {
         let mut options = OpenOptions::new();
         options.write(true).create(true).truncate(true);
 
         let file = path.open_with_options(options)?;
 
         // Flush to disk in 1mb chunks.
-        let file_buffer = BufWriter::with_capacity(2usize.pow(20), file);
+        let file_buffer: ArchiveWriter =
+            Box::new(OutputWriter(BufWriter::with_capacity(2usize.pow(20), file)));
+        let file_buffer: ArchiveWriter = match key {
+            Some(key) => Box::new(EncryptingWriter::new(file_buffer, key)?),
+            None => file_buffer,
+        };
 
-        let is_compressed = path.extension() == Some("zst");
+        let writer: ArchiveWriter = match CompressionAlgorithm::from_path(path) {
+            CompressionAlgorithm::Zstd => Box::new(SeekableZstdWriter::new(
+                file_buffer,
+                compression_level,
+                compression_workers,
+            )?),
+            CompressionAlgorithm::Lz4 => Box::new(lz4_flex::frame::FrameEncoder::new(file_buffer)),
+            CompressionAlgorithm::None => file_buffer,
+        };
 
-        if is_compressed {
-            let zw = zstd::Encoder::new(file_buffer, 0)?.auto_finish();
+        Ok(writer)
+    }
 
-            Ok(CacheWriter {
-                builder: tar::Builder::new(Box::new(zw)),
-            })
-        } else {
-            Ok(CacheWriter {
-                builder: tar::Builder::new(Box::new(file_buffer)),
-            })
-        }
+    /// Adds the logs of the task that produced the artifact. They're kept in
+    /// a designated entry that restoring skips, and that has to come before
+    /// any file so that `CacheReader::read_logs` only reads the start of the
+    /// archive.
+    pub fn add_logs(&mut self, logs: &[u8]) -> Result<(), CacheError> {
+        debug_assert!(self.index.is_empty(), "logs are added before any file");
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(logs.len() as u64);
+        header.set_mtime(0);
+        self.append_data(&mut header, LOGS_ENTRY, logs)
     }
 
     // Adds a user-cached item to the tar
@@ -81,26 +286,152 @@ impl<'a> CacheWriter<'a> {
 
         // Grab the file info to construct the header.
         let file_info = source_path.symlink_metadata()?;
+        let file_path_buf = file_path.to_owned();
 
         // Normalize the path within the cache
         let mut file_path = file_path.to_unix();
         file_path.make_canonical_for_tar(file_info.is_dir());
 
-        let mut header = Self::create_header(&source_path, &file_info)?;
+        let mut header = Self::create_header(&source_path, &file_info, self.mtimes)?;
+
+        let kind = match header.entry_type() {
+            EntryType::Directory => ArchiveEntryKind::Directory,
+            EntryType::Symlink => ArchiveEntryKind::Symlink,
+            _ => ArchiveEntryKind::File,
+        };
+        self.index.push(ArchiveEntry {
+            path: file_path_buf.clone(),
+            kind,
+            size: if kind == ArchiveEntryKind::File {
+                file_info.len()
+            } else {
+                0
+            },
+            mode: header.mode()?,
+            offset: self.bytes_written.load(Ordering::Relaxed),
+            digest: None,
+            link: None,
+        });
+
+        if self.xattrs {
+            let xattrs = read_xattrs(&source_path)?;
+            if !xattrs.is_empty() {
+                let (xattrs_header, records) = pax_header(&xattrs)?;
+                self.builder.append(&xattrs_header, records.as_slice())?;
+            }
+        }
 
-        if matches!(header.entry_type(), EntryType::Regular) && file_info.len() > 0 {
-            let file = source_path.open()?;
-            self.append_data(&mut header, file_path.as_str(), file)?;
+        let digest = if matches!(header.entry_type(), EntryType::Regular) && file_info.len() > 0 {
+            // Sparse files aren't deduplicated, since restoring a duplicate
+            // keeps its original in memory
+            let sparse_digest =
+                self.add_sparse(&mut header, file_path.as_str(), &source_path, &file_info)?;
+            let digest = if sparse_digest.is_some() {
+                sparse_digest
+            } else {
+                let (digest, original) = self.find_original(&source_path, file_info.len())?;
+                match original {
+                    Some(original) => {
+                        header.set_entry_type(EntryType::Link);
+                        header.set_size(0);
+                        self.builder.append_link(
+                            &mut header,
+                            file_path.as_str(),
+                            original.to_unix().as_str(),
+                        )?;
+                        if let Some(entry) = self.index.last_mut() {
+                            entry.link = Some(original);
+                        }
+                        digest
+                    }
+                    None => {
+                        let mut file = DigestingReader::new(source_path.open()?);
+                        self.append_data(&mut header, file_path.as_str(), &mut file)?;
+                        let digest = file.digest();
+                        if self.dedupe {
+                            self.originals
+                                .entry(file_info.len())
+                                .or_default()
+                                .push((digest.clone(), file_path_buf));
+                        }
+                        Some(digest)
+                    }
+                }
+            };
+            self.progress.entry_done(file_info.len());
+            digest
         } else {
+            let digest = match kind {
+                ArchiveEntryKind::File => Some(digest_bytes(&[])),
+                ArchiveEntryKind::Symlink => {
+                    header.link_name_bytes().map(|target| digest_bytes(&target))
+                }
+                ArchiveEntryKind::Directory => None,
+            };
             self.append_data(&mut header, file_path.as_str(), &mut std::io::empty())?;
+            self.progress.entry_done(0);
+            digest
+        };
+        if let Some(entry) = self.index.last_mut() {
+            entry.digest = digest;
         }
 
         Ok(())
     }
 
+    // Adds a file with holes as a sparse entry, returning its digest. Returns
+    // `None` without adding anything if the file has no holes.
+    fn add_sparse(
+        &mut self,
+        header: &mut Header,
+        path: &str,
+        source_path: &AbsoluteSystemPath,
+        file_info: &fs::Metadata,
+    ) -> Result<Option<String>, CacheError> {
+        if !self.sparse {
+            return Ok(None);
+        }
+        let file = source_path.open()?;
+        let Some(regions) = data_regions(&file, file_info)? else {
+            return Ok(None);
+        };
+
+        // The extension headers describing regions that don't fit in the
+        // header come before the data
+        let extensions = set_sparse_header(header, &regions, file_info.len());
+        let mut contents = SparseReader::new(&file, regions);
+        self.append_data(header, path, extensions.as_slice().chain(&mut contents))?;
+
+        Ok(Some(contents.digest(file_info.len())))
+    }
+
+    // Finds an earlier file with the same contents as the one at
+    // `source_path`. Files are only hashed here when one of the same size has
+    // been added, and the digest is returned so they aren't hashed again.
+    fn find_original(
+        &self,
+        source_path: &AbsoluteSystemPath,
+        size: u64,
+    ) -> Result<(Option<String>, Option<AnchoredSystemPathBuf>), CacheError> {
+        let Some(candidates) = self.originals.get(&size) else {
+            return Ok((None, None));
+        };
+
+        let mut file = DigestingReader::new(source_path.open()?);
+        io::copy(&mut file, &mut io::sink())?;
+        let digest = file.digest();
+        let original = candidates
+            .iter()
+            .find(|(candidate, _)| *candidate == digest)
+            .map(|(_, path)| path.clone());
+
+        Ok((Some(digest), original))
+    }
+
     fn create_header(
         source_path: &AbsoluteSystemPath,
         file_info: &fs::Metadata,
+        mtimes: bool,
     ) -> Result<Header, CacheError> {
         let mut header = Header::new_gnu();
 
@@ -120,9 +451,11 @@ impl<'a> CacheWriter<'a> {
 
         // Do we need to populate the additional linkname field in Header?
         if file_info.is_symlink() {
+            // Targets are archived verbatim, so relative links stay relative
             let link = source_path.read_link()?;
             header.set_link_name(link)?;
             header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
         } else if file_info.is_dir() {
             header.set_size(0);
             header.set_entry_type(EntryType::Directory);
@@ -142,6 +475,15 @@ impl<'a> CacheWriter<'a> {
         header.set_mtime(0);
         header.as_gnu_mut().unwrap().set_ctime(0);
 
+        if mtimes && file_info.is_file() {
+            // Files from before the epoch are treated as having no mtime
+            let mtime = file_info
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |mtime| mtime.as_secs());
+            header.set_mtime(mtime);
+        }
+
         Ok(header)
     }
 }
@@ -272,9 +614,9 @@ mod tests {
                 file_type: FileType::File,
             }
         ],
-        "2e6febdd2e8180f91f481ae58510e4afd3f071e66b7b64d82616ebb2d2d560b9a8a814e41f723cdaa5faec90405818421d590fcf8e617df0aabaa6fc61427d4f",
-        "0ece16efdb0b7e2a087e622ed52f29f21a4c080d77c31c4ed940b57dcdcb1f60b910d15232c0a2747325c22dadbfd069f15de969626dc49746be2d4b9b22e239",
-        "2e8ad9651964faa76082306dc95bff86fa0db821681e7a8acb982244ce0a9375417e867c3a9cb82f70bc6f03c7fb085e402712d3e9f27b980d5a0c22e086f4e2",
+        "a18d77592d6bfe4e88ff44d50c6f46b06350d855c6a3ea8230817a5dc4b416a0d9cbb82a6beb30de1396b87c2826c1c075108f44d169f0f2e2d6966c5711e18b",
+        "25ac95549f8aa50cb31054b30aa19fd3c00bec6e38db5364f534b6498cca0ae57a8ca6b71b9a6035803a4b44171eb69a9e63ab1d5b0427a86a7dd829d178d9ef",
+        "aee23c406b045fdb900c0588be2635d7070c9d2d9d35a58abfcbf51faeada1dc3c8d6fd323ab26d49ac9e6bd05d6e2aedcbbb3ff690b5c0779cba5a7a35341c4",
         None
         ; "create symlinks"
     )]
@@ -305,9 +647,9 @@ mod tests {
                 file_type: FileType::Symlink { linkname: "two".to_string() },
             },
         ],
-        "40ce0d42109bb5e5a6b1d4ba9087a317b4c1c6c51822a57c9cb983f878b0ff765637c05fadd4bac32c8dd2b496c2a24825b183d9720b0cdd5b33f9248b692cc1",
-        "c113763393a9fb498cc676e1fe4843206cda665afe2144829fe7434da9e81f0cf6d11386fa79877d3c514d108f9696740256af952b57d32216fbed2eb2fb049d",
-        "fe692a000551a60da6cc303a9552a16d7ed5c462e33153a96824e96596da6d642fc671448f06f34e9685a13fe5bbb4220f59db73a856626b8a0962916a8f5ea3",
+        "104b9251fcadbce43b8793a9a98222408b0fa1b3764c4a5c44b01b9f7b1934cc519b1ad3b895e4f25d43fdb01dcf7761ba19cbb5997af6398bf8d27136d8b889",
+        "573717fece5ea288ca06d97ab223e15931ec93c1e5a310f2cfc445e89a16f157cdb60fb26027669c1d2ac95854e2cd70b6432d042f2ac1b8c59f9b9c43e5b755",
+        "dcc1c2449c65a06edc5b11d9c8b4dec2beaab97d3b1052c8632d4fa2f8b04685d44beb909fd4904aaa34244957215580b6dbef871dc8961501f0735f9ced72ea",
         None
         ; "create broken symlink"
     )]
@@ -342,7 +684,7 @@ mod tests {
                 AbsoluteSystemPathBuf::try_from(archive_dir.path().join("out.tar"))?
             };
 
-            let mut cache_archive = CacheWriter::create(&archive_path)?;
+            let mut cache_archive = CacheWriter::create(&archive_path, 0, 0)?;
 
             for file in files.iter() {
                 let result = create_entry(&input_dir_path, file);
@@ -413,7 +755,7 @@ mod tests {
 
         let anchor = AbsoluteSystemPath::new(dir.path().to_str().unwrap())?;
         let out_path = anchor.join_component("test.tar");
-        let mut archive = CacheWriter::create(&out_path)?;
+        let mut archive = CacheWriter::create(&out_path, 0, 0)?;
         let really_long_file = AnchoredSystemPath::new("this-is-a-really-really-really-long-path-like-so-very-long-that-i-can-list-all-of-my-favorite-directors-like-edward-yang-claire-denis-lucrecia-martel-wong-kar-wai-even-kurosawa").unwrap();
 
         let really_long_path = anchor.resolve(really_long_file);
@@ -423,6 +765,129 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_multithreaded_compression() -> Result<()> {
+        let input_dir = tempdir()?;
+        let archive_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let archive_dir_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?;
+
+        let file = AnchoredSystemPath::new("large-file")?;
+        // Large enough that zstd splits the work into multiple jobs
+        let contents: Vec<u8> = (0..8 * 2usize.pow(20)).map(|i| (i % 251) as u8).collect();
+        input_dir_path
+            .resolve(file)
+            .create_with_contents(&contents)?;
+
+        let archive_path = archive_dir_path.join_component("out.tar.zst");
+        let mut archive = CacheWriter::create(&archive_path, 0, 4)?;
+        archive.add_file(input_dir_path, file)?;
+        archive.finish()?;
+
+        let output_dir = tempdir()?;
+        let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        let restored = CacheReader::open(&archive_path)?.restore(output_dir_path)?;
+        assert_eq!(restored, vec![file.to_owned()]);
+        assert_eq!(output_dir_path.resolve(file).read()?, contents);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_modes_and_mtimes() -> Result<()> {
+        let input_dir = tempdir()?;
+        let archive_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let archive_dir_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?;
+
+        let dir = AnchoredSystemPathBuf::from_raw("bin")?;
+        let script = dir.join_component("run");
+        let data = AnchoredSystemPathBuf::from_raw("data.txt")?;
+        input_dir_path.resolve(&dir).create_dir_all()?;
+        input_dir_path
+            .resolve(&script)
+            .create_with_contents("#!/bin/sh")?;
+        input_dir_path
+            .resolve(&data)
+            .create_with_contents("Yi Yi")?;
+        #[cfg(unix)]
+        {
+            input_dir_path.resolve(&script).set_mode(0o755)?;
+            input_dir_path.resolve(&data).set_mode(0o600)?;
+        }
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        for file in [&script, &data] {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(input_dir_path.resolve(file).as_path())?
+                .set_modified(mtime)?;
+        }
+
+        let archive_path = archive_dir_path.join_component("out.tar.zst");
+        let mut archive = CacheWriter::create(&archive_path, 0, 0)?.with_mtimes(true);
+        for file in [&dir, &script, &data] {
+            archive.add_file(input_dir_path, file)?;
+        }
+        archive.finish()?;
+
+        // Paths in the archive use forward slashes on every platform
+        let raw_archive = zstd::Decoder::new(archive_path.open()?)?;
+        let paths = tar::Archive::new(raw_archive)
+            .entries()?
+            .map(|entry| Ok(String::from_utf8(entry?.path_bytes().into_owned())?))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(paths, vec!["bin/", "bin/run", "data.txt"]);
+
+        for workers in [0, 4] {
+            let output_dir = tempdir()?;
+            let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            // An existing file keeps its mode unless the restore sets it
+            output_dir_path.resolve(&dir).create_dir_all()?;
+            output_dir_path
+                .resolve(&script)
+                .create_with_contents("old")?;
+
+            CacheReader::open(&archive_path)?
+                .with_mtimes(true)
+                .restore_with_workers(output_dir_path, workers)?;
+
+            for file in [&script, &data] {
+                let metadata = output_dir_path.resolve(file).symlink_metadata()?;
+                assert_eq!(metadata.modified()?, mtime);
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = |file| -> Result<u32> {
+                    let metadata = output_dir_path.resolve(file).symlink_metadata()?;
+                    Ok(metadata.permissions().mode() & 0o777)
+                };
+                assert_eq!(mode(&script)?, 0o755);
+                assert_eq!(mode(&data)?, 0o600);
+            }
+            #[cfg(windows)]
+            {
+                let metadata = output_dir_path.resolve(&script).symlink_metadata()?;
+                assert!(!metadata.permissions().readonly());
+            }
+        }
+
+        // Without mtimes every header has an mtime of 0
+        let archive_path = archive_dir_path.join_component("no-mtimes.tar");
+        let mut archive = CacheWriter::create(&archive_path, 0, 0)?;
+        archive.add_file(input_dir_path, &data)?;
+        archive.finish()?;
+        let output_dir = tempdir()?;
+        let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        CacheReader::open(&archive_path)?
+            .with_mtimes(true)
+            .restore(output_dir_path)?;
+        let metadata = output_dir_path.resolve(&data).symlink_metadata()?;
+        assert_ne!(metadata.modified()?, mtime);
+
+        Ok(())
+    }
+
     #[test]
     fn test_compression() -> Result<()> {
         let mut buffer = Vec::new();
@@ -439,4 +904,306 @@ mod tests {
 
         Ok(())
     }
+
+    // Accepts `limit` bytes and fails after that, like a full disk
+    struct LimitedWriter {
+        limit: usize,
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.limit == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "no space left"));
+            }
+            let n = buf.len().min(self.limit);
+            self.limit -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_finish_errors() -> Result<()> {
+        let input_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        input_dir_path
+            .resolve(&file)
+            .create_with_contents("My Blueberry Nights")?;
+
+        // The compressed archive only reaches the writer once it's finished,
+        // so that's where failing to write it shows up
+        let mut archive = CacheWriter::from_writer(LimitedWriter { limit: 10 }, true)?;
+        archive.add_file(input_dir_path, &file)?;
+        assert!(archive.finish().is_err());
+
+        let mut archive = CacheWriter::from_writer(LimitedWriter { limit: 1 << 20 }, true)?;
+        archive.add_file(input_dir_path, &file)?;
+        archive.finish()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_logs() -> Result<()> {
+        let input_dir = tempdir()?;
+        let archive_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let archive_dir_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        input_dir_path.resolve(&file).create_with_contents("2046")?;
+
+        let with_logs = archive_dir_path.join_component("with-logs.tar.zst");
+        let mut archive = CacheWriter::create(&with_logs, 0, 0)?;
+        archive.add_logs(b"> build\nIn the Mood for Love\n")?;
+        archive.add_file(input_dir_path, &file)?;
+        // Logs aren't an output, so they aren't indexed
+        assert_eq!(archive.index().len(), 1);
+        archive.finish()?;
+
+        let mut logs = Vec::new();
+        let size = CacheReader::open(&with_logs)?.read_logs(&mut logs)?;
+        assert_eq!(size, Some(logs.len() as u64));
+        assert_eq!(logs, b"> build\nIn the Mood for Love\n");
+
+        // Restoring skips the logs, with or without workers
+        for workers in [0, 4] {
+            let output_dir = tempdir()?;
+            let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            let restored =
+                CacheReader::open(&with_logs)?.restore_with_workers(output_dir_path, workers)?;
+            assert_eq!(restored, vec![file.clone()]);
+            assert!(!output_dir_path.join_component(LOGS_ENTRY).exists());
+        }
+        let entries = CacheReader::open(&with_logs)?.entries()?;
+        assert_eq!(entries.len(), 1);
+
+        let without_logs = archive_dir_path.join_component("without-logs.tar.zst");
+        let mut archive = CacheWriter::create(&without_logs, 0, 0)?;
+        archive.add_file(input_dir_path, &file)?;
+        archive.finish()?;
+        assert_eq!(
+            CacheReader::open(&without_logs)?.read_logs(&mut Vec::new())?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe() -> Result<()> {
+        let input_dir = tempdir()?;
+        let archive_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let archive_dir_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?;
+
+        let [original, same_size, duplicate] = ["a/logo.svg", "a/other.svg", "b/logo.svg"]
+            .map(|path| AnchoredSystemPathBuf::from_raw(path).unwrap());
+        let contents = "Days of Being Wild ".repeat(1000);
+        for (file, contents) in [
+            (&original, contents.clone()),
+            (&same_size, contents.replace("Wild", "Mild")),
+            (&duplicate, contents.clone()),
+        ] {
+            input_dir_path.resolve(file).ensure_dir()?;
+            input_dir_path
+                .resolve(file)
+                .create_with_contents(contents)?;
+        }
+
+        let write = |path: &str, dedupe: bool| -> Result<(AbsoluteSystemPathBuf, Vec<_>)> {
+            let archive_path = archive_dir_path.join_component(path);
+            let mut archive = CacheWriter::create(&archive_path, 0, 0)?.with_dedupe(dedupe);
+            for file in [&original, &same_size, &duplicate] {
+                archive.add_file(input_dir_path, file)?;
+            }
+            let index = archive.index().to_vec();
+            archive.finish()?;
+            Ok((archive_path, index))
+        };
+        let (deduped, index) = write("deduped.tar", true)?;
+        let (full, _) = write("full.tar", false)?;
+        assert!(deduped.symlink_metadata()?.len() < full.symlink_metadata()?.len());
+
+        // The duplicate is listed like any other file
+        assert_eq!(index[2].link, Some(original.clone()));
+        assert_eq!(index[2].size, index[0].size);
+        assert_eq!(index[2].digest, index[0].digest);
+        assert_eq!(index[1].link, None);
+        assert_eq!(CacheReader::open(&deduped)?.entries()?, index);
+
+        for workers in [0, 4] {
+            let output_dir = tempdir()?;
+            let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            let restored =
+                CacheReader::open(&deduped)?.restore_with_workers(output_dir_path, workers)?;
+            assert!(restored.contains(&duplicate));
+            assert_eq!(
+                output_dir_path.resolve(&duplicate).read_to_string()?,
+                contents
+            );
+
+            // Restored as a copy rather than a link
+            output_dir_path
+                .resolve(&original)
+                .create_with_contents("Happy Together")?;
+            assert_eq!(
+                output_dir_path.resolve(&duplicate).read_to_string()?,
+                contents
+            );
+        }
+
+        // Restoring only the duplicate needs its original's contents kept
+        let only_duplicate = |path: &AnchoredSystemPath| *path == *duplicate;
+        let output_dir = tempdir()?;
+        let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        assert!(CacheReader::open(&deduped)?
+            .restore_matching(output_dir_path, only_duplicate, None)
+            .is_err());
+        let restored = CacheReader::open(&deduped)?
+            .with_link_targets([original.clone()].into())
+            .restore_matching(output_dir_path, only_duplicate, None)?;
+        assert_eq!(restored, vec![duplicate.clone()]);
+        assert_eq!(
+            output_dir_path.resolve(&duplicate).read_to_string()?,
+            contents
+        );
+        assert!(!output_dir_path.resolve(&original).exists());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sparse() -> Result<()> {
+        use std::{
+            io::{Seek, SeekFrom},
+            os::unix::fs::MetadataExt,
+        };
+
+        let input_dir = tempdir()?;
+        let archive_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let archive_dir_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?;
+
+        // More regions than fit in the header, and a trailing hole
+        let sparse = AnchoredSystemPathBuf::from_raw("disk.img")?;
+        let mut file = fs::File::create(input_dir_path.resolve(&sparse).as_std_path())?;
+        for i in 0..30 {
+            file.seek(SeekFrom::Start(i * 2u64.pow(16)))?;
+            file.write_all(format!("The Grandmaster {}", i).as_bytes())?;
+        }
+        let size = 2u64.pow(21);
+        file.set_len(size)?;
+        drop(file);
+        let is_sparse = |path: &AbsoluteSystemPath| -> Result<bool> {
+            let metadata = path.symlink_metadata()?;
+            Ok(metadata.blocks() * 512 < metadata.len())
+        };
+        if !is_sparse(&input_dir_path.resolve(&sparse))? {
+            // Holes aren't supported by this filesystem
+            return Ok(());
+        }
+
+        let write = |path: &str, sparse_files: bool| -> Result<(AbsoluteSystemPathBuf, Vec<_>)> {
+            let archive_path = archive_dir_path.join_component(path);
+            let mut archive = CacheWriter::create(&archive_path, 0, 0)?.with_sparse(sparse_files);
+            archive.add_file(input_dir_path, &sparse)?;
+            let index = archive.index().to_vec();
+            archive.finish()?;
+            Ok((archive_path, index))
+        };
+        let (sparse_archive, index) = write("sparse.tar", true)?;
+        let (full_archive, full_index) = write("full.tar", false)?;
+        assert!(sparse_archive.symlink_metadata()?.len() < size / 10);
+        assert!(full_archive.symlink_metadata()?.len() > size);
+
+        // Listed with the size and digest of its contents
+        let contents = input_dir_path.resolve(&sparse).read()?;
+        assert_eq!(index, full_index);
+        assert_eq!(index[0].size, size);
+        assert_eq!(index[0].digest, Some(digest_bytes(&contents)));
+        assert_eq!(CacheReader::open(&sparse_archive)?.entries()?, index);
+
+        for workers in [0, 4] {
+            let output_dir = tempdir()?;
+            let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            let restored = CacheReader::open(&sparse_archive)?
+                .restore_with_workers(output_dir_path, workers)?;
+            assert_eq!(restored, vec![sparse.clone()]);
+            let restored_path = output_dir_path.resolve(&sparse);
+            assert_eq!(restored_path.read()?, contents);
+            assert!(is_sparse(&restored_path)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_xattrs() -> Result<()> {
+        let input_dir = tempdir()?;
+        let archive_dir = tempdir()?;
+        let input_dir_path = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let archive_dir_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?;
+
+        let dir = AnchoredSystemPathBuf::from_raw("dist")?;
+        let file = AnchoredSystemPathBuf::from_raw("dist/app")?;
+        input_dir_path.resolve(&dir).create_dir_all()?;
+        input_dir_path
+            .resolve(&file)
+            .create_with_contents("Chungking Express")?;
+        for path in [&dir, &file] {
+            let path = input_dir_path.resolve(path);
+            match xattr::set(path.as_std_path(), "user.turbo", b"Fallen Angels") {
+                Ok(()) => {}
+                // Extended attributes aren't supported by this filesystem
+                Err(_) => return Ok(()),
+            }
+        }
+
+        let archive_path = archive_dir_path.join_component("out.tar");
+        let mut archive = CacheWriter::create(&archive_path, 0, 0)?.with_xattrs(true);
+        archive.add_file(input_dir_path, &dir)?;
+        archive.add_file(input_dir_path, &file)?;
+        let index = archive.index().to_vec();
+        archive.finish()?;
+        assert_eq!(CacheReader::open(&archive_path)?.entries()?, index);
+
+        for workers in [0, 4] {
+            for xattrs in [true, false] {
+                let output_dir = tempdir()?;
+                let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+                let restored = CacheReader::open(&archive_path)?
+                    .with_xattrs(xattrs)
+                    .restore_with_workers(output_dir_path, workers)?;
+                assert_eq!(restored, vec![dir.clone(), file.clone()]);
+                assert_eq!(
+                    output_dir_path.resolve(&file).read_to_string()?,
+                    "Chungking Express"
+                );
+                for path in [&dir, &file] {
+                    let value =
+                        xattr::get(output_dir_path.resolve(path).as_std_path(), "user.turbo")?;
+                    assert_eq!(value, xattrs.then(|| b"Fallen Angels".to_vec()));
+                }
+            }
+        }
+
+        // An entry's offset includes the header holding its attributes
+        let output_dir = tempdir()?;
+        let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        output_dir_path.resolve(&dir).create_dir_all()?;
+        CacheReader::open_at(&archive_path, index[1].offset)?
+            .with_xattrs(true)
+            .restore(output_dir_path)?;
+        let value = xattr::get(output_dir_path.resolve(&file).as_std_path(), "user.turbo")?;
+        assert_eq!(value, Some(b"Fallen Angels".to_vec()));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,341 @@
+use std::{
+    backtrace::Backtrace,
+    env, fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::{Digest, Sha256};
+
+use crate::CacheError;
+
+/// The environment variable holding the base64 encoded key used to encrypt
+/// the filesystem cache.
+pub const ENCRYPTION_KEY_ENV: &str = "TURBO_CACHE_ENCRYPTION_KEY";
+
+const KEY_SIZE: usize = 32;
+// Starts every encrypted archive. The last byte is the format version.
+const MAGIC: &[u8; 8] = b"TURBOAE\x01";
+// Followed by a random prefix shared by the nonces of every chunk
+const NONCE_PREFIX_SIZE: usize = 7;
+// Plaintext is sealed in chunks of this size, each followed by its tag.
+// Only the last chunk is smaller, which is how readers know it's the last.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// A key for AES-256-GCM encryption of archives.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+// Keeps the key out of logs
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EncryptionKey").field(&self.id()).finish()
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self(key)
+    }
+
+    /// Parses a base64 encoded 32 byte key, such as the output of
+    /// `openssl rand -base64 32`.
+    pub fn from_base64(encoded: &str) -> Result<Self, CacheError> {
+        BASE64_STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .map(Self)
+            .ok_or_else(|| CacheError::InvalidEncryptionKey(Backtrace::capture()))
+    }
+
+    /// Reads the key from `TURBO_CACHE_ENCRYPTION_KEY`, if it is set.
+    pub fn from_env() -> Result<Option<Self>, CacheError> {
+        env::var(ENCRYPTION_KEY_ENV)
+            .ok()
+            .map(|encoded| Self::from_base64(&encoded))
+            .transpose()
+    }
+
+    /// Identifies the key without revealing it, so that archives encrypted
+    /// with a different key can be told apart.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"turbo cache encryption key id");
+        hasher.update(self.0);
+        hex::encode(&hasher.finalize()[..8])
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &self.0).expect("key is the right size for AES-256"),
+        )
+    }
+}
+
+// The nonce of each chunk is the archive's prefix, the chunk's index and
+// whether it is the last chunk, so chunks can't be reordered, dropped or
+// truncated without failing to decrypt.
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..aead::NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[aead::NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn next_counter(counter: u32) -> io::Result<u32> {
+    counter
+        .checked_add(1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "archive is too large to encrypt"))
+}
+
+/// Checks whether `file` starts like an encrypted archive, leaving it at the
+/// start.
+pub(crate) fn is_encrypted(mut file: impl Read + Seek) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    (&mut file)
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(magic == MAGIC)
+}
+
+// Encrypts everything written to it. The last chunk is only written by
+// `finish`, without which the archive fails to decrypt.
+pub(crate) struct EncryptingWriter<W: Write> {
+    writer: W,
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(mut writer: W, key: &EncryptionKey) -> io::Result<Self> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        SystemRandom::new()
+            .fill(&mut nonce_prefix)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to generate a nonce"))?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&nonce_prefix)?;
+
+        Ok(Self {
+            writer,
+            key: key.aead_key(),
+            nonce_prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+        })
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut self.buffer)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to encrypt archive"))?;
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.counter = next_counter(self.counter)?;
+        Ok(())
+    }
+
+    /// Writes the last chunk, which marks the end of the archive, and
+    /// returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // The last chunk has to be short, even if it's empty
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        self.seal_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    // Only whole chunks are written, so a partial chunk stays buffered
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Decrypts an archive written by `EncryptingWriter`, failing if it was
+// encrypted with a different key or has been tampered with.
+pub(crate) struct DecryptingReader<R: Read> {
+    reader: R,
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    // The current chunk, which holds `plaintext_size` bytes of plaintext once
+    // decrypted
+    chunk: Vec<u8>,
+    plaintext_size: usize,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(mut reader: R, key: &EncryptionKey) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive is not encrypted",
+            ));
+        }
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        reader.read_exact(&mut nonce_prefix)?;
+
+        Ok(Self {
+            reader,
+            key: key.aead_key(),
+            nonce_prefix,
+            counter: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            plaintext_size: 0,
+            position: 0,
+            done: false,
+        })
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        self.chunk.clear();
+        (&mut self.reader)
+            .take((CHUNK_SIZE + TAG_SIZE) as u64)
+            .read_to_end(&mut self.chunk)?;
+        let last = self.chunk.len() < CHUNK_SIZE + TAG_SIZE;
+
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        self.plaintext_size = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut self.chunk)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "failed to decrypt archive, it is truncated, tampered with or was encrypted \
+                     with a different key",
+                )
+            })?
+            .len();
+        self.position = 0;
+        self.counter = next_counter(self.counter)?;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext_size {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+
+        let n = buf.len().min(self.plaintext_size - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use super::*;
+
+    fn encrypt(contents: &[u8], key: &EncryptionKey) -> Result<Vec<u8>> {
+        let mut encrypted = Vec::new();
+        let mut writer = EncryptingWriter::new(&mut encrypted, key)?;
+        writer.write_all(contents)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+
+    fn decrypt(encrypted: &[u8], key: &EncryptionKey) -> io::Result<Vec<u8>> {
+        let mut decrypted = Vec::new();
+        DecryptingReader::new(encrypted, key)?.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn test_encryption_round_trip() -> Result<()> {
+        let key = EncryptionKey::new([7; KEY_SIZE]);
+        // Includes a chunk boundary and an exact multiple of the chunk size
+        for size in [0, 100, CHUNK_SIZE, CHUNK_SIZE * 2 + 100] {
+            let contents: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt(&contents, &key)?;
+            assert!(is_encrypted(Cursor::new(&encrypted))?);
+            assert_eq!(decrypt(&encrypted, &key)?, contents);
+        }
+        assert!(!is_encrypted(Cursor::new(b"TURBO"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decryption_failures() -> Result<()> {
+        let key = EncryptionKey::new([7; KEY_SIZE]);
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt(&contents, &key)?;
+
+        let other_key = EncryptionKey::new([8; KEY_SIZE]);
+        assert_ne!(key.id(), other_key.id());
+        assert!(decrypt(&encrypted, &other_key).is_err());
+
+        let mut tampered = encrypted.clone();
+        tampered[100] ^= 1;
+        assert!(decrypt(&tampered, &key).is_err());
+
+        // Truncated both at a chunk boundary and within a chunk
+        let header_size = MAGIC.len() + NONCE_PREFIX_SIZE;
+        let chunk_boundary = header_size + CHUNK_SIZE + TAG_SIZE;
+        assert!(decrypt(&encrypted[..chunk_boundary], &key).is_err());
+        assert!(decrypt(&encrypted[..chunk_boundary + 100], &key).is_err());
+
+        // So is an archive that was never finished
+        let mut unfinished = Vec::new();
+        let mut writer = EncryptingWriter::new(&mut unfinished, &key)?;
+        writer.write_all(&contents)?;
+        drop(writer);
+        assert!(decrypt(&unfinished, &key).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_from_base64() -> Result<()> {
+        let encoded = BASE64_STANDARD.encode([7; KEY_SIZE]);
+        assert_eq!(
+            EncryptionKey::from_base64(&format!("{}\n", encoded))?,
+            EncryptionKey::new([7; KEY_SIZE])
+        );
+        assert!(EncryptionKey::from_base64("c2hvcnQ=").is_err());
+        assert!(EncryptionKey::from_base64("not base64!").is_err());
+        // The key itself is never printed
+        assert!(!format!("{:?}", EncryptionKey::new([7; KEY_SIZE])).contains("7, 7"));
+
+        Ok(())
+    }
+}
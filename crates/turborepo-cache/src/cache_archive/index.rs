@@ -0,0 +1,135 @@
+use std::{
+    io,
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use turbopath::AnchoredSystemPathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ArchiveEntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Describes a single entry of an archive without having to read it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub path: AnchoredSystemPathBuf,
+    pub kind: ArchiveEntryKind,
+    pub size: u64,
+    pub mode: u32,
+    // Where the entry's headers start in the uncompressed tar
+    pub offset: u64,
+    // Hex encoded SHA-256 of a file's contents, or of a symlink's target.
+    // Missing from indexes written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    // The earlier file of the archive that this one is a duplicate of, for
+    // files stored once by `CacheWriter::with_dedupe`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<AnchoredSystemPathBuf>,
+}
+
+// Hashes what's read through it, so that a file's digest is computed while
+// it's being archived rather than by reading it twice.
+pub(crate) struct DigestingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn digest(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for DigestingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+pub(crate) fn digest_bytes(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+// Counts the bytes read through it, so that listing an archive can tell where
+// each entry's headers start, including any extension headers before it.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> (Self, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        (
+            CountingReader {
+                inner,
+                count: count.clone(),
+            },
+            count,
+        )
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// Counts the bytes written through it so that we know where each entry
+// starts in the tar stream, before it gets compressed.
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> (Self, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        (
+            CountingWriter {
+                inner,
+                count: count.clone(),
+            },
+            count,
+        )
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,9 +1,89 @@
 #![allow(dead_code)]
+mod case_collision;
 mod create;
+mod encryption;
+mod index;
+mod progress;
 mod restore;
 mod restore_directory;
+mod restore_hardlink;
 mod restore_regular;
+mod restore_sparse;
 mod restore_symlink;
+mod seekable;
+mod sparse;
+mod xattrs;
 
 pub use create::CacheWriter;
+pub use encryption::{EncryptionKey, ENCRYPTION_KEY_ENV};
+pub use index::{ArchiveEntry, ArchiveEntryKind};
+pub use progress::Progress;
+pub(crate) use progress::ProgressReporter;
 pub use restore::CacheReader;
+pub(crate) use restore_directory::CachedDirTree;
+pub(crate) use restore_regular::{check_existing, file_digest, write_regular, ExistingFile};
+pub(crate) use restore_symlink::checked_symlink_target;
+use turbopath::AbsoluteSystemPath;
+pub(crate) use xattrs::{read_xattrs, write_xattrs};
+
+/// The path of the entry holding a task's logs, see `CacheWriter::add_logs`.
+/// Restoring an archive skips it, so no output can have this path.
+pub const LOGS_ENTRY: &str = ".turbo-artifact-logs";
+
+/// How an archive is compressed. The algorithm is encoded in the archive's
+/// file extension so that it can be read back without any extra metadata.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Zstd,
+    Lz4,
+    None,
+}
+
+impl CompressionAlgorithm {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Zstd => "tar.zst",
+            CompressionAlgorithm::Lz4 => "tar.lz4",
+            CompressionAlgorithm::None => "tar",
+        }
+    }
+
+    pub fn from_path(path: &AbsoluteSystemPath) -> Self {
+        match path.extension() {
+            Some("zst") => CompressionAlgorithm::Zstd,
+            Some("lz4") => CompressionAlgorithm::Lz4,
+            _ => CompressionAlgorithm::None,
+        }
+    }
+}
+
+/// What restoring an archive does with symlinks whose targets are absolute
+/// or lead outside of the directory being restored into. Files are never
+/// written through such a symlink, whatever the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Restore every target as it was archived.
+    Verbatim,
+    /// Fail the restore at the first absolute or escaping target.
+    #[default]
+    RejectEscaping,
+    /// Rewrite absolute targets inside of the restore directory as relative
+    /// ones, and fail the restore on targets outside of it.
+    RelativizeAbsolute,
+}
+
+/// What restoring an archive does when one of its regular files already
+/// exists. Directories are always reused and symlinks always replaced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    #[default]
+    Overwrite,
+    SkipExisting,
+    /// Fails the restore at the first file that already exists. Files
+    /// restored before it are left in place.
+    FailIfExists,
+    /// Only writes files whose contents differ from the existing file, so
+    /// that identical files keep their mtime.
+    OnlyIfDifferent,
+}
@@ -0,0 +1,36 @@
+/// How far a restore or a write of an archive has got. Every entry counts as
+/// a file, while `bytes` only counts the contents of regular files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub files: u64,
+    pub bytes: u64,
+    // Only known when the caller knows what the archive contains up front
+    pub total_files: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+// Accumulates progress and hands it to the callback, if there is one, after
+// every entry.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ProgressReporter<'a> {
+    callback: Option<&'a dyn Fn(Progress)>,
+    progress: Progress,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub fn new(callback: Option<&'a dyn Fn(Progress)>) -> Self {
+        Self {
+            callback,
+            progress: Progress::default(),
+        }
+    }
+
+    pub fn entry_done(&mut self, bytes: u64) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        self.progress.files += 1;
+        self.progress.bytes += bytes;
+        callback(self.progress);
+    }
+}
@@ -1,23 +1,77 @@
-use std::{backtrace::Backtrace, collections::HashMap, io::Read};
+use std::{
+    backtrace::Backtrace,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io,
+    io::{Read, Seek, SeekFrom, Write},
+    sync::atomic::Ordering,
+    time::SystemTime,
+};
 
 use petgraph::graph::DiGraph;
 use sha2::{Digest, Sha512};
 use tar::Entry;
-use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
+use turbopath::{
+    AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPath, AnchoredSystemPathBuf,
+};
 
 use crate::{
     cache_archive::{
+        case_collision::CaseCollisions,
+        encryption::{is_encrypted, DecryptingReader},
+        index::{digest_bytes, CountingReader, DigestingReader},
         restore_directory::{restore_directory, CachedDirTree},
-        restore_regular::restore_regular,
+        restore_hardlink::{prepare_hardlink, restore_copies, restore_original},
+        restore_regular::{
+            check_existing, create_regular, prepare_regular, restore_regular, restored_mtime,
+            set_mtime, update_regular, write_regular, ExistingFile,
+        },
+        restore_sparse::restore_sparse,
         restore_symlink::{
             canonicalize_linkname, restore_symlink, restore_symlink_allow_missing_target,
         },
+        seekable::read_seek_table,
+        xattrs::restore_xattrs,
+        ArchiveEntry, ArchiveEntryKind, CompressionAlgorithm, EncryptionKey, OverwritePolicy,
+        Progress, ProgressReporter, SymlinkPolicy, LOGS_ENTRY,
     },
     CacheError,
 };
 
+// Regular files larger than this are written by the reading thread during a
+// parallel restore rather than being buffered for a worker.
+const PARALLEL_RESTORE_BUFFER_LIMIT: u64 = 4 * 1024 * 1024;
+
 pub struct CacheReader<'a> {
     reader: Box<dyn Read + 'a>,
+    options: RestoreOptions<'a>,
+    progress: Option<&'a dyn Fn(Progress)>,
+    link_targets: HashSet<AnchoredSystemPathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RestoreOptions<'a> {
+    pub overwrite_policy: OverwritePolicy,
+    pub symlink_policy: SymlinkPolicy,
+    // Restore the mtimes of regular files, if the archive recorded them
+    pub mtimes: bool,
+    // Restore the extended attributes recorded for each entry
+    pub xattrs: bool,
+    // The directory the archive was written from, when it's restored
+    // somewhere else by `restore_to`
+    pub rebase_from: Option<&'a AbsoluteSystemPath>,
+}
+
+// A regular file handed to a worker during a parallel restore
+enum RestoreJob {
+    Write(File, Option<SystemTime>, Vec<u8>),
+    // The file already exists and is only written if `contents` differ
+    Update {
+        path: AbsoluteSystemPathBuf,
+        mode: u32,
+        mtime: Option<SystemTime>,
+        contents: Vec<u8>,
+    },
 }
 
 impl<'a> CacheReader<'a> {
@@ -28,20 +82,148 @@ impl<'a> CacheReader<'a> {
             Box::new(reader)
         };
 
-        Ok(CacheReader { reader })
+        Ok(CacheReader {
+            reader,
+            options: RestoreOptions::default(),
+            progress: None,
+            link_targets: HashSet::new(),
+        })
     }
 
     pub fn open(path: &AbsoluteSystemPathBuf) -> Result<Self, CacheError> {
-        let file = path.open()?;
-        let is_compressed = path.extension() == Some("zst");
+        Self::open_at(path, 0)
+    }
 
-        let reader: Box<dyn Read> = if is_compressed {
-            Box::new(zstd::Decoder::new(file)?)
+    /// Opens the archive at `path` so that reading starts `offset` bytes into
+    /// the uncompressed tar, which should be where an entry starts. Seekable
+    /// zstd archives are decompressed from the frame containing `offset`;
+    /// anything else is decompressed from the start and skipped.
+    pub fn open_at(path: &AbsoluteSystemPathBuf, offset: u64) -> Result<Self, CacheError> {
+        Self::open_with_key(path, offset, None)
+    }
+
+    /// Like `open_at`, but decrypts archives written with
+    /// `CacheWriter::create_with_key`, which fail to open without `key`.
+    /// Encrypted archives are always decrypted from the start.
+    pub fn open_with_key(
+        path: &AbsoluteSystemPathBuf,
+        offset: u64,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Self, CacheError> {
+        let mut file = path.open()?;
+
+        let mut skip = offset;
+        let mut reader: Box<dyn Read> = if is_encrypted(&mut file)? {
+            let key = key.ok_or_else(|| CacheError::EncryptedArchive(Backtrace::capture()))?;
+            let file = DecryptingReader::new(file, key)?;
+            match CompressionAlgorithm::from_path(path) {
+                CompressionAlgorithm::Zstd => Box::new(zstd::Decoder::new(file)?),
+                CompressionAlgorithm::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(file)),
+                CompressionAlgorithm::None => Box::new(file),
+            }
         } else {
-            Box::new(file)
+            match CompressionAlgorithm::from_path(path) {
+                CompressionAlgorithm::Zstd => {
+                    let frame = match offset {
+                        0 => None,
+                        _ => read_seek_table(&mut file)?.and_then(|frames| {
+                            frames
+                                .into_iter()
+                                .rev()
+                                .find(|frame| frame.uncompressed_offset <= offset)
+                        }),
+                    };
+                    let compressed_offset = frame.map_or(0, |frame| frame.compressed_offset);
+                    skip -= frame.map_or(0, |frame| frame.uncompressed_offset);
+                    file.seek(SeekFrom::Start(compressed_offset))?;
+                    Box::new(zstd::Decoder::new(file)?)
+                }
+                CompressionAlgorithm::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(file)),
+                CompressionAlgorithm::None => {
+                    file.seek(SeekFrom::Start(offset))?;
+                    skip = 0;
+                    Box::new(file)
+                }
+            }
         };
+        io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+
+        Ok(CacheReader {
+            reader,
+            options: RestoreOptions::default(),
+            progress: None,
+            link_targets: HashSet::new(),
+        })
+    }
+
+    pub fn with_overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.options.overwrite_policy = overwrite_policy;
+        self
+    }
+
+    pub fn with_symlink_policy(mut self, symlink_policy: SymlinkPolicy) -> Self {
+        self.options.symlink_policy = symlink_policy;
+        self
+    }
+
+    /// Gives restored files the mtimes recorded in the archive. Archives
+    /// written without `CacheWriter::with_mtimes` don't have any.
+    pub fn with_mtimes(mut self, mtimes: bool) -> Self {
+        self.options.mtimes = mtimes;
+        self
+    }
+
+    /// Restores the extended attributes recorded by
+    /// `CacheWriter::with_xattrs`. Attributes that the filesystem doesn't
+    /// support are skipped with a warning.
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.options.xattrs = xattrs;
+        self
+    }
+
+    /// Calls `progress` after each entry is restored. Totals are left for the
+    /// caller to fill in.
+    pub fn with_progress(mut self, progress: &'a dyn Fn(Progress)) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Keeps the contents of these files in memory while restoring, so that
+    /// the duplicates stored as hardlink entries to them by
+    /// `CacheWriter::with_dedupe` can be restored even when they aren't, e.g.
+    /// because they don't match `restore_matching`. Otherwise duplicates are
+    /// copied from the restored file. The paths are recorded in the
+    /// archive's index.
+    pub fn with_link_targets(mut self, link_targets: HashSet<AnchoredSystemPathBuf>) -> Self {
+        self.link_targets = link_targets;
+        self
+    }
 
-        Ok(CacheReader { reader })
+    /// Reads the rest of the uncompressed tar.
+    pub fn read_tar(mut self) -> Result<Vec<u8>, CacheError> {
+        let mut tar = Vec::new();
+        self.reader.read_to_end(&mut tar)?;
+        Ok(tar)
+    }
+
+    /// Copies the rest of the uncompressed tar to `out` as it's read, without
+    /// the logs added by `CacheWriter::add_logs`, so that what's written can
+    /// be extracted by other tools. Returns the number of bytes written.
+    pub fn write_tar(&mut self, out: &mut impl Write) -> Result<u64, CacheError> {
+        // The logs can only be the first entry, so only its header is checked
+        let mut header = tar::Header::new_old();
+        self.reader.read_exact(header.as_mut_bytes())?;
+        let mut written = 0;
+        if header.path_bytes().as_ref() == LOGS_ENTRY.as_bytes() {
+            let size = header.entry_size()?.div_ceil(512) * 512;
+            io::copy(&mut (&mut self.reader).take(size), &mut io::sink())?;
+        } else {
+            out.write_all(header.as_bytes())?;
+            written += header.as_bytes().len() as u64;
+        }
+        written += io::copy(&mut self.reader, out)?;
+
+        Ok(written)
     }
 
     pub fn get_sha(mut self) -> Result<Vec<u8>, CacheError> {
@@ -61,6 +243,43 @@ impl<'a> CacheReader<'a> {
     pub fn restore(
         &mut self,
         anchor: &AbsoluteSystemPath,
+    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+        self.restore_with_workers(anchor, 0)
+    }
+
+    /// Restores the archive, handing regular files off to `workers` threads
+    /// to be written. Directories and symlinks are still created in archive
+    /// order on the calling thread. A value of 0 or 1 restores sequentially.
+    pub fn restore_with_workers(
+        &mut self,
+        anchor: &AbsoluteSystemPath,
+        workers: usize,
+    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+        self.restore_with_options(anchor, workers, self.options)
+    }
+
+    /// Restores the archive into `dest` rather than `anchor`, the directory
+    /// it was written from, e.g. to inspect or diff an artifact without
+    /// touching the working tree. Absolute symlink targets inside of `anchor`
+    /// are rebased onto `dest`, and the restore fails at symlinks that lead
+    /// anywhere else outside of `dest`, whatever the symlink policy.
+    pub fn restore_to(
+        &mut self,
+        anchor: &AbsoluteSystemPath,
+        dest: &AbsoluteSystemPath,
+    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+        let options = RestoreOptions {
+            rebase_from: Some(anchor),
+            ..self.options
+        };
+        self.restore_with_options(dest, 0, options)
+    }
+
+    fn restore_with_options(
+        &mut self,
+        anchor: &AbsoluteSystemPath,
+        workers: usize,
+        options: RestoreOptions,
     ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
         let mut restored = Vec::new();
         anchor.create_dir_all()?;
@@ -80,9 +299,31 @@ impl<'a> CacheReader<'a> {
         // not apply for your path, it will clobber and re-start from the common
         // shared prefix.
         let dir_cache = CachedDirTree::new(anchor.to_owned());
+        let progress = ProgressReporter::new(self.progress);
         let mut tr = tar::Archive::new(&mut self.reader);
 
-        Self::restore_entries(&mut tr, &mut restored, dir_cache, anchor)?;
+        if workers > 1 {
+            Self::restore_entries_parallel(
+                &mut tr,
+                &mut restored,
+                dir_cache,
+                anchor,
+                workers,
+                options,
+                &self.link_targets,
+                progress,
+            )?;
+        } else {
+            Self::restore_entries(
+                &mut tr,
+                &mut restored,
+                dir_cache,
+                anchor,
+                options,
+                &self.link_targets,
+                progress,
+            )?;
+        }
         Ok(restored)
     }
 
@@ -91,24 +332,440 @@ impl<'a> CacheReader<'a> {
         restored: &mut Vec<AnchoredSystemPathBuf>,
         mut dir_cache: CachedDirTree,
         anchor: &AbsoluteSystemPath,
+        options: RestoreOptions,
+        link_targets: &HashSet<AnchoredSystemPathBuf>,
+        mut progress: ProgressReporter,
     ) -> Result<(), CacheError> {
         // On first attempt to restore it's possible that a link target doesn't exist.
         // Save them and topologically sort them.
         let mut symlinks = Vec::new();
+        let mut copies = Vec::new();
+        let mut originals = HashMap::new();
+        let mut case_collisions = CaseCollisions::new(anchor);
 
         for entry in tr.entries()? {
             let mut entry = entry?;
-            match restore_entry(&mut dir_cache, anchor, &mut entry) {
+            if is_logs_entry(&entry) {
+                continue;
+            }
+            case_collisions.check(&entry)?;
+            let bytes = entry_bytes(&entry);
+            if entry.header().entry_type() == tar::EntryType::Link {
+                copies.push(prepare_hardlink(
+                    &mut dir_cache,
+                    anchor,
+                    &mut entry,
+                    options,
+                )?);
+                progress.entry_done(bytes);
+                continue;
+            }
+            let result = if is_original(&entry, link_targets)? {
+                restore_original(&mut dir_cache, anchor, &mut entry, options, &mut originals)
+            } else {
+                restore_entry(&mut dir_cache, anchor, &mut entry, options)
+            };
+            match result {
                 Err(CacheError::LinkTargetDoesNotExist(_, _)) => {
                     symlinks.push(entry);
                 }
                 Err(e) => return Err(e),
                 Ok(restored_path) => restored.push(restored_path),
             }
+            progress.entry_done(bytes);
         }
 
+        let mut restored_copies = restore_copies(anchor, copies, &originals, restored, options)?;
+        restored.append(&mut restored_copies);
         let mut restored_symlinks =
-            Self::topologically_restore_symlinks(&mut dir_cache, anchor, &symlinks)?;
+            Self::topologically_restore_symlinks(&mut dir_cache, anchor, &mut symlinks, options)?;
+        restored.append(&mut restored_symlinks);
+        Ok(())
+    }
+
+    /// Lists the entries of the archive without restoring anything.
+    pub fn entries(&mut self) -> Result<Vec<ArchiveEntry>, CacheError> {
+        let (reader, bytes_read) = CountingReader::new(&mut self.reader);
+        let mut tr = tar::Archive::new(reader);
+        let mut entries: Vec<ArchiveEntry> = Vec::new();
+        // Where each regular file is in `entries`, for the hardlink entries
+        // that duplicate one
+        let mut files = HashMap::new();
+
+        // Where the next entry's headers start. Its own header can come after
+        // extension headers, e.g. for a long path or extended attributes.
+        let mut next_offset = 0;
+        for entry in tr.entries()? {
+            let mut entry = entry?;
+            let offset = next_offset;
+            // All of an entry's headers are read before it's returned, so its
+            // data starts at what has been read so far
+            next_offset = (bytes_read.load(Ordering::Relaxed) + entry.header().entry_size()?)
+                .next_multiple_of(512);
+            if is_logs_entry(&entry) {
+                continue;
+            }
+            let header = entry.header();
+            let kind = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Link | tar::EntryType::GNUSparse => {
+                    ArchiveEntryKind::File
+                }
+                tar::EntryType::Directory => ArchiveEntryKind::Directory,
+                tar::EntryType::Symlink => ArchiveEntryKind::Symlink,
+                ty => {
+                    return Err(CacheError::RestoreUnsupportedFileType(
+                        ty,
+                        Backtrace::capture(),
+                    ))
+                }
+            };
+            let link = match header.entry_type() {
+                tar::EntryType::Link => {
+                    let original = entry
+                        .link_name()?
+                        .ok_or_else(|| CacheError::LinkTargetNotOnHeader(Backtrace::capture()))?;
+                    Some(AnchoredSystemPathBuf::from_system_path(&original)?)
+                }
+                _ => None,
+            };
+
+            // We don't record a size for symlinks. Sparse files have the size of
+            // their contents, rather than what's stored.
+            let size = if kind == ArchiveEntryKind::File {
+                entry.size()
+            } else {
+                0
+            };
+            let mode = header.mode()?;
+            let path = AnchoredSystemPathBuf::from_system_path(&entry.path()?)?;
+            let (size, digest) = match (kind, &link) {
+                (_, Some(original)) => {
+                    let original = files
+                        .get(original)
+                        .map(|&index: &usize| &entries[index])
+                        .ok_or_else(|| {
+                            CacheError::LinkTargetDoesNotExist(
+                                original.to_string(),
+                                Backtrace::capture(),
+                            )
+                        })?;
+                    (original.size, original.digest.clone())
+                }
+                (ArchiveEntryKind::File, None) => {
+                    files.insert(path.clone(), entries.len());
+                    let mut contents = DigestingReader::new(&mut entry);
+                    io::copy(&mut contents, &mut io::sink())?;
+                    (size, Some(contents.digest()))
+                }
+                (ArchiveEntryKind::Symlink, None) => (
+                    size,
+                    entry.link_name_bytes().map(|target| digest_bytes(&target)),
+                ),
+                (ArchiveEntryKind::Directory, None) => (size, None),
+            };
+
+            entries.push(ArchiveEntry {
+                path,
+                kind,
+                size,
+                mode,
+                offset,
+                digest,
+                link,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Copies the logs added with `CacheWriter::add_logs` to `out`, returning
+    /// their size, or `None` if the archive doesn't have any. Only the first
+    /// entry is read, however large the rest of the archive is.
+    pub fn read_logs(&mut self, out: &mut impl Write) -> Result<Option<u64>, CacheError> {
+        let mut tr = tar::Archive::new(&mut self.reader);
+        let Some(entry) = tr.entries()?.next() else {
+            return Ok(None);
+        };
+        let mut entry = entry?;
+        if !is_logs_entry(&entry) {
+            return Ok(None);
+        }
+
+        Ok(Some(io::copy(&mut entry, out)?))
+    }
+
+    /// Restores only the entries whose paths satisfy `matches`. If
+    /// `last_offset` is given, reading stops once the entry starting at that
+    /// offset in the uncompressed tar has been read, rather than
+    /// decompressing the rest of the archive.
+    pub fn restore_matching(
+        &mut self,
+        anchor: &AbsoluteSystemPath,
+        matches: impl Fn(&AnchoredSystemPath) -> bool,
+        last_offset: Option<u64>,
+    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+        let mut restored = Vec::new();
+        anchor.create_dir_all()?;
+
+        let mut dir_cache = CachedDirTree::new(anchor.to_owned());
+        let options = self.options;
+        let mut progress = ProgressReporter::new(self.progress);
+        let mut tr = tar::Archive::new(&mut self.reader);
+        let mut symlinks = Vec::new();
+        let mut copies = Vec::new();
+        let mut originals = HashMap::new();
+        let mut case_collisions = CaseCollisions::new(anchor);
+
+        for entry in tr.entries()? {
+            let mut entry = entry?;
+            let is_last = last_offset.map_or(false, |last_offset| {
+                entry.raw_header_position() >= last_offset
+            });
+
+            let path = AnchoredSystemPathBuf::from_system_path(&entry.path()?)?;
+            if !is_logs_entry(&entry) && matches(&path) {
+                case_collisions.check(&entry)?;
+                let bytes = entry_bytes(&entry);
+                let result = if entry.header().entry_type() == tar::EntryType::Link {
+                    copies.push(prepare_hardlink(
+                        &mut dir_cache,
+                        anchor,
+                        &mut entry,
+                        options,
+                    )?);
+                    Ok(None)
+                } else if is_original(&entry, &self.link_targets)? {
+                    restore_original(&mut dir_cache, anchor, &mut entry, options, &mut originals)
+                        .map(Some)
+                } else {
+                    restore_entry(&mut dir_cache, anchor, &mut entry, options).map(Some)
+                };
+                match result {
+                    Err(CacheError::LinkTargetDoesNotExist(_, _)) => {
+                        symlinks.push(entry);
+                    }
+                    Err(e) => return Err(e),
+                    Ok(restored_path) => restored.extend(restored_path),
+                }
+                progress.entry_done(bytes);
+            } else if is_original(&entry, &self.link_targets)? {
+                // Not restored itself, but a duplicate that is needs its contents
+                let mut contents = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut contents)?;
+                originals.insert(path, contents);
+            }
+
+            if is_last {
+                break;
+            }
+        }
+
+        let mut restored_copies = restore_copies(anchor, copies, &originals, &restored, options)?;
+        restored.append(&mut restored_copies);
+        let mut restored_symlinks =
+            Self::topologically_restore_symlinks(&mut dir_cache, anchor, &mut symlinks, options)?;
+        restored.append(&mut restored_symlinks);
+        Ok(restored)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn restore_entries_parallel<T: Read>(
+        tr: &mut tar::Archive<T>,
+        restored: &mut Vec<AnchoredSystemPathBuf>,
+        mut dir_cache: CachedDirTree,
+        anchor: &AbsoluteSystemPath,
+        workers: usize,
+        options: RestoreOptions,
+        link_targets: &HashSet<AnchoredSystemPathBuf>,
+        mut progress: ProgressReporter,
+    ) -> Result<(), CacheError> {
+        let mut symlinks = Vec::new();
+        let mut copies = Vec::new();
+        let mut originals = HashMap::new();
+        let (tx, rx) = crossbeam_channel::bounded::<RestoreJob>(workers * 2);
+
+        std::thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    let rx = rx.clone();
+                    scope.spawn(move || {
+                        for job in rx {
+                            match job {
+                                RestoreJob::Write(mut file, mtime, contents) => {
+                                    file.write_all(&contents)?;
+                                    set_mtime(&file, mtime)?;
+                                }
+                                RestoreJob::Update {
+                                    path,
+                                    mode,
+                                    mtime,
+                                    contents,
+                                } => update_regular(&path, mode, mtime, &contents)?,
+                            }
+                        }
+                        Ok::<(), CacheError>(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(rx);
+
+            let mut result = Ok(());
+            let mut case_collisions = CaseCollisions::new(anchor);
+            for entry in tr.entries()? {
+                let mut entry = entry?;
+                if is_logs_entry(&entry) {
+                    continue;
+                }
+                if let Err(e) = case_collisions.check(&entry) {
+                    result = Err(e);
+                    break;
+                }
+                if entry.header().entry_type() == tar::EntryType::Link {
+                    match prepare_hardlink(&mut dir_cache, anchor, &mut entry, options) {
+                        Ok(copy) => copies.push(copy),
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    }
+                    progress.entry_done(0);
+                    continue;
+                }
+                // Originals are restored here, since their contents are kept
+                // for their duplicates anyway
+                match is_original(&entry, link_targets) {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        let bytes = entry.size();
+                        match restore_original(
+                            &mut dir_cache,
+                            anchor,
+                            &mut entry,
+                            options,
+                            &mut originals,
+                        ) {
+                            Ok(restored_path) => restored.push(restored_path),
+                            Err(e) => {
+                                result = Err(e);
+                                break;
+                            }
+                        }
+                        progress.entry_done(bytes);
+                        continue;
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                if entry.header().entry_type() != tar::EntryType::Regular {
+                    let bytes = entry_bytes(&entry);
+                    match restore_entry(&mut dir_cache, anchor, &mut entry, options) {
+                        Err(CacheError::LinkTargetDoesNotExist(_, _)) => {
+                            symlinks.push(entry);
+                        }
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                        Ok(restored_path) => restored.push(restored_path),
+                    }
+                    progress.entry_done(bytes);
+                    continue;
+                }
+
+                // Directories and the file itself are created here, in order,
+                // so that later symlinks see the same tree they would during
+                // a sequential restore. Workers only write file contents.
+                let (processed_name, mode) = match prepare_regular(&mut dir_cache, anchor, &entry) {
+                    Ok(prepared) => prepared,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                };
+                let resolved_path = anchor.resolve(&processed_name);
+                let existing = restored_mtime(entry.header(), options.mtimes).and_then(|mtime| {
+                    check_existing(&resolved_path, options.overwrite_policy, entry.size())
+                        .map(|existing| (existing, mtime))
+                });
+                let (existing, mtime) = match existing {
+                    Ok(existing) => existing,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                };
+
+                let file = match existing {
+                    ExistingFile::Skip => {
+                        restored.push(processed_name);
+                        progress.entry_done(entry.size());
+                        continue;
+                    }
+                    // Large files aren't worth buffering in memory
+                    ExistingFile::Write if entry.size() > PARALLEL_RESTORE_BUFFER_LIMIT => {
+                        if let Err(e) = write_regular(&resolved_path, mode, mtime, &mut entry)
+                            .and_then(|_| restore_xattrs(&resolved_path, &mut entry, options))
+                        {
+                            result = Err(e);
+                            break;
+                        }
+                        restored.push(processed_name);
+                        progress.entry_done(entry.size());
+                        continue;
+                    }
+                    ExistingFile::Write => match create_regular(&resolved_path, mode) {
+                        Ok(file) => Some(file),
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    },
+                    ExistingFile::Compare => None,
+                };
+
+                let mut contents = Vec::with_capacity(entry.size() as usize);
+                if let Err(e) = entry.read_to_end(&mut contents) {
+                    result = Err(e.into());
+                    break;
+                }
+                // The file exists by now, and writing its contents doesn't
+                // change its extended attributes
+                if let Err(e) = restore_xattrs(&resolved_path, &mut entry, options) {
+                    result = Err(e);
+                    break;
+                }
+                let job = match file {
+                    Some(file) => RestoreJob::Write(file, mtime, contents),
+                    None => RestoreJob::Update {
+                        path: resolved_path,
+                        mode,
+                        mtime,
+                        contents,
+                    },
+                };
+                // This only fails if every worker has exited, in which case
+                // their errors are surfaced below.
+                if tx.send(job).is_err() {
+                    break;
+                }
+                restored.push(processed_name);
+                progress.entry_done(entry.size());
+            }
+            drop(tx);
+
+            for handle in handles {
+                handle.join().expect("restore worker panicked")?;
+            }
+
+            result
+        })?;
+
+        let mut restored_copies = restore_copies(anchor, copies, &originals, restored, options)?;
+        restored.append(&mut restored_copies);
+        let mut restored_symlinks =
+            Self::topologically_restore_symlinks(&mut dir_cache, anchor, &mut symlinks, options)?;
         restored.append(&mut restored_symlinks);
         Ok(())
     }
@@ -116,22 +773,20 @@ impl<'a> CacheReader<'a> {
     fn topologically_restore_symlinks<T: Read>(
         dir_cache: &mut CachedDirTree,
         anchor: &AbsoluteSystemPath,
-        symlinks: &[Entry<'_, T>],
+        symlinks: &mut [Entry<'_, T>],
+        options: RestoreOptions,
     ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
         let mut graph = DiGraph::new();
-        let mut header_lookup = HashMap::new();
+        let mut entry_lookup = HashMap::new();
         let mut restored = Vec::new();
         let mut nodes = HashMap::new();
 
-        for entry in symlinks {
-            let processed_name = AnchoredSystemPathBuf::from_system_path(&entry.header().path()?)?;
+        for (index, entry) in symlinks.iter().enumerate() {
+            let processed_name = AnchoredSystemPathBuf::from_system_path(&entry.path()?)?;
             let processed_sourcename =
                 canonicalize_linkname(anchor, &processed_name, processed_name.as_path())?;
             // symlink must have a linkname
-            let linkname = entry
-                .header()
-                .link_name()?
-                .expect("symlink without linkname");
+            let linkname = entry.link_name()?.expect("symlink without linkname");
 
             let processed_linkname = canonicalize_linkname(anchor, &processed_name, &linkname)?;
 
@@ -144,7 +799,7 @@ impl<'a> CacheReader<'a> {
 
             graph.add_edge(source_node, link_node, ());
 
-            header_lookup.insert(processed_sourcename, entry.header().clone());
+            entry_lookup.insert(processed_sourcename, index);
         }
 
         let nodes = petgraph::algo::toposort(&graph, None)
@@ -153,10 +808,12 @@ impl<'a> CacheReader<'a> {
         for node in nodes {
             let key = &graph[node];
 
-            let Some(header) = header_lookup.get(key) else {
+            let Some(&index) = entry_lookup.get(key) else {
                 continue;
             };
-            let file = restore_symlink_allow_missing_target(dir_cache, anchor, header)?;
+            let entry = &mut symlinks[index];
+            let file = restore_symlink_allow_missing_target(dir_cache, anchor, entry, options)?;
+            restore_xattrs(&anchor.resolve(&file), entry, options)?;
             restored.push(file);
         }
 
@@ -168,23 +825,58 @@ fn restore_entry<T: Read>(
     dir_cache: &mut CachedDirTree,
     anchor: &AbsoluteSystemPath,
     entry: &mut Entry<T>,
+    options: RestoreOptions,
 ) -> Result<AnchoredSystemPathBuf, CacheError> {
     let header = entry.header();
 
-    match header.entry_type() {
-        tar::EntryType::Directory => restore_directory(dir_cache, anchor, entry.header()),
-        tar::EntryType::Regular => restore_regular(dir_cache, anchor, entry),
-        tar::EntryType::Symlink => restore_symlink(dir_cache, anchor, entry.header()),
-        ty => Err(CacheError::RestoreUnsupportedFileType(
-            ty,
-            Backtrace::capture(),
-        )),
+    let restored = match header.entry_type() {
+        tar::EntryType::Directory => restore_directory(dir_cache, anchor, entry)?,
+        // Regular files restore their own extended attributes, unless they're
+        // skipped
+        tar::EntryType::Regular => return restore_regular(dir_cache, anchor, entry, options),
+        tar::EntryType::GNUSparse => return restore_sparse(dir_cache, anchor, entry, options),
+        tar::EntryType::Symlink => restore_symlink(dir_cache, anchor, entry, options)?,
+        ty => {
+            return Err(CacheError::RestoreUnsupportedFileType(
+                ty,
+                Backtrace::capture(),
+            ))
+        }
+    };
+    restore_xattrs(&anchor.resolve(&restored), entry, options)?;
+
+    Ok(restored)
+}
+
+// Whether `entry` is a file that hardlink entries refer to, and whose contents
+// are to be kept for them
+fn is_original<T: Read>(
+    entry: &Entry<T>,
+    link_targets: &HashSet<AnchoredSystemPathBuf>,
+) -> Result<bool, CacheError> {
+    if link_targets.is_empty() || entry.header().entry_type() != tar::EntryType::Regular {
+        return Ok(false);
+    }
+
+    Ok(link_targets.contains(&AnchoredSystemPathBuf::from_system_path(&entry.path()?)?))
+}
+
+// The task logs added by `CacheWriter::add_logs`, which aren't an output
+fn is_logs_entry<T: Read>(entry: &Entry<T>) -> bool {
+    entry.path_bytes().as_ref() == LOGS_ENTRY.as_bytes()
+}
+
+// Only regular files count towards the bytes restored
+fn entry_bytes<T: Read>(entry: &Entry<T>) -> u64 {
+    match entry.header().entry_type() {
+        tar::EntryType::Regular | tar::EntryType::GNUSparse => entry.size(),
+        _ => 0,
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, fs::File, io::empty, path::Path};
+    use std::{assert_matches::assert_matches, fs, fs::File, io::empty, path::Path};
 
     use anyhow::Result;
     use tar::Header;
@@ -193,7 +885,13 @@ mod tests {
     use tracing::debug;
     use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
 
-    use crate::cache_archive::{restore::CacheReader, restore_symlink::canonicalize_linkname};
+    use crate::{
+        cache_archive::{
+            restore::CacheReader, restore_symlink::canonicalize_linkname, CacheWriter,
+            SymlinkPolicy,
+        },
+        CacheError,
+    };
 
     // Expected output of the cache
     #[derive(Debug)]
@@ -867,9 +1565,10 @@ mod tests {
             },
         ];
 
-        for is_compressed in [true, false] {
+        // Restore sequentially and with a worker pool
+        for (is_compressed, workers) in [(true, 0), (false, 0), (true, 4), (false, 4)] {
             for test in &tests {
-                debug!("test: {}", test.name);
+                debug!("test: {} (workers: {})", test.name, workers);
                 let input_dir = tempdir()?;
                 let archive_path = generate_tar(&input_dir, &test.input_files)?;
                 let output_dir = tempdir()?;
@@ -884,7 +1583,10 @@ mod tests {
 
                 let mut cache_reader = CacheReader::open(&archive_path)?;
 
-                match (cache_reader.restore(anchor), &test.expected_output) {
+                match (
+                    cache_reader.restore_with_workers(anchor, workers),
+                    &test.expected_output,
+                ) {
                     (Ok(restored_files), Err(expected_error)) => {
                         panic!(
                             "expected error: {:?}, received {:?}",
@@ -914,6 +1616,335 @@ mod tests {
         Ok(())
     }
 
+    // Archives the entries of `input` with `CacheWriter`, symlinks included
+    fn write_archive(
+        input: &AbsoluteSystemPath,
+        files: &[AnchoredSystemPathBuf],
+    ) -> Result<(TempDir, AbsoluteSystemPathBuf)> {
+        let archive_dir = tempdir()?;
+        let archive_path =
+            AbsoluteSystemPath::from_std_path(archive_dir.path())?.join_component("out.tar.zst");
+        let mut writer = CacheWriter::create(&archive_path, 0, 0)?;
+        for file in files {
+            writer.add_file(input, file)?;
+        }
+        writer.finish()?;
+
+        Ok((archive_dir, archive_path))
+    }
+
+    #[test]
+    fn test_symlink_round_trip() -> Result<()> {
+        let input_dir = tempdir()?;
+        let input = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        input.join_component("dist").create_dir_all()?;
+        input
+            .join_components(&["dist", "index.js"])
+            .create_with_contents("Taipei Story")?;
+        input
+            .join_components(&["dist", "latest"])
+            .symlink_to_file("index.js")?;
+        input
+            .join_components(&["dist", "dangling"])
+            .symlink_to_file("missing.js")?;
+        input.join_component("current").symlink_to_dir("dist")?;
+
+        let files = into_anchored_system_path_vec(vec![
+            "dist",
+            "dist/index.js",
+            "dist/latest",
+            "dist/dangling",
+            "current",
+        ]);
+        let (_archive_dir, archive_path) = write_archive(input, &files)?;
+
+        for workers in [0, 4] {
+            let output_dir = tempdir()?;
+            let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            let mut restored = CacheReader::open(&archive_path)?
+                .with_symlink_policy(SymlinkPolicy::RejectEscaping)
+                .restore_with_workers(output, workers)?;
+            restored.sort();
+            let mut expected = files.clone();
+            expected.sort();
+            assert_eq!(restored, expected);
+
+            for (link, target) in [
+                ("dist/latest", "index.js"),
+                ("dist/dangling", "missing.js"),
+                ("current", "dist"),
+            ] {
+                let link = output.resolve(&AnchoredSystemPathBuf::from_raw(link)?);
+                assert_eq!(link.read_link()?, target);
+            }
+            assert!(!output.join_components(&["dist", "missing.js"]).exists());
+            assert_eq!(
+                output
+                    .join_components(&["current", "latest"])
+                    .read_to_string()?,
+                "Taipei Story"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_policies() -> Result<()> {
+        let output_dir = tempdir()?;
+        let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        let absolute_target = output.join_components(&["dist", "index.js"]);
+
+        let input_dir = tempdir()?;
+        let input = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        input.join_component("dist").create_dir_all()?;
+        input
+            .join_components(&["dist", "absolute"])
+            .symlink_to_file(absolute_target.as_str())?;
+        input
+            .join_component("escape")
+            .symlink_to_dir("../outside")?;
+
+        let absolute = into_anchored_system_path_vec(vec!["dist", "dist/absolute"]);
+        let escape = into_anchored_system_path_vec(vec!["escape"]);
+        let (_absolute_dir, absolute_archive) = write_archive(input, &absolute)?;
+        let (_escape_dir, escape_archive) = write_archive(input, &escape)?;
+
+        let restore = |archive: &AbsoluteSystemPathBuf, policy| {
+            CacheReader::open(archive)?
+                .with_symlink_policy(policy)
+                .restore(output)
+        };
+        let link = output.join_components(&["dist", "absolute"]);
+
+        restore(&absolute_archive, SymlinkPolicy::Verbatim)?;
+        assert_eq!(link.read_link()?, absolute_target.as_str());
+        restore(&absolute_archive, SymlinkPolicy::RelativizeAbsolute)?;
+        assert_eq!(link.read_link()?, "index.js");
+        assert_matches!(
+            restore(&absolute_archive, SymlinkPolicy::RejectEscaping),
+            Err(CacheError::LinkOutsideOfDirectory(..))
+        );
+
+        restore(&escape_archive, SymlinkPolicy::Verbatim)?;
+        for policy in [
+            SymlinkPolicy::RejectEscaping,
+            SymlinkPolicy::RelativizeAbsolute,
+        ] {
+            assert_matches!(
+                restore(&escape_archive, policy),
+                Err(CacheError::LinkOutsideOfDirectory(..))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlinks_through_symlinks() -> Result<()> {
+        let input_dir = tempdir()?;
+        let input = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        input.join_component("a").symlink_to_dir(".")?;
+        input.join_component("b").symlink_to_dir("a/a/../..")?;
+        input.join_component("c").symlink_to_dir("..")?;
+        input.join_component("dist").create_dir_all()?;
+        input.join_component("d").symlink_to_dir("a/dist/../dist")?;
+
+        // Each target is inside of the directory when taken lexically, but
+        // leads out of it through `a` once that's restored, whichever entry
+        // comes first. `a/c` is `c` itself.
+        for files in [vec!["a", "b"], vec!["b", "a"], vec!["a", "a/c"]] {
+            let files = into_anchored_system_path_vec(files);
+            let (_archive_dir, archive_path) = write_archive(input, &files)?;
+            for policy in [
+                SymlinkPolicy::RejectEscaping,
+                SymlinkPolicy::RelativizeAbsolute,
+            ] {
+                let output_dir = tempdir()?;
+                let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+                assert_matches!(
+                    CacheReader::open(&archive_path)?
+                        .with_symlink_policy(policy)
+                        .restore(output),
+                    Err(CacheError::LinkOutsideOfDirectory(..)),
+                    "{:?}",
+                    files
+                );
+            }
+        }
+
+        // Symlinks through symlinks that stay inside are restored
+        let files = into_anchored_system_path_vec(vec!["dist", "a", "d"]);
+        let (_archive_dir, archive_path) = write_archive(input, &files)?;
+        let output_dir = tempdir()?;
+        let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        CacheReader::open(&archive_path)?
+            .with_symlink_policy(SymlinkPolicy::RejectEscaping)
+            .restore(output)?;
+        assert_eq!(output.join_component("d").read_link()?, "a/dist/../dist");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_to() -> Result<()> {
+        let repo_root_dir = tempdir()?;
+        let repo_root = AbsoluteSystemPath::from_std_path(repo_root_dir.path())?;
+        repo_root.join_component("dist").create_dir_all()?;
+        repo_root
+            .join_components(&["dist", "index.js"])
+            .create_with_contents("Ashes of Time")?;
+        repo_root
+            .join_components(&["dist", "latest"])
+            .symlink_to_file("index.js")?;
+        repo_root
+            .join_components(&["dist", "absolute"])
+            .symlink_to_file(repo_root.join_components(&["dist", "index.js"]).as_str())?;
+        repo_root
+            .join_component("escape")
+            .symlink_to_dir("../outside")?;
+
+        let files = into_anchored_system_path_vec(vec![
+            "dist",
+            "dist/index.js",
+            "dist/latest",
+            "dist/absolute",
+        ]);
+        let (_archive_dir, archive_path) = write_archive(repo_root, &files)?;
+        let (_escape_dir, escape_archive) =
+            write_archive(repo_root, &into_anchored_system_path_vec(vec!["escape"]))?;
+
+        // Restoring over the working tree would replace this
+        repo_root
+            .join_components(&["dist", "index.js"])
+            .create_with_contents("Fallen Angels")?;
+
+        let dest_dir = tempdir()?;
+        let dest = AbsoluteSystemPath::from_std_path(dest_dir.path())?;
+        let mut restored = CacheReader::open(&archive_path)?.restore_to(repo_root, dest)?;
+        restored.sort();
+        let mut expected = files.clone();
+        expected.sort();
+        assert_eq!(restored, expected);
+
+        // Links lead to the restored files rather than the working tree
+        assert_eq!(
+            dest.join_components(&["dist", "absolute"]).read_link()?,
+            "index.js"
+        );
+        assert_eq!(
+            dest.join_components(&["dist", "latest"]).read_to_string()?,
+            "Ashes of Time"
+        );
+        assert_eq!(
+            repo_root
+                .join_components(&["dist", "index.js"])
+                .read_to_string()?,
+            "Fallen Angels"
+        );
+
+        assert_matches!(
+            CacheReader::open(&escape_archive)?.restore_to(repo_root, dest),
+            Err(CacheError::LinkOutsideOfDirectory(..))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_paths() -> Result<()> {
+        // Longer than MAX_PATH on Windows. The standard library adds the `\\?\`
+        // prefix that Windows needs for these itself.
+        let segment = "a-directory-name-that-goes-on-for-quite-a-while";
+        let mut dir = AnchoredSystemPathBuf::from_raw(segment)?;
+        for _ in 0..6 {
+            dir.push(segment);
+        }
+        let file = dir.join_component("Summer at Grandpa's.txt");
+        assert!(file.as_str().len() > 260);
+
+        let input_dir = tempdir()?;
+        let input = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        input.resolve(&dir).create_dir_all()?;
+        input
+            .resolve(&file)
+            .create_with_contents("A City of Sadness")?;
+        let (_archive_dir, archive_path) = write_archive(input, &[file.clone()])?;
+
+        for workers in [0, 4] {
+            let output_dir = tempdir()?;
+            let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            let restored =
+                CacheReader::open(&archive_path)?.restore_with_workers(output, workers)?;
+            assert_eq!(restored, vec![file.clone()]);
+            assert_eq!(output.resolve(&file).read_to_string()?, "A City of Sadness");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_at() -> Result<()> {
+        let input_dir = tempdir()?;
+        let input = AbsoluteSystemPath::from_std_path(input_dir.path())?;
+        let files = ["large.bin", "build.log"]
+            .into_iter()
+            .map(AnchoredSystemPathBuf::from_raw)
+            .collect::<Result<Vec<_>, _>>()?;
+        // Spans several frames of a seekable archive
+        let large: Vec<u8> = (0..10 * 2usize.pow(20)).map(|i| (i % 251) as u8).collect();
+        input.resolve(&files[0]).create_with_contents(large)?;
+        input.resolve(&files[1]).create_with_contents("Yi Yi")?;
+
+        for extension in ["tar", "tar.zst", "tar.lz4"] {
+            let archive_dir = tempdir()?;
+            let archive_path = AbsoluteSystemPath::from_std_path(archive_dir.path())?
+                .join_component(&format!("out.{}", extension));
+            let mut writer = CacheWriter::create(&archive_path, 0, 0)?;
+            for file in &files {
+                writer.add_file(input, file)?;
+            }
+            let offset = writer.index()[1].offset;
+            writer.finish()?;
+
+            let output_dir = tempdir()?;
+            let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+            let restored = CacheReader::open_at(&archive_path, offset)?.restore(output)?;
+            assert_eq!(restored, vec![files[1].clone()]);
+            assert_eq!(output.resolve(&files[1]).read_to_string()?, "Yi Yi");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_windows_reserved_names() -> Result<()> {
+        for name in ["dist/nul.txt", "CON", "dist/com1/index.js"] {
+            let input_dir = tempdir()?;
+            let archive_path = generate_tar(
+                &input_dir,
+                &[TarFile::File {
+                    body: b"device".to_vec(),
+                    path: AnchoredSystemPathBuf::from_raw(name)?,
+                }],
+            )?;
+            let output_dir = tempdir()?;
+            let output = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+
+            let result = CacheReader::open(&archive_path)?.restore(output);
+            // Writing these on Windows would write to a device instead
+            #[cfg(windows)]
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                format!("Invalid file path: Path is not safe for windows: {}", name)
+            );
+            #[cfg(unix)]
+            assert_eq!(result?, vec![AnchoredSystemPathBuf::from_raw(name)?]);
+        }
+
+        Ok(())
+    }
+
     #[test_case(Path::new("source").try_into()?, Path::new("target"), "/Users/test/target", "C:\\Users\\test\\target" ; "hello world")]
     #[test_case(Path::new("child/source").try_into()?, Path::new("../sibling/target"), "/Users/test/sibling/target", "C:\\Users\\test\\sibling\\target" ; "Unix path subdirectory traversal")]
     #[test_case(Path::new("child/source").try_into()?, Path::new("..\\sibling\\target"), "/Users/test/child/..\\sibling\\target", "C:\\Users\\test\\sibling\\target" ; "Windows path subdirectory traversal")]
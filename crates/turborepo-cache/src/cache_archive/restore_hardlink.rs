@@ -0,0 +1,157 @@
+use std::{
+    backtrace::Backtrace,
+    collections::{HashMap, HashSet},
+    io::Read,
+    time::SystemTime,
+};
+
+use tar::Entry;
+use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+use crate::{
+    cache_archive::{
+        restore::RestoreOptions,
+        restore_directory::CachedDirTree,
+        restore_regular::{
+            check_existing, prepare_regular, restored_mtime, update_regular, write_regular,
+            ExistingFile,
+        },
+        xattrs::{entry_xattrs, write_xattrs, Xattrs},
+    },
+    CacheError,
+};
+
+// A file stored as a hardlink entry by `CacheWriter::with_dedupe`. It's
+// restored as a copy of its original, once every regular file is written.
+pub struct PendingCopy {
+    path: AnchoredSystemPathBuf,
+    original: AnchoredSystemPathBuf,
+    mode: u32,
+    mtime: Option<SystemTime>,
+    xattrs: Xattrs,
+}
+
+pub fn prepare_hardlink(
+    dir_cache: &mut CachedDirTree,
+    anchor: &AbsoluteSystemPath,
+    entry: &mut Entry<impl Read>,
+    options: RestoreOptions,
+) -> Result<PendingCopy, CacheError> {
+    let (path, mode) = prepare_regular(dir_cache, anchor, entry)?;
+    let original = entry
+        .link_name()?
+        .ok_or_else(|| CacheError::LinkTargetNotOnHeader(Backtrace::capture()))?;
+
+    Ok(PendingCopy {
+        path,
+        original: AnchoredSystemPathBuf::from_system_path(&original)?,
+        mode,
+        mtime: restored_mtime(entry.header(), options.mtimes)?,
+        xattrs: restored_xattrs(entry, options)?,
+    })
+}
+
+// Restores a regular file that hardlink entries refer to, keeping its
+// contents for them in case the file itself isn't written.
+pub fn restore_original(
+    dir_cache: &mut CachedDirTree,
+    anchor: &AbsoluteSystemPath,
+    entry: &mut Entry<impl Read>,
+    options: RestoreOptions,
+    originals: &mut HashMap<AnchoredSystemPathBuf, Vec<u8>>,
+) -> Result<AnchoredSystemPathBuf, CacheError> {
+    let mut contents = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut contents)?;
+    let (path, mode) = prepare_regular(dir_cache, anchor, entry)?;
+    let mtime = restored_mtime(entry.header(), options.mtimes)?;
+    let xattrs = restored_xattrs(entry, options)?;
+    restore_contents(
+        &anchor.resolve(&path),
+        mode,
+        mtime,
+        &xattrs,
+        options,
+        &contents,
+    )?;
+    originals.insert(path.clone(), contents);
+
+    Ok(path)
+}
+
+// Restores each copy from the contents kept for its original, or else from
+// the original as it was restored. Originals have to be regular files that
+// were restored from the same archive, so that a copy can't be used to read
+// files from elsewhere.
+pub fn restore_copies(
+    anchor: &AbsoluteSystemPath,
+    copies: Vec<PendingCopy>,
+    originals: &HashMap<AnchoredSystemPathBuf, Vec<u8>>,
+    restored: &[AnchoredSystemPathBuf],
+    options: RestoreOptions,
+) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+    let restored = restored.iter().collect::<HashSet<_>>();
+    let mut restored_copies = Vec::with_capacity(copies.len());
+    for copy in copies {
+        let original_path = anchor.resolve(&copy.original);
+        let read;
+        let contents = match originals.get(&copy.original) {
+            Some(contents) => contents,
+            None if restored.contains(&copy.original)
+                && original_path
+                    .symlink_metadata()
+                    .map_or(false, |metadata| metadata.is_file()) =>
+            {
+                read = original_path.read()?;
+                &read
+            }
+            None => {
+                return Err(CacheError::LinkTargetDoesNotExist(
+                    copy.original.to_string(),
+                    Backtrace::capture(),
+                ))
+            }
+        };
+        restore_contents(
+            &anchor.resolve(&copy.path),
+            copy.mode,
+            copy.mtime,
+            &copy.xattrs,
+            options,
+            contents,
+        )?;
+        restored_copies.push(copy.path);
+    }
+
+    Ok(restored_copies)
+}
+
+fn restore_contents(
+    resolved_path: &AbsoluteSystemPath,
+    mode: u32,
+    mtime: Option<SystemTime>,
+    xattrs: &Xattrs,
+    options: RestoreOptions,
+    contents: &[u8],
+) -> Result<(), CacheError> {
+    match check_existing(
+        resolved_path,
+        options.overwrite_policy,
+        contents.len() as u64,
+    )? {
+        ExistingFile::Write => write_regular(resolved_path, mode, mtime, contents)?,
+        ExistingFile::Skip => return Ok(()),
+        ExistingFile::Compare => update_regular(resolved_path, mode, mtime, contents)?,
+    }
+    write_xattrs(resolved_path, xattrs)
+}
+
+// The extended attributes to give the file restored from `entry`
+fn restored_xattrs(
+    entry: &mut Entry<impl Read>,
+    options: RestoreOptions,
+) -> Result<Xattrs, CacheError> {
+    if !options.xattrs {
+        return Ok(Vec::new());
+    }
+    entry_xattrs(entry)
+}
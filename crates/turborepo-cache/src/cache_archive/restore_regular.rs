@@ -1,40 +1,208 @@
-use std::{fs::OpenOptions, io, io::Read, path::Path};
+use std::{
+    backtrace::Backtrace,
+    fs,
+    fs::{File, OpenOptions},
+    io,
+    io::Read,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use sha2::{Digest, Sha256};
 use tar::Entry;
 use turbopath::{AbsoluteSystemPath, AnchoredSystemPath, AnchoredSystemPathBuf};
 
-use crate::{cache_archive::restore_directory::CachedDirTree, CacheError};
+use crate::{
+    cache_archive::{
+        restore::RestoreOptions, restore_directory::CachedDirTree, xattrs::restore_xattrs,
+        OverwritePolicy,
+    },
+    CacheError,
+};
 
 pub fn restore_regular(
     dir_cache: &mut CachedDirTree,
     anchor: &AbsoluteSystemPath,
     entry: &mut Entry<impl Read>,
+    options: RestoreOptions,
 ) -> Result<AnchoredSystemPathBuf, CacheError> {
-    let header = entry.header();
+    let (processed_name, mode) = prepare_regular(dir_cache, anchor, entry)?;
+    let mtime = restored_mtime(entry.header(), options.mtimes)?;
+    let resolved_path = anchor.resolve(&processed_name);
+    match check_existing(&resolved_path, options.overwrite_policy, entry.size())? {
+        ExistingFile::Write => {
+            write_regular(&resolved_path, mode, mtime, &mut *entry)?;
+            restore_xattrs(&resolved_path, entry, options)?;
+        }
+        ExistingFile::Skip => {}
+        ExistingFile::Compare => {
+            let mut contents = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut contents)?;
+            update_regular(&resolved_path, mode, mtime, &contents)?;
+            restore_xattrs(&resolved_path, entry, options)?;
+        }
+    }
+
+    Ok(processed_name)
+}
+
+// What restoring a regular file should do with whatever is already at its
+// path.
+pub enum ExistingFile {
+    Write,
+    Skip,
+    // A file of the same size exists, so its contents have to be compared
+    Compare,
+}
+
+pub fn check_existing(
+    resolved_path: &AbsoluteSystemPath,
+    policy: OverwritePolicy,
+    size: u64,
+) -> Result<ExistingFile, CacheError> {
+    if policy == OverwritePolicy::Overwrite {
+        return Ok(ExistingFile::Write);
+    }
+    let metadata = match fs::symlink_metadata(resolved_path.as_path()) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ExistingFile::Write),
+        Err(e) => return Err(e.into()),
+    };
+
+    match policy {
+        OverwritePolicy::Overwrite => Ok(ExistingFile::Write),
+        OverwritePolicy::SkipExisting => Ok(ExistingFile::Skip),
+        OverwritePolicy::FailIfExists => Err(CacheError::FileExists(
+            resolved_path.to_string(),
+            Backtrace::capture(),
+        )),
+        OverwritePolicy::OnlyIfDifferent if metadata.is_file() && metadata.len() == size => {
+            Ok(ExistingFile::Compare)
+        }
+        OverwritePolicy::OnlyIfDifferent => Ok(ExistingFile::Write),
+    }
+}
+
+// Writes `contents` unless the file already has them, in which case only its
+// mode is updated so that its mtime doesn't change.
+pub fn update_regular(
+    resolved_path: &AbsoluteSystemPath,
+    mode: u32,
+    mtime: Option<SystemTime>,
+    contents: &[u8],
+) -> Result<(), CacheError> {
+    if file_digest(resolved_path)? != Sha256::digest(contents).to_vec() {
+        return write_regular(resolved_path, mode, mtime, contents);
+    }
+
+    update_mode(resolved_path, mode)
+}
+
+// Updates the mode of a file whose contents are already restored, if it
+// differs.
+#[cfg_attr(windows, allow(unused_variables))]
+pub fn update_mode(resolved_path: &AbsoluteSystemPath, mode: u32) -> Result<(), CacheError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let current_mode = resolved_path.symlink_metadata()?.permissions().mode();
+        if current_mode & 0o7777 != mode & 0o7777 {
+            resolved_path.set_mode(mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn file_digest(path: &AbsoluteSystemPath) -> Result<Vec<u8>, CacheError> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut path.open()?, &mut hasher)?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+// Validates the path of a regular file and creates its parent directories,
+// returning the path to write to and the file's mode.
+pub fn prepare_regular(
+    dir_cache: &mut CachedDirTree,
+    anchor: &AbsoluteSystemPath,
+    entry: &Entry<impl Read>,
+) -> Result<(AnchoredSystemPathBuf, u32), CacheError> {
     // Assuming this was a `turbo`-created input, we currently have an
     // AnchoredUnixPath. Assuming this is malicious input we don't really care
     // if we do the wrong thing.
-    let processed_name = AnchoredSystemPathBuf::from_system_path(&header.path()?)?;
+    let processed_name = AnchoredSystemPathBuf::from_system_path(&entry.path()?)?;
 
     // We need to traverse `processedName` from base to root split at
     // `os.Separator` to make sure we don't end up following a symlink
     // outside of the restore path.
     dir_cache.safe_mkdir_file(anchor, &processed_name)?;
 
-    let resolved_path = anchor.resolve(&processed_name);
+    Ok((processed_name, entry.header().mode()?))
+}
+
+// Writes the contents of a regular file. Any parent directories must already
+// have been created through `CachedDirTree` so that symlinks are checked.
+pub fn write_regular(
+    resolved_path: &AbsoluteSystemPath,
+    mode: u32,
+    mtime: Option<SystemTime>,
+    mut contents: impl Read,
+) -> Result<(), CacheError> {
+    let mut file = create_regular(resolved_path, mode)?;
+    io::copy(&mut contents, &mut file)?;
+    set_mtime(&file, mtime)?;
+
+    Ok(())
+}
+
+#[cfg_attr(windows, allow(unused_variables))]
+pub fn create_regular(resolved_path: &AbsoluteSystemPath, mode: u32) -> Result<File, CacheError> {
     let mut open_options = OpenOptions::new();
     open_options.write(true).truncate(true).create(true);
 
     #[cfg(unix)]
     {
         use std::os::unix::fs::OpenOptionsExt;
-        open_options.mode(header.mode()?);
+        open_options.mode(mode);
     }
 
-    let mut file = open_options.open(resolved_path.as_path())?;
-    io::copy(entry, &mut file)?;
+    let file = open_options.open(resolved_path.as_path())?;
 
-    Ok(processed_name)
+    // The mode given to `open` is masked by the umask and is ignored if the
+    // file already exists, either of which can drop the executable bit.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+
+    Ok(file)
+}
+
+// The mtime to give a restored file. Archives only record mtimes when they
+// were written with them, otherwise the header's mtime is 0.
+pub fn restored_mtime(
+    header: &tar::Header,
+    restore: bool,
+) -> Result<Option<SystemTime>, CacheError> {
+    if !restore {
+        return Ok(None);
+    }
+
+    Ok(match header.mtime()? {
+        0 => None,
+        mtime => Some(UNIX_EPOCH + Duration::from_secs(mtime)),
+    })
+}
+
+// Must be called after the file's contents are written, since writing updates
+// the mtime.
+pub fn set_mtime(file: &File, mtime: Option<SystemTime>) -> io::Result<()> {
+    match mtime {
+        Some(mtime) => file.set_modified(mtime),
+        None => Ok(()),
+    }
 }
 
 impl CachedDirTree {
@@ -0,0 +1,100 @@
+use std::{
+    io,
+    io::{Read, Seek, SeekFrom, Write},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+use tar::Entry;
+use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+use crate::{
+    cache_archive::{
+        restore::RestoreOptions,
+        restore_directory::CachedDirTree,
+        restore_regular::{
+            check_existing, create_regular, file_digest, prepare_regular, restored_mtime,
+            set_mtime, update_mode, ExistingFile,
+        },
+        xattrs::restore_xattrs,
+    },
+    CacheError,
+};
+
+// Blocks of zeros this large are left as holes rather than written
+const HOLE_SIZE: usize = 4096;
+
+// Restores a file stored as a GNU sparse entry. The tar reader fills in its
+// holes with zeros, which are turned back into holes when written.
+pub fn restore_sparse(
+    dir_cache: &mut CachedDirTree,
+    anchor: &AbsoluteSystemPath,
+    entry: &mut Entry<impl Read>,
+    options: RestoreOptions,
+) -> Result<AnchoredSystemPathBuf, CacheError> {
+    let (processed_name, mode) = prepare_regular(dir_cache, anchor, entry)?;
+    let mtime = restored_mtime(entry.header(), options.mtimes)?;
+    let resolved_path = anchor.resolve(&processed_name);
+    let size = entry.size();
+    match check_existing(&resolved_path, options.overwrite_policy, size)? {
+        ExistingFile::Write => {
+            write_sparse(&resolved_path, mode, mtime, size, &mut *entry)?;
+            restore_xattrs(&resolved_path, entry, options)?;
+        }
+        ExistingFile::Skip => {}
+        ExistingFile::Compare => {
+            let mut contents = Vec::with_capacity(size as usize);
+            entry.read_to_end(&mut contents)?;
+            if file_digest(&resolved_path)? == Sha256::digest(&contents).to_vec() {
+                update_mode(&resolved_path, mode)?;
+            } else {
+                write_sparse(&resolved_path, mode, mtime, size, contents.as_slice())?;
+            }
+            restore_xattrs(&resolved_path, entry, options)?;
+        }
+    }
+
+    Ok(processed_name)
+}
+
+fn write_sparse(
+    resolved_path: &AbsoluteSystemPath,
+    mode: u32,
+    mtime: Option<SystemTime>,
+    size: u64,
+    mut contents: impl Read,
+) -> Result<(), CacheError> {
+    let mut file = create_regular(resolved_path, mode)?;
+    let mut block = vec![0; HOLE_SIZE];
+    loop {
+        let len = read_block(&mut contents, &mut block)?;
+        if len == 0 {
+            break;
+        }
+        if block[..len].iter().all(|&byte| byte == 0) {
+            file.seek(SeekFrom::Current(len as i64))?;
+        } else {
+            file.write_all(&block[..len])?;
+        }
+    }
+    // Nothing is written for a trailing hole, so the file is extended over it
+    file.set_len(size)?;
+    set_mtime(&file, mtime)?;
+
+    Ok(())
+}
+
+// Fills `block` unless the contents end first, returning how much was read
+fn read_block(contents: &mut impl Read, block: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < block.len() {
+        match contents.read(&mut block[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
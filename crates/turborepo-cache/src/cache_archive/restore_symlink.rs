@@ -1,26 +1,37 @@
-use std::backtrace::Backtrace;
+use std::{
+    backtrace::Backtrace,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
 
 use camino::Utf8Path;
+use tar::Entry;
 use turbopath::{
     AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPath, AnchoredSystemPathBuf,
     PathError, UnknownPathType,
 };
 
-use crate::{cache_archive::restore_directory::CachedDirTree, CacheError};
+use crate::{
+    cache_archive::{restore::RestoreOptions, restore_directory::CachedDirTree, SymlinkPolicy},
+    CacheError,
+};
 
 pub fn restore_symlink(
     dir_cache: &mut CachedDirTree,
     anchor: &AbsoluteSystemPath,
-    header: &tar::Header,
+    entry: &Entry<impl Read>,
+    options: RestoreOptions,
 ) -> Result<AnchoredSystemPathBuf, CacheError> {
-    let processed_name = AnchoredSystemPathBuf::from_system_path(&header.path()?)?;
+    let processed_name = AnchoredSystemPathBuf::from_system_path(&entry.path()?)?;
 
-    let linkname = header
+    let linkname = entry
         .link_name()?
         .ok_or_else(|| CacheError::MalformedTar(Backtrace::capture()))?;
 
     let processed_linkname = canonicalize_linkname(anchor, &processed_name, &linkname)?;
 
+    let link_target = checked_link_target(anchor, &processed_name, &linkname, options)?;
+
     if processed_linkname.symlink_metadata().is_err() {
         return Err(CacheError::LinkTargetDoesNotExist(
             processed_linkname.to_string(),
@@ -28,7 +39,13 @@ pub fn restore_symlink(
         ));
     }
 
-    actually_restore_symlink(dir_cache, anchor, &processed_name, header)?;
+    actually_restore_symlink(
+        dir_cache,
+        anchor,
+        &processed_name,
+        entry.header(),
+        &link_target,
+    )?;
 
     Ok(processed_name)
 }
@@ -36,20 +53,175 @@ pub fn restore_symlink(
 pub fn restore_symlink_allow_missing_target(
     dir_cache: &mut CachedDirTree,
     anchor: &AbsoluteSystemPath,
-    header: &tar::Header,
+    entry: &Entry<impl Read>,
+    options: RestoreOptions,
 ) -> Result<AnchoredSystemPathBuf, CacheError> {
-    let processed_name = AnchoredSystemPathBuf::from_system_path(&header.path()?)?;
+    let processed_name = AnchoredSystemPathBuf::from_system_path(&entry.path()?)?;
 
-    actually_restore_symlink(dir_cache, anchor, &processed_name, header)?;
+    let linkname = entry
+        .link_name()?
+        .ok_or_else(|| CacheError::MalformedTar(Backtrace::capture()))?;
+    let link_target = checked_link_target(anchor, &processed_name, &linkname, options)?;
+
+    actually_restore_symlink(
+        dir_cache,
+        anchor,
+        &processed_name,
+        entry.header(),
+        &link_target,
+    )?;
 
     Ok(processed_name)
 }
 
+// Checks the target of a symlink that isn't restored from an archive, e.g.
+// by the deduplicating store, against `policy`
+pub fn checked_symlink_target(
+    anchor: &AbsoluteSystemPath,
+    processed_name: &AnchoredSystemPathBuf,
+    linkname: &str,
+    policy: SymlinkPolicy,
+) -> Result<String, CacheError> {
+    let options = RestoreOptions {
+        symlink_policy: policy,
+        ..RestoreOptions::default()
+    };
+    let target = checked_link_target(anchor, processed_name, Path::new(linkname), options)?;
+    // Built from the components of a UTF-8 target, so it's UTF-8 too
+    Ok(target.to_string_lossy().into_owned())
+}
+
+// How many symlinks are followed when checking where a target leads, before
+// it's taken to be a loop
+const MAX_LINK_HOPS: usize = 40;
+
+// Returns the target to give the symlink at `processed_name`, or an error if
+// the symlink policy doesn't allow its target. Targets are checked lexically
+// and then against the symlinks already on disk, which may have been restored
+// from the same archive.
+fn checked_link_target(
+    anchor: &AbsoluteSystemPath,
+    processed_name: &AnchoredSystemPathBuf,
+    linkname: &Path,
+    options: RestoreOptions,
+) -> Result<std::path::PathBuf, CacheError> {
+    // Restoring somewhere else mustn't leave links into the original tree
+    let policy = match options.rebase_from {
+        Some(_) => SymlinkPolicy::RelativizeAbsolute,
+        None => options.symlink_policy,
+    };
+    if policy == SymlinkPolicy::Verbatim {
+        return Ok(linkname.to_owned());
+    }
+
+    let outside_of_directory =
+        || CacheError::LinkOutsideOfDirectory(linkname.display().to_string(), Backtrace::capture());
+    let mut target = canonicalize_linkname(anchor, processed_name, linkname)?.clean()?;
+    // Absolute targets inside of the directory the archive was written from
+    // are taken to be inside of the one it's restored into
+    if let Some(original_anchor) = options.rebase_from {
+        if linkname.is_absolute() && !anchor.contains(&target) {
+            if let Ok(anchored) = original_anchor.anchor(&target) {
+                target = anchor.resolve(&anchored);
+            }
+        }
+    }
+    if !anchor.contains(&target) {
+        return Err(outside_of_directory());
+    }
+
+    let link_target = match policy {
+        _ if !linkname.is_absolute() => linkname.to_owned(),
+        SymlinkPolicy::Verbatim => linkname.to_owned(),
+        SymlinkPolicy::RejectEscaping => return Err(outside_of_directory()),
+        SymlinkPolicy::RelativizeAbsolute => {
+            let source = anchor.resolve(processed_name);
+            let parent = source.parent().expect("expected parent for file");
+            let relative = AnchoredSystemPathBuf::relative_path_between(parent, &target);
+            if relative.as_str().is_empty() {
+                ".".into()
+            } else {
+                relative.as_path().to_owned()
+            }
+        }
+    };
+    if !leads_inside(anchor, processed_name, &link_target)? {
+        return Err(outside_of_directory());
+    }
+
+    Ok(link_target)
+}
+
+// Whether a symlink at `processed_name` to `link_target` leads inside of
+// `anchor` once the symlinks inside of `anchor` that it passes through are
+// followed. Symlinks outside of `anchor` aren't followed, since they can't
+// be restored from an archive.
+//
+// A component of the target that doesn't exist yet could still be restored
+// as a symlink by a later entry, so stepping out of one with `..` is taken
+// to leave `anchor`. Missing directories that the symlink itself is
+// restored into are created as real directories, so they're exempt.
+fn leads_inside(
+    anchor: &AbsoluteSystemPath,
+    processed_name: &AnchoredSystemPath,
+    link_target: &Path,
+) -> Result<bool, CacheError> {
+    // Components still to be resolved, last first, and whether they're part
+    // of a target rather than where the symlink is
+    let mut pending: Vec<(PathBuf, bool)> = Vec::new();
+    let push = |pending: &mut Vec<(PathBuf, bool)>, path: &Path, is_target: bool| {
+        pending.extend(
+            path.components()
+                .rev()
+                .map(|component| (PathBuf::from(component.as_os_str()), is_target)),
+        )
+    };
+    push(&mut pending, link_target, true);
+    if let Some(parent) = processed_name.as_path().parent() {
+        push(&mut pending, parent, false);
+    }
+
+    let anchor = anchor.as_std_path();
+    let mut resolved = anchor.to_path_buf();
+    let mut hops = 0;
+    while let Some((component, is_target)) = pending.pop() {
+        match component.components().next() {
+            Some(Component::Prefix(_)) => resolved = component,
+            Some(Component::RootDir) => resolved.push(component),
+            Some(Component::CurDir) | None => {}
+            Some(Component::ParentDir) => {
+                let unrestored = resolved.symlink_metadata().is_err();
+                if is_target && unrestored && resolved.starts_with(anchor) {
+                    return Ok(false);
+                }
+                resolved.pop();
+            }
+            Some(Component::Normal(_)) => {
+                resolved.push(&component);
+                if !resolved.starts_with(anchor) || !resolved.is_symlink() {
+                    continue;
+                }
+                hops += 1;
+                if hops > MAX_LINK_HOPS {
+                    return Ok(false);
+                }
+                let target = std::fs::read_link(&resolved)?;
+                resolved.pop();
+                push(&mut pending, &target, true);
+            }
+        }
+    }
+
+    Ok(resolved.starts_with(anchor))
+}
+
+#[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
 fn actually_restore_symlink<'a>(
     dir_cache: &mut CachedDirTree,
     anchor: &AbsoluteSystemPath,
     processed_name: &'a AnchoredSystemPath,
     header: &tar::Header,
+    link_name: &Path,
 ) -> Result<&'a AnchoredSystemPath, CacheError> {
     dir_cache.safe_mkdir_file(anchor, processed_name)?;
 
@@ -57,7 +229,6 @@ fn actually_restore_symlink<'a>(
 
     _ = symlink_from.remove();
 
-    let link_name = header.link_name()?.expect("have linkname");
     let symlink_to = link_name.to_str().ok_or_else(|| {
         CacheError::PathError(
             PathError::InvalidUnicode(link_name.to_string_lossy().to_string()),
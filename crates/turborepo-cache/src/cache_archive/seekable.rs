@@ -0,0 +1,287 @@
+use std::{
+    io,
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use zstd::stream::raw::{self, CParameter, InBuffer, Operation, OutBuffer};
+
+use crate::cache_archive::index::CountingWriter;
+
+// How much of the tar goes into each zstd frame. Smaller frames mean less to
+// decompress before reaching an entry, at the cost of compression ratio.
+const FRAME_SIZE: u64 = 4 * 1024 * 1024;
+
+// Constants from zstd's seekable format:
+// https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+const SEEK_TABLE_ENTRY_SIZE: u64 = 8;
+const SKIPPABLE_FRAME_HEADER_SIZE: u64 = 8;
+
+/// Where a frame of a seekable archive starts, in both the compressed file
+/// and the uncompressed tar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SeekableFrame {
+    pub compressed_offset: u64,
+    pub uncompressed_offset: u64,
+}
+
+// Compresses into independent zstd frames of at most `FRAME_SIZE`
+// uncompressed bytes, followed by a seek table that lets readers start
+// decompressing at any frame. Other zstd decoders skip the seek table, so the
+// output is still an ordinary zstd stream to them. The last frame and the
+// seek table are only written by `finish`.
+//
+// Every frame is compressed by the same context, so that multithreaded
+// compression starts its workers once rather than for every frame.
+pub(crate) struct SeekableZstdWriter<W: Write> {
+    encoder: raw::Encoder<'static>,
+    writer: CountingWriter<W>,
+    // Holds compressed output until it's written to `writer`
+    buffer: Vec<u8>,
+    compressed_bytes: Arc<AtomicU64>,
+    frame_compressed_offset: u64,
+    frame_uncompressed_size: u64,
+    // Compressed and uncompressed size of every finished frame
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> SeekableZstdWriter<W> {
+    pub fn new(writer: W, compression_level: i32, compression_workers: u32) -> io::Result<Self> {
+        let (writer, compressed_bytes) = CountingWriter::new(writer);
+        let mut encoder = raw::Encoder::new(compression_level)?;
+        if compression_workers > 0 {
+            encoder.set_parameter(CParameter::NbWorkers(compression_workers))?;
+            // zstd's jobs are larger than a frame by default, and a job can't
+            // span frames, so each frame would only ever use one worker
+            encoder.set_parameter(CParameter::JobSize(job_size(compression_workers)))?;
+        }
+
+        Ok(Self {
+            encoder,
+            writer,
+            buffer: Vec::with_capacity(zstd::zstd_safe::CCtx::out_size()),
+            compressed_bytes,
+            frame_compressed_offset: 0,
+            frame_uncompressed_size: 0,
+            frames: Vec::new(),
+        })
+    }
+
+    // Writes out whatever `operation` leaves in the buffer, until it returns
+    // 0, i.e. it has nothing more to write
+    fn drain(
+        &mut self,
+        mut operation: impl FnMut(
+            &mut raw::Encoder<'static>,
+            &mut OutBuffer<'_, Vec<u8>>,
+        ) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        loop {
+            let mut output = OutBuffer::around(&mut self.buffer);
+            let remaining = operation(&mut self.encoder, &mut output)?;
+            self.writer.write_all(output.as_slice())?;
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn end_frame(&mut self) -> io::Result<()> {
+        self.drain(|encoder, output| encoder.finish(output, true))?;
+        // Starts the next frame with the same parameters and workers
+        self.encoder.reinit()?;
+
+        let compressed_offset = self.compressed_bytes.load(Ordering::Relaxed);
+        self.frames.push((
+            (compressed_offset - self.frame_compressed_offset) as u32,
+            self.frame_uncompressed_size as u32,
+        ));
+        self.frame_compressed_offset = compressed_offset;
+        self.frame_uncompressed_size = 0;
+        Ok(())
+    }
+
+    /// Writes the last frame and the seek table, and returns the underlying
+    /// writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_frame()?;
+
+        let frame_size = self.frames.len() as u64 * SEEK_TABLE_ENTRY_SIZE + SEEK_TABLE_FOOTER_SIZE;
+        let writer = &mut self.writer;
+        writer.write_all(&SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+        writer.write_all(&(frame_size as u32).to_le_bytes())?;
+        for (compressed_size, uncompressed_size) in &self.frames {
+            writer.write_all(&compressed_size.to_le_bytes())?;
+            writer.write_all(&uncompressed_size.to_le_bytes())?;
+        }
+        writer.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+        // No per-frame checksums
+        writer.write_all(&[0])?;
+        writer.write_all(&SEEKABLE_MAGIC.to_le_bytes())?;
+        writer.flush()?;
+        Ok(self.writer.into_inner())
+    }
+}
+
+// The size of the jobs that multithreaded compression splits frames into, so
+// that every worker gets a share of each frame. zstd raises sizes under its
+// minimum of 512 KiB, which leaves some workers idle past 8 of them.
+fn job_size(compression_workers: u32) -> u32 {
+    (FRAME_SIZE / compression_workers as u64) as u32
+}
+
+impl<W: Write> Write for SeekableZstdWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.frame_uncompressed_size >= FRAME_SIZE {
+            self.end_frame()?;
+        }
+
+        let remaining = (FRAME_SIZE - self.frame_uncompressed_size) as usize;
+        let mut input = InBuffer::around(&buf[..buf.len().min(remaining)]);
+        // Nothing is taken while the output buffer is full, in which case it's
+        // written out and the input offered again
+        while input.pos() == 0 && !input.src.is_empty() {
+            let mut output = OutBuffer::around(&mut self.buffer);
+            self.encoder.run(&mut input, &mut output)?;
+            self.writer.write_all(output.as_slice())?;
+        }
+        self.frame_uncompressed_size += input.pos() as u64;
+        Ok(input.pos())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain(|encoder, output| encoder.flush(output))?;
+        self.writer.flush()
+    }
+}
+
+/// Reads the seek table from the end of `file`, if it was written in the
+/// seekable format. The file is left at an unspecified position.
+pub(crate) fn read_seek_table(
+    mut file: impl Read + Seek,
+) -> io::Result<Option<Vec<SeekableFrame>>> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    if file_size < SKIPPABLE_FRAME_HEADER_SIZE + SEEK_TABLE_FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut footer = [0; SEEK_TABLE_FOOTER_SIZE as usize];
+    file.seek(SeekFrom::End(-(SEEK_TABLE_FOOTER_SIZE as i64)))?;
+    file.read_exact(&mut footer)?;
+    let num_frames = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+    let has_checksums = footer[4] & 0x80 != 0;
+    if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+
+    let entry_size = SEEK_TABLE_ENTRY_SIZE + if has_checksums { 4 } else { 0 };
+    let table_size = SKIPPABLE_FRAME_HEADER_SIZE + num_frames * entry_size + SEEK_TABLE_FOOTER_SIZE;
+    if table_size > file_size {
+        return Ok(None);
+    }
+
+    let mut table = vec![0; (table_size - SEEK_TABLE_FOOTER_SIZE) as usize];
+    file.seek(SeekFrom::End(-(table_size as i64)))?;
+    file.read_exact(&mut table)?;
+    if u32::from_le_bytes(table[0..4].try_into().unwrap()) != SKIPPABLE_FRAME_MAGIC {
+        return Ok(None);
+    }
+
+    let mut frames = Vec::with_capacity(num_frames as usize);
+    let mut frame = SeekableFrame {
+        compressed_offset: 0,
+        uncompressed_offset: 0,
+    };
+    for entry in table[SKIPPABLE_FRAME_HEADER_SIZE as usize..].chunks_exact(entry_size as usize) {
+        frames.push(frame);
+        frame.compressed_offset += u32::from_le_bytes(entry[0..4].try_into().unwrap()) as u64;
+        frame.uncompressed_offset += u32::from_le_bytes(entry[4..8].try_into().unwrap()) as u64;
+    }
+
+    Ok(Some(frames))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_seekable_round_trip() -> Result<()> {
+        let contents: Vec<u8> = (0..(FRAME_SIZE * 2 + 100))
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let mut archive = Vec::new();
+        let mut writer = SeekableZstdWriter::new(&mut archive, 0, 0)?;
+        writer.write_all(&contents)?;
+        writer.finish()?;
+
+        // Ordinary decoders see the whole stream
+        assert_eq!(zstd::decode_all(&archive[..])?, contents);
+
+        let frames = read_seek_table(Cursor::new(&archive))?.unwrap();
+        let uncompressed_offsets = frames
+            .iter()
+            .map(|frame| frame.uncompressed_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(uncompressed_offsets, vec![0, FRAME_SIZE, FRAME_SIZE * 2]);
+
+        // Decompressing from the start of a frame gives the rest of the stream
+        let last_frame = frames[2];
+        let rest = zstd::decode_all(&archive[last_frame.compressed_offset as usize..])?;
+        assert_eq!(rest, &contents[last_frame.uncompressed_offset as usize..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multithreaded_frames() -> Result<()> {
+        // Only partly compressible, so that the frames aren't trivially small
+        let contents: Vec<u8> = (0..(FRAME_SIZE * 3 + 100))
+            .map(|i| ((i * i) >> 7) as u8)
+            .collect();
+
+        let mut archive = Vec::new();
+        let mut writer = SeekableZstdWriter::new(&mut archive, 0, 4)?;
+        writer.write_all(&contents)?;
+        writer.finish()?;
+        assert_eq!(zstd::decode_all(&archive[..])?, contents);
+
+        // Frames written after the context is reset are still independent
+        let frames = read_seek_table(Cursor::new(&archive))?.unwrap();
+        assert_eq!(frames.len(), 4);
+        for frame in frames {
+            let rest = zstd::decode_all(&archive[frame.compressed_offset as usize..])?;
+            assert_eq!(rest, &contents[frame.uncompressed_offset as usize..]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_size() {
+        // Each worker gets at least one job of every full frame
+        for workers in 1..=8 {
+            assert!(FRAME_SIZE / job_size(workers) as u64 >= workers as u64);
+        }
+    }
+
+    #[test]
+    fn test_seek_table_missing() -> Result<()> {
+        let archive = zstd::encode_all(&b"not seekable"[..], 0)?;
+        assert_eq!(read_seek_table(Cursor::new(&archive))?, None);
+        assert_eq!(read_seek_table(Cursor::new(&[0u8; 4]))?, None);
+
+        Ok(())
+    }
+}
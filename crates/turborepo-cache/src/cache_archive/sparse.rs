@@ -0,0 +1,197 @@
+use std::{
+    fs,
+    fs::File,
+    io,
+    io::{Read, Seek, SeekFrom},
+};
+
+use sha2::{Digest, Sha256};
+use tar::{EntryType, GnuExtSparseHeader, GnuSparseHeader, Header};
+
+// Data regions are stored in whole tar blocks, since every region but the
+// last has to end on a block boundary in the archive.
+const BLOCK_SIZE: u64 = 512;
+
+// Offsets in sparse headers are written as 12 octal digits, which is as far
+// as the tar reader goes. Larger files are stored in full.
+const MAX_SPARSE_SIZE: u64 = 8u64.pow(12);
+
+// The number of regions described by each extension header
+const EXTENSION_BLOCKS: usize = 21;
+
+/// The regions of `file` that hold data, as offsets and lengths, or `None`
+/// if it has no holes or they can't be found on this platform.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+))]
+pub fn data_regions(file: &File, metadata: &fs::Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::{fd::AsRawFd, unix::fs::MetadataExt};
+
+    let size = metadata.len();
+    // Files with holes take up fewer blocks than their size needs
+    if metadata.blocks() * 512 >= size || size >= MAX_SPARSE_SIZE {
+        return Ok(None);
+    }
+
+    let seek = |offset: u64, whence: libc::c_int| -> io::Result<Option<u64>> {
+        // SAFETY: the file descriptor is valid for the duration of the call.
+        let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        match result {
+            // There's no data past `offset`
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            -1 => Err(io::Error::last_os_error()),
+            offset => Ok(Some(offset as u64)),
+        }
+    };
+
+    let mut regions: Vec<(u64, u64)> = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let Some(start) = seek(offset, libc::SEEK_DATA)? else {
+            break;
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.unwrap_or(size).min(size);
+        let start = start / BLOCK_SIZE * BLOCK_SIZE;
+        let end = end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        match regions.last_mut() {
+            Some((last_start, last_len)) if start <= *last_start + *last_len => {
+                *last_len = end - *last_start;
+            }
+            _ => regions.push((start, end - start)),
+        }
+        offset = end;
+    }
+    // Only the last region can end past the end of the file
+    if let Some((start, len)) = regions.last_mut() {
+        *len = (*len).min(size - *start);
+    }
+
+    // Filesystems without support for holes report the whole file as data
+    let stored = regions.iter().map(|(_, len)| len).sum::<u64>();
+    if stored == size {
+        return Ok(None);
+    }
+
+    Ok(Some(regions))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+)))]
+pub fn data_regions(_file: &File, _metadata: &fs::Metadata) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Turns `header` into the header of a GNU sparse entry for a file of `size`
+/// bytes with data in `regions`. Returns the extension headers that have to
+/// follow it in the archive, before the data of each region.
+pub fn set_sparse_header(header: &mut Header, regions: &[(u64, u64)], size: u64) -> Vec<u8> {
+    // A trailing hole is described by an empty region at the end of the file
+    let mut blocks = regions.to_vec();
+    if blocks
+        .last()
+        .map_or(true, |(start, len)| start + len < size)
+    {
+        blocks.push((size, 0));
+    }
+
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(regions.iter().map(|(_, len)| len).sum());
+    let gnu = header
+        .as_gnu_mut()
+        .expect("archives are written with GNU headers");
+    write_octal(&mut gnu.realsize, size);
+
+    let (first, rest) = blocks.split_at(blocks.len().min(gnu.sparse.len()));
+    for (slot, &block) in gnu.sparse.iter_mut().zip(first) {
+        write_block(slot, block);
+    }
+    gnu.isextended[0] = u8::from(!rest.is_empty());
+
+    let mut extensions = Vec::new();
+    for (i, chunk) in rest.chunks(EXTENSION_BLOCKS).enumerate() {
+        let mut extension = GnuExtSparseHeader::new();
+        for (slot, &block) in extension.sparse.iter_mut().zip(chunk) {
+            write_block(slot, block);
+        }
+        extension.isextended[0] = u8::from((i + 1) * EXTENSION_BLOCKS < rest.len());
+        extensions.extend_from_slice(extension.as_bytes());
+    }
+
+    extensions
+}
+
+fn write_block(slot: &mut GnuSparseHeader, (offset, len): (u64, u64)) {
+    write_octal(&mut slot.offset, offset);
+    write_octal(&mut slot.numbytes, len);
+}
+
+// Fills the whole field with zero padded octal digits, without the NUL
+// terminator, so that values up to `MAX_SPARSE_SIZE` fit.
+fn write_octal(field: &mut [u8; 12], value: u64) {
+    field.copy_from_slice(format!("{:012o}", value).as_bytes());
+}
+
+/// Reads the data regions of a sparse file one after another, while hashing
+/// the whole file, holes included, so that its digest is that of its
+/// contents however it's stored.
+pub struct SparseReader<'a> {
+    file: &'a File,
+    regions: std::vec::IntoIter<(u64, u64)>,
+    current: io::Take<&'a File>,
+    position: u64,
+    hasher: Sha256,
+}
+
+impl<'a> SparseReader<'a> {
+    pub fn new(file: &'a File, regions: Vec<(u64, u64)>) -> Self {
+        Self {
+            file,
+            regions: regions.into_iter(),
+            current: file.take(0),
+            position: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // Finishes hashing the file, up to the trailing hole if there is one
+    pub fn digest(mut self, size: u64) -> String {
+        self.hash_hole(size);
+        hex::encode(self.hasher.finalize())
+    }
+
+    fn hash_hole(&mut self, until: u64) {
+        const ZEROS: [u8; 8192] = [0; 8192];
+        while self.position < until {
+            let len = (until - self.position).min(ZEROS.len() as u64);
+            self.hasher.update(&ZEROS[..len as usize]);
+            self.position += len;
+        }
+    }
+}
+
+impl<'a> Read for SparseReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.hasher.update(&buf[..n]);
+                self.position += n as u64;
+                return Ok(n);
+            }
+            let Some((offset, len)) = self.regions.next() else {
+                return Ok(0);
+            };
+            self.hash_hole(offset);
+            let mut file = self.file;
+            file.seek(SeekFrom::Start(offset))?;
+            self.current = file.take(len);
+        }
+    }
+}
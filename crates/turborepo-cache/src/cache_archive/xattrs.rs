@@ -0,0 +1,155 @@
+use std::{io, io::Read};
+
+use tar::{Entry, EntryType, Header};
+use tracing::warn;
+use turbopath::AbsoluteSystemPath;
+
+use crate::{cache_archive::restore::RestoreOptions, CacheError};
+
+// The prefix of the PAX records holding extended attributes, as written by
+// GNU tar and bsdtar
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+// The path of the PAX header that precedes an entry with extended attributes.
+// Readers don't use it, but it has to be short so that it doesn't need a
+// long name entry of its own.
+const PAX_HEADER_PATH: &str = "PaxHeader";
+
+// Linux namespaces managed by the system, or that need privileges to set.
+// Attributes in them aren't archived.
+const SYSTEM_NAMESPACES: &[&str] = &["security.", "system.", "trusted."];
+
+pub type Xattrs = Vec<(String, Vec<u8>)>;
+
+/// Reads the extended attributes of the file at `path`, without following
+/// symlinks. Names that aren't UTF-8 are skipped.
+pub fn read_xattrs(path: &AbsoluteSystemPath) -> Result<Xattrs, CacheError> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(Vec::new());
+    }
+
+    let mut xattrs = Vec::new();
+    for name in xattr::list(path.as_std_path())? {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if SYSTEM_NAMESPACES
+            .iter()
+            .any(|namespace| name.starts_with(namespace))
+        {
+            continue;
+        }
+        // The attribute may have been removed since it was listed
+        if let Some(value) = xattr::get(path.as_std_path(), name)? {
+            xattrs.push((name.to_string(), value));
+        }
+    }
+    xattrs.sort();
+
+    Ok(xattrs)
+}
+
+/// Builds the PAX header to append before an entry with `xattrs`, along with
+/// its records.
+pub fn pax_header(xattrs: &Xattrs) -> Result<(Header, Vec<u8>), CacheError> {
+    let mut records = Vec::new();
+    for (name, value) in xattrs {
+        let key = format!("{}{}", PAX_XATTR_PREFIX, name);
+        // Each record is prefixed with its length in decimal, which counts
+        // the digits of the length itself
+        let base_len = key.len() + value.len() + 3;
+        let mut record_len = base_len + base_len.to_string().len();
+        if record_len.to_string().len() > base_len.to_string().len() {
+            record_len += 1;
+        }
+        records.extend_from_slice(format!("{} {}=", record_len, key).as_bytes());
+        records.extend_from_slice(value);
+        records.push(b'\n');
+    }
+
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_path(PAX_HEADER_PATH)?;
+    header.set_mode(0o644);
+    header.set_size(records.len() as u64);
+    header.set_mtime(0);
+    header.set_cksum();
+
+    Ok((header, records))
+}
+
+/// The extended attributes recorded for `entry`, if any.
+pub fn entry_xattrs(entry: &mut Entry<impl Read>) -> Result<Xattrs, CacheError> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(Vec::new());
+    };
+
+    let mut xattrs = Vec::new();
+    for extension in extensions {
+        let extension = extension?;
+        let Some(name) = extension
+            .key()
+            .ok()
+            .and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX))
+        else {
+            continue;
+        };
+        xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+    }
+
+    Ok(xattrs)
+}
+
+/// Restores the extended attributes recorded for `entry` onto the file at
+/// `path`, if `options` say to.
+pub fn restore_xattrs(
+    path: &AbsoluteSystemPath,
+    entry: &mut Entry<impl Read>,
+    options: RestoreOptions,
+) -> Result<(), CacheError> {
+    if !options.xattrs {
+        return Ok(());
+    }
+    write_xattrs(path, &entry_xattrs(entry)?)
+}
+
+/// Gives the file at `path` these extended attributes, without following
+/// symlinks. Attributes that the filesystem doesn't support, e.g. macOS
+/// attributes restored on Linux, are skipped with a warning.
+pub fn write_xattrs(path: &AbsoluteSystemPath, xattrs: &Xattrs) -> Result<(), CacheError> {
+    if xattrs.is_empty() {
+        return Ok(());
+    }
+    if !xattr::SUPPORTED_PLATFORM {
+        warn!(
+            "extended attributes of {} are not restored on this platform",
+            path
+        );
+        return Ok(());
+    }
+
+    for (name, value) in xattrs {
+        match xattr::set(path.as_std_path(), name, value) {
+            Ok(()) => {}
+            Err(e) if is_unsupported(&e) => {
+                warn!(
+                    "could not restore extended attribute {} of {}: {}",
+                    name, path, e
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(code) if code == libc::ENOTSUP || code == libc::EOPNOTSUPP)
+}
+
+#[cfg(not(unix))]
+fn is_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+}
@@ -0,0 +1,518 @@
+use std::{
+    backtrace::Backtrace,
+    collections::HashSet,
+    fs::File,
+    io::{self, Read, Write},
+    process,
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
+use turborepo_analytics::AnalyticsSender;
+use turborepo_api_client::{analytics, analytics::AnalyticsEvent};
+
+use crate::{
+    cache_archive::{checked_symlink_target, write_regular, CachedDirTree, SymlinkPolicy},
+    events::EventReporter,
+    fs::FSCache,
+    CacheError, CacheHitMetadata, CacheOpts, CacheSource,
+};
+
+const CAS_DIRECTORY: &str = "cas";
+const OBJECTS_DIRECTORY: &str = "objects";
+const MANIFESTS_DIRECTORY: &str = "manifests";
+
+/// A local cache that stores each file once, keyed by the SHA-256 of its
+/// contents, alongside a manifest per artifact listing the files it contains.
+/// Files that are identical across artifacts, such as outputs that didn't
+/// change between builds, only take up space once.
+pub struct CASCache {
+    cache_directory: AbsoluteSystemPathBuf,
+    analytics_recorder: Option<AnalyticsSender>,
+    events: EventReporter,
+    symlink_policy: SymlinkPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Manifest {
+    hash: String,
+    duration: u64,
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ManifestEntry {
+    File {
+        path: AnchoredSystemPathBuf,
+        digest: String,
+        size: u64,
+        mode: u32,
+    },
+    Directory {
+        path: AnchoredSystemPathBuf,
+        mode: u32,
+    },
+    Symlink {
+        path: AnchoredSystemPathBuf,
+        target: String,
+    },
+}
+
+impl Manifest {
+    // Total size of the files in the artifact, however many of them are
+    // shared with other artifacts
+    fn size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| match entry {
+                ManifestEntry::File { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+impl CASCache {
+    pub fn new(
+        opts: &CacheOpts,
+        repo_root: &AbsoluteSystemPath,
+        analytics_recorder: Option<AnalyticsSender>,
+    ) -> Result<Self, CacheError> {
+        let cache_directory =
+            FSCache::resolve_cache_dir(repo_root, opts.override_dir).join_component(CAS_DIRECTORY);
+        cache_directory
+            .join_component(OBJECTS_DIRECTORY)
+            .create_dir_all()?;
+        cache_directory
+            .join_component(MANIFESTS_DIRECTORY)
+            .create_dir_all()?;
+
+        Ok(CASCache {
+            cache_directory,
+            analytics_recorder,
+            events: EventReporter::new(opts, CacheSource::Local),
+            symlink_policy: opts.symlink_policy,
+        })
+    }
+
+    fn manifest_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_components(&[MANIFESTS_DIRECTORY, &format!("{}.json", hash)])
+    }
+
+    // Objects are sharded by the first two characters of their digest to keep
+    // directories small.
+    fn object_path(&self, digest: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_components(&[OBJECTS_DIRECTORY, &digest[..2], digest])
+    }
+
+    // A path next to `path` that is unique to this process, used to write
+    // files that are then renamed into place.
+    fn temp_path(path: &AbsoluteSystemPathBuf) -> AbsoluteSystemPathBuf {
+        let parent = path.parent().expect("cache paths have a parent");
+        let file_name = path.file_name().expect("cache paths have a file name");
+        parent.join_component(&format!(".{}.{}.tmp", file_name, process::id()))
+    }
+
+    fn read_manifest(&self, hash: &str) -> Result<Option<Manifest>, CacheError> {
+        let manifest_path = self.manifest_path(hash);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+
+        serde_json::from_str(&manifest_path.read_to_string()?)
+            .map(Some)
+            .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))
+    }
+
+    fn log_fetch(&self, event: analytics::CacheEvent, hash: &str, duration: u64) {
+        // If analytics fails to record, it's not worth failing the cache
+        if let Some(analytics_recorder) = &self.analytics_recorder {
+            let analytics_event = AnalyticsEvent {
+                session_id: None,
+                source: analytics::CacheSource::Local,
+                event,
+                hash: hash.to_string(),
+                duration,
+            };
+
+            let _ = analytics_recorder.send(analytics_event);
+        }
+    }
+
+    pub fn fetch(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        let start = Instant::now();
+        let result = self.restore(anchor, hash);
+        let bytes = match &result {
+            Ok(Some((_, _, bytes))) => *bytes,
+            _ => 0,
+        };
+        let result = result.map(|hit| hit.map(|(hit, restored, _)| (hit, restored)));
+        self.events.fetch(hash, start, &result, || bytes);
+        result
+    }
+
+    // Also returns the total size of the restored files
+    fn restore(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>, u64)>, CacheError> {
+        let Some(manifest) = self.read_manifest(hash)? else {
+            self.log_fetch(analytics::CacheEvent::Miss, hash, 0);
+            return Ok(None);
+        };
+
+        anchor.create_dir_all()?;
+        let mut dir_cache = CachedDirTree::new(anchor.to_owned());
+        let mut restored = Vec::with_capacity(manifest.entries.len());
+
+        for entry in &manifest.entries {
+            match entry {
+                ManifestEntry::Directory { path, mode } => {
+                    dir_cache.safe_mkdir_all(anchor, path, *mode)?;
+                    restored.push(path.clone());
+                }
+                ManifestEntry::File {
+                    path,
+                    digest,
+                    size,
+                    mode,
+                } => {
+                    let object_path = self.object_path(digest);
+                    let object = object_path.open()?;
+                    if object.metadata()?.len() != *size {
+                        return Err(CacheError::Corrupt(hash.to_string(), Backtrace::capture()));
+                    }
+
+                    dir_cache.safe_mkdir_file(anchor, path)?;
+                    write_regular(&anchor.resolve(path), *mode, None, object)?;
+                    restored.push(path.clone());
+                }
+                ManifestEntry::Symlink { path, target } => {
+                    let target = checked_symlink_target(anchor, path, target, self.symlink_policy)?;
+                    dir_cache.safe_mkdir_file(anchor, path)?;
+                    let link_path = anchor.resolve(path);
+                    match link_path.remove_file() {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
+                    }
+
+                    let is_dir = link_path
+                        .parent()
+                        .map_or(false, |parent| parent.as_std_path().join(&target).is_dir());
+                    if is_dir {
+                        link_path.symlink_to_dir(target)?;
+                    } else {
+                        link_path.symlink_to_file(target)?;
+                    }
+                    restored.push(path.clone());
+                }
+            }
+        }
+
+        self.log_fetch(analytics::CacheEvent::Hit, hash, manifest.duration);
+
+        Ok(Some((
+            CacheHitMetadata {
+                time_saved: manifest.duration,
+                source: CacheSource::Local,
+            },
+            restored,
+            manifest.size(),
+        )))
+    }
+
+    pub(crate) fn exists(&self, hash: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
+        let Some(manifest) = self.read_manifest(hash)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(CacheHitMetadata {
+            time_saved: manifest.duration,
+            source: CacheSource::Local,
+        }))
+    }
+
+    pub fn put(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+    ) -> Result<(), CacheError> {
+        let start = Instant::now();
+        let result = self.store(anchor, hash, files, duration);
+        let bytes = *result.as_ref().unwrap_or(&0);
+        let result = result.map(|_| ());
+        self.events.put(hash, start, &result, || bytes);
+        result
+    }
+
+    // Returns the total size of the stored files
+    fn store(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+    ) -> Result<u64, CacheError> {
+        let mut entries = Vec::with_capacity(files.len());
+        for file in files {
+            let source_path = anchor.resolve(file);
+            let metadata = source_path.symlink_metadata()?;
+
+            let entry = if metadata.is_symlink() {
+                ManifestEntry::Symlink {
+                    path: file.clone(),
+                    target: source_path.read_link()?.into_string(),
+                }
+            } else if metadata.is_dir() {
+                ManifestEntry::Directory {
+                    path: file.clone(),
+                    mode: file_mode(&metadata),
+                }
+            } else if metadata.is_file() {
+                let digest = self.store_object(&source_path)?;
+                ManifestEntry::File {
+                    path: file.clone(),
+                    digest,
+                    size: metadata.len(),
+                    mode: file_mode(&metadata),
+                }
+            } else {
+                return Err(CacheError::CreateUnsupportedFileType(Backtrace::capture()));
+            };
+            entries.push(entry);
+        }
+
+        let manifest = Manifest {
+            hash: hash.to_string(),
+            duration,
+            entries,
+        };
+        let contents = serde_json::to_string(&manifest)
+            .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+
+        let manifest_path = self.manifest_path(hash);
+        let temp_path = Self::temp_path(&manifest_path);
+        temp_path.create_with_contents(contents)?;
+        temp_path.rename(&manifest_path)?;
+
+        Ok(manifest.size())
+    }
+
+    // Copies `source_path` into the object store, unless an identical file is
+    // already there, and returns its digest.
+    fn store_object(&self, source_path: &AbsoluteSystemPath) -> Result<String, CacheError> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut source_path.open()?, &mut hasher)?;
+        let digest = hex::encode(hasher.finalize());
+
+        let object_path = self.object_path(&digest);
+        if object_path.exists() {
+            return Ok(digest);
+        }
+
+        object_path
+            .parent()
+            .expect("objects have a parent directory")
+            .create_dir_all()?;
+        let temp_path = Self::temp_path(&object_path);
+        let mut source = source_path.open()?;
+        let mut temp_file = temp_path.create()?;
+        copy_and_hash(&mut source, &mut temp_file, &digest)?;
+        // Objects are only ever replaced with identical contents, so it doesn't
+        // matter if another process wins the race.
+        temp_path.rename(&object_path)?;
+
+        Ok(digest)
+    }
+
+    /// Removes the manifest for `hash`. Objects it referenced are left until
+    /// the next call to `remove_unreferenced_objects`.
+    pub fn evict(&self, hash: &str) -> Result<(), CacheError> {
+        match self.manifest_path(hash).remove_file() {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Deletes objects that aren't referenced by any manifest, returning the
+    /// number of bytes freed.
+    pub fn remove_unreferenced_objects(&self) -> Result<u64, CacheError> {
+        let mut referenced = HashSet::new();
+        for entry in std::fs::read_dir(self.cache_directory.join_component(MANIFESTS_DIRECTORY))? {
+            let entry = entry?;
+            let Some(hash) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .map(|hash| hash.to_string())
+            else {
+                continue;
+            };
+            let Some(manifest) = self.read_manifest(&hash)? else {
+                continue;
+            };
+            for entry in manifest.entries {
+                if let ManifestEntry::File { digest, .. } = entry {
+                    referenced.insert(digest);
+                }
+            }
+        }
+
+        let mut bytes_freed = 0;
+        for shard in std::fs::read_dir(self.cache_directory.join_component(OBJECTS_DIRECTORY))? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for object in std::fs::read_dir(shard.path())? {
+                let object = object?;
+                let is_referenced = object
+                    .file_name()
+                    .to_str()
+                    .map_or(false, |digest| referenced.contains(digest));
+                if is_referenced {
+                    continue;
+                }
+
+                debug!("removing unreferenced object {:?}", object.file_name());
+                bytes_freed += object.metadata()?.len();
+                std::fs::remove_file(object.path())?;
+            }
+        }
+
+        Ok(bytes_freed)
+    }
+}
+
+// Copies `source` into `destination`, failing if the file changed since it
+// was hashed so that an object never has contents that don't match its name.
+fn copy_and_hash(
+    source: &mut impl Read,
+    destination: &mut File,
+    expected_digest: &str,
+) -> Result<(), CacheError> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let n = source.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        destination.write_all(&buffer[..n])?;
+    }
+
+    if hex::encode(hasher.finalize()) != expected_digest {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "file was modified while it was being cached",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.mode()
+}
+
+#[cfg(windows)]
+fn file_mode(_metadata: &std::fs::Metadata) -> u32 {
+    // Matches the mode we record for archives on Windows
+    0o755
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+
+    fn object_count(cache: &CASCache) -> Result<usize> {
+        let mut count = 0;
+        for shard in std::fs::read_dir(cache.cache_directory.join_component(OBJECTS_DIRECTORY))? {
+            count += std::fs::read_dir(shard?.path())?.count();
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_cas_round_trip_and_dedupe() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = CASCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let dir = AnchoredSystemPathBuf::from_raw("dist")?;
+        let unchanged = AnchoredSystemPathBuf::from_raw("dist/vendor.js")?;
+        let changed = AnchoredSystemPathBuf::from_raw("dist/index.js")?;
+        let link = AnchoredSystemPathBuf::from_raw("dist/latest.js")?;
+        repo_root_path.resolve(&dir).create_dir_all()?;
+        repo_root_path
+            .resolve(&unchanged)
+            .create_with_contents("Fallen Angels")?;
+        repo_root_path
+            .resolve(&changed)
+            .create_with_contents("first")?;
+        repo_root_path.resolve(&link).symlink_to_file("index.js")?;
+        let files = vec![dir, unchanged.clone(), changed.clone(), link.clone()];
+
+        assert_eq!(cache.exists("first")?, None);
+        cache.put(repo_root_path, "first", &files, 10)?;
+        assert_eq!(object_count(&cache)?, 2);
+
+        // Only the changed file adds an object
+        repo_root_path
+            .resolve(&changed)
+            .create_with_contents("second")?;
+        cache.put(repo_root_path, "second", &files, 20)?;
+        assert_eq!(object_count(&cache)?, 3);
+
+        assert_eq!(
+            cache.exists("first")?,
+            Some(CacheHitMetadata {
+                time_saved: 10,
+                source: CacheSource::Local,
+            })
+        );
+
+        let output = tempdir()?;
+        let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+        let (hit, restored) = cache.fetch(output_path, "first")?.unwrap();
+        assert_eq!(hit.time_saved, 10);
+        assert_eq!(restored, files);
+        assert_eq!(
+            output_path.resolve(&unchanged).read_to_string()?,
+            "Fallen Angels"
+        );
+        assert_eq!(output_path.resolve(&changed).read_to_string()?, "first");
+        assert_eq!(output_path.resolve(&link).read_link()?, "index.js");
+
+        // Evicting an artifact only frees the objects nothing else uses
+        cache.evict("first")?;
+        assert_eq!(cache.fetch(output_path, "first")?, None);
+        assert_eq!(cache.remove_unreferenced_objects()?, "first".len() as u64);
+        assert_eq!(object_count(&cache)?, 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use std::io;
+
+use reqwest::StatusCode;
+use turborepo_api_client::Error as ApiError;
+
+use crate::{signature_authentication::SignatureError, CacheError};
+
+/// The category of a `CacheError`, for deciding what to do about it without
+/// matching on every variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheErrorKind {
+    /// The artifact, or something it depends on, doesn't exist.
+    NotFound,
+    /// An artifact or its metadata is damaged or failed verification.
+    Corrupt,
+    /// The filesystem or the remote cache refused access.
+    PermissionDenied,
+    /// A network failure that may not happen again, like a timeout or a 5xx
+    /// response.
+    NetworkTransient,
+    /// The remote cache rejected the request and will do so again.
+    NetworkPermanent,
+    /// Storing the artifact would exceed the cache's quota.
+    QuotaExceeded,
+    /// The cache or the request is misconfigured, e.g. an invalid key, tag
+    /// or glob.
+    InvalidInput,
+    /// Anything else, such as an unexpected IO error.
+    Other,
+}
+
+impl CacheErrorKind {
+    fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => CacheErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => CacheErrorKind::PermissionDenied,
+            _ => CacheErrorKind::Other,
+        }
+    }
+
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => CacheErrorKind::NotFound,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => CacheErrorKind::PermissionDenied,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+                CacheErrorKind::NetworkTransient
+            }
+            StatusCode::NOT_IMPLEMENTED => CacheErrorKind::NetworkPermanent,
+            status if status.is_server_error() => CacheErrorKind::NetworkTransient,
+            _ => CacheErrorKind::NetworkPermanent,
+        }
+    }
+
+    fn from_reqwest(error: &reqwest::Error) -> Self {
+        if let Some(status) = error.status() {
+            Self::from_status(status)
+        } else if error.is_connect() || error.is_timeout() || error.is_body() {
+            CacheErrorKind::NetworkTransient
+        } else if error.is_builder() {
+            CacheErrorKind::InvalidInput
+        } else {
+            CacheErrorKind::NetworkPermanent
+        }
+    }
+
+    fn from_api(error: &ApiError) -> Self {
+        match error {
+            ApiError::ReqwestError(e) => Self::from_reqwest(e),
+            ApiError::TooManyFailures(e) => Self::from_reqwest(e),
+            ApiError::ArtifactReadError(e) => Self::from_io(e),
+            ApiError::UnknownStatus { code, .. } => code
+                .parse::<u16>()
+                .ok()
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .map_or(CacheErrorKind::NetworkPermanent, Self::from_status),
+            ApiError::CacheMiss => CacheErrorKind::NotFound,
+            ApiError::TlsError(_)
+            | ApiError::TlsUnavailable
+            | ApiError::InvalidProxy(..)
+            | ApiError::CertificateReadError(..)
+            | ApiError::InvalidCertificate(..)
+            | ApiError::NoCertificates(_)
+            | ApiError::InvalidUrl(_) => CacheErrorKind::InvalidInput,
+            ApiError::CacheDisabled { .. }
+            | ApiError::InvalidHeader(_)
+            | ApiError::UnknownCachingStatus(..) => CacheErrorKind::NetworkPermanent,
+        }
+    }
+
+    fn from_signature(error: &SignatureError) -> Self {
+        match error {
+            SignatureError::NoSignatureSecretKey | SignatureError::Hmac(_) => {
+                CacheErrorKind::InvalidInput
+            }
+            SignatureError::IO(e) => Self::from_io(e),
+            // A tag that isn't valid base64 was tampered with
+            SignatureError::SerializationError(_) | SignatureError::Base64EncodingError(_) => {
+                CacheErrorKind::Corrupt
+            }
+        }
+    }
+}
+
+impl CacheError {
+    pub fn kind(&self) -> CacheErrorKind {
+        match self {
+            CacheError::IO(e, _) => CacheErrorKind::from_io(e),
+            CacheError::ApiClientError(e, _) => CacheErrorKind::from_api(e),
+            CacheError::SignatureError(e, _) => CacheErrorKind::from_signature(e),
+            CacheError::MissingDeltaBase(..) => CacheErrorKind::NotFound,
+            CacheError::ArtifactTagMissing(_)
+            | CacheError::InvalidTag(_)
+            | CacheError::InvalidFilePath(..)
+            | CacheError::InvalidDuration(_)
+            | CacheError::CycleDetected(_)
+            | CacheError::LinkTargetDoesNotExist(..)
+            | CacheError::LinkTargetNotOnHeader(_)
+            | CacheError::RestoreUnsupportedFileType(..)
+            | CacheError::MalformedTar(_)
+            | CacheError::WindowsUnsafeName(..)
+            | CacheError::LinkOutsideOfDirectory(..)
+            | CacheError::InvalidMetadata(..)
+            | CacheError::Corrupt(..)
+            | CacheError::CaseCollision(..)
+            | CacheError::InvalidBundle(..)
+            | CacheError::InvalidOutputs(..) => CacheErrorKind::Corrupt,
+            CacheError::UnexpectedRange(..) | CacheError::RemoteUnreachable(..) => {
+                CacheErrorKind::NetworkTransient
+            }
+            CacheError::RemoteBackendError(..) => CacheErrorKind::NetworkPermanent,
+            CacheError::QuotaExceeded(..) => CacheErrorKind::QuotaExceeded,
+            CacheError::PathError(..)
+            | CacheError::CreateUnsupportedFileType(_)
+            | CacheError::EncryptedArchive(_)
+            | CacheError::InvalidEncryptionKey(_)
+            | CacheError::InvalidGlob(..)
+            | CacheError::InvalidArtifactTag(..) => CacheErrorKind::InvalidInput,
+            CacheError::MetadataWriteFailure(..)
+            | CacheError::FileExists(..)
+            | CacheError::CacheShuttingDown => CacheErrorKind::Other,
+        }
+    }
+
+    /// Whether the operation can be treated as a cache miss, leaving the task
+    /// to run as if there were no cache. Errors that aren't recoverable point
+    /// at a problem with the cache's configuration or environment, which will
+    /// keep failing until someone fixes it.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self.kind(),
+            CacheErrorKind::NotFound
+                | CacheErrorKind::Corrupt
+                | CacheErrorKind::NetworkTransient
+                | CacheErrorKind::NetworkPermanent
+                | CacheErrorKind::QuotaExceeded
+        )
+    }
+
+    /// Whether the same operation may succeed if it's tried again.
+    pub fn is_transient(&self) -> bool {
+        self.kind() == CacheErrorKind::NetworkTransient
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::backtrace::Backtrace;
+
+    use super::*;
+
+    #[test]
+    fn test_error_kinds() {
+        let io_error = |kind| CacheError::from(io::Error::from(kind));
+        assert_eq!(
+            io_error(io::ErrorKind::NotFound).kind(),
+            CacheErrorKind::NotFound
+        );
+        assert_eq!(
+            io_error(io::ErrorKind::PermissionDenied).kind(),
+            CacheErrorKind::PermissionDenied
+        );
+        assert!(!io_error(io::ErrorKind::PermissionDenied).is_recoverable());
+
+        let corrupt = CacheError::Corrupt("the-hash".to_string(), Backtrace::capture());
+        assert_eq!(corrupt.kind(), CacheErrorKind::Corrupt);
+        assert!(corrupt.is_recoverable());
+        assert!(!corrupt.is_transient());
+
+        let unreachable = CacheError::RemoteUnreachable("offline".into(), Backtrace::capture());
+        assert!(unreachable.is_transient());
+
+        let status = |code: &str| {
+            CacheError::from(ApiError::UnknownStatus {
+                code: code.to_string(),
+                message: String::new(),
+                backtrace: Backtrace::capture(),
+            })
+        };
+        assert_eq!(status("503").kind(), CacheErrorKind::NetworkTransient);
+        assert_eq!(status("429").kind(), CacheErrorKind::NetworkTransient);
+        assert_eq!(status("400").kind(), CacheErrorKind::NetworkPermanent);
+        assert_eq!(status("403").kind(), CacheErrorKind::PermissionDenied);
+        assert_eq!(
+            CacheError::from(ApiError::CacheMiss).kind(),
+            CacheErrorKind::NotFound
+        );
+
+        let quota = CacheError::QuotaExceeded("the-hash".into(), 20, 10, Backtrace::capture());
+        assert!(quota.is_recoverable());
+        let tag = CacheError::InvalidArtifactTag("Branch".into(), Backtrace::capture());
+        assert_eq!(tag.kind(), CacheErrorKind::InvalidInput);
+        assert!(!tag.is_recoverable());
+    }
+}
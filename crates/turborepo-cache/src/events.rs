@@ -0,0 +1,181 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{CacheError, CacheOpts, CacheSource};
+
+/// A fetch or put of a single artifact, as reported to a
+/// `CacheEventHandler`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheEvent<'a> {
+    pub hash: &'a str,
+    pub source: CacheSource,
+    // Size of the artifact as stored by the cache that handled it, or 0 for
+    // misses and errors
+    pub bytes: u64,
+    // How long the fetch or put took
+    pub duration: Duration,
+}
+
+/// Receives an event for every fetch and put handled by each cache, so a
+/// fetch that misses locally and hits remotely reports both. Handlers are
+/// called on the thread doing the work and should return quickly.
+pub trait CacheEventHandler: fmt::Debug + Send + Sync {
+    fn on_hit(&self, _event: &CacheEvent) {}
+    fn on_miss(&self, _event: &CacheEvent) {}
+    fn on_put(&self, _event: &CacheEvent) {}
+    fn on_error(&self, _event: &CacheEvent, _error: &CacheError) {}
+}
+
+// Reports the events of one cache to the handler from `CacheOpts`, if any.
+#[derive(Debug, Clone)]
+pub(crate) struct EventReporter {
+    handler: Option<Arc<dyn CacheEventHandler>>,
+    source: CacheSource,
+}
+
+impl EventReporter {
+    pub fn new(opts: &CacheOpts, source: CacheSource) -> Self {
+        Self {
+            handler: opts.event_handler.clone(),
+            source,
+        }
+    }
+
+    // `bytes` is only called for hits
+    pub fn fetch<T>(
+        &self,
+        hash: &str,
+        start: Instant,
+        result: &Result<Option<T>, CacheError>,
+        bytes: impl FnOnce() -> u64,
+    ) {
+        let Some(handler) = &self.handler else {
+            return;
+        };
+
+        match result {
+            Ok(Some(_)) => handler.on_hit(&self.event(hash, start, bytes())),
+            Ok(None) => handler.on_miss(&self.event(hash, start, 0)),
+            Err(e) => handler.on_error(&self.event(hash, start, 0), e),
+        }
+    }
+
+    // `bytes` is only called for successful puts
+    pub fn put(
+        &self,
+        hash: &str,
+        start: Instant,
+        result: &Result<(), CacheError>,
+        bytes: impl FnOnce() -> u64,
+    ) {
+        let Some(handler) = &self.handler else {
+            return;
+        };
+
+        match result {
+            Ok(()) => handler.on_put(&self.event(hash, start, bytes())),
+            Err(e) => handler.on_error(&self.event(hash, start, 0), e),
+        }
+    }
+
+    fn event<'a>(&self, hash: &'a str, start: Instant, bytes: u64) -> CacheEvent<'a> {
+        CacheEvent {
+            hash,
+            source: self.source,
+            bytes,
+            duration: start.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::fs::FSCache;
+
+    #[derive(Debug, Default)]
+    struct RecordingHandler {
+        events: Mutex<Vec<(&'static str, String, u64)>>,
+    }
+
+    impl RecordingHandler {
+        fn record(&self, kind: &'static str, event: &CacheEvent) {
+            assert_eq!(event.source, CacheSource::Local);
+            self.events
+                .lock()
+                .unwrap()
+                .push((kind, event.hash.to_string(), event.bytes));
+        }
+    }
+
+    impl CacheEventHandler for RecordingHandler {
+        fn on_hit(&self, event: &CacheEvent) {
+            self.record("hit", event);
+        }
+
+        fn on_miss(&self, event: &CacheEvent) {
+            self.record("miss", event);
+        }
+
+        fn on_put(&self, event: &CacheEvent) {
+            self.record("put", event);
+        }
+
+        fn on_error(&self, event: &CacheEvent, _error: &CacheError) {
+            self.record("error", event);
+        }
+    }
+
+    #[test]
+    fn test_fs_cache_events() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let handler = Arc::new(RecordingHandler::default());
+        let opts = CacheOpts {
+            event_handler: Some(handler.clone()),
+            ..Default::default()
+        };
+        let cache = FSCache::new(&opts, repo_root_path, None)?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Chungking Express")?;
+        cache.put(repo_root_path, "the-hash", &[file], 10)?;
+        cache.fetch(repo_root_path, "the-hash")?;
+        cache.fetch(repo_root_path, "missing")?;
+        assert!(cache
+            .fetch_filtered(repo_root_path, "the-hash", &["[".to_string()])
+            .is_err());
+
+        let events = handler.events.lock().unwrap();
+        let kinds = events
+            .iter()
+            .map(|(kind, hash, _)| (*kind, hash.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("put", "the-hash"),
+                ("hit", "the-hash"),
+                ("miss", "missing"),
+                ("error", "the-hash"),
+            ]
+        );
+        // Puts and hits report the size of the archive
+        assert!(events[0].2 > 0);
+        assert_eq!(events[0].2, events[1].2);
+        assert_eq!(events[2].2, 0);
+
+        Ok(())
+    }
+}
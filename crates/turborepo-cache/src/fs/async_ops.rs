@@ -0,0 +1,145 @@
+use std::{io::Read, panic, sync::Arc};
+
+use turbopath::{AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
+
+use super::{ArtifactDetails, FSCache};
+use crate::{CacheError, CacheHitMetadata};
+
+// Runs filesystem work on tokio's blocking pool so that large archives don't
+// tie up a runtime worker.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, CacheError> + Send + 'static,
+) -> Result<T, CacheError> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        // The runtime is shutting down
+        Err(_) => Err(CacheError::CacheShuttingDown),
+    }
+}
+
+impl FSCache {
+    pub async fn fetch_async(
+        self: Arc<Self>,
+        anchor: AbsoluteSystemPathBuf,
+        hash: String,
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        run_blocking(move || self.fetch(&anchor, &hash)).await
+    }
+
+    pub async fn exists_async(
+        self: Arc<Self>,
+        hash: String,
+    ) -> Result<Option<CacheHitMetadata>, CacheError> {
+        run_blocking(move || self.exists(&hash)).await
+    }
+
+    pub async fn put_async(
+        self: Arc<Self>,
+        anchor: AbsoluteSystemPathBuf,
+        hash: String,
+        files: Vec<AnchoredSystemPathBuf>,
+        duration: u64,
+    ) -> Result<(), CacheError> {
+        run_blocking(move || self.put(&anchor, &hash, &files, duration)).await
+    }
+
+    pub async fn put_with_details_async(
+        self: Arc<Self>,
+        anchor: AbsoluteSystemPathBuf,
+        hash: String,
+        files: Vec<AnchoredSystemPathBuf>,
+        duration: u64,
+        details: ArtifactDetails,
+    ) -> Result<(), CacheError> {
+        run_blocking(move || self.put_with_details(&anchor, &hash, &files, duration, details)).await
+    }
+
+    pub async fn put_with_logs_async(
+        self: Arc<Self>,
+        anchor: AbsoluteSystemPathBuf,
+        hash: String,
+        files: Vec<AnchoredSystemPathBuf>,
+        duration: u64,
+        details: ArtifactDetails,
+        logs: Vec<u8>,
+    ) -> Result<(), CacheError> {
+        run_blocking(move || self.put_with_logs(&anchor, &hash, &files, duration, details, &logs))
+            .await
+    }
+
+    pub async fn put_archive_async(
+        self: Arc<Self>,
+        hash: String,
+        archive: impl Read + Send + 'static,
+        duration: u64,
+        details: ArtifactDetails,
+    ) -> Result<(), CacheError> {
+        run_blocking(move || self.put_archive(&hash, archive, duration, details)).await
+    }
+
+    pub async fn fetch_logs_async(
+        self: Arc<Self>,
+        hash: String,
+    ) -> Result<Option<Vec<u8>>, CacheError> {
+        run_blocking(move || {
+            let mut logs = Vec::new();
+            Ok(self.fetch_logs(&hash, &mut logs)?.map(|_| logs))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::AbsoluteSystemPath;
+
+    use super::*;
+    use crate::{CacheOpts, CacheSource};
+
+    #[tokio::test]
+    async fn test_async_round_trip() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = Arc::new(FSCache::new(&CacheOpts::default(), repo_root_path, None)?);
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Days of Being Wild")?;
+
+        assert_eq!(cache.clone().exists_async("the-hash".into()).await?, None);
+        cache
+            .clone()
+            .put_async(
+                repo_root_path.to_owned(),
+                "the-hash".into(),
+                vec![file.clone()],
+                10,
+            )
+            .await?;
+
+        let hit = CacheHitMetadata {
+            time_saved: 10,
+            source: CacheSource::Local,
+        };
+        assert_eq!(
+            cache.clone().exists_async("the-hash".into()).await?,
+            Some(hit)
+        );
+
+        repo_root_path.resolve(&file).remove_file()?;
+        let fetched = cache
+            .fetch_async(repo_root_path.to_owned(), "the-hash".into())
+            .await?;
+        assert_eq!(fetched, Some((hit, vec![file.clone()])));
+        assert_eq!(
+            repo_root_path.resolve(&file).read_to_string()?,
+            "Days of Being Wild"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeSet,
+    fs::OpenOptions,
+    io::{self, BufReader, BufWriter, Write},
+    path::Component,
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf};
+
+use super::{
+    sync_path, ArtifactFiles, CacheMetadata, FSCache, ARCHIVE_EXTENSIONS, ENTRY_INDEX_SUFFIX,
+    METADATA_SUFFIX,
+};
+use crate::CacheError;
+
+const BUNDLE_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+
+// Lists the artifacts in a bundle. The rest of the bundle is the archive,
+// metadata and entry index files of each artifact, named as they are in the
+// cache directory.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleManifest {
+    version: u32,
+    artifacts: Vec<String>,
+}
+
+/// The artifacts handled by `FSCache::export_bundle` or
+/// `FSCache::import_bundle`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundleSummary {
+    pub artifacts: Vec<String>,
+    // Hashes that weren't in the cache when exporting, or that already were
+    // when importing
+    pub skipped: Vec<String>,
+}
+
+// Bundles come from other machines, so hashes are used as file names only if
+// they can't name anything outside of the cache directory
+fn is_plain_hash(hash: &str) -> bool {
+    !hash.is_empty()
+        && !hash.starts_with('.')
+        && !hash.contains(|c| c == '/' || c == '\\' || c == '\0')
+}
+
+fn invalid_bundle(reason: impl Into<String>) -> CacheError {
+    CacheError::InvalidBundle(reason.into(), Backtrace::capture())
+}
+
+impl FSCache {
+    /// Writes the artifacts for `hashes` to a single bundle file that
+    /// `import_bundle` can load into another cache, e.g. on a machine without
+    /// network access. The bases of delta artifacts are included as well.
+    pub fn export_bundle(
+        &self,
+        hashes: &[&str],
+        bundle_path: &AbsoluteSystemPath,
+    ) -> Result<BundleSummary, CacheError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let mut builder =
+            tar::Builder::new(BufWriter::new(bundle_path.open_with_options(options)?));
+
+        let mut summary = BundleSummary::default();
+        let mut seen = BTreeSet::new();
+        let mut pending: Vec<String> = hashes.iter().rev().map(|hash| hash.to_string()).collect();
+        while let Some(hash) = pending.pop() {
+            if !seen.insert(hash.clone()) {
+                continue;
+            }
+
+            // Keep the artifact from being rewritten while we copy it
+            let metadata_path = self.metadata_path(&hash);
+            let (Some(_lock), Some(archive_path), true) = (
+                self.lock_shared_if_cached(&hash)?,
+                self.archive_path(&hash),
+                metadata_path.exists(),
+            ) else {
+                summary.skipped.push(hash);
+                continue;
+            };
+            let meta = CacheMetadata::read(&metadata_path)?;
+
+            let entry_index_path = Some(self.entry_index_path(&hash)).filter(|path| path.exists());
+            for path in [&archive_path, &metadata_path]
+                .into_iter()
+                .chain(entry_index_path.as_ref())
+            {
+                let file_name = path
+                    .file_name()
+                    .expect("artifact files are always in the cache directory");
+                builder.append_path_with_name(path.as_std_path(), file_name)?;
+            }
+            if let Some(base) = meta.delta_base {
+                pending.push(base);
+            }
+            summary.artifacts.push(hash);
+        }
+
+        let manifest = serde_json::to_vec(&BundleManifest {
+            version: BUNDLE_VERSION,
+            artifacts: summary.artifacts.clone(),
+        })
+        .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, MANIFEST_FILE, manifest.as_slice())?;
+        builder.into_inner()?.flush()?;
+
+        Ok(summary)
+    }
+
+    /// Adds the artifacts in a bundle written by `export_bundle` to this
+    /// cache. Artifacts that are already cached are left alone, and any that
+    /// fail verification stop the import.
+    pub fn import_bundle(
+        &self,
+        bundle_path: &AbsoluteSystemPath,
+    ) -> Result<BundleSummary, CacheError> {
+        // Unpacked next to the cache so that artifacts can be renamed into it
+        let staging = tempfile::Builder::new()
+            .prefix(".import.")
+            .tempdir_in(&self.cache_directory)?;
+        let staging_path = AbsoluteSystemPath::from_std_path(staging.path())?;
+
+        let manifest = Self::unpack_bundle(bundle_path, staging_path)?;
+
+        let mut summary = BundleSummary::default();
+        for hash in manifest.artifacts {
+            if self.import_artifact(&hash, staging_path)? {
+                summary.artifacts.push(hash);
+            } else {
+                summary.skipped.push(hash);
+            }
+        }
+        if self.fsync {
+            sync_path(&self.cache_directory)?;
+        }
+
+        Ok(summary)
+    }
+
+    fn unpack_bundle(
+        bundle_path: &AbsoluteSystemPath,
+        staging_path: &AbsoluteSystemPath,
+    ) -> Result<BundleManifest, CacheError> {
+        let mut archive = tar::Archive::new(BufReader::new(bundle_path.open()?));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                return Err(invalid_bundle("bundles can only contain regular files"));
+            }
+            let path = entry.path()?;
+            let file_name = match path.components().collect::<Vec<_>>().as_slice() {
+                [Component::Normal(name)] => name.to_str().filter(|name| is_plain_hash(name)),
+                _ => None,
+            }
+            .ok_or_else(|| invalid_bundle(format!("unexpected file {}", path.display())))?
+            .to_string();
+
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            let mut file = staging_path
+                .join_component(&file_name)
+                .open_with_options(options)?;
+            io::copy(&mut entry, &mut file)?;
+        }
+
+        let manifest_path = staging_path.join_component(MANIFEST_FILE);
+        if !manifest_path.exists() {
+            return Err(invalid_bundle("missing manifest"));
+        }
+        let manifest: BundleManifest = serde_json::from_str(&manifest_path.read_to_string()?)
+            .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))?;
+        if manifest.version > BUNDLE_VERSION {
+            return Err(invalid_bundle(format!(
+                "unsupported version {}",
+                manifest.version
+            )));
+        }
+        if let Some(hash) = manifest.artifacts.iter().find(|hash| !is_plain_hash(hash)) {
+            return Err(invalid_bundle(format!("invalid hash {}", hash)));
+        }
+
+        Ok(manifest)
+    }
+
+    // Moves an unpacked artifact into the cache. Returns false if the cache
+    // already had it.
+    fn import_artifact(
+        &self,
+        hash: &str,
+        staging_path: &AbsoluteSystemPath,
+    ) -> Result<bool, CacheError> {
+        let staged_metadata_path =
+            staging_path.join_component(&format!("{}{}", hash, METADATA_SUFFIX));
+        let staged_entry_index_path =
+            staging_path.join_component(&format!("{}{}", hash, ENTRY_INDEX_SUFFIX));
+        let staged_archive_path = ARCHIVE_EXTENSIONS
+            .iter()
+            .map(|extension| staging_path.join_component(&format!("{}.{}", hash, extension)))
+            .find(|path| path.exists());
+        let (Some(staged_archive_path), true) =
+            (staged_archive_path, staged_metadata_path.exists())
+        else {
+            return Err(invalid_bundle(format!("missing files for {}", hash)));
+        };
+
+        let mut meta = CacheMetadata::read(&staged_metadata_path)?;
+        if meta.hash != hash {
+            return Err(invalid_bundle(format!(
+                "metadata for {} is for {}",
+                hash, meta.hash
+            )));
+        }
+        self.verify_archive(hash, &staged_archive_path, &meta)?;
+
+        let _lock = self.lock_exclusive(hash)?;
+        let metadata_path = self.metadata_path(hash);
+        if self.archive_path(hash).is_some() && metadata_path.exists() {
+            debug!("not importing {}, it's already cached", hash);
+            return Ok(false);
+        }
+        self.remove_extracted(hash)?;
+
+        let archive_path: AbsoluteSystemPathBuf = self.cache_directory.join_component(
+            staged_archive_path
+                .file_name()
+                .expect("staged files have a file name"),
+        );
+        if self.fsync {
+            sync_path(&staged_archive_path)?;
+        }
+        staged_archive_path.rename(&archive_path)?;
+        // Artifacts written by older versions don't have an entry index
+        let entry_index_path = self.entry_index_path(hash);
+        if staged_entry_index_path.exists() {
+            staged_entry_index_path.rename(&entry_index_path)?;
+        } else if entry_index_path.exists() {
+            entry_index_path.remove_file()?;
+        }
+
+        // Pins are local to a cache, and an imported artifact counts as used
+        // now so that gc doesn't immediately evict it as stale
+        meta.pinned = false;
+        meta.last_accessed = Some(Utc::now());
+        meta.verified = Some(self.archive_stamp(hash, &archive_path)?);
+        meta.write_synced(&metadata_path, self.fsync)?;
+
+        let size = self.stored_size(hash)?;
+        let record = Self::index_record(&meta, size, archive_path.extension() != Some("tar"));
+        self.update_index(|index| {
+            index.insert(hash.to_string(), record);
+        })?;
+
+        self.enforce_quota(
+            ArtifactFiles {
+                hash: hash.to_string(),
+                archives: vec![archive_path],
+                metadata: Some(metadata_path),
+                entry_index: Some(entry_index_path),
+            },
+            size,
+        )?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::assert_matches::assert_matches;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{test_cases::TestCache, CacheOpts};
+
+    #[test]
+    fn test_bundles() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let file = cache.write_file("out.txt", "Fallen Angels")?;
+        cache.put(repo_root_path, "first", &[file.clone()], 10)?;
+        cache.write_file("out.txt", "Happy Together")?;
+        cache.put(repo_root_path, "second", &[file.clone()], 20)?;
+
+        let bundle_path = repo_root_path.join_component("cache.bundle");
+        let summary =
+            cache.export_bundle(&["first", "second", "first", "missing"], &bundle_path)?;
+        assert_eq!(summary.artifacts, vec!["first", "second"]);
+        assert_eq!(summary.skipped, vec!["missing"]);
+
+        let other_cache = TestCache::new(&CacheOpts::default())?;
+        let other_root_path = other_cache.repo_root();
+        let summary = other_cache.import_bundle(&bundle_path)?;
+        assert_eq!(summary.artifacts, vec!["first", "second"]);
+        assert!(summary.skipped.is_empty());
+
+        let hit = other_cache.fetch(other_root_path, "second")?;
+        assert_eq!(hit.map(|(hit, _)| hit.time_saved), Some(20));
+        assert_eq!(
+            other_root_path.resolve(&file).read_to_string()?,
+            "Happy Together"
+        );
+        assert_eq!(other_cache.stats()?.artifact_count, 2);
+
+        // Importing again doesn't replace anything
+        let summary = other_cache.import_bundle(&bundle_path)?;
+        assert!(summary.artifacts.is_empty());
+        assert_eq!(summary.skipped, vec!["first", "second"]);
+
+        // Files that could escape the cache directory are rejected
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        // `set_path` refuses to write this name
+        let name = b"../escape-meta.json";
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_cksum();
+        builder.append(&header, io::empty())?;
+        let evil_bundle_path = repo_root_path.join_component("evil.bundle");
+        evil_bundle_path.create_with_contents(builder.into_inner()?)?;
+        assert_matches!(
+            other_cache.import_bundle(&evil_bundle_path),
+            Err(CacheError::InvalidBundle(..))
+        );
+
+        Ok(())
+    }
+}
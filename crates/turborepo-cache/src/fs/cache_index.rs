@@ -0,0 +1,359 @@
+use std::{
+    backtrace::Backtrace,
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use turbopath::AbsoluteSystemPathBuf;
+
+use super::{CacheMetadata, FSCache};
+use crate::CacheError;
+
+const INDEX_FILE: &str = "index.json";
+// How the index file starts, followed by its generation. Enough of the file
+// is read to cover the longest generation.
+const GENERATION_PREFIX: &[u8] = b"{\"generation\":";
+const GENERATION_PREFIX_LEN: u64 = GENERATION_PREFIX.len() as u64 + 20;
+
+/// A summary of one artifact, kept in the cache index so that it can be
+/// answered without reading the artifact's metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexRecord {
+    // Size of the archive, its metadata file and any extracted copy
+    pub size: u64,
+    pub duration: u64,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_accessed: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub flags: u8,
+    // The artifact this one is a delta against, so that gc can evict deltas
+    // together with their base
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_base: Option<String>,
+}
+
+impl IndexRecord {
+    pub const COMPRESSED: u8 = 1;
+    pub const SIGNED: u8 = 1 << 1;
+    pub const PINNED: u8 = 1 << 2;
+
+    pub fn last_used(&self) -> Option<DateTime<Utc>> {
+        self.last_accessed.or(self.created_at)
+    }
+}
+
+type Records = HashMap<String, IndexRecord>;
+type LoadedIndex = (Option<u64>, Arc<Records>);
+
+// The contents of the index file. Every write increments `generation`, which
+// is serialized first so that it can be read without parsing the records.
+// Index files written before generations were added are just the records.
+#[derive(Serialize, Deserialize)]
+struct IndexFile<R> {
+    generation: u64,
+    records: R,
+}
+
+// The generation and records of the index file we last read or wrote, so
+// that repeated lookups only cost reading the start of the file until
+// another write changes it.
+#[derive(Default)]
+pub(crate) struct CacheIndex {
+    loaded: Mutex<Option<(u64, Arc<Records>)>>,
+}
+
+fn parse_generation(prefix: &[u8]) -> Option<u64> {
+    let digits = prefix.strip_prefix(GENERATION_PREFIX)?;
+    let len = digits.iter().take_while(|c| c.is_ascii_digit()).count();
+    std::str::from_utf8(&digits[..len]).ok()?.parse().ok()
+}
+
+// The generation of an index that replaces one without a generation, e.g.
+// after it was deleted. Starting from the current time keeps it from
+// matching what another process loaded before.
+fn initial_generation() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+impl FSCache {
+    fn index_path(&self) -> AbsoluteSystemPathBuf {
+        self.cache_directory.join_component(INDEX_FILE)
+    }
+
+    /// Returns the current index, or `None` if there isn't one yet. The index
+    /// is created by the first write to the cache.
+    pub(crate) fn read_index(&self) -> Result<Option<Arc<Records>>, CacheError> {
+        Ok(self.load_index()?.map(|(_, records)| records))
+    }
+
+    // Returns the index along with its generation, which is `None` for index
+    // files written before generations were added
+    fn load_index(&self) -> Result<Option<LoadedIndex>, CacheError> {
+        // The generation and the records are read from the same file, which
+        // an update may replace at any time
+        let Ok(mut file) = File::open(self.index_path().as_std_path()) else {
+            return Ok(None);
+        };
+        let mut contents = Vec::new();
+        (&mut file)
+            .take(GENERATION_PREFIX_LEN)
+            .read_to_end(&mut contents)?;
+        let generation = parse_generation(&contents);
+
+        let mut loaded = self.index.loaded.lock().expect("index lock poisoned");
+        if let (Some(generation), Some((loaded_generation, records))) = (generation, &*loaded) {
+            if *loaded_generation == generation {
+                return Ok(Some((Some(generation), records.clone())));
+            }
+        }
+
+        file.read_to_end(&mut contents)?;
+        let records = match generation {
+            Some(_) => serde_json::from_slice::<IndexFile<Records>>(&contents)
+                .map(|index_file| index_file.records),
+            None => serde_json::from_slice::<Records>(&contents),
+        }
+        .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))?;
+        let records = Arc::new(records);
+        if let Some(generation) = generation {
+            *loaded = Some((generation, records.clone()));
+        }
+
+        Ok(Some((generation, records)))
+    }
+
+    /// Applies `update` to the index. The index is locked across processes
+    /// for the duration of the update, and is rebuilt from the cache directory
+    /// if it doesn't exist.
+    pub(crate) fn update_index(&self, update: impl FnOnce(&mut Records)) -> Result<(), CacheError> {
+        let _lock = self.lock_index()?;
+
+        let (generation, mut records) = match self.load_index() {
+            Ok(Some((generation, records))) => (generation, Records::clone(&records)),
+            Ok(None) => (None, self.scan_index()?),
+            Err(e) => {
+                debug!("rebuilding unreadable cache index: {}", e);
+                (None, self.scan_index()?)
+            }
+        };
+        update(&mut records);
+
+        let generation = generation.map_or_else(initial_generation, |generation| generation + 1);
+        let contents = serde_json::to_string(&IndexFile {
+            generation,
+            records: &records,
+        })
+        .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+        let temp_path = self.cache_directory.join_component(&format!(
+            ".{}.{}.tmp",
+            INDEX_FILE,
+            std::process::id()
+        ));
+        temp_path.create_with_contents(contents)?;
+        temp_path.rename(&self.index_path())?;
+
+        // Saves reading back what we just wrote
+        *self.index.loaded.lock().expect("index lock poisoned") =
+            Some((generation, Arc::new(records)));
+
+        Ok(())
+    }
+
+    /// Recreates the index from the artifacts in the cache directory.
+    pub fn rebuild_index(&self) -> Result<(), CacheError> {
+        let records = self.scan_index()?;
+        self.update_index(|index| *index = records)
+    }
+
+    fn scan_index(&self) -> Result<Records, CacheError> {
+        let mut records = Records::new();
+        for artifact in self.list_artifacts()? {
+            let Some(metadata_path) = &artifact.metadata else {
+                continue;
+            };
+            if artifact.archives.is_empty() {
+                continue;
+            }
+            let Ok(meta) = CacheMetadata::read(metadata_path) else {
+                continue;
+            };
+
+            let mut size = self.extracted_size(&artifact.hash)?;
+            for path in artifact.paths() {
+                size += path.symlink_metadata()?.len();
+            }
+            let compressed = artifact
+                .archives
+                .iter()
+                .any(|archive| archive.extension() != Some("tar"));
+            records.insert(artifact.hash, Self::index_record(&meta, size, compressed));
+        }
+
+        Ok(records)
+    }
+
+    pub(super) fn index_record(meta: &CacheMetadata, size: u64, compressed: bool) -> IndexRecord {
+        let mut flags = 0;
+        if compressed {
+            flags |= IndexRecord::COMPRESSED;
+        }
+        if meta.tag.is_some() {
+            flags |= IndexRecord::SIGNED;
+        }
+        if meta.pinned {
+            flags |= IndexRecord::PINNED;
+        }
+
+        IndexRecord {
+            size,
+            duration: meta.duration,
+            created_at: meta.created_at,
+            last_accessed: meta.last_accessed,
+            flags,
+            delta_base: meta.delta_base.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        fs::CacheGcOptions, test_cases::TestCache, CacheHitMetadata, CacheOpts, CacheSource,
+    };
+
+    #[test]
+    fn test_index_tracks_puts_and_evictions() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+        assert!(cache.read_index()?.is_none());
+
+        let file = cache.write_file("out.txt", "Chungking Express")?;
+        cache.put(repo_root_path, "first", &[file.clone()], 10)?;
+        cache.put(repo_root_path, "second", &[file], 20)?;
+
+        let index = cache.read_index()?.unwrap();
+        assert_eq!(index.len(), 2);
+        let record = &index["first"];
+        assert_eq!(record.duration, 10);
+        assert_eq!(record.flags, IndexRecord::COMPRESSED);
+        assert_eq!(
+            record.size,
+            cache
+                .stats()?
+                .artifacts
+                .iter()
+                .find(|artifact| artifact.hash == "first")
+                .unwrap()
+                .size
+        );
+
+        // `exists` answers from the index without reading the metadata
+        cache
+            .metadata_path("second")
+            .create_with_contents("garbage")?;
+        let hit = |time_saved| {
+            Some(CacheHitMetadata {
+                time_saved,
+                source: CacheSource::Local,
+            })
+        };
+        assert_eq!(
+            cache.exists_many(&["first", "second", "third"])?,
+            vec![hit(10), hit(20), None]
+        );
+
+        // Artifacts missing from the index are looked for on disk
+        cache.update_index(|index| {
+            index.remove("first");
+        })?;
+        assert_eq!(cache.exists("first")?, hit(10));
+
+        cache.evict(&cache.list_artifacts()?[0])?;
+        let index = cache.read_index()?.unwrap();
+        assert_eq!(index.keys().collect::<Vec<_>>(), vec!["second"]);
+        assert_eq!(cache.exists("first")?, None);
+
+        // A missing index is rebuilt from the cache directory
+        cache.index_path().remove_file()?;
+        assert_eq!(cache.exists("second")?, hit(0));
+        cache.rebuild_index()?;
+        assert_eq!(cache.read_index()?.unwrap().len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_updates_index_once() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let file = cache.write_file("out.txt", "Fallen Angels")?;
+        for hash in ["first", "second", "third"] {
+            cache.put(repo_root_path, hash, &[file.clone()], 10)?;
+        }
+        let generation = |cache: &FSCache| -> Result<_> { Ok(cache.load_index()?.unwrap().0) };
+        let before = generation(&cache)?.unwrap();
+
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: None,
+        })?;
+        assert_eq!(summary.evicted.len(), 3);
+        assert_eq!(generation(&cache)?, Some(before + 1));
+        assert!(cache.read_index()?.unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_generations() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        // Stands in for another process using the same cache
+        let other_cache = cache.reopen(&CacheOpts::default())?;
+
+        let record = |duration| IndexRecord {
+            size: 1,
+            duration,
+            created_at: None,
+            last_accessed: None,
+            flags: 0,
+            delta_base: None,
+        };
+        cache.update_index(|index| {
+            index.insert("aaaa".to_string(), record(1));
+        })?;
+        assert_eq!(other_cache.read_index()?.unwrap()["aaaa"].duration, 1);
+
+        // Writes of the same size in quick succession are still told apart
+        for duration in 2..5 {
+            cache.update_index(|index| {
+                index.insert("aaaa".to_string(), record(duration));
+            })?;
+            assert_eq!(other_cache.read_index()?.unwrap()["aaaa"], record(duration));
+        }
+
+        // Index files written before generations were added are still read
+        let records = Records::from([("bbbb".to_string(), record(5))]);
+        cache
+            .index_path()
+            .create_with_contents(serde_json::to_string(&records)?)?;
+        assert_eq!(*cache.read_index()?.unwrap(), records);
+        cache.update_index(|index| {
+            index.insert("cccc".to_string(), record(6));
+        })?;
+        assert_eq!(other_cache.read_index()?.unwrap().len(), 2);
+
+        Ok(())
+    }
+}
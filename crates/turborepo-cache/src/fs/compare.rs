@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+
+use turbopath::AnchoredSystemPathBuf;
+
+use super::{CacheMetadata, FSCache};
+use crate::{
+    cache_archive::{ArchiveEntry, ArchiveEntryKind},
+    CacheError,
+};
+
+/// How the files of two artifacts differ, as reported by `FSCache::compare`.
+/// Entries are sorted by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArtifactDiff {
+    // Only in the second artifact
+    pub added: Vec<ArchiveEntry>,
+    // Only in the first artifact
+    pub removed: Vec<ArchiveEntry>,
+    // In both, with a different kind, mode or contents. Each pair is the
+    // entry of the first artifact and then that of the second.
+    pub changed: Vec<(ArchiveEntry, ArchiveEntry)>,
+}
+
+impl ArtifactDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+fn is_changed(a: &ArchiveEntry, b: &ArchiveEntry) -> bool {
+    a.kind != b.kind || a.size != b.size || a.mode != b.mode || a.digest != b.digest
+}
+
+impl FSCache {
+    /// Compares the files of two artifacts without restoring either. Returns
+    /// `None` if either artifact isn't in the cache.
+    pub fn compare(&self, hash_a: &str, hash_b: &str) -> Result<Option<ArtifactDiff>, CacheError> {
+        let (Some(a), Some(b)) = (self.manifest(hash_a)?, self.manifest(hash_b)?) else {
+            return Ok(None);
+        };
+
+        let mut a = a
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect::<BTreeMap<AnchoredSystemPathBuf, _>>();
+        let mut diff = ArtifactDiff::default();
+        for entry_b in b {
+            match a.remove(&entry_b.path) {
+                Some(entry_a) if is_changed(&entry_a, &entry_b) => {
+                    diff.changed.push((entry_a, entry_b))
+                }
+                Some(_) => {}
+                None => diff.added.push(entry_b),
+            }
+        }
+        diff.removed = a.into_values().collect();
+
+        diff.added.sort_by(|a, b| a.path.cmp(&b.path));
+        diff.changed.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+
+        Ok(Some(diff))
+    }
+
+    // The entries of an artifact with their digests. Indexes written before
+    // digests were recorded don't have them, in which case the archive is
+    // read instead.
+    fn manifest(&self, hash: &str) -> Result<Option<Vec<ArchiveEntry>>, CacheError> {
+        let (Some(_lock), Some(cache_path)) =
+            (self.lock_shared_if_cached(hash)?, self.archive_path(hash))
+        else {
+            return Ok(None);
+        };
+        let meta = CacheMetadata::read_with_index(&self.metadata_path(hash))?;
+
+        let has_digests = meta.index.as_ref().map_or(false, |index| {
+            index
+                .iter()
+                .all(|entry| entry.kind == ArchiveEntryKind::Directory || entry.digest.is_some())
+        });
+        let entries = match meta.index {
+            Some(index) if has_digests => index,
+            _ => self.open_archive(&cache_path, &meta, 0)?.entries()?,
+        };
+
+        Ok(Some(entries))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::AbsoluteSystemPath;
+
+    use super::*;
+    use crate::CacheOpts;
+
+    #[test]
+    fn test_compare() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let [same, changed, resized, removed, added] = [
+            "same.txt",
+            "changed.txt",
+            "resized.txt",
+            "removed.txt",
+            "added.txt",
+        ]
+        .map(|path| AnchoredSystemPathBuf::from_raw(path).unwrap());
+        for file in [&same, &changed, &resized, &removed] {
+            repo_root_path
+                .resolve(file)
+                .create_with_contents("Chungking")?;
+        }
+        cache.put(
+            repo_root_path,
+            "first",
+            &[
+                same.clone(),
+                changed.clone(),
+                resized.clone(),
+                removed.clone(),
+            ],
+            10,
+        )?;
+
+        // Same size, different contents
+        repo_root_path
+            .resolve(&changed)
+            .create_with_contents("Express!!")?;
+        repo_root_path
+            .resolve(&resized)
+            .create_with_contents("Chungking Express")?;
+        repo_root_path
+            .resolve(&added)
+            .create_with_contents("Chungking")?;
+        cache.put(
+            repo_root_path,
+            "second",
+            &[
+                same.clone(),
+                changed.clone(),
+                resized.clone(),
+                added.clone(),
+            ],
+            10,
+        )?;
+
+        assert_eq!(cache.compare("first", "missing")?, None);
+        assert!(cache.compare("first", "first")?.unwrap().is_empty());
+
+        let diff = cache.compare("first", "second")?.unwrap();
+        let paths = |entries: Vec<&ArchiveEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.path.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(diff.added.iter().collect()), vec![added]);
+        assert_eq!(paths(diff.removed.iter().collect()), vec![removed]);
+        assert_eq!(
+            paths(diff.changed.iter().map(|(a, _)| a).collect()),
+            vec![changed, resized]
+        );
+        let (before, after) = &diff.changed[0];
+        assert_eq!(before.size, after.size);
+        assert_ne!(before.digest, after.digest);
+
+        // Artifacts indexed without digests are read instead
+        let metadata_path = cache.metadata_path("first");
+        let mut meta = CacheMetadata::read_with_index(&metadata_path)?;
+        for entry in meta.index.iter_mut().flatten() {
+            entry.digest = None;
+        }
+        meta.write(&metadata_path)?;
+        assert_eq!(cache.compare("first", "second")?, Some(diff));
+
+        Ok(())
+    }
+}
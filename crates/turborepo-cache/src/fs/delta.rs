@@ -0,0 +1,385 @@
+use std::{
+    backtrace::Backtrace,
+    fs::OpenOptions,
+    io::{BufWriter, Cursor, Read, Write},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use turbopath::AbsoluteSystemPathBuf;
+
+use super::{ArtifactDetails, CacheMetadata, FSCache};
+use crate::{cache_archive::CacheReader, CacheError};
+
+pub(super) const DELTA_EXTENSION: &str = "tar.delta";
+const DELTA_BASES_DIRECTORY: &str = ".delta-bases";
+// Both tars are held in memory while making or applying a delta, so larger
+// artifacts are always stored in full. zstd needs a window covering both.
+const MAX_DELTA_TAR_SIZE: usize = 128 * 1024 * 1024;
+const MIN_WINDOW_LOG: u32 = 10;
+
+// The smallest window that lets the delta reference all of the base
+fn window_log(base_size: usize, size: usize) -> u32 {
+    (base_size + size)
+        .next_power_of_two()
+        .trailing_zeros()
+        .max(MIN_WINDOW_LOG)
+}
+
+impl FSCache {
+    // Where the hash of the last artifact of a task stored in full is kept.
+    // Tasks are identified by their package and task id.
+    fn delta_base_path(&self, details: &ArtifactDetails) -> Option<AbsoluteSystemPathBuf> {
+        Some(
+            self.cache_directory
+                .join_components(&[DELTA_BASES_DIRECTORY, &Self::task_key(details)?]),
+        )
+    }
+
+    fn task_key(details: &ArtifactDetails) -> Option<String> {
+        let task_id = details.task_id.as_ref()?;
+        let mut hasher = Sha256::new();
+        hasher.update(details.package_name.as_deref().unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(task_id);
+        Some(hex::encode(hasher.finalize()))
+    }
+
+    // Makes `hash` the base of the next delta of the same task. Deltas are
+    // always against an artifact stored in full, so they never chain.
+    pub(super) fn set_delta_base(
+        &self,
+        hash: &str,
+        details: &ArtifactDetails,
+    ) -> Result<(), CacheError> {
+        let Some(task_key) = Self::task_key(details) else {
+            return Ok(());
+        };
+        self.cache_directory
+            .join_component(DELTA_BASES_DIRECTORY)
+            .create_dir_all()?;
+
+        let temp_path = self.cache_directory.join_components(&[
+            DELTA_BASES_DIRECTORY,
+            &format!(".{}.{}.tmp", task_key, std::process::id()),
+        ]);
+        temp_path.create_with_contents(hash)?;
+        temp_path.rename(
+            &self
+                .cache_directory
+                .join_components(&[DELTA_BASES_DIRECTORY, &task_key]),
+        )?;
+
+        Ok(())
+    }
+
+    // Reads the uncompressed tar of a full artifact and the `sha256` of its
+    // archive, if it can be a delta base. With `sha256` set, a base whose
+    // archive has changed since is treated as missing. Reading the base counts
+    // as using it, so that gc keeps it at least as long as its deltas. Must be
+    // called with the shared lock on `base` held.
+    fn read_delta_base(
+        &self,
+        base: &str,
+        sha256: Option<&str>,
+    ) -> Result<Option<(Vec<u8>, String)>, CacheError> {
+        let Some(base_path) = self.archive_path(base) else {
+            return Ok(None);
+        };
+        let base_meta = CacheMetadata::read(&self.metadata_path(base))?;
+        if base_meta.delta_base.is_some() || base_meta.encryption_key_id.is_some() {
+            return Ok(None);
+        }
+        let Some(base_sha256) = base_meta.sha256.clone() else {
+            return Ok(None);
+        };
+        if sha256.map_or(false, |sha256| sha256 != base_sha256) {
+            debug!("{} was rewritten since deltas were made against it", base);
+            return Ok(None);
+        }
+        // Also catches the base being rewritten while we read it
+        self.verify_changed_archive(base, &base_path, &base_meta)?;
+
+        let tar = CacheReader::open(&base_path)?.read_tar()?;
+        if tar.len() > MAX_DELTA_TAR_SIZE {
+            return Ok(None);
+        }
+        self.touch(base, &base_meta);
+
+        Ok(Some((tar, base_sha256)))
+    }
+
+    /// Replaces the full archive of `hash` at `full_path` with a delta against
+    /// the last artifact of the same task stored in full, if there is one and
+    /// the delta is smaller. Returns the base, the `sha256` of its archive and
+    /// the path of the delta if it was kept. Failing to make a delta leaves
+    /// the full archive in place.
+    pub(super) fn write_delta(
+        &self,
+        hash: &str,
+        full_path: &AbsoluteSystemPathBuf,
+        details: &ArtifactDetails,
+    ) -> Option<(String, String, AbsoluteSystemPathBuf)> {
+        let base = self
+            .delta_base_path(details)
+            .and_then(|path| path.read_to_string().ok())?;
+        if base == hash {
+            return None;
+        }
+
+        let delta_path = self
+            .cache_directory
+            .join_component(&format!("{}.{}", hash, DELTA_EXTENSION));
+        match self.try_write_delta(&base, full_path, &delta_path) {
+            Ok(Some(base_sha256)) => {
+                debug!("stored {} as a delta against {}", hash, base);
+                Some((base, base_sha256, delta_path))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                debug!("failed to store {} as a delta: {}", hash, e);
+                let _ = delta_path.remove_file();
+                None
+            }
+        }
+    }
+
+    // Returns the `sha256` of the base's archive if the delta was kept
+    fn try_write_delta(
+        &self,
+        base: &str,
+        full_path: &AbsoluteSystemPathBuf,
+        delta_path: &AbsoluteSystemPathBuf,
+    ) -> Result<Option<String>, CacheError> {
+        let _lock = self.lock_shared(base)?;
+        let Some((base_tar, base_sha256)) = self.read_delta_base(base, None)? else {
+            return Ok(None);
+        };
+        let tar = CacheReader::open(full_path)?.read_tar()?;
+        if tar.len() > MAX_DELTA_TAR_SIZE {
+            return Ok(None);
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        let file = BufWriter::new(delta_path.open_with_options(options)?);
+        let mut encoder = zstd::Encoder::with_dictionary(file, self.compression_level, &base_tar)?;
+        encoder.window_log(window_log(base_tar.len(), tar.len()))?;
+        encoder.long_distance_matching(true)?;
+        encoder.write_all(&tar)?;
+        encoder.finish()?.flush()?;
+
+        if delta_path.symlink_metadata()?.len() >= full_path.symlink_metadata()?.len() {
+            delta_path.remove_file()?;
+            return Ok(None);
+        }
+        full_path.remove_file()?;
+
+        Ok(Some(base_sha256))
+    }
+
+    /// Opens the archive of an artifact so that reading starts `offset` bytes
+    /// into its tar. Deltas are applied to their base in memory, and so are
+    /// the contents of files that duplicates are stored as hardlinks to.
+    pub(super) fn open_archive(
+        &self,
+        cache_path: &AbsoluteSystemPathBuf,
+        meta: &CacheMetadata,
+        offset: u64,
+    ) -> Result<CacheReader<'static>, CacheError> {
+        let Some(base) = &meta.delta_base else {
+            return Ok(CacheReader::open_with_key(
+                cache_path,
+                offset,
+                self.encryption_key.as_ref(),
+            )?
+            .with_link_targets(meta.link_targets()));
+        };
+
+        // Keep the base from being rewritten while we read it
+        let _lock = self.lock_shared(base)?;
+        let (base_tar, _) = self
+            .read_delta_base(base, meta.delta_base_sha256.as_deref())?
+            .ok_or_else(|| CacheError::MissingDeltaBase(base.clone(), Backtrace::capture()))?;
+
+        let mut decoder =
+            zstd::Decoder::with_dictionary(std::io::BufReader::new(cache_path.open()?), &base_tar)?;
+        decoder.window_log_max(window_log(MAX_DELTA_TAR_SIZE, MAX_DELTA_TAR_SIZE))?;
+        let mut tar = Vec::new();
+        decoder.read_to_end(&mut tar)?;
+
+        let mut tar = Cursor::new(tar);
+        tar.set_position(offset);
+        Ok(CacheReader::from_reader(tar, false)?.with_link_targets(meta.link_targets()))
+    }
+
+    // Whether the base of a delta is still in the cache, unchanged. Deltas
+    // whose base was evicted or rewritten can't be restored. Deltas written
+    // by older versions don't record the base's `sha256`.
+    pub(super) fn has_delta_base(&self, meta: &CacheMetadata) -> bool {
+        let Some(base) = &meta.delta_base else {
+            return true;
+        };
+        if self.archive_path(base).is_none() {
+            return false;
+        }
+        meta.delta_base_sha256.as_ref().map_or(true, |sha256| {
+            CacheMetadata::read(&self.metadata_path(base))
+                .map_or(false, |base_meta| base_meta.sha256.as_ref() == Some(sha256))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        fs::CacheGcOptions,
+        test_cases::{incompressible_bytes, TestCache},
+        CacheOpts,
+    };
+
+    #[test]
+    fn test_delta_artifacts() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts {
+            fs_cache_delta: true,
+            ..Default::default()
+        })?;
+        let repo_root_path = cache.repo_root();
+        let details = ArtifactDetails {
+            task_id: Some("build".to_string()),
+            package_name: Some("web".to_string()),
+            ..Default::default()
+        };
+
+        // Incompressible, so only a delta can make the second artifact small
+        let mut contents = incompressible_bytes(256 * 1024);
+        let file = cache.write_file("bundle.js", &contents)?;
+        let file_path = repo_root_path.resolve(&file);
+        cache.put_with_details(
+            repo_root_path,
+            "first",
+            &[file.clone()],
+            10,
+            details.clone(),
+        )?;
+
+        contents[1000] ^= 0xff;
+        file_path.create_with_contents(&contents)?;
+        cache.put_with_details(
+            repo_root_path,
+            "second",
+            &[file.clone()],
+            10,
+            details.clone(),
+        )?;
+
+        let first_size = cache.archive_size("first");
+        let second_path = cache.archive_path("second").unwrap();
+        assert_eq!(second_path.extension(), Some("delta"));
+        assert!(cache.archive_size("second") * 10 < first_size);
+
+        // Other tasks don't get deltas against it
+        cache.put(repo_root_path, "other", &[file.clone()], 10)?;
+        assert_ne!(
+            cache.archive_path("other").unwrap().extension(),
+            Some("delta")
+        );
+
+        file_path.remove_file()?;
+        assert!(cache.fetch(repo_root_path, "second")?.is_some());
+        assert_eq!(file_path.read()?, contents);
+        file_path.remove_file()?;
+        assert!(cache
+            .fetch_filtered(repo_root_path, "second", &["*.js".to_string()])?
+            .is_some());
+        assert_eq!(file_path.read()?, contents);
+        assert!(cache.verify_all(false, &|_| {})?.issues.is_empty());
+
+        // A delta can't be restored without its base
+        cache.archive_path("first").unwrap().remove_file()?;
+        assert_eq!(cache.fetch(repo_root_path, "second")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_bases() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts {
+            fs_cache_delta: true,
+            ..Default::default()
+        })?;
+        let repo_root_path = cache.repo_root();
+        let details = ArtifactDetails {
+            task_id: Some("build".to_string()),
+            package_name: Some("web".to_string()),
+            ..Default::default()
+        };
+
+        let mut contents = incompressible_bytes(256 * 1024);
+        let file = cache.write_file("bundle.js", &contents)?;
+        let file_path = repo_root_path.resolve(&file);
+        let mut put = |hash: &str| -> Result<()> {
+            contents[0] = contents[0].wrapping_add(1);
+            file_path.create_with_contents(&contents)?;
+            cache.put_with_details(repo_root_path, hash, &[file.clone()], 10, details.clone())?;
+            Ok(())
+        };
+        let last_used = |hash: &str| -> Result<_> {
+            Ok(CacheMetadata::read(&cache.metadata_path(hash))?.last_used())
+        };
+        let two_days_ago = Utc::now() - chrono::Duration::days(2);
+        let make_old = |hash: &str| -> Result<()> {
+            let metadata_path = cache.metadata_path(hash);
+            let mut meta = CacheMetadata::read(&metadata_path)?;
+            meta.created_at = Some(two_days_ago);
+            meta.last_accessed = Some(two_days_ago);
+            meta.write(&metadata_path)?;
+            cache.rebuild_index()?;
+            Ok(())
+        };
+        let max_age = CacheGcOptions {
+            max_size_bytes: None,
+            max_age: Some(std::time::Duration::from_secs(24 * 60 * 60)),
+        };
+
+        put("first")?;
+        put("second")?;
+        put("third")?;
+        let first_sha256 = CacheMetadata::read(&cache.metadata_path("first"))?.sha256;
+        let second_meta = CacheMetadata::read(&cache.metadata_path("second"))?;
+        assert_eq!(second_meta.delta_base.as_deref(), Some("first"));
+        assert_eq!(second_meta.delta_base_sha256, first_sha256);
+
+        // Restoring a delta uses its base
+        make_old("first")?;
+        assert!(cache.fetch(repo_root_path, "second")?.is_some());
+        assert!(last_used("first")? > Some(two_days_ago));
+
+        // The base of a pinned delta is kept
+        make_old("first")?;
+        cache.pin("third")?;
+        assert!(cache.gc(&max_age)?.evicted.is_empty());
+
+        // Otherwise its deltas are evicted with it
+        cache.unpin("third")?;
+        let summary = cache.gc(&max_age)?;
+        assert_eq!(summary.evicted, vec!["first", "second", "third"]);
+        assert!(cache.list_artifacts()?.is_empty());
+
+        // A delta isn't restored against a base that was rewritten since
+        put("first")?;
+        put("second")?;
+        assert_eq!(
+            cache.archive_path("second").unwrap().extension(),
+            Some("delta")
+        );
+        put("first")?;
+        assert_eq!(cache.fetch(repo_root_path, "second")?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    time::{Duration, SystemTime},
+};
+
+use tracing::debug;
+
+use super::{ArtifactFiles, CacheMetadata, FSCache, IndexRecord};
+use crate::CacheError;
+
+/// Limits enforced by `FSCache::gc`. An artifact is evicted if it violates
+/// either limit; leaving both unset makes `gc` a no-op. Pinned artifacts are
+/// never evicted, but still count towards `max_size_bytes`. Evicting the base
+/// of delta artifacts evicts the deltas with it, and the base of a pinned
+/// delta is never evicted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheGcOptions {
+    /// Evict least-recently-used artifacts until the cache is no larger than
+    /// this many bytes.
+    pub max_size_bytes: Option<u64>,
+    /// Evict artifacts that haven't been used for longer than this.
+    pub max_age: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcSummary {
+    pub evicted: Vec<String>,
+    pub bytes_freed: u64,
+    pub bytes_remaining: u64,
+}
+
+struct GcCandidate {
+    files: ArtifactFiles,
+    size: u64,
+    last_used: SystemTime,
+    pinned: bool,
+    delta_base: Option<String>,
+}
+
+impl GcCandidate {
+    // Takes everything from the artifact's index record, without touching its
+    // files
+    fn from_record(files: ArtifactFiles, record: &IndexRecord) -> Self {
+        GcCandidate {
+            files,
+            size: record.size,
+            last_used: record
+                .last_used()
+                .map_or(SystemTime::UNIX_EPOCH, SystemTime::from),
+            pinned: record.flags & IndexRecord::PINNED != 0,
+            delta_base: record.delta_base.clone(),
+        }
+    }
+
+    // Reads the artifact's files, for artifacts that aren't in the index.
+    // Returns `None` if the artifact was removed after it was listed.
+    fn new(cache: &FSCache, files: ArtifactFiles) -> Result<Option<Self>, CacheError> {
+        let mut size = cache.extracted_size(&files.hash)?;
+        let mut file_last_used = SystemTime::UNIX_EPOCH;
+        for path in files.paths() {
+            let metadata = match path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(e) if e.is_io_error(io::ErrorKind::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            size += metadata.len();
+            // Access times are often disabled or coarse (`relatime`), so take
+            // whichever of the two timestamps is more recent.
+            for time in [metadata.accessed(), metadata.modified()]
+                .into_iter()
+                .flatten()
+            {
+                file_last_used = file_last_used.max(time);
+            }
+        }
+
+        let meta = files
+            .metadata
+            .as_ref()
+            .and_then(|path| CacheMetadata::read(path).ok());
+        // Prefer the timestamps we record ourselves, falling back to the
+        // filesystem for artifacts written by older versions.
+        let last_used = meta
+            .as_ref()
+            .and_then(|meta| meta.last_used())
+            .map_or(file_last_used, SystemTime::from);
+
+        Ok(Some(GcCandidate {
+            files,
+            size,
+            last_used,
+            pinned: meta.as_ref().map_or(false, |meta| meta.pinned),
+            delta_base: meta.and_then(|meta| meta.delta_base),
+        }))
+    }
+
+    fn is_expired(&self, now: SystemTime, max_age: Option<Duration>) -> bool {
+        let Some(max_age) = max_age else {
+            return false;
+        };
+
+        now.duration_since(self.last_used)
+            .map_or(false, |age| age > max_age)
+    }
+}
+
+impl FSCache {
+    /// Evicts artifacts until the cache satisfies `options`. The archive and
+    /// its metadata file are always removed together. Sizes and access times
+    /// come from the index where it has them.
+    pub fn gc(&self, options: &CacheGcOptions) -> Result<GcSummary, CacheError> {
+        let index = self.read_index()?;
+        let mut candidates = Vec::new();
+        for files in self.list_artifacts()? {
+            let record = index.as_ref().and_then(|index| index.get(&files.hash));
+            let candidate = match record {
+                Some(record) if record.last_used().is_some() => {
+                    Some(GcCandidate::from_record(files, record))
+                }
+                _ => GcCandidate::new(self, files)?,
+            };
+            candidates.extend(candidate);
+        }
+        // Least recently used first
+        candidates.sort_by_key(|candidate| candidate.last_used);
+
+        let mut summary = GcSummary {
+            bytes_remaining: candidates.iter().map(|candidate| candidate.size).sum(),
+            ..Default::default()
+        };
+
+        // The index is updated once for the whole pass rather than once per
+        // artifact, including when the pass fails part way through
+        let result = self.evict_candidates(&candidates, options, &mut summary);
+        if !summary.evicted.is_empty() {
+            self.update_index(|index| {
+                for hash in &summary.evicted {
+                    index.remove(hash);
+                }
+            })?;
+        }
+        result?;
+
+        Ok(summary)
+    }
+
+    // Evicts candidates in order until `options` are satisfied, recording
+    // what was evicted in `summary`. Leaves the index to the caller.
+    fn evict_candidates(
+        &self,
+        candidates: &[GcCandidate],
+        options: &CacheGcOptions,
+        summary: &mut GcSummary,
+    ) -> Result<(), CacheError> {
+        let now = SystemTime::now();
+        // Deltas can't be restored without their base, so they're evicted
+        // with it, and a pinned delta keeps its base
+        let mut deltas: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut pinned_bases = HashSet::new();
+        for (i, candidate) in candidates.iter().enumerate() {
+            if let Some(base) = &candidate.delta_base {
+                deltas.entry(base.as_str()).or_default().push(i);
+                if candidate.pinned {
+                    pinned_bases.insert(base.as_str());
+                }
+            }
+        }
+
+        let mut evicted = vec![false; candidates.len()];
+        for (i, candidate) in candidates.iter().enumerate() {
+            let hash = candidate.files.hash.as_str();
+            let over_budget = options
+                .max_size_bytes
+                .map_or(false, |max_size| summary.bytes_remaining > max_size);
+            if evicted[i]
+                || candidate.pinned
+                || pinned_bases.contains(hash)
+                || (!over_budget && !candidate.is_expired(now, options.max_age))
+            {
+                continue;
+            }
+
+            let group: Vec<usize> = std::iter::once(i)
+                .chain(deltas.get(hash).into_iter().flatten().copied())
+                .filter(|&j| !evicted[j])
+                .collect();
+            // Leave artifacts that another process is using alone
+            let mut locks = Vec::with_capacity(group.len());
+            for &j in &group {
+                match self.try_lock_exclusive(&candidates[j].files.hash)? {
+                    Some(lock) => locks.push(lock),
+                    None => break,
+                }
+            }
+            if locks.len() < group.len() {
+                debug!("skipping eviction of {}, it is in use", hash);
+                continue;
+            }
+
+            for j in group {
+                let candidate = &candidates[j];
+                debug!("evicting {} from fs cache", candidate.files.hash);
+                self.remove_artifact_files(&candidate.files)?;
+                self.remove_lock_file(&candidate.files.hash)?;
+                evicted[j] = true;
+                summary.bytes_remaining -= candidate.size;
+                summary.bytes_freed += candidate.size;
+                summary.evicted.push(candidate.files.hash.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes every artifact that hasn't been used within `max_age`.
+    pub fn prune_older_than(&self, max_age: Duration) -> Result<GcSummary, CacheError> {
+        self.gc(&CacheGcOptions {
+            max_size_bytes: None,
+            max_age: Some(max_age),
+        })
+    }
+
+    /// Exempts an artifact from gc. Returns false if there's no artifact for
+    /// `hash`. The pin survives the artifact being written again.
+    pub fn pin(&self, hash: &str) -> Result<bool, CacheError> {
+        self.set_pinned(hash, true)
+    }
+
+    /// Makes a pinned artifact subject to gc again. Returns false if there's
+    /// no artifact for `hash`.
+    pub fn unpin(&self, hash: &str) -> Result<bool, CacheError> {
+        self.set_pinned(hash, false)
+    }
+
+    fn set_pinned(&self, hash: &str, pinned: bool) -> Result<bool, CacheError> {
+        // Checked before locking too, so that a miss doesn't leave a lock file
+        if self.archive_path(hash).is_none() {
+            return Ok(false);
+        }
+        let _lock = self.lock_exclusive(hash)?;
+
+        let metadata_path = self.metadata_path(hash);
+        if self.archive_path(hash).is_none() || !metadata_path.exists() {
+            return Ok(false);
+        }
+
+        let mut meta = CacheMetadata::read(&metadata_path)?;
+        if meta.pinned != pinned {
+            meta.pinned = pinned;
+            meta.write(&metadata_path)?;
+        }
+        // The index is updated even if the metadata was already right, in
+        // case the two disagree
+        let indexed = self.read_index()?.and_then(|index| {
+            index
+                .get(hash)
+                .map(|record| record.flags & IndexRecord::PINNED != 0)
+        });
+        if indexed.map_or(false, |indexed| indexed != pinned) {
+            self.update_index(|index| {
+                if let Some(record) = index.get_mut(hash) {
+                    if pinned {
+                        record.flags |= IndexRecord::PINNED;
+                    } else {
+                        record.flags &= !IndexRecord::PINNED;
+                    }
+                }
+            })?;
+        }
+
+        Ok(true)
+    }
+
+    // Must be called with the exclusive lock on the artifact held
+    pub(crate) fn evict(&self, artifact: &ArtifactFiles) -> Result<(), CacheError> {
+        self.remove_artifact_files(artifact)?;
+        self.update_index(|index| {
+            index.remove(&artifact.hash);
+        })?;
+        self.remove_lock_file(&artifact.hash)
+    }
+
+    // Like `evict`, but leaves the artifact's lock file and its index record
+    // to the caller, so that evicting many artifacts updates the index once
+    fn remove_artifact_files(&self, artifact: &ArtifactFiles) -> Result<(), CacheError> {
+        for path in artifact.paths() {
+            match path.remove_file() {
+                Ok(()) => {}
+                // Someone else got there first, which is fine.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.remove_extracted(&artifact.hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{File, FileTimes},
+        time::{Duration, SystemTime},
+    };
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::{test_cases::TestCache, CacheOpts};
+
+    fn put_artifact(
+        cache: &FSCache,
+        repo_root: &AbsoluteSystemPath,
+        hash: &str,
+        last_used: SystemTime,
+    ) -> Result<()> {
+        let file = AnchoredSystemPathBuf::from_raw(format!("{}.txt", hash))?;
+        repo_root
+            .resolve(&file)
+            .create_with_contents(hash.repeat(100))?;
+        cache.put(repo_root, hash, &[file], 0)?;
+
+        let metadata_path = cache.metadata_path(hash);
+        let mut meta = CacheMetadata::read(&metadata_path)?;
+        meta.created_at = Some(last_used.into());
+        meta.last_accessed = Some(last_used.into());
+        meta.write(&metadata_path)?;
+
+        let times = FileTimes::new()
+            .set_accessed(last_used)
+            .set_modified(last_used);
+        for artifact in cache.list_artifacts()? {
+            if artifact.hash == hash {
+                for path in artifact.paths() {
+                    File::options()
+                        .write(true)
+                        .open(path.as_std_path())?
+                        .set_times(times)?;
+                }
+            }
+        }
+        cache.rebuild_index()?;
+
+        Ok(())
+    }
+
+    fn cached_hashes(cache: &FSCache) -> Result<Vec<String>> {
+        Ok(cache
+            .list_artifacts()?
+            .into_iter()
+            .map(|artifact| artifact.hash)
+            .collect())
+    }
+
+    #[test]
+    fn test_gc_evicts_least_recently_used() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let now = SystemTime::now();
+        put_artifact(
+            &cache,
+            repo_root_path,
+            "oldest",
+            now - Duration::from_secs(300),
+        )?;
+        put_artifact(
+            &cache,
+            repo_root_path,
+            "middle",
+            now - Duration::from_secs(200),
+        )?;
+        put_artifact(
+            &cache,
+            repo_root_path,
+            "newest",
+            now - Duration::from_secs(100),
+        )?;
+
+        let unbounded = cache.gc(&CacheGcOptions::default())?;
+        assert!(unbounded.evicted.is_empty());
+
+        // Leave just enough room for two artifacts
+        let budget = unbounded.bytes_remaining - 1;
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(budget),
+            max_age: None,
+        })?;
+
+        assert_eq!(summary.evicted, vec!["oldest".to_string()]);
+        assert!(summary.bytes_remaining <= budget);
+        assert_eq!(
+            summary.bytes_freed + summary.bytes_remaining,
+            unbounded.bytes_remaining
+        );
+        assert_eq!(cached_hashes(&cache)?, vec!["middle", "newest"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_artifacts_are_not_evicted() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        put_artifact(&cache, repo_root_path, "release", now - 3 * day)?;
+        put_artifact(&cache, repo_root_path, "stale", now - 2 * day)?;
+
+        let index_pinned = |hash: &str| -> Result<bool> {
+            let index = cache.read_index()?.unwrap();
+            Ok(index[hash].flags & IndexRecord::PINNED != 0)
+        };
+        // Pinning is idempotent
+        assert!(cache.pin("release")?);
+        assert!(cache.pin("release")?);
+        assert!(index_pinned("release")?);
+        assert!(!index_pinned("stale")?);
+        // An index that disagrees with the metadata is corrected
+        cache.update_index(|index| {
+            index.get_mut("release").unwrap().flags &= !IndexRecord::PINNED;
+        })?;
+        assert!(cache.pin("release")?);
+        assert!(index_pinned("release")?);
+        assert!(!cache.pin("missing")?);
+        // Writing the artifact again keeps the pin
+        let file = AnchoredSystemPathBuf::from_raw("release.txt")?;
+        cache.put(repo_root_path, "release", &[file], 0)?;
+        assert_eq!(cache.stats()?.pinned, vec!["release".to_string()]);
+
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: Some(day),
+        })?;
+        assert_eq!(summary.evicted, vec!["stale".to_string()]);
+        assert_eq!(cached_hashes(&cache)?, vec!["release"]);
+
+        assert!(cache.unpin("release")?);
+        assert!(cache.unpin("release")?);
+        assert!(!index_pinned("release")?);
+        assert!(cache.stats()?.pinned.is_empty());
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: None,
+        })?;
+        assert_eq!(summary.evicted, vec!["release".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_uses_index() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        put_artifact(&cache, repo_root_path, "indexed", now)?;
+        put_artifact(&cache, repo_root_path, "unindexed", now - 3 * day)?;
+        put_artifact(&cache, repo_root_path, "removed", now - 3 * day)?;
+
+        // Indexed artifacts are judged by their record alone
+        cache.update_index(|index| {
+            let record = index.get_mut("indexed").unwrap();
+            record.last_accessed = Some((now - 2 * day).into());
+            record.size = 1;
+            index.remove("unindexed");
+            index.remove("removed");
+        })?;
+
+        // Artifacts removed while gc runs are skipped
+        let removed = cache
+            .list_artifacts()?
+            .into_iter()
+            .find(|artifact| artifact.hash == "removed")
+            .unwrap();
+        for path in removed.paths() {
+            path.remove_file()?;
+        }
+        assert!(GcCandidate::new(&cache, removed)?.is_none());
+
+        let summary = cache.prune_older_than(day)?;
+        assert_eq!(summary.evicted, vec!["unindexed", "indexed"]);
+        assert_eq!(summary.bytes_remaining, 0);
+        assert!(cached_hashes(&cache)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hits_update_last_used() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        put_artifact(&cache, repo_root_path, "fetched", now - 3 * day)?;
+        put_artifact(&cache, repo_root_path, "checked", now - 2 * day)?;
+        put_artifact(&cache, repo_root_path, "untouched", now - day)?;
+
+        // Both kinds of hit count as a use
+        let output = tempdir()?;
+        let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+        cache.fetch(output_path, "fetched")?.unwrap();
+        cache.exists("checked")?.unwrap();
+
+        let summary = cache.prune_older_than(Duration::from_secs(60 * 60))?;
+        assert_eq!(summary.evicted, vec!["untouched".to_string()]);
+        assert_eq!(cached_hashes(&cache)?, vec!["checked", "fetched"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_older_than() -> Result<()> {
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        put_artifact(&cache, repo_root_path, "stale", now - 3 * day)?;
+        put_artifact(&cache, repo_root_path, "fresh", now)?;
+
+        let summary = cache.prune_older_than(day)?;
+
+        assert_eq!(summary.evicted, vec!["stale".to_string()]);
+        assert_eq!(cached_hashes(&cache)?, vec!["fresh"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_prunes_with_ttl() -> Result<()> {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        let cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = cache.repo_root();
+        put_artifact(&cache, repo_root_path, "stale", now - 3 * day)?;
+
+        let ttl_cache = cache.reopen(&CacheOpts {
+            fs_cache_ttl: Some(day),
+            ..Default::default()
+        })?;
+        put_artifact(&ttl_cache, repo_root_path, "fresh", now)?;
+
+        assert_eq!(cached_hashes(&ttl_cache)?, vec!["fresh"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use chrono::{DateTime, Utc};
+
+use super::{ArtifactDetails, CacheMetadata, FSCache};
+use crate::{cache_archive::ArchiveEntry, CacheError};
+
+/// What an artifact contains, as reported by `FSCache::inspect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactInfo {
+    pub hash: String,
+    pub duration: u64,
+    pub created_at: Option<DateTime<Utc>>,
+    pub details: ArtifactDetails,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+impl FSCache {
+    /// Lists the files in an artifact without restoring it. The entry index
+    /// recorded when the artifact was written is used if there is one,
+    /// otherwise the archive's headers are read.
+    pub fn inspect(&self, hash: &str) -> Result<Option<ArtifactInfo>, CacheError> {
+        // Wait for any in-progress write of this artifact to finish
+        let (Some(_lock), Some(cache_path)) =
+            (self.lock_shared_if_cached(hash)?, self.archive_path(hash))
+        else {
+            return Ok(None);
+        };
+        let meta = CacheMetadata::read_with_index(&self.metadata_path(hash))?;
+
+        let entries = match &meta.index {
+            Some(index) => index.clone(),
+            None => self.open_archive(&cache_path, &meta, 0)?.entries()?,
+        };
+
+        Ok(Some(ArtifactInfo {
+            hash: hash.to_string(),
+            duration: meta.duration,
+            created_at: meta.created_at,
+            details: meta.details,
+            entries,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::{cache_archive::ArchiveEntryKind, CacheOpts};
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        assert_eq!(cache.inspect("missing")?, None);
+
+        let dir = AnchoredSystemPathBuf::from_raw("dist")?;
+        let file = AnchoredSystemPathBuf::from_raw("dist/index.js")?;
+        repo_root_path.resolve(&dir).create_dir_all()?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Happy Together")?;
+        cache.put(repo_root_path, "the-hash", &[dir.clone(), file.clone()], 10)?;
+
+        let from_index = cache.inspect("the-hash")?.unwrap();
+        assert_eq!(from_index.duration, 10);
+        assert_eq!(
+            from_index
+                .entries
+                .iter()
+                .map(|entry| (entry.path.clone(), entry.kind, entry.size))
+                .collect::<Vec<_>>(),
+            vec![
+                (dir, ArchiveEntryKind::Directory, 0),
+                (file, ArchiveEntryKind::File, "Happy Together".len() as u64),
+            ]
+        );
+
+        // Reading the archive gives the same answer as the index
+        cache.entry_index_path("the-hash").remove_file()?;
+        assert_eq!(cache.inspect("the-hash")?, Some(from_index));
+
+        Ok(())
+    }
+}
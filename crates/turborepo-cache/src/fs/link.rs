@@ -0,0 +1,472 @@
+use std::{backtrace::Backtrace, fs, io, process};
+
+use tracing::debug;
+use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
+
+use super::{CacheMetadata, FSCache};
+use crate::{
+    cache_archive::{
+        check_existing, file_digest, read_xattrs, write_xattrs, CachedDirTree, ExistingFile,
+        Progress,
+    },
+    CacheError,
+};
+
+const EXTRACTED_DIRECTORY: &str = ".extracted";
+
+/// How `FSCache::fetch` puts an artifact's files into the workspace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Extract the archive on every fetch.
+    #[default]
+    Extract,
+    /// Extract the archive once into the cache directory and reflink its
+    /// files into the workspace on every fetch, so that modifying an output
+    /// never modifies the extracted copy. The extracted copy counts towards
+    /// the size of the cache.
+    ///
+    /// Whether the cache directory's filesystem supports reflinks is checked
+    /// once per process. Where it doesn't (e.g. ext4 or NTFS), artifacts are
+    /// extracted as with `Extract` and no extracted copy is kept: copying
+    /// every file out of it would be slower than extracting, and hardlinks
+    /// would let writes to an output change what later fetches restore.
+    /// Outputs on another filesystem than the cache are still copied.
+    Link,
+}
+
+impl FSCache {
+    // `restore_mode`, unless that's link mode and reflinks aren't supported
+    pub(super) fn effective_restore_mode(&self) -> RestoreMode {
+        if self.restore_mode == RestoreMode::Link
+            && !*self.reflinks.get_or_init(|| self.probe_reflinks())
+        {
+            return RestoreMode::Extract;
+        }
+        self.restore_mode
+    }
+
+    // Tries to reflink a small file in the directory extracted copies are
+    // kept in
+    fn probe_reflinks(&self) -> bool {
+        let directory = self.cache_directory.join_component(EXTRACTED_DIRECTORY);
+        let source = directory.join_component(&format!(".reflink.{}.tmp", process::id()));
+        let destination =
+            directory.join_component(&format!(".reflink.{}.clone.tmp", process::id()));
+
+        let supported = directory.create_dir_all().is_ok()
+            && source.create_with_contents("reflink").is_ok()
+            && reflink(&source, &destination).is_ok();
+        let _ = source.remove_file();
+        let _ = destination.remove_file();
+        if !supported {
+            debug!("the cache directory doesn't support reflinks, extracting instead of linking");
+        }
+
+        supported
+    }
+
+    fn extracted_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_components(&[EXTRACTED_DIRECTORY, hash])
+    }
+
+    // The list of restored files, written once extraction has finished.
+    fn extracted_manifest_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_components(&[EXTRACTED_DIRECTORY, &format!("{}.json", hash)])
+    }
+
+    /// Returns the files of a previous extraction of `hash`, if there is one.
+    pub(crate) fn read_extracted(
+        &self,
+        hash: &str,
+    ) -> Result<Option<Vec<AnchoredSystemPathBuf>>, CacheError> {
+        let manifest_path = self.extracted_manifest_path(hash);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+
+        let files = serde_json::from_str(&manifest_path.read_to_string()?)
+            .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))?;
+
+        Ok(Some(files))
+    }
+
+    /// Extracts `archive_path` into the cache directory. Concurrent
+    /// extractions of the same hash each use their own directory and the
+    /// first to finish wins.
+    pub(super) fn extract(
+        &self,
+        hash: &str,
+        archive_path: &AbsoluteSystemPathBuf,
+        meta: &CacheMetadata,
+        progress: &dyn Fn(Progress),
+    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+        let extracted_path = self.extracted_path(hash);
+        let temp_path = self.cache_directory.join_components(&[
+            EXTRACTED_DIRECTORY,
+            &format!("{}.{}.tmp", hash, process::id()),
+        ]);
+        if temp_path.exists() {
+            temp_path.remove_dir_all()?;
+        }
+
+        let restored = self
+            .open_archive(archive_path, meta, 0)?
+            .with_symlink_policy(self.symlink_policy)
+            .with_mtimes(self.mtimes)
+            .with_xattrs(self.xattrs)
+            .with_progress(progress)
+            .restore_with_workers(&temp_path, self.restore_workers as usize)?;
+
+        // An extraction without a manifest was interrupted, so it can't be
+        // trusted.
+        if extracted_path.exists() && !self.extracted_manifest_path(hash).exists() {
+            extracted_path.remove_dir_all()?;
+        }
+        if let Err(e) = temp_path.rename(&extracted_path) {
+            debug!("{} was already extracted: {}", hash, e);
+            temp_path.remove_dir_all()?;
+        }
+
+        let manifest_temp_path = self.cache_directory.join_components(&[
+            EXTRACTED_DIRECTORY,
+            &format!("{}.{}.json.tmp", hash, process::id()),
+        ]);
+        let manifest = serde_json::to_string(&restored)
+            .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+        manifest_temp_path.create_with_contents(manifest)?;
+        manifest_temp_path.rename(&self.extracted_manifest_path(hash))?;
+
+        // So that gc and the quota see the extracted copy
+        let size = self.stored_size(hash)?;
+        self.update_index(|index| {
+            if let Some(record) = index.get_mut(hash) {
+                record.size = size;
+            }
+        })?;
+
+        Ok(restored)
+    }
+
+    /// The size of the extracted copy of `hash` and its manifest, or 0 if it
+    /// hasn't been extracted.
+    pub(crate) fn extracted_size(&self, hash: &str) -> Result<u64, CacheError> {
+        let mut size = match self.extracted_manifest_path(hash).symlink_metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.is_io_error(io::ErrorKind::NotFound) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let mut directories = vec![self.extracted_path(hash).as_std_path().to_owned()];
+        while let Some(directory) = directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                } else {
+                    size += metadata.len();
+                }
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Removes the extracted copy of `hash`, if any.
+    pub(crate) fn remove_extracted(&self, hash: &str) -> Result<(), CacheError> {
+        match self.extracted_manifest_path(hash).remove_file() {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let extracted_path = self.extracted_path(hash);
+        if extracted_path.exists() {
+            extracted_path.remove_dir_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Recreates the extracted files of `hash` under `anchor`, with the same
+    /// checks against escaping `anchor` as a regular restore.
+    pub(crate) fn link_extracted(
+        &self,
+        hash: &str,
+        anchor: &AbsoluteSystemPath,
+        files: &[AnchoredSystemPathBuf],
+    ) -> Result<(), CacheError> {
+        let extracted_path = self.extracted_path(hash);
+        anchor.create_dir_all()?;
+        let mut dir_cache = CachedDirTree::new(anchor.to_owned());
+
+        for file in files {
+            let source = extracted_path.resolve(file);
+            let destination = anchor.resolve(file);
+            let metadata = source.symlink_metadata()?;
+
+            if metadata.is_dir() {
+                dir_cache.safe_mkdir_all(anchor, file, permissions_mode(&metadata))?;
+                continue;
+            }
+
+            dir_cache.safe_mkdir_file(anchor, file)?;
+            if metadata.is_file() {
+                let skip =
+                    match check_existing(&destination, self.overwrite_policy, metadata.len())? {
+                        ExistingFile::Write => false,
+                        ExistingFile::Skip => true,
+                        ExistingFile::Compare => {
+                            file_digest(&source)? == file_digest(&destination)?
+                        }
+                    };
+                if skip {
+                    continue;
+                }
+            }
+            match destination.remove_file() {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if metadata.is_symlink() {
+                let target = source.read_link()?;
+                if source.as_std_path().is_dir() {
+                    destination.symlink_to_dir(target)?;
+                } else {
+                    destination.symlink_to_file(target)?;
+                }
+            } else {
+                link_file(&source, &destination)?;
+                // Clones and copies are new files, so they need the extracted
+                // file's mtime
+                if self.mtimes {
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .open(&destination)?
+                        .set_modified(metadata.modified()?)?;
+                }
+                // Nor do they keep the extracted file's extended attributes
+                if self.xattrs {
+                    write_xattrs(&destination, &read_xattrs(&source)?)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn permissions_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(windows)]
+fn permissions_mode(_metadata: &fs::Metadata) -> u32 {
+    0o755
+}
+
+// Prefers a copy-on-write clone, which behaves like a copy, and falls back to
+// a real copy when the workspace is on a different filesystem than the cache.
+// Link mode isn't used at all where the cache can't reflink.
+fn link_file(source: &AbsoluteSystemPath, destination: &AbsoluteSystemPath) -> io::Result<()> {
+    if reflink(source, destination).is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, destination).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &AbsoluteSystemPath, destination: &AbsoluteSystemPath) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source_file = source.open()?;
+    let destination_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(destination)?;
+
+    // SAFETY: both file descriptors are valid for the duration of the call.
+    let result = unsafe {
+        libc::ioctl(
+            destination_file.as_raw_fd(),
+            libc::FICLONE,
+            source_file.as_raw_fd(),
+        )
+    };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        drop(destination_file);
+        let _ = fs::remove_file(destination);
+        return Err(err);
+    }
+
+    destination_file.set_permissions(source_file.metadata()?.permissions())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(source: &AbsoluteSystemPath, destination: &AbsoluteSystemPath) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    extern "C" {
+        fn clonefile(
+            src: *const libc::c_char,
+            dst: *const libc::c_char,
+            flags: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    let source = CString::new(source.as_std_path().as_os_str().as_bytes())?;
+    let destination = CString::new(destination.as_std_path().as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid, nul-terminated strings.
+    let result = unsafe { clonefile(source.as_ptr(), destination.as_ptr(), 0) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_source: &AbsoluteSystemPath, _destination: &AbsoluteSystemPath) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::{fs::CacheGcOptions, CacheOpts};
+
+    #[test]
+    fn test_link_restore() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(
+            &CacheOpts {
+                fs_cache_restore_mode: RestoreMode::Link,
+                ..Default::default()
+            },
+            repo_root_path,
+            None,
+        )?;
+        // Copies stand in for reflinks where the test runs without them
+        cache.reflinks.set(true).unwrap();
+
+        let dir = AnchoredSystemPathBuf::from_raw("dist")?;
+        let file = AnchoredSystemPathBuf::from_raw("dist/out.txt")?;
+        repo_root_path.resolve(&dir).create_dir_all()?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("In the Mood for Love")?;
+        let link = AnchoredSystemPathBuf::from_raw("dist/latest")?;
+        repo_root_path.resolve(&link).symlink_to_file("out.txt")?;
+        let files = vec![dir, file.clone(), link.clone()];
+        cache.put(repo_root_path, "linked", &files, 0)?;
+
+        let first_output = tempdir()?;
+        let first_output_path = AbsoluteSystemPath::from_std_path(first_output.path())?;
+        let (_, first_restored) = cache.fetch(first_output_path, "linked")?.unwrap();
+        assert!(cache.read_extracted("linked")?.is_some());
+
+        // The extracted copy counts towards the size of the cache
+        let record = cache.read_index()?.unwrap()["linked"].clone();
+        assert!(cache.extracted_size("linked")? > 0);
+        assert_eq!(record.size, cache.stored_size("linked")?);
+
+        // The second fetch uses the existing extraction, even if the archive
+        // is no longer readable.
+        let archive_path = cache.archive_path("linked").unwrap();
+        archive_path.create_with_contents("garbage")?;
+        let second_output = tempdir()?;
+        let second_output_path = AbsoluteSystemPath::from_std_path(second_output.path())?;
+        let (_, second_restored) = cache.fetch(second_output_path, "linked")?.unwrap();
+        assert_eq!(first_restored, second_restored);
+
+        for output in [first_output_path, second_output_path] {
+            assert_eq!(
+                output.resolve(&file).read_to_string()?,
+                "In the Mood for Love"
+            );
+            assert_eq!(output.resolve(&link).read_link()?, "out.txt");
+        }
+
+        // Writing to an output in place leaves the extracted copy alone
+        fs::OpenOptions::new()
+            .append(true)
+            .open(first_output_path.resolve(&file))?
+            .write_all(b", 2046")?;
+        let third_output = tempdir()?;
+        let third_output_path = AbsoluteSystemPath::from_std_path(third_output.path())?;
+        cache.fetch(third_output_path, "linked")?.unwrap();
+        assert_eq!(
+            third_output_path.resolve(&file).read_to_string()?,
+            "In the Mood for Love"
+        );
+
+        // gc counts the extracted copy, and evicting the artifact removes it
+        let size = cache.read_index()?.unwrap()["linked"].size;
+        assert!(size > cache.extracted_size("linked")?);
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: None,
+        })?;
+        assert_eq!(summary.evicted, vec!["linked".to_string()]);
+        assert_eq!(summary.bytes_freed, size);
+        assert!(cache.read_extracted("linked")?.is_none());
+        assert!(!cache.extracted_path("linked").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_restore_without_reflinks() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(
+            &CacheOpts {
+                fs_cache_restore_mode: RestoreMode::Link,
+                ..Default::default()
+            },
+            repo_root_path,
+            None,
+        )?;
+        cache.reflinks.set(false).unwrap();
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Happy Together")?;
+        cache.put(repo_root_path, "extracted", &[file.clone()], 0)?;
+
+        // The artifact is extracted straight into the workspace
+        let output = tempdir()?;
+        let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+        cache.fetch(output_path, "extracted")?.unwrap();
+        assert_eq!(
+            output_path.resolve(&file).read_to_string()?,
+            "Happy Together"
+        );
+        assert!(cache.read_extracted("extracted")?.is_none());
+        assert!(!cache.extracted_path("extracted").exists());
+
+        Ok(())
+    }
+}
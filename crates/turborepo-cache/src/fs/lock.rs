@@ -0,0 +1,268 @@
+use std::{fs::File, io};
+
+use fs2::FileExt;
+use turbopath::AbsoluteSystemPathBuf;
+
+use super::FSCache;
+use crate::CacheError;
+
+const LOCK_DIRECTORY: &str = ".locks";
+// Can't collide with an artifact hash since hashes never start with a dot
+const INDEX_LOCK: &str = ".index";
+const SAVINGS_LOCK: &str = ".savings";
+
+/// An advisory lock on a single artifact, shared between every process using
+/// the same cache directory. The lock is released when this is dropped.
+///
+/// Lock files are only created for artifacts that exist, and are removed
+/// when the artifact is evicted. A process that was waiting on a removed lock
+/// file notices once it gets the lock and retries with a fresh one, so two
+/// processes never hold "the same" lock on different files.
+#[derive(Debug)]
+pub(crate) struct ArtifactLock {
+    file: File,
+}
+
+impl Drop for ArtifactLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock regardless, this just makes it
+        // explicit.
+        let _ = self.file.unlock();
+    }
+}
+
+// Whether `file` is still the lock file at `path`, rather than one that was
+// removed while we waited to lock it
+#[cfg(unix)]
+fn is_current(path: &AbsoluteSystemPathBuf, file: &File) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locked = file.metadata()?;
+    match std::fs::metadata(path.as_std_path()) {
+        Ok(current) => Ok(current.dev() == locked.dev() && current.ino() == locked.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Lock files aren't removed on other platforms
+#[cfg(not(unix))]
+fn is_current(_path: &AbsoluteSystemPathBuf, _file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+impl FSCache {
+    fn lock_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_components(&[LOCK_DIRECTORY, &format!("{}.lock", hash)])
+    }
+
+    // Opens the lock file for `hash` and locks it with `lock`, which returns
+    // false if the lock is held by someone else
+    fn acquire(
+        &self,
+        hash: &str,
+        lock: impl Fn(&File) -> io::Result<bool>,
+    ) -> Result<Option<ArtifactLock>, CacheError> {
+        self.cache_directory
+            .join_component(LOCK_DIRECTORY)
+            .create_dir_all()?;
+        let lock_path = self.lock_path(hash);
+
+        let mut options = File::options();
+        options.read(true).write(true).create(true);
+        loop {
+            let file = lock_path.open_with_options(options.clone())?;
+            if !lock(&file)? {
+                return Ok(None);
+            }
+            if is_current(&lock_path, &file)? {
+                return Ok(Some(ArtifactLock { file }));
+            }
+        }
+    }
+
+    /// Blocks until no other process is writing `hash`.
+    pub(crate) fn lock_shared(&self, hash: &str) -> Result<ArtifactLock, CacheError> {
+        let lock = self.acquire(hash, |file| file.lock_shared().map(|()| true))?;
+        Ok(lock.expect("blocking locks always succeed"))
+    }
+
+    /// Like `lock_shared`, but returns `None` without creating a lock file if
+    /// `hash` isn't cached. Callers still need to check that the artifact
+    /// exists once they have the lock, since it may have been evicted while
+    /// they waited.
+    pub(crate) fn lock_shared_if_cached(
+        &self,
+        hash: &str,
+    ) -> Result<Option<ArtifactLock>, CacheError> {
+        if self.archive_path(hash).is_none() {
+            return Ok(None);
+        }
+        self.lock_shared(hash).map(Some)
+    }
+
+    /// Blocks until no other process is reading or writing `hash`.
+    pub(crate) fn lock_exclusive(&self, hash: &str) -> Result<ArtifactLock, CacheError> {
+        let lock = self.acquire(hash, |file| file.lock_exclusive().map(|()| true))?;
+        Ok(lock.expect("blocking locks always succeed"))
+    }
+
+    /// Blocks until no other process is updating the cache index.
+    pub(crate) fn lock_index(&self) -> Result<ArtifactLock, CacheError> {
+        self.lock_exclusive(INDEX_LOCK)
+    }
+
+    /// Blocks until no other process is updating the savings summary.
+    pub(crate) fn lock_savings(&self) -> Result<ArtifactLock, CacheError> {
+        self.lock_exclusive(SAVINGS_LOCK)
+    }
+
+    /// Takes an exclusive lock on `hash` if nobody else is using it.
+    pub(crate) fn try_lock_exclusive(
+        &self,
+        hash: &str,
+    ) -> Result<Option<ArtifactLock>, CacheError> {
+        self.acquire(hash, |file| match file.try_lock_exclusive() {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Ok(false),
+            Err(e) => Err(e),
+        })
+    }
+
+    // Removes the lock file of an evicted artifact. Must be called with the
+    // exclusive lock held, so that nobody else is using the file. Anyone
+    // waiting on it retries once they get it. Windows can't tell a removed
+    // file from a new one at the same path, so lock files are left there.
+    pub(crate) fn remove_lock_file(&self, hash: &str) -> Result<(), CacheError> {
+        if cfg!(not(unix)) {
+            return Ok(());
+        }
+        match self.lock_path(hash).remove_file() {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, thread};
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use crate::{
+        fs::{CacheGcOptions, FSCache},
+        CacheError, CacheOpts,
+    };
+
+    #[test]
+    fn test_exclusive_lock_blocks_others() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let lock = cache.lock_exclusive("hash")?;
+        assert!(cache.try_lock_exclusive("hash")?.is_none());
+        // Other artifacts are unaffected
+        assert!(cache.try_lock_exclusive("other-hash")?.is_some());
+
+        drop(lock);
+        assert!(cache.try_lock_exclusive("hash")?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_skips_locked_artifacts() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Chungking Express")?;
+        cache.put(repo_root_path, "in-use", &[file], 0)?;
+
+        let lock = cache.lock_shared("in-use")?;
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: None,
+        })?;
+        assert!(summary.evicted.is_empty());
+
+        drop(lock);
+        let summary = cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: None,
+        })?;
+        assert_eq!(summary.evicted, vec!["in-use".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_files_are_removed() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        assert!(cache.fetch(repo_root_path, "missing")?.is_none());
+        assert!(!cache.lock_path("missing").exists());
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("In the Mood for Love")?;
+        cache.put(repo_root_path, "evicted", &[file], 0)?;
+        assert!(cache.fetch(repo_root_path, "evicted")?.is_some());
+        assert!(cache.lock_path("evicted").exists());
+
+        cache.gc(&CacheGcOptions {
+            max_size_bytes: Some(0),
+            max_age: None,
+        })?;
+        assert_eq!(cache.lock_path("evicted").exists(), cfg!(not(unix)));
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_waiting_on_removed_lock_file() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let cache = &cache;
+        let lock = cache.lock_exclusive("hash")?;
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        thread::scope(|scope| -> Result<()> {
+            let waiter = scope.spawn(move || -> Result<(), CacheError> {
+                let _lock = cache.lock_exclusive("hash")?;
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                Ok(())
+            });
+
+            // Evicting removes the file the waiter is blocked on
+            cache.remove_lock_file("hash")?;
+            drop(lock);
+            locked_rx.recv()?;
+            // The waiter retried with a new lock file, which excludes others
+            assert!(cache.try_lock_exclusive("hash")?.is_none());
+
+            release_tx.send(())?;
+            waiter.join().unwrap()?;
+            Ok(())
+        })?;
+        assert!(cache.try_lock_exclusive("hash")?.is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io::Write, time::Instant};
+
+use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+use super::{ArtifactDetails, CacheMetadata, FSCache};
+use crate::CacheError;
+
+impl FSCache {
+    /// Writes an artifact like `put_with_details`, along with the logs of the
+    /// task that produced it so that they can be replayed on a hit.
+    pub fn put_with_logs(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        details: ArtifactDetails,
+        logs: &[u8],
+    ) -> Result<(), CacheError> {
+        let start = Instant::now();
+        let result =
+            self.write_artifact(anchor, hash, files, duration, details, Some(logs), &|_| {});
+        self.events
+            .put(hash, start, &result, || self.archive_size(hash));
+        result
+    }
+
+    /// Copies the logs stored with `put_with_logs` to `out` without restoring
+    /// any files, returning their size. `None` if the artifact isn't in the
+    /// cache or was written without logs.
+    ///
+    /// The archive isn't verified first, since that would mean reading all
+    /// of it. Files are still verified when the artifact is fetched.
+    pub fn fetch_logs(&self, hash: &str, out: &mut impl Write) -> Result<Option<u64>, CacheError> {
+        // Wait for any in-progress write of this artifact to finish
+        let (Some(_lock), Some(cache_path)) =
+            (self.lock_shared_if_cached(hash)?, self.archive_path(hash))
+        else {
+            return Ok(None);
+        };
+        let meta = CacheMetadata::read(&self.metadata_path(hash))?;
+
+        self.open_archive(&cache_path, &meta, 0)?.read_logs(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::CacheOpts;
+
+    #[test]
+    fn test_logs() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("My Blueberry Nights")?;
+
+        let logs = b"> build\nMy Blueberry Nights\n";
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+        cache.put_with_logs(
+            repo_root_path,
+            "with-logs",
+            &[file.clone()],
+            10,
+            ArtifactDetails::default(),
+            logs,
+        )?;
+        cache.put(repo_root_path, "without-logs", &[file.clone()], 10)?;
+
+        let mut replayed = Vec::new();
+        assert_eq!(
+            cache.fetch_logs("with-logs", &mut replayed)?,
+            Some(logs.len() as u64)
+        );
+        assert_eq!(replayed, logs);
+        assert_eq!(cache.fetch_logs("without-logs", &mut Vec::new())?, None);
+        assert_eq!(cache.fetch_logs("missing", &mut Vec::new())?, None);
+
+        // The logs aren't restored with the files
+        let output_dir = tempdir()?;
+        let output_dir_path = AbsoluteSystemPath::from_std_path(output_dir.path())?;
+        let (_, files) = cache.fetch(output_dir_path, "with-logs")?.unwrap();
+        assert_eq!(files, vec![file]);
+        assert_eq!(cache.inspect("with-logs")?.unwrap().entries.len(), 1);
+
+        Ok(())
+    }
+}
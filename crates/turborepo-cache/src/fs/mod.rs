@@ -0,0 +1,1947 @@
+mod async_ops;
+mod bundle;
+mod cache_index;
+mod compare;
+mod delta;
+mod gc;
+mod inspect;
+mod link;
+mod lock;
+mod logs;
+mod quarantine;
+mod quota;
+mod savings;
+mod stats;
+mod stream;
+mod tags;
+mod verify;
+
+use std::{
+    backtrace::Backtrace,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io,
+    io::Read,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use camino::Utf8Path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use turbopath::{
+    AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPath, AnchoredSystemPathBuf,
+};
+use turborepo_analytics::AnalyticsSender;
+use turborepo_api_client::{analytics, analytics::AnalyticsEvent};
+use wax::Pattern;
+
+pub(crate) use self::tags::validate_tags;
+pub use self::{
+    bundle::BundleSummary,
+    cache_index::IndexRecord,
+    compare::ArtifactDiff,
+    gc::{CacheGcOptions, GcSummary},
+    inspect::ArtifactInfo,
+    link::RestoreMode,
+    quota::QuotaPolicy,
+    savings::{Savings, SavingsSummary},
+    stats::{ArtifactStats, CacheStats},
+    tags::{ArtifactTags, TagFilter},
+    verify::{VerifyIssue, VerifyProblem, VerifySummary},
+};
+use self::{cache_index::CacheIndex, delta::DELTA_EXTENSION, lock::ArtifactLock};
+use crate::{
+    cache_archive::{
+        ArchiveEntry, CacheReader, CacheWriter, CompressionAlgorithm, EncryptionKey,
+        OverwritePolicy, Progress, SymlinkPolicy,
+    },
+    events::EventReporter,
+    signature_authentication::ArtifactSignatureAuthenticator,
+    CacheError, CacheHitMetadata, CacheOpts, CacheSource,
+};
+
+// Archive extensions in the order we prefer them when more than one exists.
+const ARCHIVE_EXTENSIONS: [&str; 4] = ["tar", "tar.zst", "tar.lz4", DELTA_EXTENSION];
+const METADATA_SUFFIX: &str = "-meta.json";
+// The entry index of an artifact is kept out of its metadata, which is read
+// far more often and would otherwise grow with the number of files
+const ENTRY_INDEX_SUFFIX: &str = "-index.json";
+// How stale an artifact's last access time can get before a hit updates it
+const ACCESS_TIME_RESOLUTION: Duration = Duration::from_secs(10 * 60);
+
+pub struct FSCache {
+    cache_directory: AbsoluteSystemPathBuf,
+    analytics_recorder: Option<AnalyticsSender>,
+    events: EventReporter,
+    ttl: Option<Duration>,
+    remove_corrupt: bool,
+    signer_verifier: Option<ArtifactSignatureAuthenticator>,
+    compression: CompressionAlgorithm,
+    compression_level: i32,
+    compression_workers: u32,
+    restore_workers: u32,
+    restore_mode: RestoreMode,
+    // Whether files in the cache directory can be reflinked, checked on the
+    // first fetch in link mode
+    reflinks: OnceLock<bool>,
+    overwrite_policy: OverwritePolicy,
+    symlink_policy: SymlinkPolicy,
+    mtimes: bool,
+    dedupe_files: bool,
+    sparse_files: bool,
+    xattrs: bool,
+    encryption_key: Option<EncryptionKey>,
+    delta: bool,
+    quota: Option<u64>,
+    quota_policy: QuotaPolicy,
+    fsync: bool,
+    turbo_version: Option<String>,
+    // Pruning walks the entire cache directory so we only do it once per
+    // process, on the first write.
+    pruned: AtomicBool,
+    index: CacheIndex,
+}
+
+// Bumped whenever the metadata format changes. Metadata without a version
+// was written before versioning was introduced, and version 1 held the entry
+// index itself.
+const METADATA_VERSION: u32 = 2;
+
+/// Where an artifact came from. The caller provides the task and package;
+/// the rest is filled in by the cache when the artifact is written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ArtifactDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turbo_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    // See `ArtifactTags`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: ArtifactTags,
+}
+
+// Every field other than `hash` must have a default so that metadata from
+// other versions can always be read.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CacheMetadata {
+    #[serde(default)]
+    version: u32,
+    hash: String,
+    #[serde(default)]
+    duration: u64,
+    // Timestamps are optional so that metadata written by older versions
+    // can still be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_accessed: Option<DateTime<Utc>>,
+    // Hex encoded SHA-256 of the archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    // HMAC of the archive, only present if signing is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    // Every entry of the archive, used to restore part of it without reading
+    // the whole thing. It's written to its own file, and only loaded by
+    // `read_with_index`, except when read from version 1 metadata.
+    #[serde(default, skip_serializing)]
+    index: Option<Vec<ArchiveEntry>>,
+    // The archive as it was when it was last verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verified: Option<ArchiveStamp>,
+    // Pinned artifacts are never evicted by gc
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+    // `EncryptionKey::id` of the key the archive is encrypted with, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption_key_id: Option<String>,
+    // The artifact the archive is a delta against, if it's a delta
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta_base: Option<String>,
+    // The `sha256` of the base's archive when the delta was made against it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta_base_sha256: Option<String>,
+    #[serde(flatten)]
+    details: ArtifactDetails,
+    // Fields written by newer versions that we don't know about. They're kept
+    // so that rewriting the metadata doesn't lose them.
+    #[serde(flatten)]
+    unknown: serde_json::Map<String, serde_json::Value>,
+}
+
+impl CacheMetadata {
+    fn read(path: &AbsoluteSystemPath) -> Result<CacheMetadata, CacheError> {
+        let mut meta: CacheMetadata = serde_json::from_str(&path.read_to_string()?)
+            .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))?;
+        // Newer versions only add optional fields, so we can still use the
+        // parts we understand.
+        if meta.version > METADATA_VERSION {
+            debug!(
+                "cache metadata for {} has version {}, newer than {}",
+                meta.hash, meta.version, METADATA_VERSION
+            );
+        }
+        meta.migrate(path);
+
+        Ok(meta)
+    }
+
+    // Like `read`, but also loads the entry index. Artifacts written by
+    // older versions may not have one.
+    fn read_with_index(path: &AbsoluteSystemPath) -> Result<CacheMetadata, CacheError> {
+        let mut meta = Self::read(path)?;
+        if meta.index.is_none() {
+            let index_path = meta.entry_index_path(path);
+            meta.index = match index_path.read_to_string() {
+                Ok(contents) => Some(
+                    serde_json::from_str(&contents)
+                        .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))?,
+                ),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+        }
+
+        Ok(meta)
+    }
+
+    fn entry_index_path(&self, metadata_path: &AbsoluteSystemPath) -> AbsoluteSystemPathBuf {
+        metadata_path
+            .parent()
+            .expect("metadata has a parent")
+            .join_component(&format!("{}{}", self.hash, ENTRY_INDEX_SUFFIX))
+    }
+
+    // Brings metadata written by older versions up to date. This only happens
+    // in memory, the result is persisted the next time the metadata is
+    // written.
+    fn migrate(&mut self, path: &AbsoluteSystemPath) {
+        if self.version < 1 && self.created_at.is_none() {
+            // Version 0 had no timestamps, so the best we can do is when the
+            // metadata file was written.
+            self.created_at = path
+                .symlink_metadata()
+                .ok()
+                .and_then(|metadata| metadata.modified().ok())
+                .map(DateTime::from);
+        }
+
+        self.version = self.version.max(METADATA_VERSION);
+    }
+
+    // Metadata can be rewritten while other processes are reading it, so it's
+    // written to a temporary file first and then renamed into place.
+    fn write(&self, path: &AbsoluteSystemPathBuf) -> Result<(), CacheError> {
+        self.write_synced(path, false)
+    }
+
+    // Like `write`, but if `sync` is set the new metadata is flushed to disk
+    // before it replaces the old one. The entry index is written first if
+    // it's loaded, which also moves it out of version 1 metadata.
+    fn write_synced(&self, path: &AbsoluteSystemPathBuf, sync: bool) -> Result<(), CacheError> {
+        if let Some(index) = &self.index {
+            write_json(&self.entry_index_path(path), index, sync)?;
+        }
+        write_json(path, self, sync)
+    }
+
+    // The most recent time we know this artifact was used
+    fn last_used(&self) -> Option<DateTime<Utc>> {
+        self.last_accessed.or(self.created_at)
+    }
+
+    // The number of entries and bytes of file contents in the archive, if it
+    // was written with an index
+    fn totals(&self) -> (Option<u64>, Option<u64>) {
+        match &self.index {
+            Some(index) => (
+                Some(index.len() as u64),
+                Some(index.iter().map(|entry| entry.size).sum()),
+            ),
+            None => (None, None),
+        }
+    }
+
+    // The files that duplicates in the archive are stored as hardlinks to
+    fn link_targets(&self) -> HashSet<AnchoredSystemPathBuf> {
+        self.index
+            .iter()
+            .flatten()
+            .filter_map(|entry| entry.link.clone())
+            .collect()
+    }
+}
+
+// Files other processes may be reading are written to a temporary file first
+// and then renamed into place
+fn write_json(
+    path: &AbsoluteSystemPathBuf,
+    value: &impl Serialize,
+    sync: bool,
+) -> Result<(), CacheError> {
+    static WRITE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let temp_path = path
+        .parent()
+        .expect("files are written in a directory")
+        .join_component(&format!(
+            ".{}.{}.{}.tmp",
+            path.file_name().expect("files have a name"),
+            process::id(),
+            WRITE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    let file = temp_path.open_with_options(options)?;
+
+    serde_json::to_writer(&file, value)
+        .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+    if sync {
+        file.sync_all()?;
+    }
+    temp_path.rename(path)?;
+
+    Ok(())
+}
+
+// What an archive's file looked like when the archive was verified, so that
+// it only needs to be read again once that changes. Unlike the modification
+// time, the change time and inode are set by the filesystem and can't be put
+// back after rewriting the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct ArchiveStamp {
+    len: u64,
+    modified: SystemTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    changed: Option<SystemTime>,
+    #[serde(default)]
+    inode: u64,
+    // Identifies the signing key the archive was verified with, so that a
+    // new key or enabling signing verifies it again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signer: Option<String>,
+}
+
+// The files on disk that together make up a single cached artifact.
+#[derive(Debug)]
+pub(crate) struct ArtifactFiles {
+    pub hash: String,
+    pub archives: Vec<AbsoluteSystemPathBuf>,
+    pub metadata: Option<AbsoluteSystemPathBuf>,
+    pub entry_index: Option<AbsoluteSystemPathBuf>,
+}
+
+// Flushes a file, or on unix a directory, to disk. Syncing a directory makes
+// the files created and renamed in it durable; Windows can't open directories
+// to do that.
+fn sync_path(path: &AbsoluteSystemPath) -> Result<(), CacheError> {
+    if cfg!(windows) && path.as_std_path().is_dir() {
+        return Ok(());
+    }
+    File::open(path.as_std_path())?.sync_all()?;
+    Ok(())
+}
+
+impl ArtifactFiles {
+    pub fn paths(&self) -> impl Iterator<Item = &AbsoluteSystemPathBuf> {
+        self.archives
+            .iter()
+            .chain(self.metadata.iter())
+            .chain(self.entry_index.iter())
+    }
+}
+
+impl FSCache {
+    pub(crate) fn resolve_cache_dir(
+        repo_root: &AbsoluteSystemPath,
+        override_dir: Option<&Utf8Path>,
+    ) -> AbsoluteSystemPathBuf {
+        if let Some(override_dir) = override_dir {
+            AbsoluteSystemPathBuf::from_unknown(repo_root, override_dir)
+        } else {
+            repo_root.join_components(&["node_modules", ".cache", "turbo"])
+        }
+    }
+
+    pub fn new(
+        opts: &CacheOpts,
+        repo_root: &AbsoluteSystemPath,
+        analytics_recorder: Option<AnalyticsSender>,
+    ) -> Result<Self, CacheError> {
+        let cache_directory = Self::resolve_cache_dir(repo_root, opts.override_dir);
+        cache_directory.create_dir_all()?;
+
+        let signer_verifier = opts
+            .fs_cache_signature
+            .then(|| ArtifactSignatureAuthenticator {
+                team_id: opts
+                    .remote_cache_opts
+                    .as_ref()
+                    .map(|remote_cache_opts| remote_cache_opts.team_id.as_bytes().to_vec())
+                    .unwrap_or_default(),
+                secret_key_override: None,
+                previous_keys_override: None,
+            });
+
+        let mut restore_mode = opts.fs_cache_restore_mode;
+        if opts.fs_cache_encryption_key.is_some() && restore_mode == RestoreMode::Link {
+            warn!(
+                "link restore mode would keep decrypted artifacts in the cache, extracting instead"
+            );
+            restore_mode = RestoreMode::Extract;
+        }
+
+        Ok(FSCache {
+            cache_directory,
+            analytics_recorder,
+            events: EventReporter::new(opts, CacheSource::Local),
+            ttl: opts.fs_cache_ttl,
+            remove_corrupt: opts.fs_cache_remove_corrupt,
+            signer_verifier,
+            compression: opts.fs_cache_compression,
+            compression_level: opts.fs_cache_compression_level,
+            compression_workers: opts.fs_cache_compression_workers,
+            restore_workers: opts.fs_cache_restore_workers,
+            restore_mode,
+            reflinks: OnceLock::new(),
+            overwrite_policy: opts.fs_cache_overwrite_policy,
+            symlink_policy: opts.symlink_policy,
+            mtimes: opts.fs_cache_mtimes,
+            dedupe_files: opts.fs_cache_dedupe_files,
+            sparse_files: opts.fs_cache_sparse_files,
+            xattrs: opts.fs_cache_xattrs,
+            encryption_key: opts.fs_cache_encryption_key.clone(),
+            delta: opts.fs_cache_delta && opts.fs_cache_encryption_key.is_none(),
+            quota: opts.fs_cache_quota,
+            quota_policy: opts.fs_cache_quota_policy,
+            fsync: opts.fs_cache_fsync,
+            turbo_version: opts.turbo_version.clone(),
+            pruned: AtomicBool::new(false),
+            index: CacheIndex::default(),
+        })
+    }
+
+    fn archive_path(&self, hash: &str) -> Option<AbsoluteSystemPathBuf> {
+        ARCHIVE_EXTENSIONS
+            .iter()
+            .map(|extension| {
+                self.cache_directory
+                    .join_component(&format!("{}.{}", hash, extension))
+            })
+            .find(|path| path.exists())
+    }
+
+    // Size of the archive for `hash`, or 0 if there isn't one
+    fn archive_size(&self, hash: &str) -> u64 {
+        self.archive_path(hash)
+            .and_then(|path| path.symlink_metadata().ok())
+            .map_or(0, |metadata| metadata.len())
+    }
+
+    // Size of everything stored for `hash`: its archive, its metadata and
+    // the copy extracted by link restore mode
+    fn stored_size(&self, hash: &str) -> Result<u64, CacheError> {
+        let mut size = self.archive_size(hash) + self.extracted_size(hash)?;
+        for path in [self.metadata_path(hash), self.entry_index_path(hash)] {
+            match path.symlink_metadata() {
+                Ok(metadata) => size += metadata.len(),
+                Err(e) if e.is_io_error(io::ErrorKind::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(size)
+    }
+
+    fn metadata_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_component(&format!("{}{}", hash, METADATA_SUFFIX))
+    }
+
+    fn entry_index_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_component(&format!("{}{}", hash, ENTRY_INDEX_SUFFIX))
+    }
+
+    fn archive_digest(path: &AbsoluteSystemPath) -> Result<String, CacheError> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut path.open()?, &mut hasher)?;
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    // Checks the archive against the checksum recorded when it was written.
+    // Metadata from older versions has no checksum and is accepted unless
+    // signing is enabled, in which case every artifact must have a valid tag.
+    fn verify_archive(
+        &self,
+        hash: &str,
+        archive_path: &AbsoluteSystemPath,
+        meta: &CacheMetadata,
+    ) -> Result<(), CacheError> {
+        if let Some(expected) = &meta.sha256 {
+            if *expected != Self::archive_digest(archive_path)? {
+                return Err(CacheError::Corrupt(hash.to_string(), Backtrace::capture()));
+            }
+        }
+
+        if let Some(signer_verifier) = &self.signer_verifier {
+            let tag = meta
+                .tag
+                .as_deref()
+                .ok_or_else(|| CacheError::InvalidTag(Backtrace::capture()))?;
+            if !signer_verifier.validate_reader(hash.as_bytes(), archive_path.open()?, tag)? {
+                return Err(CacheError::InvalidTag(Backtrace::capture()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like `verify_archive`, but only reads the archive if it has changed
+    // since it was last verified. Checking an unchanged archive costs a
+    // `stat`, and an archive that passes is stamped for next time.
+    fn verify_changed_archive(
+        &self,
+        hash: &str,
+        archive_path: &AbsoluteSystemPath,
+        meta: &CacheMetadata,
+    ) -> Result<(), CacheError> {
+        // Taken first, so that a change while we read the archive is noticed
+        // next time
+        let stamp = self.archive_stamp(hash, archive_path)?;
+        if meta.verified.as_ref() == Some(&stamp) {
+            return Ok(());
+        }
+        self.verify_archive(hash, archive_path, meta)?;
+
+        let mut meta = meta.clone();
+        meta.verified = Some(stamp);
+        if let Err(e) = meta.write(&self.metadata_path(hash)) {
+            debug!("failed to record that {} was verified: {}", hash, e);
+        }
+        Ok(())
+    }
+
+    fn archive_stamp(
+        &self,
+        hash: &str,
+        archive_path: &AbsoluteSystemPath,
+    ) -> Result<ArchiveStamp, CacheError> {
+        let metadata = archive_path.symlink_metadata()?;
+        #[cfg(unix)]
+        let (changed, inode) = {
+            use std::os::unix::fs::MetadataExt;
+            let changed = SystemTime::UNIX_EPOCH
+                + Duration::new(metadata.ctime().max(0) as u64, metadata.ctime_nsec() as u32);
+            (Some(changed), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (changed, inode) = (None, 0);
+
+        Ok(ArchiveStamp {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            changed,
+            inode,
+            signer: self
+                .signer_verifier
+                .as_ref()
+                .map(|signer| signer.generate_tag(hash.as_bytes(), b""))
+                .transpose()?,
+        })
+    }
+
+    // Groups the files in the cache directory by the hash they belong to.
+    // Files that don't look like cache artifacts are ignored.
+    pub(crate) fn list_artifacts(&self) -> Result<Vec<ArtifactFiles>, CacheError> {
+        let mut artifacts: BTreeMap<String, ArtifactFiles> = BTreeMap::new();
+        for entry in std::fs::read_dir(&self.cache_directory)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Some(file_name) = entry.file_name().to_str().map(|name| name.to_string()) else {
+                continue;
+            };
+            let path = self.cache_directory.join_component(&file_name);
+
+            let hash = if let Some(hash) = file_name.strip_suffix(METADATA_SUFFIX) {
+                hash
+            } else if let Some(hash) = file_name.strip_suffix(ENTRY_INDEX_SUFFIX) {
+                hash
+            } else if let Some(hash) = ARCHIVE_EXTENSIONS
+                .iter()
+                .rev()
+                .find_map(|extension| file_name.strip_suffix(&format!(".{}", extension)))
+            {
+                hash
+            } else {
+                continue;
+            };
+
+            let artifact = artifacts
+                .entry(hash.to_string())
+                .or_insert_with(|| ArtifactFiles {
+                    hash: hash.to_string(),
+                    archives: Vec::new(),
+                    metadata: None,
+                    entry_index: None,
+                });
+            if file_name.ends_with(METADATA_SUFFIX) {
+                artifact.metadata = Some(path);
+            } else if file_name.ends_with(ENTRY_INDEX_SUFFIX) {
+                artifact.entry_index = Some(path);
+            } else {
+                artifact.archives.push(path);
+            }
+        }
+
+        Ok(artifacts.into_values().collect())
+    }
+
+    fn log_fetch(&self, event: analytics::CacheEvent, hash: &str, duration: u64) {
+        // If analytics fails to record, it's not worth failing the cache
+        if let Some(analytics_recorder) = &self.analytics_recorder {
+            let analytics_event = AnalyticsEvent {
+                session_id: None,
+                source: analytics::CacheSource::Local,
+                event,
+                hash: hash.to_string(),
+                duration,
+            };
+
+            let _ = analytics_recorder.send(analytics_event);
+        }
+    }
+
+    fn needs_touch(last_used: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        // A last use in the future means the clock moved, leave it alone
+        last_used.map_or(true, |last_used| {
+            (now - last_used)
+                .to_std()
+                .map_or(false, |age| age >= ACCESS_TIME_RESOLUTION)
+        })
+    }
+
+    // `bytes_restored` is only known for artifacts written with an index, and
+    // is counted as 0 otherwise
+    fn record_hit(&self, hash: &str, meta: &CacheMetadata, bytes_restored: Option<u64>) {
+        self.log_fetch(analytics::CacheEvent::Hit, hash, meta.duration);
+        self.touch(hash, meta);
+        self.record_savings(meta.duration, bytes_restored.unwrap_or_default());
+    }
+
+    // Records that the artifact was just used, for least-recently-used
+    // eviction. To avoid rewriting the metadata on every hit, the access time
+    // is only updated once it is out of date by more than
+    // `ACCESS_TIME_RESOLUTION`. Failing to update it shouldn't fail the hit.
+    fn touch(&self, hash: &str, meta: &CacheMetadata) {
+        let now = Utc::now();
+        if !Self::needs_touch(meta.last_used(), now) {
+            return;
+        }
+
+        let mut meta = meta.clone();
+        meta.last_accessed = Some(now);
+        let result = meta.write(&self.metadata_path(hash)).and_then(|()| {
+            self.update_index(|index| {
+                if let Some(record) = index.get_mut(hash) {
+                    record.last_accessed = Some(now);
+                }
+            })
+        });
+        if let Err(e) = result {
+            debug!("failed to update access time for {}: {}", hash, e);
+        }
+    }
+
+    pub fn fetch(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        self.fetch_with_progress(anchor, hash, &|_| {})
+    }
+
+    /// Fetches an artifact, calling `progress` as its entries are restored.
+    /// Totals are only known for artifacts written with an entry index.
+    pub fn fetch_with_progress(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        progress: &dyn Fn(Progress),
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        let start = Instant::now();
+        let result = self.restore_artifact(anchor, hash, progress);
+        self.events
+            .fetch(hash, start, &result, || self.archive_size(hash));
+        result
+    }
+
+    fn restore_artifact(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        progress: &dyn Fn(Progress),
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        // Wait for any in-progress write of this artifact to finish
+        let (Some(lock), Some(cache_path)) =
+            (self.lock_shared_if_cached(hash)?, self.archive_path(hash))
+        else {
+            self.log_fetch(analytics::CacheEvent::Miss, hash, 0);
+            return Ok(None);
+        };
+
+        let meta = CacheMetadata::read_with_index(&self.metadata_path(hash))?;
+        if !self.can_restore(hash, &meta) {
+            return Ok(None);
+        }
+        let (total_files, total_bytes) = meta.totals();
+        let progress = |current: Progress| {
+            progress(Progress {
+                total_files,
+                total_bytes,
+                ..current
+            })
+        };
+
+        let restore_mode = self.effective_restore_mode();
+        // The archive was verified when it was extracted
+        if restore_mode == RestoreMode::Link {
+            if let Some(restored_files) = self.read_extracted(hash)? {
+                self.link_extracted(hash, anchor, &restored_files)?;
+                self.record_hit(hash, &meta, total_bytes);
+                // Linking is quick enough that it's only reported once done
+                progress(Progress {
+                    files: restored_files.len() as u64,
+                    bytes: total_bytes.unwrap_or_default(),
+                    ..Progress::default()
+                });
+
+                return Ok(Some((
+                    CacheHitMetadata {
+                        time_saved: meta.duration,
+                        source: CacheSource::Local,
+                    },
+                    restored_files,
+                )));
+            }
+        }
+
+        let Some(_lock) = self.verify_or_quarantine(hash, lock, &cache_path, &meta)? else {
+            return Ok(None);
+        };
+
+        let restored_files = match restore_mode {
+            RestoreMode::Extract => self
+                .open_archive(&cache_path, &meta, 0)?
+                .with_overwrite_policy(self.overwrite_policy)
+                .with_symlink_policy(self.symlink_policy)
+                .with_mtimes(self.mtimes)
+                .with_xattrs(self.xattrs)
+                .with_progress(&progress)
+                .restore_with_workers(anchor, self.restore_workers as usize)?,
+            RestoreMode::Link => {
+                let restored_files = self.extract(hash, &cache_path, &meta, &progress)?;
+                self.link_extracted(hash, anchor, &restored_files)?;
+                restored_files
+            }
+        };
+
+        self.record_hit(hash, &meta, total_bytes);
+
+        Ok(Some((
+            CacheHitMetadata {
+                time_saved: meta.duration,
+                source: CacheSource::Local,
+            },
+            restored_files,
+        )))
+    }
+
+    /// Restores only the entries of `hash` whose paths match one of `globs`.
+    /// Artifacts written with an entry index are only decompressed as far as
+    /// the last matching entry, and not at all if nothing matches.
+    pub fn fetch_filtered(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        globs: &[String],
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        let start = Instant::now();
+        let result = self.restore_filtered(anchor, hash, globs);
+        self.events
+            .fetch(hash, start, &result, || self.archive_size(hash));
+        result
+    }
+
+    fn restore_filtered(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        globs: &[String],
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        let matcher = wax::any(globs.iter().map(String::as_str))?;
+        let matches = |path: &AnchoredSystemPath| matcher.is_match(path.to_unix().as_str());
+
+        // Wait for any in-progress write of this artifact to finish
+        let (Some(lock), Some(cache_path)) =
+            (self.lock_shared_if_cached(hash)?, self.archive_path(hash))
+        else {
+            self.log_fetch(analytics::CacheEvent::Miss, hash, 0);
+            return Ok(None);
+        };
+
+        let meta = CacheMetadata::read_with_index(&self.metadata_path(hash))?;
+        if !self.can_restore(hash, &meta) {
+            return Ok(None);
+        }
+        let Some(_lock) = self.verify_or_quarantine(hash, lock, &cache_path, &meta)? else {
+            return Ok(None);
+        };
+
+        let hit = CacheHitMetadata {
+            time_saved: meta.duration,
+            source: CacheSource::Local,
+        };
+        // Reading starts at the first matching entry, so the last offset is
+        // relative to it
+        let (first_offset, last_offset, bytes_restored) = match &meta.index {
+            Some(index) => {
+                let matching = index
+                    .iter()
+                    .filter(|entry| matches(&entry.path))
+                    .collect::<Vec<_>>();
+                let bytes_restored = matching.iter().map(|entry| entry.size).sum();
+                let (Some(first), Some(last)) = (matching.first(), matching.last()) else {
+                    self.record_hit(hash, &meta, Some(0));
+                    return Ok(Some((hit, Vec::new())));
+                };
+                // Duplicates are restored from their original, which has to
+                // be read even if it doesn't match
+                let offsets = index
+                    .iter()
+                    .map(|entry| (&entry.path, entry.offset))
+                    .collect::<HashMap<_, _>>();
+                let first_offset = matching
+                    .iter()
+                    .filter_map(|entry| entry.link.as_ref())
+                    .filter_map(|original| offsets.get(original).copied())
+                    .fold(first.offset, u64::min);
+                (
+                    first_offset,
+                    Some(last.offset - first_offset),
+                    Some(bytes_restored),
+                )
+            }
+            None => (0, None, None),
+        };
+
+        let restored_files = self
+            .open_archive(&cache_path, &meta, first_offset)?
+            .with_overwrite_policy(self.overwrite_policy)
+            .with_symlink_policy(self.symlink_policy)
+            .with_mtimes(self.mtimes)
+            .with_xattrs(self.xattrs)
+            .restore_matching(anchor, matches, last_offset)?;
+
+        self.record_hit(hash, &meta, bytes_restored);
+
+        Ok(Some((hit, restored_files)))
+    }
+
+    // Artifacts encrypted with another key, or not encrypted when we have a
+    // key, and deltas whose base is gone are misses so that the next put
+    // replaces them.
+    fn can_restore(&self, hash: &str, meta: &CacheMetadata) -> bool {
+        if meta.encryption_key_id != self.encryption_key.as_ref().map(EncryptionKey::id) {
+            debug!("{} is not encrypted with the configured key", hash);
+        } else if !self.has_delta_base(meta) {
+            debug!("{} is a delta against an artifact that was evicted", hash);
+        } else {
+            return true;
+        }
+        self.log_fetch(analytics::CacheEvent::Miss, hash, 0);
+        false
+    }
+
+    // Checks the archive before it is restored. A corrupt artifact is moved
+    // to the quarantine directory, or deleted if `remove_corrupt` is set, and
+    // treated as a miss so that the task runs again and replaces it.
+    fn verify_or_quarantine(
+        &self,
+        hash: &str,
+        lock: ArtifactLock,
+        cache_path: &AbsoluteSystemPathBuf,
+        meta: &CacheMetadata,
+    ) -> Result<Option<ArtifactLock>, CacheError> {
+        let Err(err) = self.verify_changed_archive(hash, cache_path, meta) else {
+            return Ok(Some(lock));
+        };
+
+        drop(lock);
+        // Leave it to whoever is already replacing or removing the artifact
+        if let Some(_lock) = self.try_lock_exclusive(hash)? {
+            let artifact = ArtifactFiles {
+                hash: hash.to_string(),
+                archives: vec![cache_path.clone()],
+                metadata: Some(self.metadata_path(hash)),
+                entry_index: Some(self.entry_index_path(hash)),
+            };
+            if self.remove_corrupt {
+                warn!("{}, removing it", err);
+                self.evict(&artifact)?;
+            } else {
+                warn!("{}, moving it to quarantine", err);
+                self.quarantine(&artifact, &err.to_string())?;
+            }
+        }
+        self.log_fetch(analytics::CacheEvent::Miss, hash, 0);
+
+        Ok(None)
+    }
+
+    pub(crate) fn exists(&self, hash: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
+        Ok(self.exists_many(&[hash])?.pop().flatten())
+    }
+
+    /// Checks for many artifacts at once. Artifacts in the cache index are
+    /// answered with a single `stat` of the archive rather than reading their
+    /// metadata, unless their access time needs updating. Anything missing
+    /// from the index is checked on disk, since artifacts can be added
+    /// without updating it, e.g. by older versions.
+    pub fn exists_many(
+        &self,
+        hashes: &[&str],
+    ) -> Result<Vec<Option<CacheHitMetadata>>, CacheError> {
+        let index = self.read_index()?;
+        let now = Utc::now();
+
+        let mut hits = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let indexed = index.as_ref().and_then(|index| index.get(*hash));
+            let hit = match indexed {
+                // The archive may have been removed without going through the
+                // cache, so we still check that it's there
+                Some(record) if !Self::needs_touch(record.last_used(), now) => {
+                    self.archive_path(hash).map(|_| CacheHitMetadata {
+                        time_saved: record.duration,
+                        source: CacheSource::Local,
+                    })
+                }
+                _ => self.exists_with_details(hash)?.map(|(hit, _)| hit),
+            };
+            hits.push(hit);
+        }
+
+        Ok(hits)
+    }
+
+    /// Like `exists`, but also returns what produced the artifact. Details
+    /// are empty for artifacts written by older versions.
+    pub fn exists_with_details(
+        &self,
+        hash: &str,
+    ) -> Result<Option<(CacheHitMetadata, ArtifactDetails)>, CacheError> {
+        if self.archive_path(hash).is_none() {
+            return Ok(None);
+        }
+
+        let (duration, details) = match CacheMetadata::read(&self.metadata_path(hash)) {
+            Ok(meta) => {
+                self.touch(hash, &meta);
+                (meta.duration, meta.details)
+            }
+            Err(_) => Default::default(),
+        };
+
+        Ok(Some((
+            CacheHitMetadata {
+                time_saved: duration,
+                source: CacheSource::Local,
+            },
+            details,
+        )))
+    }
+
+    pub fn put(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+    ) -> Result<(), CacheError> {
+        self.put_with_details(anchor, hash, files, duration, ArtifactDetails::default())
+    }
+
+    /// Writes an artifact, recording `details` in its metadata. The turbo
+    /// version, OS and architecture are always filled in by the cache.
+    pub fn put_with_details(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        details: ArtifactDetails,
+    ) -> Result<(), CacheError> {
+        self.put_with_progress(anchor, hash, files, duration, details, &|_| {})
+    }
+
+    /// Writes an artifact like `put_with_details`, calling `progress` as each
+    /// file is added. Only the total number of files is known up front.
+    pub fn put_with_progress(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        details: ArtifactDetails,
+        progress: &dyn Fn(Progress),
+    ) -> Result<(), CacheError> {
+        let start = Instant::now();
+        let result = self.write_artifact(anchor, hash, files, duration, details, None, progress);
+        self.events
+            .put(hash, start, &result, || self.archive_size(hash));
+        result
+    }
+
+    /// Adds an artifact from an archive downloaded from a remote cache,
+    /// without restoring its files and archiving them again. `archive` is a
+    /// zstd compressed tar, the way remote caches store artifacts. It's
+    /// stored with the cache's own compression and encryption.
+    pub fn put_archive(
+        &self,
+        hash: &str,
+        archive: impl Read,
+        duration: u64,
+        details: ArtifactDetails,
+    ) -> Result<(), CacheError> {
+        let start = Instant::now();
+        let result = self.store_artifact(hash, duration, details, |cache_path| {
+            let mut tar = zstd::Decoder::new(archive)?;
+            CacheWriter::copy_tar(
+                cache_path,
+                self.compression_level,
+                self.compression_workers,
+                self.encryption_key.as_ref(),
+                &mut tar,
+            )?;
+            // The index is read back from what was stored, so that it
+            // describes the archive even if the download didn't
+            CacheReader::open_with_key(&cache_path.to_owned(), 0, self.encryption_key.as_ref())?
+                .entries()
+        });
+        self.events
+            .put(hash, start, &result, || self.archive_size(hash));
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_artifact(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        details: ArtifactDetails,
+        logs: Option<&[u8]>,
+        progress: &dyn Fn(Progress),
+    ) -> Result<(), CacheError> {
+        let total_files = Some(files.len() as u64);
+        let progress = |current: Progress| {
+            progress(Progress {
+                total_files,
+                ..current
+            })
+        };
+
+        self.store_artifact(hash, duration, details, |cache_path| {
+            let mut cache_item = CacheWriter::create_with_key(
+                cache_path,
+                self.compression_level,
+                self.compression_workers,
+                self.encryption_key.as_ref(),
+            )?
+            .with_mtimes(self.mtimes)
+            .with_xattrs(self.xattrs)
+            .with_dedupe(self.dedupe_files)
+            .with_sparse(self.sparse_files)
+            .with_progress(&progress);
+
+            if let Some(logs) = logs {
+                cache_item.add_logs(logs)?;
+            }
+            for file in files {
+                cache_item.add_file(anchor, file)?;
+            }
+
+            let index = cache_item.index().to_vec();
+            cache_item.finish()?;
+            Ok(index)
+        })
+    }
+
+    // Writes the archive of `hash` with `write_archive`, which returns the
+    // archive's entry index, followed by its metadata and index record.
+    fn store_artifact(
+        &self,
+        hash: &str,
+        duration: u64,
+        details: ArtifactDetails,
+        write_archive: impl FnOnce(&AbsoluteSystemPath) -> Result<Vec<ArchiveEntry>, CacheError>,
+    ) -> Result<(), CacheError> {
+        {
+            // Keep other processes from reading a partially written artifact
+            let _lock = self.lock_exclusive(hash)?;
+
+            let cache_path = self.cache_directory.join_component(&format!(
+                "{}.{}",
+                hash,
+                self.compression.extension()
+            ));
+
+            // An archive written with a different compression setting would
+            // shadow the one we're about to write.
+            for extension in ARCHIVE_EXTENSIONS {
+                let path = self
+                    .cache_directory
+                    .join_component(&format!("{}.{}", hash, extension));
+                if path != cache_path && path.exists() {
+                    path.remove_file()?;
+                }
+            }
+            self.remove_extracted(hash)?;
+
+            let index = write_archive(&cache_path)?;
+
+            // The full archive is kept when there's no smaller delta
+            let delta = if self.delta {
+                self.write_delta(hash, &cache_path, &details)
+            } else {
+                None
+            };
+            let (cache_path, delta_base, delta_base_sha256) = match delta {
+                Some((base, base_sha256, delta_path)) => {
+                    (delta_path, Some(base), Some(base_sha256))
+                }
+                None => (cache_path, None, None),
+            };
+
+            let now = Utc::now();
+            let metadata_path = self.metadata_path(hash);
+            // Replacing a pinned artifact keeps it pinned
+            let pinned = CacheMetadata::read(&metadata_path).map_or(false, |meta| meta.pinned);
+            let meta = CacheMetadata {
+                version: METADATA_VERSION,
+                hash: hash.to_string(),
+                duration,
+                created_at: Some(now),
+                last_accessed: Some(now),
+                sha256: Some(Self::archive_digest(&cache_path)?),
+                tag: self
+                    .signer_verifier
+                    .as_ref()
+                    .map(|signer| {
+                        signer.generate_tag_from_reader(hash.as_bytes(), cache_path.open()?)
+                    })
+                    .transpose()?,
+                index: Some(index),
+                verified: Some(self.archive_stamp(hash, &cache_path)?),
+                pinned,
+                encryption_key_id: self.encryption_key.as_ref().map(EncryptionKey::id),
+                delta_base,
+                delta_base_sha256,
+                details: ArtifactDetails {
+                    turbo_version: self.turbo_version.clone(),
+                    os: Some(std::env::consts::OS.to_string()),
+                    arch: Some(std::env::consts::ARCH.to_string()),
+                    ..details
+                },
+                unknown: Default::default(),
+            };
+            // The archive has to be on disk before the metadata that points to
+            // it, and both before the directory entries that name them
+            if self.fsync {
+                sync_path(&cache_path)?;
+            }
+            meta.write_synced(&metadata_path, self.fsync)?;
+            if self.fsync {
+                sync_path(&self.cache_directory)?;
+            }
+            if self.delta && meta.delta_base.is_none() {
+                if let Err(e) = self.set_delta_base(hash, &meta.details) {
+                    debug!("failed to record {} as a delta base: {}", hash, e);
+                }
+            }
+
+            let size = self.stored_size(hash)?;
+            let record = Self::index_record(&meta, size, cache_path.extension() != Some("tar"));
+            self.update_index(|index| {
+                index.insert(hash.to_string(), record);
+            })?;
+
+            self.enforce_quota(
+                ArtifactFiles {
+                    hash: hash.to_string(),
+                    archives: vec![cache_path],
+                    metadata: Some(metadata_path),
+                    entry_index: Some(self.entry_index_path(hash)),
+                },
+                size,
+            )?;
+        }
+
+        self.prune_if_needed();
+
+        Ok(())
+    }
+
+    // Opportunistically prunes stale artifacts if a TTL is configured.
+    // Failing to prune shouldn't fail the write that triggered it.
+    fn prune_if_needed(&self) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        if self.pruned.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        match self.prune_older_than(ttl) {
+            Ok(summary) => debug!(
+                "pruned {} stale artifacts from fs cache",
+                summary.evicted.len()
+            ),
+            Err(err) => debug!("failed to prune fs cache: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::assert_matches::assert_matches;
+
+    use anyhow::Result;
+    use futures::future::try_join_all;
+    use tempfile::tempdir;
+    use turbopath::AnchoredSystemPath;
+    use turborepo_analytics::start_analytics;
+    use turborepo_api_client::{APIAuth, APIClient};
+    use turborepo_vercel_api_mock::start_test_server;
+
+    use super::*;
+    use crate::test_cases::{get_test_cases, validate_analytics, TestCase};
+
+    #[tokio::test]
+    async fn test_fs_cache() -> Result<()> {
+        let port = port_scanner::request_open_port().unwrap();
+        tokio::spawn(start_test_server(port));
+
+        let test_cases = get_test_cases();
+
+        try_join_all(
+            test_cases
+                .iter()
+                .map(|test_case| round_trip_test(test_case, port)),
+        )
+        .await?;
+
+        validate_analytics(&test_cases, analytics::CacheSource::Local, port).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_corrupt_artifact() -> Result<()> {
+        for remove_corrupt in [false, true] {
+            let repo_root = tempdir()?;
+            let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+            let opts = CacheOpts {
+                fs_cache_remove_corrupt: remove_corrupt,
+                ..Default::default()
+            };
+            let cache = FSCache::new(&opts, repo_root_path, None)?;
+
+            let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+            repo_root_path
+                .resolve(&file)
+                .create_with_contents("In the Mood for Love")?;
+            cache.put(repo_root_path, "the-hash", &[file.clone()], 10)?;
+
+            let archive_path = cache.archive_path("the-hash").unwrap();
+            let mut archive = archive_path.read()?;
+            archive[0] ^= 0xff;
+            archive_path.create_with_contents(archive)?;
+
+            // Corrupt artifacts are misses, and are moved out of the way
+            assert_eq!(cache.fetch(repo_root_path, "the-hash")?, None);
+            assert!(!archive_path.exists());
+            assert!(!cache.metadata_path("the-hash").exists());
+
+            let archive_name = archive_path.as_path().file_name().unwrap();
+            assert_eq!(
+                cache.quarantine_path(archive_name).exists(),
+                !remove_corrupt
+            );
+            let record_path = cache.quarantine_path("the-hash-quarantine.json");
+            assert_eq!(record_path.exists(), !remove_corrupt);
+            if !remove_corrupt {
+                let record: quarantine::QuarantineRecord =
+                    serde_json::from_str(&record_path.read_to_string()?)?;
+                assert!(record.reason.contains("the-hash"));
+            }
+
+            // Running the task again replaces the artifact
+            cache.put(repo_root_path, "the-hash", &[file.clone()], 10)?;
+            assert!(cache.fetch(repo_root_path, "the-hash")?.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verified_archives() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Chungking Express")?;
+        cache.put(repo_root_path, "the-hash", &[file.clone()], 10)?;
+
+        // Entries are kept out of the metadata, which is read on every fetch
+        let metadata_path = cache.metadata_path("the-hash");
+        assert!(!metadata_path.read_to_string()?.contains("\"index\""));
+        assert!(cache.entry_index_path("the-hash").exists());
+        let meta = CacheMetadata::read_with_index(&metadata_path)?;
+        assert!(meta.index.is_some());
+
+        // An archive that hasn't changed since it was stored isn't read again
+        let mut meta = CacheMetadata::read(&metadata_path)?;
+        meta.sha256 = Some("not the checksum".to_string());
+        meta.write(&metadata_path)?;
+        assert!(cache.fetch(repo_root_path, "the-hash")?.is_some());
+
+        // But one that has been rewritten is
+        let archive_path = cache.archive_path("the-hash").unwrap();
+        let archive = archive_path.read()?;
+        archive_path.remove_file()?;
+        archive_path.create_with_contents(archive)?;
+        assert_eq!(cache.fetch(repo_root_path, "the-hash")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_escaping_symlink() -> Result<()> {
+        for restore_mode in [RestoreMode::Extract, RestoreMode::Link] {
+            let repo_root = tempdir()?;
+            let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+            let opts = CacheOpts {
+                fs_cache_restore_mode: restore_mode,
+                ..Default::default()
+            };
+            let cache = FSCache::new(&opts, repo_root_path, None)?;
+
+            let link = AnchoredSystemPathBuf::from_raw("escape")?;
+            repo_root_path
+                .resolve(&link)
+                .symlink_to_file("../Days of Being Wild")?;
+            cache.put(repo_root_path, "the-hash", &[link.clone()], 10)?;
+            repo_root_path.resolve(&link).remove_file()?;
+
+            // Fetched artifacts can't link outside of the repo by default
+            assert_matches!(
+                cache.fetch(repo_root_path, "the-hash"),
+                Err(CacheError::LinkOutsideOfDirectory(..))
+            );
+            assert!(repo_root_path.resolve(&link).symlink_metadata().is_err());
+
+            let opts = CacheOpts {
+                fs_cache_restore_mode: restore_mode,
+                symlink_policy: SymlinkPolicy::Verbatim,
+                ..Default::default()
+            };
+            let cache = FSCache::new(&opts, repo_root_path, None)?;
+            let (_, restored) = cache.fetch(repo_root_path, "the-hash")?.unwrap();
+            assert_eq!(restored, vec![link.clone()]);
+            assert_eq!(
+                repo_root_path.resolve(&link).read_link()?,
+                "../Days of Being Wild"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_artifacts() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+
+        let signed_cache = |key: &[u8]| -> Result<FSCache> {
+            let mut cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+            cache.signer_verifier = Some(ArtifactSignatureAuthenticator::new(
+                b"team".to_vec(),
+                Some(key.to_vec()),
+            ));
+            Ok(cache)
+        };
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Happy Together")?;
+
+        // Artifacts written without signing are rejected once signing is on
+        let unsigned_cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+        unsigned_cache.put(repo_root_path, "unsigned", &[file.clone()], 10)?;
+
+        let cache = signed_cache(b"secret")?;
+        assert_eq!(cache.fetch(repo_root_path, "unsigned")?, None);
+
+        cache.put(repo_root_path, "signed", &[file], 10)?;
+        assert!(cache.fetch(repo_root_path, "signed")?.is_some());
+        // Signed artifacts are still readable without verification
+        assert!(unsigned_cache.fetch(repo_root_path, "signed")?.is_some());
+
+        let other_key_cache = signed_cache(b"another secret")?;
+        assert_eq!(other_key_cache.fetch(repo_root_path, "signed")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_artifacts() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+
+        let encrypted_cache = |key: u8| {
+            FSCache::new(
+                &CacheOpts {
+                    fs_cache_encryption_key: Some(EncryptionKey::new([key; 32])),
+                    // Replaced by extract mode
+                    fs_cache_restore_mode: RestoreMode::Link,
+                    ..Default::default()
+                },
+                repo_root_path,
+                None,
+            )
+        };
+
+        let contents = "Fallen Angels ".repeat(100);
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents(&contents)?;
+
+        let cache = encrypted_cache(1)?;
+        assert_eq!(cache.restore_mode, RestoreMode::Extract);
+        cache.put(repo_root_path, "encrypted", &[file.clone()], 10)?;
+        let archive = cache.archive_path("encrypted").unwrap().read()?;
+        assert!(!archive
+            .windows(14)
+            .any(|window| window == b"Fallen Angels "));
+
+        repo_root_path.resolve(&file).remove_file()?;
+        assert!(cache.fetch(repo_root_path, "encrypted")?.is_some());
+        assert_eq!(repo_root_path.resolve(&file).read_to_string()?, contents);
+        assert!(cache
+            .fetch_filtered(repo_root_path, "encrypted", &["*.txt".to_string()])?
+            .is_some());
+        assert!(cache.verify_all(false, &|_| {})?.issues.is_empty());
+
+        // Artifacts encrypted with another key, or not at all, are misses
+        let unencrypted_cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+        assert_eq!(unencrypted_cache.fetch(repo_root_path, "encrypted")?, None);
+        assert_eq!(
+            encrypted_cache(2)?.fetch(repo_root_path, "encrypted")?,
+            None
+        );
+        unencrypted_cache.put(repo_root_path, "unencrypted", &[file], 10)?;
+        assert_eq!(cache.fetch(repo_root_path, "unencrypted")?, None);
+        // and can only be checked against their checksum
+        assert!(unencrypted_cache
+            .verify_all(false, &|_| {})?
+            .issues
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_algorithms() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        let contents = "Stray Dogs".repeat(1000);
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents(&contents)?;
+
+        for (compression, compression_level) in [
+            (CompressionAlgorithm::Zstd, 19),
+            (CompressionAlgorithm::Lz4, 0),
+            (CompressionAlgorithm::None, 0),
+        ] {
+            let opts = CacheOpts {
+                fs_cache_compression: compression,
+                fs_cache_compression_level: compression_level,
+                ..Default::default()
+            };
+            let cache = FSCache::new(&opts, repo_root_path, None)?;
+            cache.put(repo_root_path, "the-hash", &[file.clone()], 10)?;
+
+            // Only the archive for the current algorithm should exist
+            let archive_path = cache.archive_path("the-hash").unwrap();
+            assert!(archive_path.as_str().ends_with(compression.extension()));
+            assert_eq!(cache.list_artifacts()?[0].archives, vec![archive_path]);
+
+            repo_root_path.resolve(&file).remove_file()?;
+            let (_, files) = cache.fetch(repo_root_path, "the-hash")?.unwrap();
+            assert_eq!(files, vec![file.clone()]);
+            assert_eq!(repo_root_path.resolve(&file).read_to_string()?, contents);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+
+        let files = ["dist", "dist/a.txt", "dist/b.txt"]
+            .into_iter()
+            .map(AnchoredSystemPathBuf::from_raw)
+            .collect::<Result<Vec<_>, _>>()?;
+        repo_root_path.resolve(&files[0]).create_dir_all()?;
+        repo_root_path
+            .resolve(&files[1])
+            .create_with_contents("a".repeat(100))?;
+        repo_root_path
+            .resolve(&files[2])
+            .create_with_contents("b".repeat(50))?;
+
+        let reported = std::sync::Mutex::new(Vec::new());
+        let record = |progress: Progress| reported.lock().unwrap().push(progress);
+
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+        cache.put_with_progress(
+            repo_root_path,
+            "the-hash",
+            &files,
+            10,
+            ArtifactDetails::default(),
+            &record,
+        )?;
+        let put_progress = std::mem::take(&mut *reported.lock().unwrap());
+        assert_eq!(put_progress.len(), 3);
+        assert_eq!(
+            put_progress.last(),
+            Some(&Progress {
+                files: 3,
+                bytes: 150,
+                total_files: Some(3),
+                total_bytes: None,
+            })
+        );
+
+        let done = Progress {
+            files: 3,
+            bytes: 150,
+            total_files: Some(3),
+            total_bytes: Some(150),
+        };
+        for (restore_mode, restore_workers) in [
+            (RestoreMode::Extract, 0),
+            (RestoreMode::Extract, 4),
+            (RestoreMode::Link, 0),
+        ] {
+            let opts = CacheOpts {
+                fs_cache_restore_mode: restore_mode,
+                fs_cache_restore_workers: restore_workers,
+                ..Default::default()
+            };
+            let cache = FSCache::new(&opts, repo_root_path, None)?;
+
+            // With the link mode, the second fetch links an existing extraction
+            for _ in 0..2 {
+                let output = tempdir()?;
+                let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+                cache.fetch_with_progress(output_path, "the-hash", &record)?;
+
+                let fetch_progress = std::mem::take(&mut *reported.lock().unwrap());
+                assert!(fetch_progress
+                    .windows(2)
+                    .all(|pair| pair[0].files < pair[1].files));
+                assert_eq!(fetch_progress.last(), Some(&done));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_filtered() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let files = [
+            "dist",
+            "dist/index.js",
+            "dist/nested",
+            "dist/nested/chunk.js",
+            "build.log",
+        ]
+        .into_iter()
+        .map(AnchoredSystemPathBuf::from_raw)
+        .collect::<Result<Vec<_>, _>>()?;
+        for file in &files {
+            let path = repo_root_path.resolve(file);
+            if file.as_str().contains('.') {
+                path.create_with_contents(file.as_str())?;
+            } else {
+                path.create_dir_all()?;
+            }
+        }
+        cache.put(repo_root_path, "the-hash", &files, 10)?;
+
+        let dist = vec!["dist/**".to_string()];
+        for with_index in [true, false] {
+            if !with_index {
+                // Artifacts written by older versions don't have an index
+                cache.entry_index_path("the-hash").remove_file()?;
+            }
+
+            let output = tempdir()?;
+            let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+            let (_, restored) = cache
+                .fetch_filtered(output_path, "the-hash", &dist)?
+                .unwrap();
+
+            assert_eq!(restored, files[..4]);
+            assert_eq!(
+                output_path.resolve(&files[3]).read_to_string()?,
+                files[3].as_str()
+            );
+            assert!(!output_path.resolve(&files[4]).exists());
+
+            let (_, restored) = cache
+                .fetch_filtered(output_path, "the-hash", &["*.txt".to_string()])?
+                .unwrap();
+            assert!(restored.is_empty());
+        }
+
+        assert_eq!(
+            cache.fetch_filtered(repo_root_path, "missing", &dist)?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_filtered_deduped() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(
+            &CacheOpts {
+                fs_cache_dedupe_files: true,
+                ..CacheOpts::default()
+            },
+            repo_root_path,
+            None,
+        )?;
+
+        let [original, duplicate] = ["apps/web/logo.svg", "apps/docs/logo.svg"]
+            .map(|path| AnchoredSystemPathBuf::from_raw(path).unwrap());
+        for file in [&original, &duplicate] {
+            let path = repo_root_path.resolve(file);
+            path.ensure_dir()?;
+            path.create_with_contents("My Blueberry Nights")?;
+        }
+        cache.put(
+            repo_root_path,
+            "the-hash",
+            &[original.clone(), duplicate.clone()],
+            10,
+        )?;
+
+        // The original is read but not restored
+        let output = tempdir()?;
+        let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+        let (_, restored) = cache
+            .fetch_filtered(output_path, "the-hash", &["apps/docs/**".to_string()])?
+            .unwrap();
+        assert_eq!(restored, vec![duplicate.clone()]);
+        assert_eq!(
+            output_path.resolve(&duplicate).read_to_string()?,
+            "My Blueberry Nights"
+        );
+        assert!(!output_path.resolve(&original).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_policies() -> Result<()> {
+        let unchanged = AnchoredSystemPathBuf::from_raw("unchanged.txt")?;
+        let edited = AnchoredSystemPathBuf::from_raw("edited.txt")?;
+        let missing = AnchoredSystemPathBuf::from_raw("missing.txt")?;
+        let files = vec![unchanged.clone(), edited.clone(), missing.clone()];
+
+        for (policy, restore_mode, workers) in [
+            (OverwritePolicy::Overwrite, RestoreMode::Extract, 0),
+            (OverwritePolicy::SkipExisting, RestoreMode::Extract, 0),
+            (OverwritePolicy::OnlyIfDifferent, RestoreMode::Extract, 0),
+            (OverwritePolicy::OnlyIfDifferent, RestoreMode::Extract, 4),
+            (OverwritePolicy::OnlyIfDifferent, RestoreMode::Link, 0),
+            (OverwritePolicy::FailIfExists, RestoreMode::Extract, 0),
+        ] {
+            let repo_root = tempdir()?;
+            let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+            let cache = FSCache::new(
+                &CacheOpts {
+                    fs_cache_overwrite_policy: policy,
+                    fs_cache_restore_mode: restore_mode,
+                    fs_cache_restore_workers: workers,
+                    ..CacheOpts::default()
+                },
+                repo_root_path,
+                None,
+            )?;
+
+            for file in &files {
+                repo_root_path
+                    .resolve(file)
+                    .create_with_contents("In the Mood for Love")?;
+            }
+            cache.put(repo_root_path, "the-hash", &files, 10)?;
+
+            // Same size as the cached contents, but different
+            repo_root_path
+                .resolve(&edited)
+                .create_with_contents("In the Mood for Lust")?;
+            repo_root_path.resolve(&missing).remove_file()?;
+            let unchanged_modified = repo_root_path
+                .resolve(&unchanged)
+                .symlink_metadata()?
+                .modified()?;
+
+            let result = cache.fetch(repo_root_path, "the-hash");
+            if policy == OverwritePolicy::FailIfExists {
+                assert_matches!(result, Err(CacheError::FileExists(..)));
+                continue;
+            }
+            assert_eq!(result?.unwrap().1, files);
+
+            let read = |file| repo_root_path.resolve(file).read_to_string();
+            assert_eq!(read(&missing)?, "In the Mood for Love");
+            let expected_edited = match policy {
+                OverwritePolicy::SkipExisting => "In the Mood for Lust",
+                _ => "In the Mood for Love",
+            };
+            assert_eq!(read(&edited)?, expected_edited);
+
+            let unchanged_was_written = repo_root_path
+                .resolve(&unchanged)
+                .symlink_metadata()?
+                .modified()?
+                != unchanged_modified;
+            assert_eq!(
+                unchanged_was_written,
+                policy == OverwritePolicy::Overwrite,
+                "{:?}",
+                policy
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifact_details() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(
+            &CacheOpts {
+                turbo_version: Some("1.11.0".to_string()),
+                ..Default::default()
+            },
+            repo_root_path,
+            None,
+        )?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Ashes of Time")?;
+        cache.put_with_details(
+            repo_root_path,
+            "the-hash",
+            &[file.clone()],
+            10,
+            ArtifactDetails {
+                task_id: Some("web#build".to_string()),
+                package_name: Some("web".to_string()),
+                // Overridden by the cache
+                os: Some("plan9".to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        let expected = ArtifactDetails {
+            task_id: Some("web#build".to_string()),
+            package_name: Some("web".to_string()),
+            turbo_version: Some("1.11.0".to_string()),
+            os: Some(std::env::consts::OS.to_string()),
+            arch: Some(std::env::consts::ARCH.to_string()),
+            ..Default::default()
+        };
+        let (hit, details) = cache.exists_with_details("the-hash")?.unwrap();
+        assert_eq!(hit.time_saved, 10);
+        assert_eq!(details, expected);
+
+        let info = cache.inspect("the-hash")?.unwrap();
+        assert_eq!(info.details, expected);
+        assert!(info.created_at.is_some());
+
+        // Metadata written before versioning has no details
+        cache
+            .metadata_path("the-hash")
+            .create_with_contents(r#"{"hash":"the-hash","duration":20}"#)?;
+        let (hit, details) = cache.exists_with_details("the-hash")?.unwrap();
+        assert_eq!(hit.time_saved, 20);
+        assert_eq!(details, ArtifactDetails::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsync() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(
+            &CacheOpts {
+                fs_cache_fsync: true,
+                ..Default::default()
+            },
+            repo_root_path,
+            None,
+        )?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path.resolve(&file).create_with_contents("2046")?;
+        cache.put(repo_root_path, "the-hash", &[file.clone()], 10)?;
+        // Nothing is left behind by the synced metadata write
+        assert_eq!(cache.list_artifacts()?.len(), 1);
+        assert!(cache.fetch(repo_root_path, "the-hash")?.is_some());
+        assert!(sync_path(&cache.cache_directory).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_compatibility() -> Result<()> {
+        let dir = tempdir()?;
+        let dir_path = AbsoluteSystemPath::from_std_path(dir.path())?;
+        let metadata_path = dir_path.join_component("the-hash-meta.json");
+
+        // Metadata from before versioning is migrated when read
+        metadata_path.create_with_contents(r#"{"hash":"the-hash","duration":10}"#)?;
+        let meta = CacheMetadata::read(&metadata_path)?;
+        assert_eq!(meta.version, METADATA_VERSION);
+        assert_eq!(meta.duration, 10);
+        assert!(meta.created_at.is_some());
+
+        // Metadata from a newer version is readable and rewriting it keeps
+        // the fields we don't understand
+        metadata_path.create_with_contents(
+            r#"{"version":99,"hash":"the-hash","task_id":"web#build","from_the_future":[1,2]}"#,
+        )?;
+        let meta = CacheMetadata::read(&metadata_path)?;
+        assert_eq!(meta.version, 99);
+        assert_eq!(meta.duration, 0);
+        assert_eq!(meta.details.task_id.as_deref(), Some("web#build"));
+        meta.write(&metadata_path)?;
+
+        let rewritten: serde_json::Value = serde_json::from_str(&metadata_path.read_to_string()?)?;
+        assert_eq!(rewritten["version"], 99);
+        assert_eq!(rewritten["task_id"], "web#build");
+        assert_eq!(rewritten["from_the_future"], serde_json::json!([1, 2]));
+
+        Ok(())
+    }
+
+    async fn round_trip_test(test_case: &TestCase, port: u16) -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        test_case.initialize(repo_root_path)?;
+
+        let api_client = APIClient::new(format!("http://localhost:{}", port), 200, "2.0.0", true)?;
+        let api_auth = APIAuth {
+            team_id: Some("my-team".to_string()),
+            token: "my-token".to_string(),
+            team_slug: None,
+        };
+        let (analytics_sender, analytics_handle) =
+            start_analytics(api_auth.clone(), api_client.clone());
+
+        let cache = FSCache::new(
+            &CacheOpts::default(),
+            repo_root_path,
+            Some(analytics_sender.clone()),
+        )?;
+
+        let expected_miss = cache.fetch(repo_root_path, test_case.hash)?;
+        assert!(expected_miss.is_none());
+
+        let files: Vec<_> = test_case
+            .files
+            .iter()
+            .map(|f| f.path().to_owned())
+            .collect();
+        cache.put(repo_root_path, test_case.hash, &files, test_case.duration)?;
+
+        let (status, files) = cache.fetch(repo_root_path, test_case.hash)?.unwrap();
+
+        assert_eq!(
+            status,
+            CacheHitMetadata {
+                time_saved: test_case.duration,
+                source: CacheSource::Local
+            }
+        );
+
+        assert_eq!(files.len(), test_case.files.len());
+        for (expected, actual) in test_case.files.iter().zip(files.iter()) {
+            let actual: &AnchoredSystemPath = actual;
+            assert_eq!(expected.path(), actual);
+            let actual_file = repo_root_path.resolve(actual);
+            if let Some(contents) = expected.contents() {
+                assert_eq!(contents, actual_file.read_to_string()?);
+            } else {
+                assert!(actual_file.exists());
+            }
+        }
+
+        analytics_handle.close_with_timeout().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use std::{backtrace::Backtrace, io};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use turbopath::AbsoluteSystemPathBuf;
+
+use super::{ArtifactFiles, FSCache};
+use crate::CacheError;
+
+const QUARANTINE_DIRECTORY: &str = ".quarantine";
+
+// Written next to the quarantined files to record why they were moved there
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuarantineRecord {
+    pub reason: String,
+    pub quarantined_at: DateTime<Utc>,
+}
+
+impl FSCache {
+    pub(crate) fn quarantine_path(&self, file_name: &str) -> AbsoluteSystemPathBuf {
+        self.cache_directory
+            .join_components(&[QUARANTINE_DIRECTORY, file_name])
+    }
+
+    /// Moves the files of an artifact that failed verification out of the
+    /// cache, so that the next `put` replaces it while the old files are
+    /// kept around for inspection. Any earlier quarantine of the same hash is
+    /// overwritten.
+    pub(crate) fn quarantine(
+        &self,
+        artifact: &ArtifactFiles,
+        reason: &str,
+    ) -> Result<(), CacheError> {
+        self.cache_directory
+            .join_component(QUARANTINE_DIRECTORY)
+            .create_dir_all()?;
+
+        for path in artifact.paths() {
+            let file_name = path
+                .as_path()
+                .file_name()
+                .expect("artifact files are always in the cache directory");
+            let quarantined_path = self.quarantine_path(file_name);
+            // Windows can't rename over an existing file
+            if quarantined_path.exists() {
+                quarantined_path.remove_file()?;
+            }
+            match path.rename(&quarantined_path) {
+                Ok(()) => {}
+                // Someone else got there first, which is fine.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let record = QuarantineRecord {
+            reason: reason.to_string(),
+            quarantined_at: Utc::now(),
+        };
+        let record = serde_json::to_string(&record)
+            .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+        self.quarantine_path(&format!("{}-quarantine.json", artifact.hash))
+            .create_with_contents(record)?;
+
+        self.remove_extracted(&artifact.hash)?;
+        self.update_index(|index| {
+            index.remove(&artifact.hash);
+        })?;
+        self.remove_lock_file(&artifact.hash)
+    }
+}
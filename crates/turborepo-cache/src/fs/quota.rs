@@ -0,0 +1,125 @@
+use std::backtrace::Backtrace;
+
+use tracing::debug;
+
+use super::{ArtifactFiles, CacheGcOptions, FSCache};
+use crate::CacheError;
+
+/// What `FSCache::put` does when an artifact takes the cache over its quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// Evict least-recently-used artifacts until the cache fits. The put
+    /// still fails if it can't be made to fit, e.g. because of pinned
+    /// artifacts.
+    #[default]
+    EvictToFit,
+    /// Fail the put with `CacheError::QuotaExceeded`.
+    Reject,
+}
+
+impl FSCache {
+    // Called with `artifact` written and locked, so that gc can't evict it.
+    // An artifact that doesn't fit is removed again.
+    pub(super) fn enforce_quota(
+        &self,
+        artifact: ArtifactFiles,
+        size: u64,
+    ) -> Result<(), CacheError> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+
+        let mut used = self
+            .read_index()?
+            .map_or(size, |index| index.values().map(|record| record.size).sum());
+        if used <= quota {
+            return Ok(());
+        }
+
+        if self.quota_policy == QuotaPolicy::EvictToFit && size <= quota {
+            let summary = self.gc(&CacheGcOptions {
+                max_size_bytes: Some(quota),
+                max_age: None,
+            })?;
+            if summary.bytes_remaining <= quota {
+                return Ok(());
+            }
+            used = summary.bytes_remaining;
+        }
+
+        debug!(
+            "removing {}, the cache would use {} bytes of its {} byte quota",
+            artifact.hash, used, quota
+        );
+        self.evict(&artifact)?;
+        Err(CacheError::QuotaExceeded(
+            artifact.hash,
+            used,
+            quota,
+            Backtrace::capture(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::assert_matches::assert_matches;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        test_cases::{incompressible_bytes, TestCache},
+        CacheOpts,
+    };
+
+    fn hashes(cache: &FSCache) -> Result<Vec<String>> {
+        Ok(cache
+            .list_artifacts()?
+            .into_iter()
+            .map(|artifact| artifact.hash)
+            .collect())
+    }
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let unlimited_cache = TestCache::new(&CacheOpts::default())?;
+        let repo_root_path = unlimited_cache.repo_root();
+        let file = unlimited_cache.write_file("out.txt", "Days of Being Wild")?;
+        unlimited_cache.put(repo_root_path, "aaaa", &[file.clone()], 10)?;
+        let size = unlimited_cache.stats()?.total_bytes;
+
+        let quota_cache = |policy| {
+            unlimited_cache.reopen(&CacheOpts {
+                fs_cache_quota: Some(size * 5 / 2),
+                fs_cache_quota_policy: policy,
+                ..Default::default()
+            })
+        };
+
+        // The least recently used artifact makes room for the new one
+        let cache = quota_cache(QuotaPolicy::EvictToFit)?;
+        cache.put(repo_root_path, "bbbb", &[file.clone()], 10)?;
+        cache.put(repo_root_path, "cccc", &[file.clone()], 10)?;
+        assert_eq!(hashes(&cache)?, vec!["bbbb", "cccc"]);
+
+        let cache = quota_cache(QuotaPolicy::Reject)?;
+        assert_matches!(
+            cache.put(repo_root_path, "dddd", &[file.clone()], 10),
+            Err(CacheError::QuotaExceeded(hash, _, _, _)) if hash == "dddd"
+        );
+        assert_eq!(hashes(&cache)?, vec!["bbbb", "cccc"]);
+
+        // An artifact larger than the whole quota is rejected without
+        // evicting anything
+        unlimited_cache.write_file("out.txt", incompressible_bytes(size as usize * 3))?;
+        let cache = quota_cache(QuotaPolicy::EvictToFit)?;
+        assert_matches!(
+            cache.put(repo_root_path, "eeee", &[file], 10),
+            Err(CacheError::QuotaExceeded(..))
+        );
+        assert_eq!(hashes(&cache)?, vec!["bbbb", "cccc"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::{backtrace::Backtrace, collections::BTreeMap};
+
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use turbopath::AbsoluteSystemPathBuf;
+
+use super::FSCache;
+use crate::CacheError;
+
+const SAVINGS_FILE: &str = "savings.json";
+
+/// What hits from a cache have saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Savings {
+    pub hits: u64,
+    // Sum of the `time_saved` of every hit, in milliseconds
+    pub time_saved: u64,
+    pub bytes_restored: u64,
+}
+
+impl Savings {
+    fn add_hit(&mut self, time_saved: u64, bytes_restored: u64) {
+        self.hits += 1;
+        self.time_saved += time_saved;
+        self.bytes_restored += bytes_restored;
+    }
+}
+
+/// The savings of every hit since the cache directory was created, both in
+/// total and broken down by week.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavingsSummary {
+    pub total: Savings,
+    // Keyed by the Monday, in UTC, that each week starts on
+    pub weekly: BTreeMap<NaiveDate, Savings>,
+}
+
+impl FSCache {
+    fn savings_path(&self) -> AbsoluteSystemPathBuf {
+        self.cache_directory.join_component(SAVINGS_FILE)
+    }
+
+    /// Returns the savings recorded in this cache directory.
+    pub fn savings_summary(&self) -> Result<SavingsSummary, CacheError> {
+        let savings_path = self.savings_path();
+        if !savings_path.exists() {
+            return Ok(SavingsSummary::default());
+        }
+
+        serde_json::from_str(&savings_path.read_to_string()?)
+            .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))
+    }
+
+    // Adds a hit to the savings summary. The summary is only a report, so an
+    // unreadable one is started over and failing to update it shouldn't fail
+    // the hit.
+    pub(crate) fn record_savings(&self, time_saved: u64, bytes_restored: u64) {
+        if let Err(e) = self.update_savings(time_saved, bytes_restored) {
+            debug!("failed to update savings summary: {}", e);
+        }
+    }
+
+    fn update_savings(&self, time_saved: u64, bytes_restored: u64) -> Result<(), CacheError> {
+        let _lock = self.lock_savings()?;
+
+        let mut summary = self.savings_summary().unwrap_or_else(|e| {
+            debug!("replacing unreadable savings summary: {}", e);
+            SavingsSummary::default()
+        });
+        let today = Utc::now().date_naive();
+        let week = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+        summary.total.add_hit(time_saved, bytes_restored);
+        summary
+            .weekly
+            .entry(week)
+            .or_default()
+            .add_hit(time_saved, bytes_restored);
+
+        let contents = serde_json::to_string(&summary)
+            .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+        let temp_path = self.cache_directory.join_component(&format!(
+            ".{}.{}.tmp",
+            SAVINGS_FILE,
+            std::process::id()
+        ));
+        temp_path.create_with_contents(contents)?;
+        temp_path.rename(&self.savings_path())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::CacheOpts;
+
+    #[test]
+    fn test_savings_summary() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        assert_eq!(cache.savings_summary()?, SavingsSummary::default());
+
+        let contents = "In the Mood for Love";
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents(contents)?;
+        cache.put(repo_root_path, "the-hash", &[file], 250)?;
+        cache.fetch(repo_root_path, "the-hash")?;
+        cache.fetch(repo_root_path, "the-hash")?;
+        cache.fetch_filtered(repo_root_path, "the-hash", &["nothing".to_string()])?;
+        // Misses don't save anything
+        cache.fetch(repo_root_path, "missing")?;
+
+        let summary = cache.savings_summary()?;
+        let expected = Savings {
+            hits: 3,
+            time_saved: 750,
+            // Nothing matched the filtered fetch
+            bytes_restored: 2 * contents.len() as u64,
+        };
+        assert_eq!(summary.total, expected);
+        assert_eq!(summary.weekly.len(), 1);
+        let (week, weekly) = summary.weekly.iter().next().unwrap();
+        assert_eq!(week.weekday(), chrono::Weekday::Mon);
+        assert_eq!(*weekly, expected);
+
+        // An unreadable summary is started over
+        cache.savings_path().create_with_contents("not json")?;
+        assert!(cache.savings_summary().is_err());
+        cache.fetch(repo_root_path, "the-hash")?;
+        assert_eq!(cache.savings_summary()?.total.hits, 1);
+
+        Ok(())
+    }
+}
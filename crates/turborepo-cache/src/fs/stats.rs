@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+
+use super::{CacheMetadata, FSCache};
+use crate::CacheError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactStats {
+    pub hash: String,
+    // Size of the archive and its metadata file
+    pub size: u64,
+    pub compressed: bool,
+    pub created_at: DateTime<Utc>,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub artifact_count: usize,
+    pub total_bytes: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    pub artifacts: Vec<ArtifactStats>,
+    // Hashes of the artifacts exempt from gc
+    pub pinned: Vec<String>,
+}
+
+impl FSCache {
+    /// Summarizes the contents of the cache directory. Metadata files without
+    /// a matching archive aren't counted as artifacts.
+    pub fn stats(&self) -> Result<CacheStats, CacheError> {
+        let mut stats = CacheStats::default();
+
+        for artifact in self.list_artifacts()? {
+            let Some(archive) = artifact.archives.first() else {
+                continue;
+            };
+            let compressed = archive.extension() != Some("tar");
+
+            let mut size = 0;
+            for archive in &artifact.archives {
+                let archive_size = archive.symlink_metadata()?.len();
+                if archive.extension() != Some("tar") {
+                    stats.compressed_bytes += archive_size;
+                } else {
+                    stats.uncompressed_bytes += archive_size;
+                }
+                size += archive_size;
+            }
+
+            let meta = artifact
+                .metadata
+                .as_ref()
+                .and_then(|path| CacheMetadata::read(path).ok());
+            for path in artifact.metadata.iter().chain(&artifact.entry_index) {
+                size += path.symlink_metadata()?.len();
+            }
+
+            // Fall back to the archive's mtime for metadata written by older
+            // versions.
+            let pinned = meta.as_ref().map_or(false, |meta| meta.pinned);
+            let created_at = match meta.and_then(|meta| meta.created_at) {
+                Some(created_at) => created_at,
+                None => archive.symlink_metadata()?.modified()?.into(),
+            };
+
+            stats.oldest = Some(stats.oldest.map_or(created_at, |t| t.min(created_at)));
+            stats.newest = Some(stats.newest.map_or(created_at, |t| t.max(created_at)));
+            stats.total_bytes += size;
+            if pinned {
+                stats.pinned.push(artifact.hash.clone());
+            }
+            stats.artifacts.push(ArtifactStats {
+                hash: artifact.hash,
+                size,
+                compressed,
+                created_at,
+                pinned,
+            });
+        }
+
+        stats.artifact_count = stats.artifacts.len();
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::CacheOpts;
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        assert_eq!(cache.stats()?, CacheStats::default());
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Yi Yi")?;
+        cache.put(repo_root_path, "first", &[file.clone()], 10)?;
+        cache.put(repo_root_path, "second", &[file], 20)?;
+
+        // An orphaned metadata file shouldn't count as an artifact
+        cache
+            .metadata_path("orphan")
+            .create_with_contents(r#"{"hash":"orphan","duration":0}"#)?;
+
+        let stats = cache.stats()?;
+        assert_eq!(stats.artifact_count, 2);
+        assert_eq!(
+            stats
+                .artifacts
+                .iter()
+                .map(|artifact| artifact.hash.as_str())
+                .collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        assert!(stats.artifacts.iter().all(|artifact| artifact.compressed));
+        assert_eq!(stats.uncompressed_bytes, 0);
+        assert_eq!(
+            stats.total_bytes,
+            stats
+                .artifacts
+                .iter()
+                .map(|artifact| artifact.size)
+                .sum::<u64>()
+        );
+        assert!(stats.compressed_bytes < stats.total_bytes);
+        assert!(stats.oldest <= stats.newest);
+
+        Ok(())
+    }
+}
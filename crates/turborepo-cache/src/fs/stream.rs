@@ -0,0 +1,91 @@
+use std::io::Write;
+
+use super::{CacheMetadata, FSCache};
+use crate::CacheError;
+
+impl FSCache {
+    /// Writes an artifact to `out` as an uncompressed tar, e.g. for `tar x`
+    /// to extract, without restoring any files. Returns the number of bytes
+    /// written, or `None` on a miss. The archive is verified first, like on
+    /// a fetch, so that nothing is written for a corrupt artifact.
+    pub fn stream_tar(&self, hash: &str, out: &mut impl Write) -> Result<Option<u64>, CacheError> {
+        // Wait for any in-progress write of this artifact to finish
+        let (Some(lock), Some(cache_path)) =
+            (self.lock_shared_if_cached(hash)?, self.archive_path(hash))
+        else {
+            return Ok(None);
+        };
+        let meta = CacheMetadata::read(&self.metadata_path(hash))?;
+        if !self.can_restore(hash, &meta) {
+            return Ok(None);
+        }
+        let Some(_lock) = self.verify_or_quarantine(hash, lock, &cache_path, &meta)? else {
+            return Ok(None);
+        };
+
+        let written = self.open_archive(&cache_path, &meta, 0)?.write_tar(out)?;
+
+        Ok(Some(written))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::{fs::ArtifactDetails, CacheOpts};
+
+    #[test]
+    fn test_stream_tar() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let files = ["dist", "dist/index.js", "dist/latest"]
+            .into_iter()
+            .map(AnchoredSystemPathBuf::from_raw)
+            .collect::<Result<Vec<_>, _>>()?;
+        repo_root_path.resolve(&files[0]).create_dir_all()?;
+        repo_root_path
+            .resolve(&files[1])
+            .create_with_contents("In the Mood for Love")?;
+        repo_root_path
+            .resolve(&files[2])
+            .symlink_to_file("index.js")?;
+        cache.put_with_logs(
+            repo_root_path,
+            "the-hash",
+            &files,
+            10,
+            ArtifactDetails::default(),
+            b"building...",
+        )?;
+
+        let mut tar = Vec::new();
+        let written = cache.stream_tar("the-hash", &mut tar)?;
+        assert_eq!(written, Some(tar.len() as u64));
+
+        // Extracted by something other than the cache, without the logs
+        let output = tempdir()?;
+        let output_path = AbsoluteSystemPath::from_std_path(output.path())?;
+        tar::Archive::new(tar.as_slice()).unpack(output_path)?;
+        let mut extracted = output_path
+            .as_std_path()
+            .read_dir()?
+            .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        extracted.sort();
+        assert_eq!(extracted, vec!["dist"]);
+        assert_eq!(
+            output_path.resolve(&files[2]).read_to_string()?,
+            "In the Mood for Love"
+        );
+
+        assert_eq!(cache.stream_tar("missing", &mut Vec::new())?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::{backtrace::Backtrace, collections::BTreeMap};
+
+use tracing::debug;
+
+use super::{CacheMetadata, FSCache, GcSummary};
+use crate::{remote::SIGNATURE_TAG, CacheError};
+
+/// Key/value labels attached to an artifact when it's written, e.g. the
+/// branch or pipeline that produced it. Stored in the artifact's metadata
+/// locally, and as metadata headers by remote backends that support it.
+pub type ArtifactTags = BTreeMap<String, String>;
+
+// Tags end up in header names, so keys are limited to what every backend
+// accepts in one. `duration` and the signature's `tag` are stored alongside
+// them, so they're reserved.
+pub(crate) fn validate_tags(tags: &ArtifactTags) -> Result<(), CacheError> {
+    for (key, value) in tags {
+        let valid_key = !key.is_empty()
+            && key != "duration"
+            && key != SIGNATURE_TAG
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid_key {
+            return Err(CacheError::InvalidArtifactTag(
+                format!("{:?} is not a valid tag name", key),
+                Backtrace::capture(),
+            ));
+        }
+        if !value.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+            return Err(CacheError::InvalidArtifactTag(
+                format!("value of {} must be printable ASCII", key),
+                Backtrace::capture(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// A condition on an artifact's tags. Artifacts match a list of filters if
+/// they match all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagFilter {
+    /// The tag is set to this value.
+    Equals(String, String),
+    /// The tag is set, to a value not in this list. Artifacts without the tag
+    /// don't match, so e.g. untagged artifacts aren't taken for ones from a
+    /// deleted branch.
+    NotIn(String, Vec<String>),
+    /// The tag is set to any value.
+    Exists(String),
+}
+
+impl TagFilter {
+    pub fn matches(&self, tags: &ArtifactTags) -> bool {
+        match self {
+            TagFilter::Equals(key, value) => tags.get(key) == Some(value),
+            TagFilter::NotIn(key, values) => {
+                tags.get(key).map_or(false, |value| !values.contains(value))
+            }
+            TagFilter::Exists(key) => tags.contains_key(key),
+        }
+    }
+}
+
+impl FSCache {
+    /// Returns the hashes of the artifacts whose tags match every filter.
+    pub fn find_tagged(&self, filters: &[TagFilter]) -> Result<Vec<String>, CacheError> {
+        let mut hashes = Vec::new();
+        for artifact in self.list_artifacts()? {
+            if artifact.archives.is_empty() {
+                continue;
+            }
+            let Some(meta) = artifact
+                .metadata
+                .as_ref()
+                .and_then(|path| CacheMetadata::read(path).ok())
+            else {
+                continue;
+            };
+            if filters
+                .iter()
+                .all(|filter| filter.matches(&meta.details.tags))
+            {
+                hashes.push(artifact.hash);
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    /// Evicts the artifacts whose tags match every filter, e.g. everything
+    /// built from a branch that's since been deleted. Like `gc`, pinned
+    /// artifacts and ones in use by another process are left alone.
+    pub fn evict_tagged(&self, filters: &[TagFilter]) -> Result<GcSummary, CacheError> {
+        let mut summary = GcSummary::default();
+        for artifact in self.list_artifacts()? {
+            let size = artifact
+                .paths()
+                .filter_map(|path| path.symlink_metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum::<u64>();
+            let meta = artifact
+                .metadata
+                .as_ref()
+                .and_then(|path| CacheMetadata::read(path).ok());
+            let matches = meta.as_ref().map_or(false, |meta| {
+                !meta.pinned
+                    && filters
+                        .iter()
+                        .all(|filter| filter.matches(&meta.details.tags))
+            });
+            if !matches {
+                summary.bytes_remaining += size;
+                continue;
+            }
+
+            let Some(_lock) = self.try_lock_exclusive(&artifact.hash)? else {
+                debug!("skipping eviction of {}, it is in use", artifact.hash);
+                summary.bytes_remaining += size;
+                continue;
+            };
+
+            debug!("evicting {} from fs cache by tag", artifact.hash);
+            self.evict(&artifact)?;
+            summary.bytes_freed += size;
+            summary.evicted.push(artifact.hash);
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+    use super::*;
+    use crate::{fs::ArtifactDetails, CacheOpts};
+
+    fn tags(pairs: &[(&str, &str)]) -> ArtifactTags {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_tags() {
+        assert!(validate_tags(&tags(&[("branch", "feature/in the mood")])).is_ok());
+        assert!(validate_tags(&tags(&[("Branch", "main")])).is_err());
+        assert!(validate_tags(&tags(&[("", "main")])).is_err());
+        assert!(validate_tags(&tags(&[("duration", "10")])).is_err());
+        assert!(validate_tags(&tags(&[("branch", "main\n")])).is_err());
+    }
+
+    #[test]
+    fn test_tagged_artifacts() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Happy Together")?;
+        for (hash, artifact_tags) in [
+            (
+                "main-linux",
+                tags(&[("branch", "main"), ("platform", "linux")]),
+            ),
+            (
+                "main-macos",
+                tags(&[("branch", "main"), ("platform", "macos")]),
+            ),
+            ("deleted", tags(&[("branch", "deleted-branch")])),
+            ("pinned", tags(&[("branch", "deleted-branch")])),
+            ("untagged", tags(&[])),
+        ] {
+            cache.put_with_details(
+                repo_root_path,
+                hash,
+                &[file.clone()],
+                10,
+                ArtifactDetails {
+                    tags: artifact_tags,
+                    ..Default::default()
+                },
+            )?;
+        }
+        cache.pin("pinned")?;
+
+        assert_eq!(
+            cache.find_tagged(&[TagFilter::Equals("branch".into(), "main".into())])?,
+            vec!["main-linux", "main-macos"]
+        );
+        assert_eq!(
+            cache.find_tagged(&[
+                TagFilter::Equals("branch".into(), "main".into()),
+                TagFilter::Equals("platform".into(), "macos".into()),
+            ])?,
+            vec!["main-macos"]
+        );
+        assert_eq!(
+            cache.find_tagged(&[TagFilter::Exists("platform".into())])?,
+            vec!["main-linux", "main-macos"]
+        );
+
+        // Everything not from a live branch goes, except what's pinned or
+        // untagged
+        let summary =
+            cache.evict_tagged(&[TagFilter::NotIn("branch".into(), vec!["main".into()])])?;
+        assert_eq!(summary.evicted, vec!["deleted"]);
+        assert!(summary.bytes_freed > 0);
+        assert!(cache.exists("deleted")?.is_none());
+        for hash in ["main-linux", "main-macos", "pinned", "untagged"] {
+            assert!(cache.exists(hash)?.is_some());
+        }
+
+        Ok(())
+    }
+}
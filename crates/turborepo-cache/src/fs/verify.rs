@@ -0,0 +1,238 @@
+use tracing::debug;
+use turbopath::AbsoluteSystemPath;
+
+use super::{ArtifactFiles, CacheMetadata, FSCache};
+use crate::{
+    cache_archive::{EncryptionKey, Progress},
+    CacheError,
+};
+
+/// What `FSCache::verify_all` found wrong with an artifact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyProblem {
+    /// The archive doesn't match the checksum or signature in its metadata.
+    Corrupt(String),
+    /// The archive can't be decompressed or isn't a valid tar.
+    Unreadable(String),
+    /// The metadata file can't be parsed.
+    InvalidMetadata(String),
+    /// An archive without a metadata file.
+    MissingMetadata,
+    /// A metadata file without an archive.
+    MissingArchive,
+    /// A delta whose base artifact is no longer in the cache.
+    MissingDeltaBase(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyIssue {
+    pub hash: String,
+    pub problem: VerifyProblem,
+    // Whether the artifact's files were deleted because of the problem
+    pub removed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifySummary {
+    pub checked: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl FSCache {
+    /// Checks that every artifact in the cache can be read and matches its
+    /// metadata, and finds archives and metadata files that are missing their
+    /// counterpart. If `remove` is set, the files of every artifact with a
+    /// problem are deleted. `progress` is called after each artifact, where
+    /// each artifact counts as a file and `bytes` counts archive sizes.
+    pub fn verify_all(
+        &self,
+        remove: bool,
+        progress: &dyn Fn(Progress),
+    ) -> Result<VerifySummary, CacheError> {
+        let artifacts = self.list_artifacts()?;
+        let sizes = artifacts
+            .iter()
+            .map(|artifact| {
+                artifact
+                    .archives
+                    .iter()
+                    .map(|path| Ok(path.symlink_metadata()?.len()))
+                    .sum::<Result<u64, CacheError>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut current = Progress {
+            total_files: Some(artifacts.len() as u64),
+            total_bytes: Some(sizes.iter().sum()),
+            ..Progress::default()
+        };
+
+        let mut summary = VerifySummary::default();
+        for (artifact, size) in artifacts.into_iter().zip(sizes) {
+            if let Some(problem) = self.verify_artifact(&artifact)? {
+                let removed = remove && self.remove_unverified(&artifact)?;
+                summary.issues.push(VerifyIssue {
+                    hash: artifact.hash,
+                    problem,
+                    removed,
+                });
+            }
+            summary.checked += 1;
+
+            current.files += 1;
+            current.bytes += size;
+            progress(current);
+        }
+
+        Ok(summary)
+    }
+
+    fn verify_artifact(
+        &self,
+        artifact: &ArtifactFiles,
+    ) -> Result<Option<VerifyProblem>, CacheError> {
+        // Wait for any in-progress write of this artifact to finish
+        let _lock = self.lock_shared(&artifact.hash)?;
+
+        let Some(metadata_path) = &artifact.metadata else {
+            return Ok(Some(VerifyProblem::MissingMetadata));
+        };
+        if artifact.archives.is_empty() {
+            return Ok(Some(VerifyProblem::MissingArchive));
+        }
+        let meta = match CacheMetadata::read(metadata_path) {
+            Ok(meta) => meta,
+            Err(e) => return Ok(Some(VerifyProblem::InvalidMetadata(e.to_string()))),
+        };
+        if !self.has_delta_base(&meta) {
+            let base = meta.delta_base.unwrap_or_default();
+            return Ok(Some(VerifyProblem::MissingDeltaBase(base)));
+        }
+
+        // Archives encrypted with a different key can only be checked against
+        // their checksum
+        let readable =
+            meta.encryption_key_id == self.encryption_key.as_ref().map(EncryptionKey::id);
+        for archive_path in &artifact.archives {
+            if let Err(e) = self.verify_archive(&artifact.hash, archive_path, &meta) {
+                return Ok(Some(VerifyProblem::Corrupt(e.to_string())));
+            }
+            if !readable {
+                continue;
+            }
+            if let Err(e) = self.read_archive(archive_path, &meta) {
+                return Ok(Some(VerifyProblem::Unreadable(e.to_string())));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Reads every entry of the archive, which decompresses all of it
+    fn read_archive(
+        &self,
+        archive_path: &AbsoluteSystemPath,
+        meta: &CacheMetadata,
+    ) -> Result<(), CacheError> {
+        self.open_archive(&archive_path.to_owned(), meta, 0)?
+            .entries()?;
+        Ok(())
+    }
+
+    // Returns whether the artifact was removed. Artifacts that are in use
+    // are left alone.
+    fn remove_unverified(&self, artifact: &ArtifactFiles) -> Result<bool, CacheError> {
+        let Some(_lock) = self.try_lock_exclusive(&artifact.hash)? else {
+            debug!("not removing {}, it is in use", artifact.hash);
+            return Ok(false);
+        };
+
+        self.evict(artifact)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{assert_matches::assert_matches, cell::RefCell};
+
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use turbopath::AnchoredSystemPathBuf;
+
+    use super::*;
+    use crate::CacheOpts;
+
+    #[test]
+    fn test_verify_all() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let cache = FSCache::new(&CacheOpts::default(), repo_root_path, None)?;
+
+        for hash in [
+            "intact",
+            "corrupt",
+            "unchecked",
+            "orphaned-archive",
+            "orphaned-meta",
+        ] {
+            let file = AnchoredSystemPathBuf::from_raw(format!("{}.txt", hash))?;
+            repo_root_path
+                .resolve(&file)
+                .create_with_contents(hash.repeat(100))?;
+            cache.put(repo_root_path, hash, &[file], 0)?;
+        }
+
+        let corrupt_path = cache.archive_path("corrupt").unwrap();
+        let mut contents = corrupt_path.read()?;
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        corrupt_path.create_with_contents(contents)?;
+        // Without a checksum, the archive has to be read to find that it's
+        // unreadable
+        let metadata_path = cache.metadata_path("unchecked");
+        let mut meta = CacheMetadata::read(&metadata_path)?;
+        meta.sha256 = None;
+        meta.write(&metadata_path)?;
+        cache
+            .archive_path("unchecked")
+            .unwrap()
+            .create_with_contents("not an archive")?;
+        cache.metadata_path("orphaned-archive").remove_file()?;
+        cache.archive_path("orphaned-meta").unwrap().remove_file()?;
+
+        let reported = RefCell::new(Vec::new());
+        let summary = cache.verify_all(false, &|progress| reported.borrow_mut().push(progress))?;
+        assert_eq!(summary.checked, 5);
+        let problems = summary
+            .issues
+            .iter()
+            .map(|issue| (issue.hash.as_str(), &issue.problem, issue.removed))
+            .collect::<Vec<_>>();
+        assert_matches!(
+            problems[..],
+            [
+                ("corrupt", VerifyProblem::Corrupt(_), false),
+                ("orphaned-archive", VerifyProblem::MissingMetadata, false),
+                ("orphaned-meta", VerifyProblem::MissingArchive, false),
+                ("unchecked", VerifyProblem::Unreadable(_), false),
+            ]
+        );
+        let reported = reported.into_inner();
+        assert_eq!(reported.len(), 5);
+        assert_eq!(reported[4].files, 5);
+        assert_eq!(reported[4].total_files, Some(5));
+        assert_eq!(reported[4].bytes, reported[4].total_bytes.unwrap());
+
+        // Nothing was removed, so removing finds the same problems
+        let summary = cache.verify_all(true, &|_| {})?;
+        assert_eq!(summary.issues.len(), 4);
+        assert!(summary.issues.iter().all(|issue| issue.removed));
+
+        let summary = cache.verify_all(false, &|_| {})?;
+        assert_eq!(summary.checked, 1);
+        assert!(summary.issues.is_empty());
+        assert!(cache.fetch(repo_root_path, "intact")?.is_some());
+
+        Ok(())
+    }
+}
@@ -1,5 +1,13 @@
-use std::{backtrace::Backtrace, io::Write};
+use std::{
+    backtrace::Backtrace,
+    io::{BufWriter, Write},
+    time::Instant,
+};
 
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use reqwest::StatusCode;
+use tracing::debug;
 use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
 use turborepo_analytics::AnalyticsSender;
 use turborepo_api_client::{
@@ -7,7 +15,9 @@ use turborepo_api_client::{
 };
 
 use crate::{
-    cache_archive::{CacheReader, CacheWriter},
+    cache_archive::{CacheWriter, SymlinkPolicy},
+    events::EventReporter,
+    remote::{DownloadedArchive, RemoteArtifactMetadata},
     signature_authentication::ArtifactSignatureAuthenticator,
     CacheError, CacheHitMetadata, CacheOpts, CacheSource,
 };
@@ -18,6 +28,8 @@ pub struct HTTPCache {
     repo_root: AbsoluteSystemPathBuf,
     api_auth: APIAuth,
     analytics_recorder: Option<AnalyticsSender>,
+    events: EventReporter,
+    symlink_policy: SymlinkPolicy,
 }
 
 impl HTTPCache {
@@ -41,6 +53,7 @@ impl HTTPCache {
                     .as_bytes()
                     .to_vec(),
                 secret_key_override: None,
+                previous_keys_override: None,
             })
         } else {
             None
@@ -52,6 +65,8 @@ impl HTTPCache {
             repo_root,
             api_auth,
             analytics_recorder,
+            events: EventReporter::new(opts, CacheSource::Remote),
+            symlink_policy: opts.symlink_policy,
         }
     }
 
@@ -62,19 +77,51 @@ impl HTTPCache {
         files: &[AnchoredSystemPathBuf],
         duration: u64,
     ) -> Result<(), CacheError> {
-        let mut artifact_body = Vec::new();
-        self.write(&mut artifact_body, anchor, files).await?;
+        let start = Instant::now();
+        let result = self.upload(anchor, hash, files, duration).await;
+        let bytes = *result.as_ref().unwrap_or(&0);
+        let result = result.map(|_| ());
+        self.events.put(hash, start, &result, || bytes);
+        result
+    }
+
+    // Returns the size of the uploaded artifact
+    async fn upload(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+    ) -> Result<u64, CacheError> {
+        // The artifact is written to a temporary file rather than memory, so
+        // that large outputs can be signed and uploaded without holding the
+        // whole archive at once.
+        let artifact_file = tempfile::NamedTempFile::new()?;
+        let artifact_path = AbsoluteSystemPath::from_std_path(artifact_file.path())?;
+        self.write(BufWriter::new(artifact_file.as_file()), anchor, files)
+            .await?;
+        self.upload_archive(hash, artifact_path, duration).await?;
+
+        Ok(artifact_path.symlink_metadata()?.len())
+    }
 
+    // Signs and uploads an archive that's already been written
+    pub(crate) async fn upload_archive(
+        &self,
+        hash: &str,
+        artifact_path: &AbsoluteSystemPath,
+        duration: u64,
+    ) -> Result<(), CacheError> {
         let tag = self
             .signer_verifier
             .as_ref()
-            .map(|signer| signer.generate_tag(hash.as_bytes(), &artifact_body))
+            .map(|signer| signer.generate_tag_from_reader(hash.as_bytes(), artifact_path.open()?))
             .transpose()?;
 
         self.client
             .put_artifact(
                 hash,
-                &artifact_body,
+                artifact_path,
                 duration,
                 tag.as_deref(),
                 &self.api_auth.token,
@@ -95,7 +142,7 @@ impl HTTPCache {
             cache_archive.add_file(anchor, file)?;
         }
 
-        Ok(())
+        cache_archive.finish()
     }
 
     pub async fn exists(&self, hash: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
@@ -120,6 +167,25 @@ impl HTTPCache {
         }))
     }
 
+    /// Returns what the API knows about the artifact for `hash` without
+    /// downloading it.
+    pub async fn metadata(&self, hash: &str) -> Result<Option<RemoteArtifactMetadata>, CacheError> {
+        let Some(response) = self
+            .client
+            .artifact_exists(
+                hash,
+                &self.api_auth.token,
+                self.api_auth.team_id.as_deref(),
+                self.api_auth.team_slug.as_deref(),
+            )
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        RemoteArtifactMetadata::from_headers(response.headers(), "x-artifact-").map(Some)
+    }
+
     fn get_duration_from_response(response: &Response) -> Result<u64, CacheError> {
         if let Some(duration_value) = response.headers().get("x-artifact-duration") {
             let duration = duration_value
@@ -152,6 +218,61 @@ impl HTTPCache {
         &self,
         hash: &str,
     ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        let hit = self.fetch_with_archive(hash).await?;
+        Ok(hit.map(|(hit, files, _)| (hit, files)))
+    }
+
+    /// Like `fetch`, but also returns the downloaded archive.
+    pub(crate) async fn fetch_with_archive(
+        &self,
+        hash: &str,
+    ) -> Result<
+        Option<(
+            CacheHitMetadata,
+            Vec<AnchoredSystemPathBuf>,
+            DownloadedArchive,
+        )>,
+        CacheError,
+    > {
+        let start = Instant::now();
+        let result = self.download(hash).await;
+        let bytes = match &result {
+            Ok(Some((_, _, archive))) => archive.len().unwrap_or_default(),
+            _ => 0,
+        };
+        self.events.fetch(hash, start, &result, || bytes);
+        result
+    }
+
+    async fn download(
+        &self,
+        hash: &str,
+    ) -> Result<
+        Option<(
+            CacheHitMetadata,
+            Vec<AnchoredSystemPathBuf>,
+            DownloadedArchive,
+        )>,
+        CacheError,
+    > {
+        let Some(archive) = self.fetch_archive(hash).await? else {
+            return Ok(None);
+        };
+        let files = archive.restore(&self.repo_root, self.symlink_policy)?;
+        let hit = CacheHitMetadata {
+            source: CacheSource::Remote,
+            time_saved: archive.duration,
+        };
+
+        Ok(Some((hit, files, archive)))
+    }
+
+    /// Downloads the archive for `hash` and checks its signature without
+    /// restoring it.
+    pub(crate) async fn fetch_archive(
+        &self,
+        hash: &str,
+    ) -> Result<Option<DownloadedArchive>, CacheError> {
         let Some(response) = self
             .client
             .fetch_artifact(
@@ -179,12 +300,7 @@ impl HTTPCache {
                 .map_err(|_| CacheError::InvalidTag(Backtrace::capture()))?
                 .to_string();
 
-            let body = response.bytes().await.map_err(|e| {
-                CacheError::ApiClientError(
-                    Box::new(turborepo_api_client::Error::ReqwestError(e)),
-                    Backtrace::capture(),
-                )
-            })?;
+            let body = self.read_body(hash, response).await?;
             let is_valid = signer_verifier.validate(hash.as_bytes(), &body, &expected_tag)?;
 
             if !is_valid {
@@ -193,43 +309,103 @@ impl HTTPCache {
 
             body
         } else {
-            response.bytes().await.map_err(|e| {
-                CacheError::ApiClientError(
-                    Box::new(turborepo_api_client::Error::ReqwestError(e)),
-                    Backtrace::capture(),
-                )
-            })?
+            self.read_body(hash, response).await?
         };
 
-        let files = Self::restore_tar(&self.repo_root, &body)?;
-
         self.log_fetch(analytics::CacheEvent::Hit, hash, duration);
-        Ok(Some((
-            CacheHitMetadata {
-                source: CacheSource::Remote,
-                time_saved: duration,
-            },
-            files,
-        )))
+        Ok(Some(DownloadedArchive::from_bytes(duration, body)))
     }
 
-    pub(crate) fn restore_tar(
-        root: &AbsoluteSystemPath,
-        body: &[u8],
-    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
-        let mut cache_reader = CacheReader::from_reader(body, true)?;
-        cache_reader.restore(root)
+    // Reads the body of an artifact download. A transfer that's cut off is
+    // resumed from where it stopped with a ranged request, as allowed by the
+    // client's retry policy. Reading is paced to the client's bandwidth limits.
+    async fn read_body(&self, hash: &str, mut response: Response) -> Result<Bytes, CacheError> {
+        let retry_policy = *self.client.retry_policy();
+        let transfer = self.client.bandwidth_limiter().download();
+        let mut body = BytesMut::new();
+        let mut attempt = 1;
+        loop {
+            let mut stream = response.bytes_stream();
+            let error = loop {
+                match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        transfer.consume(chunk.len()).await;
+                        body.extend_from_slice(&chunk);
+                    }
+                    Some(Err(e)) => break e,
+                    None => return Ok(body.freeze()),
+                }
+            };
+            if !retry_policy.can_retry(attempt) || !retry_policy.should_retry_error(&error) {
+                return Err(CacheError::ApiClientError(
+                    Box::new(turborepo_api_client::Error::ReqwestError(error)),
+                    Backtrace::capture(),
+                ));
+            }
+
+            tokio::time::sleep(retry_policy.backoff(attempt)).await;
+            attempt += 1;
+            debug!(
+                "resuming download of {} after {} bytes: {}",
+                hash,
+                body.len(),
+                error
+            );
+            response = self
+                .client
+                .fetch_artifact_range(
+                    hash,
+                    &self.api_auth.token,
+                    self.api_auth.team_id.as_deref(),
+                    self.api_auth.team_slug.as_deref(),
+                    body.len() as u64,
+                )
+                .await?
+                .ok_or_else(|| {
+                    CacheError::ApiClientError(
+                        Box::new(turborepo_api_client::Error::CacheMiss),
+                        Backtrace::capture(),
+                    )
+                })?;
+            match Self::range_start(&response) {
+                Some(start) if start == body.len() as u64 => {}
+                // Servers without range support send the whole artifact again
+                None if response.status() == StatusCode::OK => body.clear(),
+                _ => {
+                    return Err(CacheError::UnexpectedRange(
+                        hash.to_string(),
+                        Backtrace::capture(),
+                    ))
+                }
+            }
+        }
+    }
+
+    // Where the body of a 206 response starts in the artifact
+    fn range_start(response: &Response) -> Option<u64> {
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return None;
+        }
+        let content_range = response.headers().get("content-range")?.to_str().ok()?;
+        let (start, _) = content_range.strip_prefix("bytes ")?.split_once('-')?;
+        start.parse().ok()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use anyhow::Result;
     use futures::future::try_join_all;
     use tempfile::tempdir;
-    use turbopath::AbsoluteSystemPathBuf;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use turbopath::{AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
     use turborepo_analytics::start_analytics;
-    use turborepo_api_client::{analytics, APIClient};
+    use turborepo_api_client::{analytics, APIClient, BandwidthLimits, RetryPolicy};
     use turborepo_vercel_api_mock::start_test_server;
 
     use crate::{
@@ -315,4 +491,148 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_bandwidth_limits() -> Result<()> {
+        let port = port_scanner::request_open_port().unwrap();
+        let handle = tokio::spawn(start_test_server(port));
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Chungking Express")?;
+
+        // Uploads are streamed through the limiter rather than sent from the
+        // file directly
+        let api_client = APIClient::new(format!("http://localhost:{}", port), 200, "2.0.0", true)?
+            .with_bandwidth_limits(BandwidthLimits {
+                upload: Some(1024 * 1024),
+                download: Some(1024 * 1024),
+                per_transfer_upload: Some(512 * 1024),
+                per_transfer_download: Some(512 * 1024),
+            });
+        let cache = HTTPCache::new(
+            api_client,
+            &CacheOpts::default(),
+            repo_root_path.clone(),
+            APIAuth {
+                team_id: Some("my-team".to_string()),
+                token: "my-token".to_string(),
+                team_slug: None,
+            },
+            None,
+        );
+
+        cache
+            .put(&repo_root_path, "bandwidth-hash", &[file.clone()], 10)
+            .await?;
+        repo_root_path.resolve(&file).remove_file()?;
+        let (hit, files) = cache.fetch("bandwidth-hash").await?.unwrap();
+        assert_eq!(hit.time_saved, 10);
+        assert_eq!(files, vec![file.clone()]);
+        assert_eq!(
+            repo_root_path.resolve(&file).read_to_string()?,
+            "Chungking Express"
+        );
+
+        handle.abort();
+        Ok(())
+    }
+
+    // Reads a request up to the end of its headers, which is all a GET has
+    async fn read_request(socket: &mut TcpStream) -> Result<String> {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(socket.read_u8().await?);
+        }
+        Ok(String::from_utf8(request)?.to_lowercase())
+    }
+
+    #[tokio::test]
+    async fn test_resumed_download() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        let contents = "Ashes of Time".repeat(1000);
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents(&contents)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let api_client = APIClient::new(
+            format!("http://{}", listener.local_addr()?),
+            200,
+            "2.0.0",
+            false,
+        )?
+        .with_retry_policy(RetryPolicy {
+            min_backoff: Duration::ZERO,
+            ..Default::default()
+        });
+        let cache = HTTPCache::new(
+            api_client,
+            &CacheOpts::default(),
+            repo_root_path.clone(),
+            APIAuth {
+                team_id: Some("my-team".to_string()),
+                token: "my-token".to_string(),
+                team_slug: None,
+            },
+            None,
+        );
+
+        let mut archive = Vec::new();
+        cache
+            .write(&mut archive, &repo_root_path, &[file.clone()])
+            .await?;
+        let len = archive.len();
+        let half = len / 2;
+
+        let server = tokio::spawn(async move {
+            // The connection drops halfway through the artifact
+            let (mut socket, _) = listener.accept().await?;
+            read_request(&mut socket).await?;
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nx-artifact-duration: \
+                         10\r\nconnection: close\r\n\r\n",
+                        len
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            socket.write_all(&archive[..half]).await?;
+            drop(socket);
+
+            let (mut socket, _) = listener.accept().await?;
+            let request = read_request(&mut socket).await?;
+            assert!(request.contains(&format!("range: bytes={}-", half)));
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\ncontent-length: {}\r\ncontent-range: \
+                         bytes {}-{}/{}\r\nconnection: close\r\n\r\n",
+                        len - half,
+                        half,
+                        len - 1,
+                        len
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            socket.write_all(&archive[half..]).await?;
+            anyhow::Ok(())
+        });
+
+        repo_root_path.resolve(&file).remove_file()?;
+        let (hit, files) = cache.fetch("the-hash").await?.unwrap();
+        assert_eq!(hit.time_saved, 10);
+        assert_eq!(files, vec![file.clone()]);
+        assert_eq!(repo_root_path.resolve(&file).read_to_string()?, contents);
+        server.await??;
+
+        Ok(())
+    }
 }
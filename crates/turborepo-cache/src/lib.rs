@@ -5,21 +5,37 @@
 
 mod async_cache;
 pub mod cache_archive;
+pub mod cas;
+mod error_kind;
+mod events;
 pub mod fs;
 pub mod http;
+pub mod memory;
 mod multiplexer;
+mod outputs;
+pub mod remote;
 pub mod signature_authentication;
 #[cfg(test)]
 mod test_cases;
+mod upload_queue;
 
-use std::{backtrace, backtrace::Backtrace};
+use std::{backtrace, backtrace::Backtrace, sync::Arc, time::Duration};
 
-pub use async_cache::AsyncCache;
+pub use async_cache::{AsyncCache, PutOptions};
 use camino::Utf8Path;
+pub use error_kind::CacheErrorKind;
+pub use events::{CacheEvent, CacheEventHandler};
+pub use outputs::{OutputGlobs, OutputValidation};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+pub use upload_queue::FlushSummary;
 
-use crate::signature_authentication::SignatureError;
+use crate::{
+    cache_archive::{CompressionAlgorithm, EncryptionKey, OverwritePolicy, SymlinkPolicy},
+    fs::{QuotaPolicy, RestoreMode},
+    remote::RemoteCacheBackend,
+    signature_authentication::SignatureError,
+};
 
 #[derive(Debug, Error)]
 pub enum CacheError {
@@ -64,6 +80,37 @@ pub enum CacheError {
     InvalidMetadata(serde_json::Error, #[backtrace] Backtrace),
     #[error("Failed to write cache metadata file")]
     MetadataWriteFailure(serde_json::Error, #[backtrace] Backtrace),
+    #[error("artifact {0} is corrupt: checksum does not match metadata")]
+    Corrupt(String, #[backtrace] Backtrace),
+    #[error("artifact contains paths that differ only in case: {0} and {1}")]
+    CaseCollision(String, String, #[backtrace] Backtrace),
+    #[error("cannot restore {0}: file already exists")]
+    FileExists(String, #[backtrace] Backtrace),
+    #[error("archive is encrypted and no key was given")]
+    EncryptedArchive(#[backtrace] Backtrace),
+    #[error(
+        "{} must be a base64 encoded 32 byte key",
+        cache_archive::ENCRYPTION_KEY_ENV
+    )]
+    InvalidEncryptionKey(#[backtrace] Backtrace),
+    #[error("base {0} of delta artifact is missing")]
+    MissingDeltaBase(String, #[backtrace] Backtrace),
+    #[error("cannot cache {0}: the cache would use {1} bytes, over its quota of {2} bytes")]
+    QuotaExceeded(String, u64, u64, #[backtrace] Backtrace),
+    #[error("invalid cache bundle: {0}")]
+    InvalidBundle(String, #[backtrace] Backtrace),
+    #[error("resumed download of {0} did not continue where it stopped")]
+    UnexpectedRange(String, #[backtrace] Backtrace),
+    #[error("remote cache backend error: {0}")]
+    RemoteBackendError(String, #[backtrace] Backtrace),
+    #[error("remote cache is unreachable: {0}")]
+    RemoteUnreachable(String, #[backtrace] Backtrace),
+    #[error("invalid artifact tag: {0}")]
+    InvalidArtifactTag(String, #[backtrace] Backtrace),
+    #[error("invalid glob: {0}")]
+    InvalidGlob(Box<wax::BuildError>, #[backtrace] Backtrace),
+    #[error("restored outputs of {0} don't match the task's outputs: {1}")]
+    InvalidOutputs(String, String, #[backtrace] Backtrace),
     #[error("Unable to perform write as cache is shutting down")]
     CacheShuttingDown,
 }
@@ -74,6 +121,82 @@ impl From<turborepo_api_client::Error> for CacheError {
     }
 }
 
+impl CacheError {
+    /// Whether the remote cache couldn't be reached at all, rather than
+    /// rejecting a request. Backends return `RemoteUnreachable` for this.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            CacheError::ApiClientError(box turborepo_api_client::Error::ReqwestError(e), _) => {
+                e.is_connect() || e.is_timeout()
+            }
+            CacheError::ApiClientError(box turborepo_api_client::Error::TooManyFailures(e), _) => {
+                e.is_connect() || e.is_timeout()
+            }
+            CacheError::RemoteUnreachable(..) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<wax::BuildError> for CacheError {
+    fn from(value: wax::BuildError) -> Self {
+        CacheError::InvalidGlob(Box::new(value), Backtrace::capture())
+    }
+}
+
+/// A layer of the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheLayer {
+    /// The filesystem cache, or the deduplicating store when it's enabled.
+    Local,
+    /// The Vercel API, or `CacheOpts::remote_backend` when it's set.
+    Remote,
+}
+
+/// The layers a fetch reads from, in order, and the layers a put writes to,
+/// e.g. only writing to the local cache for local development while CI writes
+/// to both. Layers that aren't enabled are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub read_order: Vec<CacheLayer>,
+    pub write_targets: Vec<CacheLayer>,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            read_order: vec![CacheLayer::Local, CacheLayer::Remote],
+            write_targets: vec![CacheLayer::Local, CacheLayer::Remote],
+        }
+    }
+}
+
+impl CachePolicy {
+    pub fn reads(&self, layer: CacheLayer) -> bool {
+        self.read_order.contains(&layer)
+    }
+
+    pub fn writes(&self, layer: CacheLayer) -> bool {
+        self.write_targets.contains(&layer)
+    }
+}
+
+/// When a layer of the cache, local or remote, may be written to. Unlike a
+/// `CachePolicy`, this applies to every put and fetch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Written by puts, and for the local cache, by remote hits so that the
+    /// next fetch of the artifact hits locally. Remote hits only populate the
+    /// local cache if it's one of the policy's write targets. For the remote
+    /// cache this is the same as `PutOnly`.
+    #[default]
+    WriteBack,
+    /// Written by puts only.
+    PutOnly,
+    /// Never written.
+    ReadOnly,
+}
+
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum CacheSource {
     Local,
@@ -93,6 +216,96 @@ pub struct CacheOpts<'a> {
     pub skip_filesystem: bool,
     pub workers: u32,
     pub remote_cache_opts: Option<RemoteCacheOpts>,
+    // Artifacts in the filesystem cache that haven't been used for this long
+    // are pruned the first time the cache is written to.
+    pub fs_cache_ttl: Option<Duration>,
+    // Delete artifacts from the filesystem cache that fail verification
+    // instead of moving them to its quarantine directory.
+    pub fs_cache_remove_corrupt: bool,
+    // Sign artifacts written to the filesystem cache and reject unsigned or
+    // tampered artifacts on fetch. Uses the same key as remote cache signing.
+    pub fs_cache_signature: bool,
+    pub fs_cache_compression: CompressionAlgorithm,
+    // Only used by zstd. 0 selects zstd's default level.
+    pub fs_cache_compression_level: i32,
+    // Number of threads zstd uses to compress artifacts. 0 compresses on the
+    // thread that writes the artifact.
+    pub fs_cache_compression_workers: u32,
+    // Number of threads used to write files when restoring artifacts. 0 or 1
+    // restores on the thread that fetches the artifact.
+    pub fs_cache_restore_workers: u32,
+    pub fs_cache_restore_mode: RestoreMode,
+    pub fs_cache_overwrite_policy: OverwritePolicy,
+    // What fetching an artifact from any cache does with symlinks whose
+    // targets are absolute or outside of the repo. Defaults to failing the
+    // fetch.
+    pub symlink_policy: SymlinkPolicy,
+    // Record the mtimes of files written to the filesystem cache and restore
+    // them on fetch. Artifacts are no longer byte-for-byte reproducible.
+    pub fs_cache_mtimes: bool,
+    // Store files in the filesystem cache by content hash so that files shared
+    // between artifacts are only stored once, instead of one archive per
+    // artifact.
+    pub fs_cache_dedupe: bool,
+    // Store files with the same contents as another file of the same
+    // artifact once, as a hardlink entry in the archive. Only applies to the
+    // filesystem cache, since older versions fail to restore these archives.
+    pub fs_cache_dedupe_files: bool,
+    // Store files with holes as sparse entries that only hold their data, and
+    // restore them with the same holes. Only applies to the filesystem cache,
+    // since older versions fail to restore these archives.
+    pub fs_cache_sparse_files: bool,
+    // Store the extended attributes of outputs, e.g. code signatures and
+    // quarantine flags on macOS, and restore them along with the files. Only
+    // applies to the filesystem cache.
+    pub fs_cache_xattrs: bool,
+    // Encrypt the archives written to the filesystem cache with this key.
+    // Artifacts written without it, or with another key, are treated as
+    // misses. Metadata and the files stored by `fs_cache_dedupe` are not
+    // encrypted, and link restore mode is replaced by extract mode so that
+    // no decrypted copies are kept in the cache directory.
+    pub fs_cache_encryption_key: Option<EncryptionKey>,
+    // Experimental: store artifacts in the filesystem cache as a delta against
+    // the last artifact of the same task stored in full, when that's smaller.
+    // Only artifacts put with a task id are stored as deltas, and not when
+    // encryption is enabled. A delta is a miss once its base is evicted.
+    pub fs_cache_delta: bool,
+    // Maximum size of the filesystem cache in bytes, checked every time an
+    // artifact is written
+    pub fs_cache_quota: Option<u64>,
+    pub fs_cache_quota_policy: QuotaPolicy,
+    // Flush the archive and metadata of every artifact written to the
+    // filesystem cache, and the cache directory, to disk before `put`
+    // returns, so a crash can't leave a truncated artifact behind. Much
+    // slower on most filesystems.
+    pub fs_cache_fsync: bool,
+    // Recorded in the metadata of artifacts written to the filesystem cache
+    pub turbo_version: Option<String>,
+    // Notified of the hits, misses, puts and errors of every cache
+    pub event_handler: Option<Arc<dyn CacheEventHandler>>,
+    // Stores the remote cache here instead of using the Vercel API. Remote
+    // cache signing only applies to the Vercel API.
+    pub remote_backend: Option<Arc<dyn RemoteCacheBackend>>,
+    // Queue puts that fail because the remote cache is unreachable in the
+    // cache directory, to be uploaded by `AsyncCache::flush_pending`
+    pub remote_cache_upload_queue: bool,
+    // Check whether the remote cache already has an artifact before uploading
+    // it, and skip the upload if it does. Costs an extra request for every
+    // put, but saves uploading artifacts another machine already has.
+    pub remote_cache_skip_existing: bool,
+    // Applies to the memory cache and whichever of the filesystem cache and
+    // the deduplicating store is enabled
+    pub local_cache_write_policy: WritePolicy,
+    pub remote_cache_write_policy: WritePolicy,
+    // Used by puts and fetches that aren't given a policy of their own
+    pub cache_policy: CachePolicy,
+    // Keep up to this many bytes of compressed artifacts in memory, in front
+    // of the filesystem cache. Only worth it for long-running processes like
+    // the daemon. The memory cache is part of the local layer.
+    pub memory_cache_max_bytes: Option<u64>,
+    // Fail `AsyncCache::fetch_with_outputs` when the restored paths don't
+    // match the task's outputs, rather than returning them as warnings
+    pub strict_output_validation: bool,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
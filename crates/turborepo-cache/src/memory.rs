@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tracing::debug;
+use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
+
+use crate::{
+    cache_archive::{CacheReader, CacheWriter, SymlinkPolicy},
+    CacheError, CacheHitMetadata, CacheSource,
+};
+
+/// A cache that keeps compressed archives in memory, evicting the least
+/// recently used once they take up more than `max_bytes`. Used in front of
+/// the local cache by long-running processes, and on its own where a cache
+/// that never touches the disk is handy, such as in tests.
+pub struct MemoryCache {
+    max_bytes: u64,
+    symlink_policy: SymlinkPolicy,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    artifacts: HashMap<String, MemoryArtifact>,
+    total_bytes: u64,
+    // Incremented on every use, so that artifacts can be ordered by when
+    // they were last used
+    clock: u64,
+}
+
+struct MemoryArtifact {
+    duration: u64,
+    archive: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+impl MemoryCache {
+    pub fn new(max_bytes: u64, symlink_policy: SymlinkPolicy) -> Self {
+        Self {
+            max_bytes,
+            symlink_policy,
+            state: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("memory cache lock poisoned")
+    }
+
+    pub fn put(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+    ) -> Result<(), CacheError> {
+        let mut archive = Vec::new();
+        {
+            let mut cache_archive = CacheWriter::from_writer(&mut archive, true)?;
+            for file in files {
+                cache_archive.add_file(anchor, file)?;
+            }
+            cache_archive.finish()?;
+        }
+
+        let size = archive.len() as u64;
+        if size > self.max_bytes {
+            debug!(
+                "not keeping {} in memory, its {} bytes are over the budget",
+                hash, size
+            );
+            return Ok(());
+        }
+
+        let mut state = self.lock();
+        state.clock += 1;
+        let artifact = MemoryArtifact {
+            duration,
+            archive: Arc::new(archive),
+            last_used: state.clock,
+        };
+        if let Some(replaced) = state.artifacts.insert(hash.to_string(), artifact) {
+            state.total_bytes -= replaced.archive.len() as u64;
+        }
+        state.total_bytes += size;
+
+        while state.total_bytes > self.max_bytes {
+            let Some(oldest) = state
+                .artifacts
+                .iter()
+                .min_by_key(|(_, artifact)| artifact.last_used)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = state.artifacts.remove(&oldest) {
+                debug!("evicting {} from memory cache", oldest);
+                state.total_bytes -= evicted.archive.len() as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn fetch(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+    ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
+        // Restored without holding the lock, so that other artifacts can be
+        // used in the meantime
+        let (duration, archive) = {
+            let mut state = self.lock();
+            state.clock += 1;
+            let clock = state.clock;
+            let Some(artifact) = state.artifacts.get_mut(hash) else {
+                return Ok(None);
+            };
+            artifact.last_used = clock;
+            (artifact.duration, artifact.archive.clone())
+        };
+
+        let files = CacheReader::from_reader(&archive[..], true)?
+            .with_symlink_policy(self.symlink_policy)
+            .restore(anchor)?;
+
+        Ok(Some((
+            CacheHitMetadata {
+                source: CacheSource::Local,
+                time_saved: duration,
+            },
+            files,
+        )))
+    }
+
+    pub fn exists(&self, hash: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
+        Ok(self
+            .lock()
+            .artifacts
+            .get(hash)
+            .map(|artifact| CacheHitMetadata {
+                source: CacheSource::Local,
+                time_saved: artifact.duration,
+            }))
+    }
+
+    /// The total size of the archives held, in bytes.
+    pub fn size(&self) -> u64 {
+        self.lock().total_bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_cache() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPath::from_std_path(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Fallen Angels")?;
+
+        let cache = MemoryCache::new(u64::MAX, SymlinkPolicy::default());
+        assert_eq!(cache.exists("the-hash")?, None);
+        cache.put(repo_root_path, "the-hash", &[file.clone()], 10)?;
+
+        let hit = CacheHitMetadata {
+            source: CacheSource::Local,
+            time_saved: 10,
+        };
+        assert_eq!(cache.exists("the-hash")?, Some(hit));
+        repo_root_path.resolve(&file).remove_file()?;
+        assert_eq!(
+            cache.fetch(repo_root_path, "the-hash")?,
+            Some((hit, vec![file.clone()]))
+        );
+        assert_eq!(
+            repo_root_path.resolve(&file).read_to_string()?,
+            "Fallen Angels"
+        );
+
+        // Only room for two artifacts of this size
+        let size = cache.size();
+        let cache = MemoryCache::new(size * 2, SymlinkPolicy::default());
+        for hash in ["first", "second"] {
+            cache.put(repo_root_path, hash, &[file.clone()], 10)?;
+        }
+        // Using the first makes the second the least recently used
+        cache.fetch(repo_root_path, "first")?;
+        cache.put(repo_root_path, "third", &[file.clone()], 10)?;
+        assert!(cache.exists("first")?.is_some());
+        assert!(cache.exists("second")?.is_none());
+        assert!(cache.exists("third")?.is_some());
+        assert_eq!(cache.size(), size * 2);
+
+        // Artifacts over the budget aren't kept at all
+        let cache = MemoryCache::new(size - 1, SymlinkPolicy::default());
+        cache.put(repo_root_path, "the-hash", &[file], 10)?;
+        assert!(cache.exists("the-hash")?.is_none());
+
+        Ok(())
+    }
+}
@@ -1,11 +1,28 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
+use futures::StreamExt;
+use tokio::sync::OwnedMutexGuard;
 use tracing::{debug, warn};
 use turbopath::{AbsoluteSystemPath, AnchoredSystemPathBuf};
 use turborepo_analytics::AnalyticsSender;
 use turborepo_api_client::{APIAuth, APIClient};
 
-use crate::{fs::FSCache, http::HTTPCache, CacheError, CacheHitMetadata, CacheOpts};
+use crate::{
+    cache_archive::SymlinkPolicy,
+    cas::CASCache,
+    fs::{ArtifactDetails, ArtifactTags, FSCache},
+    http::HTTPCache,
+    memory::MemoryCache,
+    remote::{DownloadedArchive, RemoteArtifactMetadata, RemoteCache},
+    upload_queue::{FlushSummary, UploadQueue},
+    CacheError, CacheHitMetadata, CacheLayer, CacheOpts, CachePolicy, WritePolicy,
+};
 
 pub struct CacheMultiplexer {
     // We use an `AtomicBool` instead of removing the cache because that would require
@@ -13,8 +30,41 @@ pub struct CacheMultiplexer {
     // This does create a mild race condition where we might use the cache
     // even though another thread might be removing it, but that's fine.
     should_use_http_cache: AtomicBool,
-    fs: Option<FSCache>,
+    // Shared with tasks on the blocking pool
+    fs: Option<Arc<FSCache>>,
+    // Replaces `fs` when the deduplicating store is enabled
+    cas: Option<CASCache>,
+    // Checked before `fs` or `cas`, and given a copy of their hits
+    memory: Option<MemoryCache>,
     http: Option<HTTPCache>,
+    // Replaces `http` when `CacheOpts::remote_backend` is set
+    remote: Option<RemoteCache>,
+    // Puts waiting for the remote cache to be reachable again
+    upload_queue: Option<UploadQueue>,
+    skip_existing_uploads: bool,
+    local_write_policy: WritePolicy,
+    remote_write_policy: WritePolicy,
+    // Artifacts being prefetched. Each is locked until its prefetch is done.
+    prefetching: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    prefetch_workers: usize,
+    symlink_policy: SymlinkPolicy,
+}
+
+// Removes a prefetch from `CacheMultiplexer::prefetching` when it's done,
+// including when it's cancelled.
+struct PrefetchGuard<'a> {
+    prefetching: &'a Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    hash: &'a str,
+    _lock: OwnedMutexGuard<()>,
+}
+
+impl Drop for PrefetchGuard<'_> {
+    fn drop(&mut self) {
+        self.prefetching
+            .lock()
+            .expect("prefetch lock poisoned")
+            .remove(self.hash);
+    }
 }
 
 impl CacheMultiplexer {
@@ -35,11 +85,18 @@ impl CacheMultiplexer {
             warn!("no caches are enabled");
         }
 
-        let fs_cache = use_fs_cache
-            .then(|| FSCache::new(opts.override_dir, repo_root, analytics_recorder.clone()))
+        let fs_cache = (use_fs_cache && !opts.fs_cache_dedupe)
+            .then(|| FSCache::new(opts, repo_root, analytics_recorder.clone()).map(Arc::new))
+            .transpose()?;
+        let cas_cache = (use_fs_cache && opts.fs_cache_dedupe)
+            .then(|| CASCache::new(opts, repo_root, analytics_recorder.clone()))
             .transpose()?;
 
-        let http_cache = use_http_cache
+        let remote_cache = use_http_cache
+            .then(|| opts.remote_backend.clone())
+            .flatten()
+            .map(|backend| RemoteCache::new(backend, opts, repo_root.to_owned()));
+        let http_cache = (use_http_cache && remote_cache.is_none())
             .then_some(api_auth)
             .flatten()
             .map(|api_auth| {
@@ -52,10 +109,26 @@ impl CacheMultiplexer {
                 )
             });
 
+        let upload_queue = (opts.remote_cache_upload_queue
+            && (http_cache.is_some() || remote_cache.is_some()))
+        .then(|| UploadQueue::new(&FSCache::resolve_cache_dir(repo_root, opts.override_dir)));
+
         Ok(CacheMultiplexer {
             should_use_http_cache: AtomicBool::new(http_cache.is_some()),
             fs: fs_cache,
+            cas: cas_cache,
+            memory: opts
+                .memory_cache_max_bytes
+                .map(|max_bytes| MemoryCache::new(max_bytes, opts.symlink_policy)),
             http: http_cache,
+            remote: remote_cache,
+            upload_queue,
+            skip_existing_uploads: opts.remote_cache_skip_existing,
+            local_write_policy: opts.local_cache_write_policy,
+            remote_write_policy: opts.remote_cache_write_policy,
+            prefetching: Mutex::default(),
+            prefetch_workers: opts.workers.max(1) as usize,
+            symlink_policy: opts.symlink_policy,
         })
     }
 
@@ -69,18 +142,35 @@ impl CacheMultiplexer {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn put(
         &self,
         anchor: &AbsoluteSystemPath,
         key: &str,
         files: &[AnchoredSystemPathBuf],
         duration: u64,
+        policy: &CachePolicy,
+        tags: &ArtifactTags,
+        logs: Option<&[u8]>,
     ) -> Result<(), CacheError> {
-        self.fs
-            .as_ref()
-            .map(|fs| fs.put(anchor, key, files, duration))
-            .transpose()?;
+        if policy.writes(CacheLayer::Local) && self.local_write_policy != WritePolicy::ReadOnly {
+            self.put_local(anchor, key, files, duration, tags, logs)
+                .await?;
+        }
+        if !policy.writes(CacheLayer::Remote) || self.remote_write_policy == WritePolicy::ReadOnly {
+            return Ok(());
+        }
+
+        if self.skip_existing_uploads && self.exists_remote(key).await.is_some() {
+            debug!(
+                "skipping upload of {}, the remote cache already has it",
+                key
+            );
+            return Ok(());
+        }
 
+        // The Vercel API has nowhere to store tags, so they're only uploaded
+        // to a `RemoteCacheBackend`
         let http_result = match self.get_http_cache() {
             Some(http) => {
                 let http_result = http.put(anchor, key, files, duration).await;
@@ -99,60 +189,408 @@ impl CacheMultiplexer {
             self.should_use_http_cache.store(false, Ordering::Relaxed);
         }
 
+        let remote_result = match &self.remote {
+            Some(remote) => Some(remote.put(anchor, key, files, duration, tags).await),
+            None => None,
+        };
+        if let Some(Err(e)) = &remote_result {
+            debug!("failed to put to remote cache: {:?}", e);
+        }
+
+        if let (Some(queue), Some(Err(e))) = (&self.upload_queue, http_result.or(remote_result)) {
+            if e.is_unreachable() {
+                debug!("remote cache is unreachable, queueing upload of {}", key);
+                if let Err(e) = queue.push(anchor, key, files, duration, tags) {
+                    warn!("failed to queue upload of {}: {:?}", key, e);
+                }
+            }
+        }
+
         Ok(())
     }
 
+    /// Uploads the puts queued while the remote cache was unreachable,
+    /// oldest first. Stops at the first one that finds the remote cache
+    /// still unreachable, leaving it and the rest queued.
+    pub async fn flush_pending(&self) -> Result<FlushSummary, CacheError> {
+        let mut summary = FlushSummary::default();
+        let Some(queue) = &self.upload_queue else {
+            return Ok(summary);
+        };
+
+        let mut pending = queue.pending()?.into_iter();
+        for upload in pending.by_ref() {
+            let archive_path = queue.archive_path(&upload.hash);
+            let result = if let Some(http) = self.get_http_cache() {
+                http.upload_archive(&upload.hash, &archive_path, upload.duration)
+                    .await
+            } else if let Some(remote) = &self.remote {
+                remote
+                    .upload_archive(&upload.hash, &archive_path, upload.duration, &upload.tags)
+                    .await
+            } else {
+                // The http cache was disabled since the put was queued
+                summary.pending.push(upload.hash);
+                break;
+            };
+
+            match result {
+                Ok(()) => {
+                    queue.remove(&upload.hash)?;
+                    summary.uploaded.push(upload.hash);
+                }
+                Err(e) if e.is_unreachable() => {
+                    summary.pending.push(upload.hash);
+                    break;
+                }
+                Err(e) => {
+                    warn!("dropping queued upload of {}: {:?}", upload.hash, e);
+                    queue.remove(&upload.hash)?;
+                    summary.dropped.push(upload.hash);
+                }
+            }
+        }
+        summary.pending.extend(pending.map(|upload| upload.hash));
+
+        Ok(summary)
+    }
+
+    // Logs are only stored by the filesystem cache
+    pub async fn fetch_logs(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        match &self.fs {
+            Some(fs) => fs.clone().fetch_logs_async(key.to_string()).await,
+            None => Ok(None),
+        }
+    }
+
     pub async fn fetch(
         &self,
         anchor: &AbsoluteSystemPath,
         key: &str,
+        policy: &CachePolicy,
     ) -> Result<Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)>, CacheError> {
-        if let Some(fs) = &self.fs {
-            if let response @ Ok(Some(_)) = fs.fetch(anchor, key) {
-                return response;
+        // Wait for a prefetch of this artifact rather than downloading it again
+        let pending = self
+            .prefetching
+            .lock()
+            .expect("prefetch lock poisoned")
+            .get(key)
+            .cloned();
+        if let Some(pending) = pending {
+            let _ = pending.lock().await;
+        }
+
+        for layer in &policy.read_order {
+            let hit = match layer {
+                CacheLayer::Local => self.fetch_local(anchor, key).await,
+                CacheLayer::Remote => self.fetch_remote(anchor, key, policy).await,
+            };
+            if hit.is_some() {
+                return Ok(hit);
             }
         }
 
-        if let Some(http) = self.get_http_cache() {
-            if let Ok(Some((CacheHitMetadata { source, time_saved }, files))) =
-                http.fetch(key).await
+        Ok(None)
+    }
+
+    async fn fetch_local(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+    ) -> Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)> {
+        if let Some(memory) = &self.memory {
+            if let Ok(Some(hit)) = memory.fetch(anchor, key) {
+                return Some(hit);
+            }
+        }
+        if let Some(fs) = &self.fs {
+            if let Ok(Some(hit)) = fs
+                .clone()
+                .fetch_async(anchor.to_owned(), key.to_string())
+                .await
             {
-                // Store this into fs cache. We can ignore errors here because we know
-                // we have previously successfully stored in HTTP cache, and so the overall
-                // result is a success at fetching. Storing in lower-priority caches is an
-                // optimization.
-                if let Some(fs) = &self.fs {
-                    let _ = fs.put(anchor, key, &files, time_saved);
+                return Some(self.keep_in_memory(anchor, key, hit));
+            }
+        }
+        if let Some(cas) = &self.cas {
+            if let Ok(Some(hit)) = cas.fetch(anchor, key) {
+                return Some(self.keep_in_memory(anchor, key, hit));
+            }
+        }
+
+        None
+    }
+
+    // Copies a hit from the filesystem into the memory cache, so that the
+    // next fetch of it doesn't touch the disk
+    fn keep_in_memory(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+        hit: (CacheHitMetadata, Vec<AnchoredSystemPathBuf>),
+    ) -> (CacheHitMetadata, Vec<AnchoredSystemPathBuf>) {
+        if let Some(memory) = &self.memory {
+            if self.local_write_policy == WritePolicy::WriteBack {
+                if let Err(e) = memory.put(anchor, key, &hit.1, hit.0.time_saved) {
+                    debug!("failed to put {} to memory cache: {:?}", key, e);
                 }
+            }
+        }
+        hit
+    }
+
+    async fn fetch_remote(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+        policy: &CachePolicy,
+    ) -> Option<(CacheHitMetadata, Vec<AnchoredSystemPathBuf>)> {
+        let remote_hit = if let Some(http) = self.get_http_cache() {
+            http.fetch_with_archive(key).await
+        } else if let Some(remote) = &self.remote {
+            remote.fetch(key).await
+        } else {
+            Ok(None)
+        };
+        let Ok(Some((hit, files, archive))) = remote_hit else {
+            return None;
+        };
 
-                return Ok(Some((CacheHitMetadata { source, time_saved }, files)));
+        // Store this into fs cache. We can ignore errors here because we know
+        // we have previously successfully stored in the remote cache, and so the
+        // overall result is a success at fetching. Storing in lower-priority caches
+        // is an optimization.
+        if policy.writes(CacheLayer::Local) && self.local_write_policy == WritePolicy::WriteBack {
+            if let Err(e) = self.put_downloaded(anchor, key, &files, archive).await {
+                debug!("failed to store {} locally: {:?}", key, e);
             }
         }
 
-        Ok(None)
+        Some((hit, files))
     }
 
-    pub async fn exists(&self, key: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
+    // Stores an artifact downloaded from the remote cache in whichever local
+    // cache is enabled, with the duration and tags it was uploaded with. The
+    // filesystem cache stores the downloaded archive as is; the others need
+    // the artifact's files, restored under `anchor`.
+    async fn put_downloaded(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+        files: &[AnchoredSystemPathBuf],
+        archive: DownloadedArchive,
+    ) -> Result<(), CacheError> {
+        if let Some(memory) = &self.memory {
+            memory.put(anchor, key, files, archive.duration)?;
+        }
+        if let Some(cas) = &self.cas {
+            cas.put(anchor, key, files, archive.duration)?;
+        }
+        if let Some(fs) = &self.fs {
+            let duration = archive.duration;
+            let details = ArtifactDetails {
+                tags: archive.tags.clone(),
+                ..Default::default()
+            };
+            fs.clone()
+                .put_archive_async(key.to_string(), archive.into_reader()?, duration, details)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Stores an artifact in whichever local cache is enabled
+    async fn put_local(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        key: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        tags: &ArtifactTags,
+        logs: Option<&[u8]>,
+    ) -> Result<(), CacheError> {
+        if let Some(memory) = &self.memory {
+            memory.put(anchor, key, files, duration)?;
+        }
         if let Some(fs) = &self.fs {
-            match fs.exists(key) {
-                cache_hit @ Ok(Some(_)) => {
-                    return cache_hit;
+            let details = ArtifactDetails {
+                tags: tags.clone(),
+                ..Default::default()
+            };
+            match logs {
+                Some(logs) => {
+                    fs.clone()
+                        .put_with_logs_async(
+                            anchor.to_owned(),
+                            key.to_string(),
+                            files.to_vec(),
+                            duration,
+                            details,
+                            logs.to_vec(),
+                        )
+                        .await?
+                }
+                None => {
+                    fs.clone()
+                        .put_with_details_async(
+                            anchor.to_owned(),
+                            key.to_string(),
+                            files.to_vec(),
+                            duration,
+                            details,
+                        )
+                        .await?
                 }
+            }
+        }
+        // The deduplicating store doesn't keep per-artifact metadata, so
+        // tags aren't recorded there
+        if let Some(cas) = &self.cas {
+            cas.put(anchor, key, files, duration)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn exists(
+        &self,
+        key: &str,
+        policy: &CachePolicy,
+    ) -> Result<Option<CacheHitMetadata>, CacheError> {
+        for layer in &policy.read_order {
+            let hit = match layer {
+                CacheLayer::Local => self.exists_local(key).await,
+                CacheLayer::Remote => self.exists_remote(key).await,
+            };
+            if hit.is_some() {
+                return Ok(hit);
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn exists_local(&self, key: &str) -> Option<CacheHitMetadata> {
+        if let Some(Ok(Some(hit))) = self.memory.as_ref().map(|memory| memory.exists(key)) {
+            return Some(hit);
+        }
+        if let Some(fs) = &self.fs {
+            match fs.clone().exists_async(key.to_string()).await {
+                Ok(Some(hit)) => return Some(hit),
+                Ok(None) => {}
+                Err(err) => debug!("failed to check fs cache: {:?}", err),
+            }
+        }
+        if let Some(cas) = &self.cas {
+            match cas.exists(key) {
+                Ok(Some(hit)) => return Some(hit),
                 Ok(None) => {}
                 Err(err) => debug!("failed to check fs cache: {:?}", err),
             }
         }
 
+        None
+    }
+
+    async fn exists_remote(&self, key: &str) -> Option<CacheHitMetadata> {
         if let Some(http) = self.get_http_cache() {
             match http.exists(key).await {
-                cache_hit @ Ok(Some(_)) => {
-                    return cache_hit;
-                }
+                Ok(Some(hit)) => return Some(hit),
                 Ok(None) => {}
                 Err(err) => debug!("failed to check http cache: {:?}", err),
             }
         }
+        if let Some(remote) = &self.remote {
+            match remote.exists(key).await {
+                Ok(Some(hit)) => return Some(hit),
+                Ok(None) => {}
+                Err(err) => debug!("failed to check remote cache: {:?}", err),
+            }
+        }
 
-        Ok(None)
+        None
+    }
+
+    /// Returns what the remote cache knows about an artifact without
+    /// downloading it.
+    pub async fn remote_metadata(
+        &self,
+        key: &str,
+    ) -> Result<Option<RemoteArtifactMetadata>, CacheError> {
+        if let Some(http) = self.get_http_cache() {
+            http.metadata(key).await
+        } else if let Some(remote) = &self.remote {
+            remote.metadata(key).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Copies the artifacts for `hashes` from the remote cache into the local
+    /// one without restoring them, so that fetching them later doesn't wait
+    /// on the network. Artifacts that are already cached locally are skipped.
+    /// Failures are only logged, since a prefetch is just a hint.
+    pub async fn prefetch(&self, hashes: &[String]) {
+        if self.fs.is_none() && self.cas.is_none() && self.memory.is_none() {
+            return;
+        }
+        if self.local_write_policy == WritePolicy::ReadOnly {
+            return;
+        }
+        if self.get_http_cache().is_none() && self.remote.is_none() {
+            return;
+        }
+
+        futures::stream::iter(hashes)
+            .for_each_concurrent(self.prefetch_workers, |hash| async move {
+                if let Err(e) = self.prefetch_artifact(hash).await {
+                    debug!("failed to prefetch {}: {:?}", hash, e);
+                }
+            })
+            .await;
+    }
+
+    async fn prefetch_artifact(&self, hash: &str) -> Result<(), CacheError> {
+        let pending = Arc::new(tokio::sync::Mutex::new(()));
+        let _guard = {
+            let mut prefetching = self.prefetching.lock().expect("prefetch lock poisoned");
+            if prefetching.contains_key(hash) {
+                return Ok(());
+            }
+            prefetching.insert(hash.to_string(), pending.clone());
+            PrefetchGuard {
+                prefetching: &self.prefetching,
+                hash,
+                _lock: pending.try_lock_owned().expect("new lock is unlocked"),
+            }
+        };
+
+        if self.exists_local(hash).await.is_some() {
+            return Ok(());
+        }
+        let archive = if let Some(http) = self.get_http_cache() {
+            http.fetch_archive(hash).await?
+        } else if let Some(remote) = &self.remote {
+            remote.fetch_archive(hash).await?
+        } else {
+            None
+        };
+        let Some(archive) = archive else {
+            return Ok(());
+        };
+
+        // Only the memory and deduplicating caches need the files. They're
+        // restored somewhere other than the repo, so that running tasks don't
+        // see them before the artifact is fetched.
+        let staging = tempfile::tempdir()?;
+        let staging_path = AbsoluteSystemPath::from_std_path(staging.path())?;
+        let files = if self.memory.is_some() || self.cas.is_some() {
+            archive.restore(staging_path, self.symlink_policy)?
+        } else {
+            Vec::new()
+        };
+        self.put_downloaded(staging_path, hash, &files, archive)
+            .await
     }
 }
@@ -0,0 +1,165 @@
+use std::{backtrace::Backtrace, collections::BTreeSet};
+
+use turbopath::AnchoredSystemPathBuf;
+use wax::{Glob, Pattern};
+
+use crate::CacheError;
+
+/// The outputs a task declares, as globs relative to the directory its
+/// artifact is restored into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputGlobs {
+    pub inclusions: Vec<String>,
+    pub exclusions: Vec<String>,
+}
+
+/// How the paths restored from an artifact differ from the outputs its task
+/// declares, as reported by `AsyncCache::fetch_with_outputs`. Usually the
+/// result of an artifact written before the task's outputs changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputValidation {
+    // Restored paths that no inclusion matches, or that an exclusion matches
+    pub unexpected: Vec<AnchoredSystemPathBuf>,
+    // Inclusions that match none of the restored paths
+    pub missing: Vec<String>,
+}
+
+impl OutputValidation {
+    pub fn is_valid(&self) -> bool {
+        self.unexpected.is_empty() && self.missing.is_empty()
+    }
+
+    // The error returned in place of these warnings in strict mode
+    pub(crate) fn into_error(self, hash: &str) -> CacheError {
+        let mut problems = self
+            .unexpected
+            .iter()
+            .map(|path| format!("unexpected {}", path))
+            .chain(
+                self.missing
+                    .iter()
+                    .map(|glob| format!("nothing matches {}", glob)),
+            )
+            .collect::<Vec<_>>();
+        problems.truncate(10);
+        CacheError::InvalidOutputs(hash.to_string(), problems.join(", "), Backtrace::capture())
+    }
+}
+
+impl OutputGlobs {
+    /// Checks restored paths against these globs. Directories don't have to
+    /// match an inclusion themselves, as long as something inside of them
+    /// does, since an inclusion like `dist/**/*.js` restores `dist` too.
+    pub fn validate(
+        &self,
+        files: &[AnchoredSystemPathBuf],
+    ) -> Result<OutputValidation, CacheError> {
+        let inclusions = self
+            .inclusions
+            .iter()
+            .map(|glob| Glob::new(glob))
+            .collect::<Result<Vec<_>, _>>()?;
+        let exclusion = wax::any(self.exclusions.iter().map(String::as_str))?;
+
+        let paths = files
+            .iter()
+            .map(|file| (file, file.to_unix().as_str().to_string()))
+            .collect::<Vec<_>>();
+        let mut matched = vec![false; inclusions.len()];
+        let mut expected = BTreeSet::new();
+        for (_, path) in &paths {
+            if exclusion.is_match(path.as_str()) {
+                continue;
+            }
+            let mut is_expected = false;
+            for (glob, matched) in inclusions.iter().zip(matched.iter_mut()) {
+                if glob.is_match(path.as_str()) {
+                    *matched = true;
+                    is_expected = true;
+                }
+            }
+            if is_expected {
+                expected.insert(path.clone());
+            }
+        }
+
+        let unexpected = paths
+            .iter()
+            .filter(|(_, path)| {
+                if expected.contains(path) {
+                    return false;
+                }
+                // A directory holding an expected path is expected itself
+                let prefix = format!("{}/", path.trim_end_matches('/'));
+                !expected
+                    .range(prefix.clone()..)
+                    .next()
+                    .map_or(false, |next| next.starts_with(&prefix))
+            })
+            .map(|(file, _)| (*file).clone())
+            .collect();
+        let missing = self
+            .inclusions
+            .iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(glob, _)| glob.clone())
+            .collect();
+
+        Ok(OutputValidation {
+            unexpected,
+            missing,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paths(raw: &[&str]) -> Vec<AnchoredSystemPathBuf> {
+        raw.iter()
+            .map(|path| AnchoredSystemPathBuf::from_raw(path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_outputs() -> Result<(), CacheError> {
+        let globs = OutputGlobs {
+            inclusions: vec![
+                "dist/**/*.js".to_string(),
+                ".turbo/turbo-build.log".to_string(),
+            ],
+            exclusions: vec!["dist/cache/**".to_string()],
+        };
+
+        let valid = globs.validate(&paths(&[
+            "dist",
+            "dist/lib",
+            "dist/lib/index.js",
+            ".turbo",
+            ".turbo/turbo-build.log",
+        ]))?;
+        assert!(valid.is_valid());
+
+        // Written when the task also output its cache and types, and before
+        // it wrote a log
+        let stale = globs.validate(&paths(&[
+            "dist",
+            "dist/index.js",
+            "dist/index.d.ts",
+            "dist/cache",
+            "dist/cache/chunk.js",
+        ]))?;
+        assert_eq!(
+            stale,
+            OutputValidation {
+                unexpected: paths(&["dist/index.d.ts", "dist/cache", "dist/cache/chunk.js"]),
+                missing: vec![".turbo/turbo-build.log".to_string()],
+            }
+        );
+        assert!(!stale.is_valid());
+
+        Ok(())
+    }
+}
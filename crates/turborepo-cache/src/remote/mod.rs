@@ -0,0 +1,1053 @@
+mod s3;
+
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeMap,
+    fmt,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    pin::Pin,
+    sync::Arc,
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::HeaderMap;
+use tempfile::NamedTempFile;
+use tokio_util::io::ReaderStream;
+use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
+
+pub use self::s3::{MultipartOpts, S3Backend, S3Credentials};
+use crate::{
+    cache_archive::{CacheReader, CacheWriter, SymlinkPolicy},
+    events::EventReporter,
+    fs::ArtifactTags,
+    signature_authentication::ArtifactSignatureAuthenticator,
+    CacheError, CacheHitMetadata, CacheOpts, CacheSource,
+};
+
+// The tag holding an artifact's signature, when signing is enabled. It's
+// stored along with the artifact's other tags, the way the Vercel API returns
+// it as `x-artifact-tag`.
+pub(crate) const SIGNATURE_TAG: &str = "tag";
+
+/// The contents of an artifact archive, as sent to or received from a
+/// `RemoteCacheBackend`.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, CacheError>> + Send + Sync>>;
+
+/// What the remote cache knows about an artifact, fetched without downloading
+/// the artifact.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoteArtifactMetadata {
+    // Size of the archive in bytes
+    pub size: Option<u64>,
+    // How long running the task took, in milliseconds
+    pub duration: u64,
+    pub created_at: Option<DateTime<Utc>>,
+    // Any other metadata stored with the artifact, e.g. its signature tag
+    pub tags: BTreeMap<String, String>,
+}
+
+impl RemoteArtifactMetadata {
+    // Reads the headers of a HEAD response. Headers starting with `prefix`
+    // are the artifact's own metadata: its duration, and otherwise tags.
+    pub(crate) fn from_headers(headers: &HeaderMap, prefix: &str) -> Result<Self, CacheError> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        let duration = header(&format!("{}duration", prefix)).map_or(Ok(0), |duration| {
+            duration
+                .parse()
+                .map_err(|_| CacheError::InvalidDuration(Backtrace::capture()))
+        })?;
+        let tags = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let tag = name.as_str().strip_prefix(prefix)?;
+                if tag == "duration" {
+                    return None;
+                }
+                Some((tag.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+
+        Ok(Self {
+            size: header("content-length").and_then(|size| size.parse().ok()),
+            duration,
+            created_at: header("last-modified")
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                .map(|date| date.with_timezone(&Utc)),
+            tags,
+        })
+    }
+}
+
+/// An artifact's archive as downloaded from the remote cache, a zstd
+/// compressed tar. It's kept after restoring so that the filesystem cache can
+/// store it as is, rather than archiving the restored files again.
+#[derive(Debug)]
+pub(crate) struct DownloadedArchive {
+    // How long running the task took, in milliseconds
+    pub duration: u64,
+    // The artifact's tags, without its signature
+    pub tags: ArtifactTags,
+    body: ArchiveBody,
+}
+
+// Downloads from the Vercel API are held in memory, and those from a
+// `RemoteCacheBackend` are streamed to a temporary file
+#[derive(Debug)]
+enum ArchiveBody {
+    Bytes(Bytes),
+    File(NamedTempFile),
+}
+
+impl DownloadedArchive {
+    pub(crate) fn from_bytes(duration: u64, body: Bytes) -> Self {
+        DownloadedArchive {
+            duration,
+            tags: ArtifactTags::new(),
+            body: ArchiveBody::Bytes(body),
+        }
+    }
+
+    fn from_file(metadata: RemoteArtifactMetadata, file: NamedTempFile) -> Self {
+        let mut tags = metadata.tags;
+        tags.remove(SIGNATURE_TAG);
+        DownloadedArchive {
+            duration: metadata.duration,
+            tags,
+            body: ArchiveBody::File(file),
+        }
+    }
+
+    // Size of the archive in bytes
+    pub(crate) fn len(&self) -> Result<u64, CacheError> {
+        Ok(match &self.body {
+            ArchiveBody::Bytes(body) => body.len() as u64,
+            ArchiveBody::File(file) => file.as_file().metadata()?.len(),
+        })
+    }
+
+    pub(crate) fn restore(
+        &self,
+        root: &AbsoluteSystemPath,
+        symlink_policy: SymlinkPolicy,
+    ) -> Result<Vec<AnchoredSystemPathBuf>, CacheError> {
+        let reader: Box<dyn Read> = match &self.body {
+            ArchiveBody::Bytes(body) => Box::new(&body[..]),
+            ArchiveBody::File(file) => Box::new(BufReader::new(file.reopen()?)),
+        };
+        CacheReader::from_reader(reader, true)?
+            .with_symlink_policy(symlink_policy)
+            .restore(root)
+    }
+
+    // Reads the archive from the start
+    pub(crate) fn into_reader(self) -> Result<Box<dyn Read + Send>, CacheError> {
+        Ok(match self.body {
+            ArchiveBody::Bytes(body) => Box::new(body.reader()),
+            ArchiveBody::File(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Box::new(BufReader::new(file))
+            }
+        })
+    }
+}
+
+/// Storage for the remote cache, used in place of the Vercel API when set as
+/// `CacheOpts::remote_backend`. Artifacts are stored as opaque archives along
+/// with the time running their task took, in milliseconds, and their tags.
+/// Tags are returned by `metadata` and `get`, and include the artifact's
+/// signature when signing is enabled.
+#[async_trait]
+pub trait RemoteCacheBackend: fmt::Debug + Send + Sync {
+    /// Returns how long the task took, if the artifact exists.
+    async fn exists(&self, hash: &str) -> Result<Option<u64>, CacheError>;
+    /// Returns what's known about the artifact without downloading it. By
+    /// default that's only the duration from `exists`.
+    async fn metadata(&self, hash: &str) -> Result<Option<RemoteArtifactMetadata>, CacheError> {
+        Ok(self
+            .exists(hash)
+            .await?
+            .map(|duration| RemoteArtifactMetadata {
+                duration,
+                ..Default::default()
+            }))
+    }
+    /// Returns what's known about the artifact and its archive, if the
+    /// artifact exists.
+    async fn get(
+        &self,
+        hash: &str,
+    ) -> Result<Option<(RemoteArtifactMetadata, ByteStream)>, CacheError>;
+    /// Stores an archive of `size` bytes, replacing any existing one.
+    async fn put(
+        &self,
+        hash: &str,
+        duration: u64,
+        tags: &ArtifactTags,
+        size: u64,
+        body: ByteStream,
+    ) -> Result<(), CacheError>;
+    /// Stores the archive at `path`. By default it's streamed to `put`;
+    /// backends that can upload parts of a file at once override this.
+    async fn put_file(
+        &self,
+        hash: &str,
+        duration: u64,
+        tags: &ArtifactTags,
+        path: &AbsoluteSystemPath,
+    ) -> Result<(), CacheError> {
+        let size = path.symlink_metadata()?.len();
+        self.put(hash, duration, tags, size, file_stream(path).await?)
+            .await
+    }
+}
+
+pub(crate) async fn file_stream(path: &AbsoluteSystemPath) -> Result<ByteStream, CacheError> {
+    let file = tokio::fs::File::open(path.as_std_path()).await?;
+    Ok(Box::pin(ReaderStream::new(file).map_err(CacheError::from)))
+}
+
+// Packs and restores artifacts for a `RemoteCacheBackend`, like `HTTPCache`
+// does for the Vercel API, including signing them.
+pub(crate) struct RemoteCache {
+    backend: Arc<dyn RemoteCacheBackend>,
+    signer_verifier: Option<ArtifactSignatureAuthenticator>,
+    repo_root: AbsoluteSystemPathBuf,
+    events: EventReporter,
+    symlink_policy: SymlinkPolicy,
+}
+
+impl RemoteCache {
+    pub fn new(
+        backend: Arc<dyn RemoteCacheBackend>,
+        opts: &CacheOpts,
+        repo_root: AbsoluteSystemPathBuf,
+    ) -> Self {
+        let signer_verifier = opts
+            .remote_cache_opts
+            .as_ref()
+            .filter(|remote_cache_opts| remote_cache_opts.signature)
+            .map(|remote_cache_opts| ArtifactSignatureAuthenticator {
+                team_id: remote_cache_opts.team_id.as_bytes().to_vec(),
+                secret_key_override: None,
+                previous_keys_override: None,
+            });
+
+        Self {
+            backend,
+            signer_verifier,
+            repo_root,
+            events: EventReporter::new(opts, CacheSource::Remote),
+            symlink_policy: opts.symlink_policy,
+        }
+    }
+
+    pub async fn put(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        tags: &ArtifactTags,
+    ) -> Result<(), CacheError> {
+        let start = Instant::now();
+        let result = self.upload(anchor, hash, files, duration, tags).await;
+        let bytes = *result.as_ref().unwrap_or(&0);
+        let result = result.map(|_| ());
+        self.events.put(hash, start, &result, || bytes);
+        result
+    }
+
+    // Returns the size of the uploaded artifact
+    async fn upload(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        tags: &ArtifactTags,
+    ) -> Result<u64, CacheError> {
+        // Written to a temporary file so that large artifacts are streamed to
+        // the backend rather than held in memory
+        let artifact_file = tempfile::NamedTempFile::new()?;
+        {
+            let mut cache_archive =
+                CacheWriter::from_writer(BufWriter::new(artifact_file.as_file()), true)?;
+            for file in files {
+                cache_archive.add_file(anchor, file)?;
+            }
+            cache_archive.finish()?;
+        }
+
+        let artifact_path = AbsoluteSystemPath::from_std_path(artifact_file.path())?;
+        self.upload_archive(hash, artifact_path, duration, tags)
+            .await?;
+
+        Ok(artifact_path.symlink_metadata()?.len())
+    }
+
+    // Signs and uploads an archive that's already been written
+    pub async fn upload_archive(
+        &self,
+        hash: &str,
+        artifact_path: &AbsoluteSystemPath,
+        duration: u64,
+        tags: &ArtifactTags,
+    ) -> Result<(), CacheError> {
+        let mut tags = tags.clone();
+        if let Some(signer) = &self.signer_verifier {
+            let tag = signer.generate_tag_from_reader(hash.as_bytes(), artifact_path.open()?)?;
+            tags.insert(SIGNATURE_TAG.to_string(), tag);
+        }
+
+        self.backend
+            .put_file(hash, duration, &tags, artifact_path)
+            .await
+    }
+
+    pub async fn exists(&self, hash: &str) -> Result<Option<CacheHitMetadata>, CacheError> {
+        Ok(self
+            .backend
+            .exists(hash)
+            .await?
+            .map(|duration| CacheHitMetadata {
+                source: CacheSource::Remote,
+                time_saved: duration,
+            }))
+    }
+
+    pub async fn metadata(&self, hash: &str) -> Result<Option<RemoteArtifactMetadata>, CacheError> {
+        self.backend.metadata(hash).await
+    }
+
+    /// Restores the artifact for `hash`, also returning the downloaded
+    /// archive so that it can be stored locally.
+    pub async fn fetch(
+        &self,
+        hash: &str,
+    ) -> Result<
+        Option<(
+            CacheHitMetadata,
+            Vec<AnchoredSystemPathBuf>,
+            DownloadedArchive,
+        )>,
+        CacheError,
+    > {
+        let start = Instant::now();
+        let result = self.download(hash).await;
+        let bytes = match &result {
+            Ok(Some((_, _, archive))) => archive.len().unwrap_or_default(),
+            _ => 0,
+        };
+        self.events.fetch(hash, start, &result, || bytes);
+        result
+    }
+
+    async fn download(
+        &self,
+        hash: &str,
+    ) -> Result<
+        Option<(
+            CacheHitMetadata,
+            Vec<AnchoredSystemPathBuf>,
+            DownloadedArchive,
+        )>,
+        CacheError,
+    > {
+        let Some(archive) = self.fetch_archive(hash).await? else {
+            return Ok(None);
+        };
+        let files = archive.restore(&self.repo_root, self.symlink_policy)?;
+        let hit = CacheHitMetadata {
+            source: CacheSource::Remote,
+            time_saved: archive.duration,
+        };
+
+        Ok(Some((hit, files, archive)))
+    }
+
+    /// Downloads the archive for `hash` to a temporary file and checks its
+    /// signature, without restoring it.
+    pub async fn fetch_archive(&self, hash: &str) -> Result<Option<DownloadedArchive>, CacheError> {
+        let Some((metadata, mut stream)) = self.backend.get(hash).await? else {
+            return Ok(None);
+        };
+        // Written to a temporary file so that large artifacts aren't held in
+        // memory
+        let archive = NamedTempFile::new()?;
+        let mut writer = BufWriter::new(archive.as_file());
+        while let Some(chunk) = stream.next().await {
+            writer.write_all(&chunk?)?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        if let Some(signer_verifier) = &self.signer_verifier {
+            let expected_tag = metadata
+                .tags
+                .get(SIGNATURE_TAG)
+                .ok_or(CacheError::ArtifactTagMissing(Backtrace::capture()))?;
+            if !signer_verifier.validate_reader(hash.as_bytes(), archive.reopen()?, expected_tag)? {
+                return Err(CacheError::InvalidTag(Backtrace::capture()));
+            }
+        }
+
+        Ok(Some(DownloadedArchive::from_file(metadata, archive)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        assert_matches::assert_matches,
+        backtrace::Backtrace,
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+    };
+
+    use anyhow::Result;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+    use turborepo_api_client::APIClient;
+
+    use super::*;
+    use crate::{
+        fs::TagFilter, AsyncCache, CacheLayer, CachePolicy, FlushSummary, PutOptions, WritePolicy,
+    };
+
+    #[derive(Debug, Default)]
+    struct MemoryBackend {
+        artifacts: Mutex<HashMap<String, (u64, Bytes)>>,
+        tags: Mutex<HashMap<String, ArtifactTags>>,
+        offline: AtomicBool,
+    }
+
+    impl MemoryBackend {
+        fn check_online(&self) -> Result<(), CacheError> {
+            if self.offline.load(Ordering::Relaxed) {
+                return Err(CacheError::RemoteUnreachable(
+                    "offline".to_string(),
+                    Backtrace::capture(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl RemoteCacheBackend for MemoryBackend {
+        async fn exists(&self, hash: &str) -> Result<Option<u64>, CacheError> {
+            self.check_online()?;
+            let artifacts = self.artifacts.lock().unwrap();
+            Ok(artifacts.get(hash).map(|(duration, _)| *duration))
+        }
+
+        async fn get(
+            &self,
+            hash: &str,
+        ) -> Result<Option<(RemoteArtifactMetadata, ByteStream)>, CacheError> {
+            self.check_online()?;
+            let artifacts = self.artifacts.lock().unwrap();
+            let tags = self.tags.lock().unwrap();
+            Ok(artifacts.get(hash).map(|(duration, body)| {
+                let metadata = RemoteArtifactMetadata {
+                    size: Some(body.len() as u64),
+                    duration: *duration,
+                    tags: tags.get(hash).cloned().unwrap_or_default(),
+                    ..Default::default()
+                };
+                let body = body.clone();
+                (
+                    metadata,
+                    Box::pin(futures::stream::once(async move { Ok(body) })) as ByteStream,
+                )
+            }))
+        }
+
+        async fn put(
+            &self,
+            hash: &str,
+            duration: u64,
+            tags: &ArtifactTags,
+            size: u64,
+            body: ByteStream,
+        ) -> Result<(), CacheError> {
+            self.check_online()?;
+            self.tags
+                .lock()
+                .unwrap()
+                .insert(hash.to_string(), tags.clone());
+            let body: Vec<Bytes> = body.try_collect().await?;
+            let body = Bytes::from(body.concat());
+            assert_eq!(body.len() as u64, size);
+            self.artifacts
+                .lock()
+                .unwrap()
+                .insert(hash.to_string(), (duration, body));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_backend() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path.resolve(&file).create_with_contents("2046")?;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let opts = CacheOpts {
+            skip_filesystem: true,
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        // Never used, the backend replaces the Vercel API
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+
+        cache
+            .put(
+                repo_root_path.clone(),
+                "the-hash".to_string(),
+                vec![file.clone()],
+                30,
+            )
+            .await?;
+        cache.wait().await;
+        assert!(backend.artifacts.lock().unwrap().contains_key("the-hash"));
+
+        let hit = CacheHitMetadata {
+            source: CacheSource::Remote,
+            time_saved: 30,
+        };
+        assert_eq!(cache.exists("the-hash").await?, Some(hit));
+        assert_eq!(cache.exists("missing").await?, None);
+
+        repo_root_path.resolve(&file).remove_file()?;
+        assert_eq!(
+            cache.fetch(&repo_root_path, "the-hash").await?,
+            Some((hit, vec![file.clone()]))
+        );
+        assert_eq!(repo_root_path.resolve(&file).read_to_string()?, "2046");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signed_artifacts() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Days of Being Wild")?;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let opts = CacheOpts {
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        let mut cache = RemoteCache::new(backend.clone(), &opts, repo_root_path.clone());
+        cache.signer_verifier = Some(ArtifactSignatureAuthenticator::new(
+            b"team".to_vec(),
+            Some(b"key".to_vec()),
+        ));
+
+        cache
+            .put(
+                &repo_root_path,
+                "the-hash",
+                &[file.clone()],
+                30,
+                &ArtifactTags::new(),
+            )
+            .await?;
+        assert!(backend.tags.lock().unwrap()["the-hash"].contains_key(SIGNATURE_TAG));
+        // The signature isn't one of the artifact's own tags
+        let (_, _, archive) = cache.fetch("the-hash").await?.unwrap();
+        assert!(archive.tags.is_empty());
+
+        // A tampered archive isn't restored
+        repo_root_path.resolve(&file).remove_file()?;
+        {
+            let mut artifacts = backend.artifacts.lock().unwrap();
+            let (_, body) = artifacts.get_mut("the-hash").unwrap();
+            let mut tampered = body.to_vec();
+            *tampered.last_mut().unwrap() ^= 0xff;
+            *body = tampered.into();
+        }
+        assert_matches!(
+            cache.fetch("the-hash").await,
+            Err(CacheError::InvalidTag(..))
+        );
+        assert!(!repo_root_path.resolve(&file).exists());
+
+        // Nor is one without a signature
+        backend
+            .tags
+            .lock()
+            .unwrap()
+            .get_mut("the-hash")
+            .unwrap()
+            .remove(SIGNATURE_TAG);
+        assert_matches!(
+            cache.fetch("the-hash").await,
+            Err(CacheError::ArtifactTagMissing(..))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_queue() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("The Grandmaster")?;
+
+        let backend = Arc::new(MemoryBackend::default());
+        backend.offline.store(true, Ordering::Relaxed);
+        let opts = CacheOpts {
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            remote_cache_upload_queue: true,
+            ..Default::default()
+        };
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+
+        cache
+            .put(
+                repo_root_path.clone(),
+                "the-hash".to_string(),
+                vec![file.clone()],
+                30,
+            )
+            .await?;
+        cache.wait().await;
+        assert!(backend.artifacts.lock().unwrap().is_empty());
+
+        // Still offline, so the upload stays queued
+        let summary = cache.flush_pending().await?;
+        assert!(summary.uploaded.is_empty());
+        assert_eq!(summary.pending, vec!["the-hash"]);
+
+        backend.offline.store(false, Ordering::Relaxed);
+        let summary = cache.flush_pending().await?;
+        assert_eq!(summary.uploaded, vec!["the-hash"]);
+        assert!(summary.pending.is_empty());
+        assert_eq!(
+            backend.exists("the-hash").await?,
+            Some(30),
+            "queued uploads keep their duration"
+        );
+        assert_eq!(cache.flush_pending().await?, FlushSummary::default());
+
+        // The uploaded archive restores like any other
+        let hit = RemoteCache::new(backend.clone(), &opts, repo_root_path.clone())
+            .fetch("the-hash")
+            .await?;
+        assert_eq!(hit.map(|(_, files, _)| files), Some(vec![file.clone()]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_existing() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Blueberry Nights")?;
+
+        for (skip_existing, expected_duration) in [(true, 10), (false, 30)] {
+            let backend = Arc::new(MemoryBackend::default());
+            backend
+                .put(
+                    "the-hash",
+                    10,
+                    &ArtifactTags::new(),
+                    4,
+                    Box::pin(futures::stream::once(async {
+                        Ok(Bytes::from_static(b"2046"))
+                    })),
+                )
+                .await?;
+            let opts = CacheOpts {
+                skip_filesystem: true,
+                workers: 10,
+                remote_backend: Some(backend.clone()),
+                remote_cache_skip_existing: skip_existing,
+                ..Default::default()
+            };
+            let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+            let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+
+            cache
+                .put(
+                    repo_root_path.clone(),
+                    "the-hash".to_string(),
+                    vec![file.clone()],
+                    30,
+                )
+                .await?;
+            cache.wait().await;
+            assert_eq!(backend.exists("the-hash").await?, Some(expected_duration));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_policies() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("My Blueberry Nights")?;
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+
+        // A read-only remote cache isn't uploaded to
+        let backend = Arc::new(MemoryBackend::default());
+        let opts = CacheOpts {
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            remote_cache_write_policy: WritePolicy::ReadOnly,
+            ..Default::default()
+        };
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client.clone(), None, None)?;
+        cache
+            .put(
+                repo_root_path.clone(),
+                "the-hash".to_string(),
+                vec![file.clone()],
+                30,
+            )
+            .await?;
+        cache.wait().await;
+        assert!(backend.artifacts.lock().unwrap().is_empty());
+        assert_eq!(
+            cache.exists("the-hash").await?.map(|hit| hit.source),
+            Some(CacheSource::Local)
+        );
+
+        // Remote hits only populate local caches with write-back
+        let opts = CacheOpts {
+            skip_filesystem: true,
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client.clone(), None, None)?;
+        cache
+            .put(
+                repo_root_path.clone(),
+                "the-hash".to_string(),
+                vec![file.clone()],
+                30,
+            )
+            .await?;
+        cache.wait().await;
+
+        for (policy, expected_source) in [
+            (WritePolicy::PutOnly, CacheSource::Remote),
+            (WritePolicy::ReadOnly, CacheSource::Remote),
+            (WritePolicy::WriteBack, CacheSource::Local),
+        ] {
+            let cache_dir = tempdir()?;
+            let cache_dir_path = camino::Utf8Path::from_path(cache_dir.path()).unwrap();
+            let opts = CacheOpts {
+                override_dir: Some(cache_dir_path),
+                workers: 10,
+                remote_backend: Some(backend.clone()),
+                local_cache_write_policy: policy,
+                ..Default::default()
+            };
+            let cache = AsyncCache::new(&opts, &repo_root_path, api_client.clone(), None, None)?;
+            let hit = cache.fetch(&repo_root_path, "the-hash").await?;
+            assert_eq!(hit.map(|(hit, _)| hit.source), Some(CacheSource::Remote));
+            assert_eq!(
+                cache.exists("the-hash").await?.map(|hit| hit.source),
+                Some(expected_source),
+                "{:?}",
+                policy
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_policy() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("Ashes of Time")?;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let opts = CacheOpts {
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+        let local_only = CachePolicy {
+            read_order: vec![CacheLayer::Local],
+            write_targets: vec![CacheLayer::Local],
+        };
+        let remote_only = CachePolicy {
+            read_order: vec![CacheLayer::Remote],
+            write_targets: vec![CacheLayer::Remote],
+        };
+
+        cache
+            .put_with_policy(
+                repo_root_path.clone(),
+                "local-hash".to_string(),
+                vec![file.clone()],
+                10,
+                local_only.clone(),
+            )
+            .await?;
+        cache
+            .put_with_policy(
+                repo_root_path.clone(),
+                "remote-hash".to_string(),
+                vec![file.clone()],
+                20,
+                remote_only.clone(),
+            )
+            .await?;
+        cache.wait().await;
+
+        assert!(cache
+            .exists_with_policy("local-hash", &remote_only)
+            .await?
+            .is_none());
+        assert!(cache
+            .exists_with_policy("remote-hash", &local_only)
+            .await?
+            .is_none());
+        assert_eq!(
+            cache.exists("remote-hash").await?.map(|hit| hit.source),
+            Some(CacheSource::Remote)
+        );
+
+        // Fetching with a policy that doesn't write locally leaves the local
+        // cache alone
+        let hit = cache
+            .fetch_with_policy(&repo_root_path, "remote-hash", &remote_only)
+            .await?;
+        assert_eq!(hit.map(|(hit, _)| hit.source), Some(CacheSource::Remote));
+        assert!(cache
+            .exists_with_policy("remote-hash", &local_only)
+            .await?
+            .is_none());
+
+        // Layers are read in the policy's order
+        cache
+            .put(
+                repo_root_path.clone(),
+                "both-hash".to_string(),
+                vec![file.clone()],
+                30,
+            )
+            .await?;
+        cache.wait().await;
+        let remote_first = CachePolicy {
+            read_order: vec![CacheLayer::Remote, CacheLayer::Local],
+            ..Default::default()
+        };
+        let hit = cache
+            .fetch_with_policy(&repo_root_path, "both-hash", &remote_first)
+            .await?;
+        assert_eq!(hit.map(|(hit, _)| hit.source), Some(CacheSource::Remote));
+        let hit = cache.fetch(&repo_root_path, "both-hash").await?;
+        assert_eq!(hit.map(|(hit, _)| hit.source), Some(CacheSource::Local));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata() -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-length", "2046".parse()?);
+        headers.insert("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT".parse()?);
+        headers.insert("x-artifact-duration", "30".parse()?);
+        headers.insert("x-artifact-tag", "days-of-being-wild".parse()?);
+        headers.insert("x-amz-meta-duration", "40".parse()?);
+        let metadata = RemoteArtifactMetadata::from_headers(&headers, "x-artifact-")?;
+        assert_eq!(
+            metadata,
+            RemoteArtifactMetadata {
+                size: Some(2046),
+                duration: 30,
+                created_at: Some(Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap()),
+                tags: [("tag".to_string(), "days-of-being-wild".to_string())].into(),
+            }
+        );
+
+        // Backends without their own metadata fall back to `exists`
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let backend = Arc::new(MemoryBackend::default());
+        backend
+            .put(
+                "the-hash",
+                10,
+                &ArtifactTags::new(),
+                4,
+                Box::pin(futures::stream::once(async {
+                    Ok(Bytes::from_static(b"2046"))
+                })),
+            )
+            .await?;
+        let opts = CacheOpts {
+            skip_filesystem: true,
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+        assert_eq!(
+            cache.remote_metadata("the-hash").await?,
+            Some(RemoteArtifactMetadata {
+                duration: 10,
+                ..Default::default()
+            })
+        );
+        assert_eq!(cache.remote_metadata("missing").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tags() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        repo_root_path
+            .resolve(&file)
+            .create_with_contents("My Blueberry Nights")?;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let opts = CacheOpts {
+            workers: 10,
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+
+        let tags: ArtifactTags = [
+            ("branch".to_string(), "main".to_string()),
+            ("pipeline".to_string(), "1046".to_string()),
+        ]
+        .into();
+        cache
+            .put_with_options(
+                repo_root_path.clone(),
+                "the-hash".to_string(),
+                vec![file.clone()],
+                30,
+                PutOptions {
+                    tags: tags.clone(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        cache.wait().await;
+        assert_eq!(backend.tags.lock().unwrap().get("the-hash"), Some(&tags));
+
+        // Written to the local cache's metadata too
+        let fs = crate::fs::FSCache::new(&opts, &repo_root_path, None)?;
+        assert_eq!(
+            fs.find_tagged(&[TagFilter::Equals("branch".into(), "main".into())])?,
+            vec!["the-hash"]
+        );
+
+        let invalid = PutOptions {
+            tags: [("Branch".to_string(), "main".to_string())].into(),
+            ..Default::default()
+        };
+        assert_matches!(
+            cache
+                .put_with_options(
+                    repo_root_path.clone(),
+                    "other".into(),
+                    vec![file],
+                    30,
+                    invalid
+                )
+                .await,
+            Err(CacheError::InvalidArtifactTag(..))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_through() -> Result<()> {
+        let repo_root = tempdir()?;
+        let repo_root_path = AbsoluteSystemPathBuf::try_from(repo_root.path())?;
+        let file = AnchoredSystemPathBuf::from_raw("out.txt")?;
+        let file_path = repo_root_path.resolve(&file);
+        file_path.create_with_contents("Ashes of Time")?;
+
+        let backend = Arc::new(MemoryBackend::default());
+        let opts = CacheOpts {
+            remote_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        // Uploaded by another machine, so only the remote cache has them
+        let tags: ArtifactTags = [("branch".to_string(), "main".to_string())].into();
+        let remote = RemoteCache::new(backend.clone(), &opts, repo_root_path.clone());
+        remote
+            .put(&repo_root_path, "fetched", &[file.clone()], 30, &tags)
+            .await?;
+        remote
+            .put(&repo_root_path, "prefetched", &[file.clone()], 40, &tags)
+            .await?;
+        file_path.remove_file()?;
+
+        let api_client = APIClient::new("http://example.com", 200, "2.0.0", true)?;
+        let cache = AsyncCache::new(&opts, &repo_root_path, api_client, None, None)?;
+        let hit = cache.fetch(&repo_root_path, "fetched").await?;
+        assert_eq!(hit.map(|(_, files)| files), Some(vec![file.clone()]));
+        cache.prefetch(vec!["prefetched".to_string()]).await?;
+
+        // Both are stored locally with the duration and tags they were
+        // uploaded with
+        let fs = crate::fs::FSCache::new(&opts, &repo_root_path, None)?;
+        assert_eq!(
+            fs.find_tagged(&[TagFilter::Equals("branch".into(), "main".into())])?,
+            vec!["fetched", "prefetched"]
+        );
+        for (hash, duration) in [("fetched", 30), ("prefetched", 40)] {
+            file_path.remove_file()?;
+            let (hit, files) = fs.fetch(&repo_root_path, hash)?.unwrap();
+            assert_eq!(hit.time_saved, duration);
+            assert_eq!(files, vec![file.clone()]);
+            assert_eq!(file_path.read_to_string()?, "Ashes of Time");
+        }
+        assert!(fs.verify_all(false, &|_| {})?.issues.is_empty());
+
+        Ok(())
+    }
+}
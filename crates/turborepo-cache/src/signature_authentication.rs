@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, io::Read};
 
 use base64::{prelude::BASE64_STANDARD, Engine};
 use hmac::{Hmac, Mac};
@@ -8,6 +8,12 @@ use thiserror::Error;
 
 type HmacSha256 = Hmac<Sha256>;
 
+const SIGNATURE_KEY_ENV: &str = "TURBO_REMOTE_CACHE_SIGNATURE_KEY";
+// Keys that were used to sign artifacts before the current one, separated by
+// commas. They're only used to verify artifacts, so that rotating the key
+// doesn't invalidate everything signed with the old one.
+const PREVIOUS_SIGNATURE_KEYS_ENV: &str = "TURBO_REMOTE_CACHE_PREVIOUS_SIGNATURE_KEYS";
+
 #[derive(Debug, Error)]
 pub enum SignatureError {
     #[error(
@@ -21,6 +27,8 @@ pub enum SignatureError {
     Base64EncodingError(#[from] base64::DecodeError),
     #[error(transparent)]
     Hmac(#[from] hmac::digest::InvalidLength),
+    #[error("failed to read artifact: {0}")]
+    IO(#[from] std::io::Error),
 }
 
 #[derive(Debug)]
@@ -28,6 +36,8 @@ pub struct ArtifactSignatureAuthenticator {
     pub(crate) team_id: Vec<u8>,
     // An override for testing purposes (to avoid env var race conditions)
     pub(crate) secret_key_override: Option<Vec<u8>>,
+    // Likewise for the previous keys
+    pub(crate) previous_keys_override: Option<Vec<Vec<u8>>>,
 }
 
 impl ArtifactSignatureAuthenticator {
@@ -35,9 +45,18 @@ impl ArtifactSignatureAuthenticator {
         Self {
             team_id,
             secret_key_override,
+            previous_keys_override: None,
         }
     }
 
+    /// Also accepts artifacts signed with any of `previous_keys`, in place of
+    /// those in `TURBO_REMOTE_CACHE_PREVIOUS_SIGNATURE_KEYS`. New artifacts
+    /// are always signed with the current key.
+    pub fn with_previous_keys(mut self, previous_keys: Vec<Vec<u8>>) -> Self {
+        self.previous_keys_override = Some(previous_keys);
+        self
+    }
+
     // Gets secret key from either secret key override or environment variable.
     // HMAC_SHA256 has no key length limit, although it's generally recommended
     // to keep key length under 64 bytes since anything longer is hashed using
@@ -47,11 +66,27 @@ impl ArtifactSignatureAuthenticator {
             return Ok(secret_key.to_vec());
         }
 
-        Ok(env::var_os("TURBO_REMOTE_CACHE_SIGNATURE_KEY")
+        Ok(env::var_os(SIGNATURE_KEY_ENV)
             .ok_or(SignatureError::NoSignatureSecretKey)?
             .into_raw_vec())
     }
 
+    fn previous_keys(&self) -> Vec<Vec<u8>> {
+        if let Some(previous_keys) = &self.previous_keys_override {
+            return previous_keys.clone();
+        }
+
+        env::var_os(PREVIOUS_SIGNATURE_KEYS_ENV)
+            .map(|keys| {
+                keys.into_raw_vec()
+                    .split(|byte| *byte == b',')
+                    .filter(|key| !key.is_empty())
+                    .map(<[u8]>::to_vec)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     fn construct_metadata(&self, hash: &[u8]) -> Result<Vec<u8>, SignatureError> {
         let mut metadata = hash.to_vec();
         metadata.extend_from_slice(&self.team_id);
@@ -68,6 +103,29 @@ impl ArtifactSignatureAuthenticator {
         Ok(mac)
     }
 
+    // A tag generator for every key a valid artifact may be signed with, the
+    // current key first
+    fn get_tag_verifiers(&self, hash: &[u8]) -> Result<Vec<HmacSha256>, SignatureError> {
+        let metadata = self.construct_metadata(hash)?;
+        let mut keys = vec![self.secret_key()?];
+        keys.extend(self.previous_keys());
+
+        keys.iter()
+            .map(|key| {
+                let mut mac = HmacSha256::new_from_slice(key)?;
+                mac.update(&metadata);
+                Ok(mac)
+            })
+            .collect()
+    }
+
+    fn verify_any(macs: Vec<HmacSha256>, expected_tag: &str) -> Result<bool, SignatureError> {
+        let expected_bytes = BASE64_STANDARD.decode(expected_tag)?;
+        Ok(macs
+            .into_iter()
+            .any(|mac| mac.verify_slice(&expected_bytes).is_ok()))
+    }
+
     pub fn generate_tag_bytes(
         &self,
         hash: &[u8],
@@ -92,19 +150,57 @@ impl ArtifactSignatureAuthenticator {
         Ok(BASE64_STANDARD.encode(hmac_output.into_bytes()))
     }
 
+    // Like `generate_tag`, but reads the artifact incrementally so it doesn't
+    // need to be held in memory.
+    pub fn generate_tag_from_reader(
+        &self,
+        hash: &[u8],
+        artifact: impl Read,
+    ) -> Result<String, SignatureError> {
+        let mut hmac_ctx = self.get_tag_generator(hash)?;
+
+        Self::update_from_reader(std::slice::from_mut(&mut hmac_ctx), artifact)?;
+        let hmac_output = hmac_ctx.finalize();
+        Ok(BASE64_STANDARD.encode(hmac_output.into_bytes()))
+    }
+
+    pub fn validate_reader(
+        &self,
+        hash: &[u8],
+        artifact: impl Read,
+        expected_tag: &str,
+    ) -> Result<bool, SignatureError> {
+        let mut macs = self.get_tag_verifiers(hash)?;
+        Self::update_from_reader(&mut macs, artifact)?;
+
+        Self::verify_any(macs, expected_tag)
+    }
+
+    fn update_from_reader(macs: &mut [HmacSha256], mut reader: impl Read) -> std::io::Result<()> {
+        let mut buffer = [0; 8192];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(());
+            }
+            for mac in macs.iter_mut() {
+                mac.update(&buffer[..n]);
+            }
+        }
+    }
+
     pub fn validate(
         &self,
         hash: &[u8],
         artifact_body: &[u8],
         expected_tag: &str,
     ) -> Result<bool, SignatureError> {
-        let mut mac = HmacSha256::new_from_slice(&self.secret_key()?)?;
-        let message = self.construct_metadata(hash)?;
-        mac.update(&message);
-        mac.update(artifact_body);
+        let mut macs = self.get_tag_verifiers(hash)?;
+        for mac in &mut macs {
+            mac.update(artifact_body);
+        }
 
-        let expected_bytes = BASE64_STANDARD.decode(expected_tag)?;
-        Ok(mac.verify_slice(&expected_bytes).is_ok())
+        Self::verify_any(macs, expected_tag)
     }
 }
 
@@ -245,6 +341,7 @@ mod tests {
         let signature = ArtifactSignatureAuthenticator {
             team_id: test_case.team_id.to_vec(),
             secret_key_override: None,
+            previous_keys_override: Some(Vec::new()),
         };
 
         let hash = test_case.artifact_hash;
@@ -268,6 +365,41 @@ mod tests {
 
         // Confirm it's valid
         assert!(signature.validate(hash, artifact_body, &tag)?);
+
+        // Reading the artifact incrementally should produce the same tag
+        let reader_tag = signature.generate_tag_from_reader(hash, &artifact_body[..])?;
+        assert_eq!(reader_tag, tag);
+        assert!(signature.validate_reader(hash, &artifact_body[..], &tag)?);
+        assert!(!signature.validate_reader(hash, &b"tampered"[..], &tag)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_rotation() -> Result<()> {
+        let hash = b"the-hash";
+        let body = b"Chungking Express";
+        let old = ArtifactSignatureAuthenticator::new(b"team".to_vec(), Some(b"old".to_vec()))
+            .with_previous_keys(Vec::new());
+        let old_tag = old.generate_tag(hash, body)?;
+
+        let rotated = ArtifactSignatureAuthenticator::new(b"team".to_vec(), Some(b"new".to_vec()))
+            .with_previous_keys(vec![b"older".to_vec(), b"old".to_vec()]);
+        // Artifacts signed with a previous key are still valid
+        assert!(rotated.validate(hash, body, &old_tag)?);
+        assert!(rotated.validate_reader(hash, &body[..], &old_tag)?);
+        assert!(!rotated.validate(hash, b"tampered", &old_tag)?);
+
+        // New artifacts are signed with the current key only
+        let new_tag = rotated.generate_tag(hash, body)?;
+        assert_ne!(new_tag, old_tag);
+        assert!(!old.validate(hash, body, &new_tag)?);
+        assert!(rotated.validate(hash, body, &new_tag)?);
+
+        // Once the old key is retired, its artifacts are rejected
+        let retired = ArtifactSignatureAuthenticator::new(b"team".to_vec(), Some(b"new".to_vec()))
+            .with_previous_keys(vec![b"older".to_vec()]);
+        assert!(!retired.validate(hash, body, &old_tag)?);
+
         Ok(())
     }
 }
@@ -1,8 +1,15 @@
+use std::ops::Deref;
+
 use anyhow::Result;
-use turbopath::{AbsoluteSystemPath, AnchoredSystemPath, AnchoredSystemPathBuf};
+use tempfile::{tempdir, TempDir};
+use turbopath::{
+    AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPath, AnchoredSystemPathBuf,
+};
 use turborepo_analytics::AnalyticsEvent;
 use turborepo_api_client::analytics;
 
+use crate::{fs::FSCache, CacheOpts};
+
 pub(crate) struct TestFile {
     path: AnchoredSystemPathBuf,
     contents: Option<&'static str>,
@@ -145,3 +152,68 @@ pub(crate) fn get_test_cases() -> Vec<TestCase> {
         },
     ]
 }
+
+// Deterministic bytes that don't compress, for tests that depend on the
+// size of artifacts
+pub(crate) fn incompressible_bytes(len: usize) -> Vec<u8> {
+    let mut state = 0x2545f491u32;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+// A filesystem cache in a temporary repo root, which is removed along with it
+pub(crate) struct TestCache {
+    cache: FSCache,
+    repo_root: AbsoluteSystemPathBuf,
+    _dir: TempDir,
+}
+
+impl TestCache {
+    pub fn new(opts: &CacheOpts) -> Result<Self> {
+        let dir = tempdir()?;
+        let repo_root = AbsoluteSystemPathBuf::try_from(dir.path())?;
+        let cache = FSCache::new(opts, &repo_root, None)?;
+        Ok(Self {
+            cache,
+            repo_root,
+            _dir: dir,
+        })
+    }
+
+    // Another cache in the same directory, with different options or
+    // standing in for another process
+    pub fn reopen(&self, opts: &CacheOpts) -> Result<FSCache> {
+        Ok(FSCache::new(opts, &self.repo_root, None)?)
+    }
+
+    pub fn repo_root(&self) -> &AbsoluteSystemPath {
+        &self.repo_root
+    }
+
+    // Writes a file in the repo root, ready to be put in the cache
+    pub fn write_file(
+        &self,
+        path: &str,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<AnchoredSystemPathBuf> {
+        let file = AnchoredSystemPathBuf::from_raw(path)?;
+        let file_path = self.repo_root.resolve(&file);
+        file_path.ensure_dir()?;
+        file_path.create_with_contents(contents)?;
+        Ok(file)
+    }
+}
+
+impl Deref for TestCache {
+    type Target = FSCache;
+
+    fn deref(&self) -> &FSCache {
+        &self.cache
+    }
+}
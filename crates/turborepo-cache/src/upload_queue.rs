@@ -0,0 +1,157 @@
+use std::{
+    backtrace::Backtrace,
+    fs::OpenOptions,
+    io::{BufWriter, ErrorKind},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf, AnchoredSystemPathBuf};
+
+use crate::{cache_archive::CacheWriter, fs::ArtifactTags, CacheError};
+
+const QUEUE_DIRECTORY: &str = ".pending-uploads";
+const RECORD_SUFFIX: &str = ".json";
+// The same format as uploads to the remote cache
+const ARCHIVE_SUFFIX: &str = ".tar.zst";
+
+// A queued put. Its archive is stored next to it, ready to upload.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PendingUpload {
+    pub hash: String,
+    pub duration: u64,
+    pub queued_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "ArtifactTags::is_empty")]
+    pub tags: ArtifactTags,
+}
+
+/// The artifacts handled by `AsyncCache::flush_pending`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlushSummary {
+    pub uploaded: Vec<String>,
+    // Rejected by the remote cache for some reason other than it being
+    // unreachable. These are removed from the queue.
+    pub dropped: Vec<String>,
+    // Left in the queue because the remote cache became unreachable again
+    pub pending: Vec<String>,
+}
+
+// A journal of puts that failed because the remote cache was unreachable,
+// kept in the cache directory so that they survive until the next
+// `flush_pending`.
+pub(crate) struct UploadQueue {
+    directory: AbsoluteSystemPathBuf,
+}
+
+impl UploadQueue {
+    pub fn new(cache_directory: &AbsoluteSystemPath) -> Self {
+        Self {
+            directory: cache_directory.join_component(QUEUE_DIRECTORY),
+        }
+    }
+
+    pub fn archive_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.directory
+            .join_component(&format!("{}{}", hash, ARCHIVE_SUFFIX))
+    }
+
+    fn record_path(&self, hash: &str) -> AbsoluteSystemPathBuf {
+        self.directory
+            .join_component(&format!("{}{}", hash, RECORD_SUFFIX))
+    }
+
+    // Written to a temporary file and renamed into place, so that the queue
+    // never has a partial file under its real name
+    fn temp_path(&self, path: &AbsoluteSystemPathBuf) -> AbsoluteSystemPathBuf {
+        static WRITE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        self.directory.join_component(&format!(
+            ".{}.{}.{}.tmp",
+            path.file_name().expect("queued files have a file name"),
+            process::id(),
+            WRITE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    pub fn push(
+        &self,
+        anchor: &AbsoluteSystemPath,
+        hash: &str,
+        files: &[AnchoredSystemPathBuf],
+        duration: u64,
+        tags: &ArtifactTags,
+    ) -> Result<(), CacheError> {
+        self.directory.create_dir_all()?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        let archive_path = self.archive_path(hash);
+        let temp_archive_path = self.temp_path(&archive_path);
+        {
+            let archive_file = temp_archive_path.open_with_options(options.clone())?;
+            let mut cache_archive = CacheWriter::from_writer(BufWriter::new(archive_file), true)?;
+            for file in files {
+                cache_archive.add_file(anchor, file)?;
+            }
+            cache_archive.finish()?;
+        }
+        temp_archive_path.rename(&archive_path)?;
+
+        // The record is written last, since it's what makes the put pending
+        let record_path = self.record_path(hash);
+        let temp_record_path = self.temp_path(&record_path);
+        let record = PendingUpload {
+            hash: hash.to_string(),
+            duration,
+            queued_at: Utc::now(),
+            tags: tags.clone(),
+        };
+        serde_json::to_writer(temp_record_path.open_with_options(options)?, &record)
+            .map_err(|e| CacheError::MetadataWriteFailure(e, Backtrace::capture()))?;
+        temp_record_path.rename(&record_path)?;
+
+        Ok(())
+    }
+
+    // Returns the queued puts, oldest first
+    pub fn pending(&self) -> Result<Vec<PendingUpload>, CacheError> {
+        let entries = match self.directory.as_std_path().read_dir() {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut pending = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if file_name.starts_with('.') || !file_name.ends_with(RECORD_SUFFIX) {
+                continue;
+            }
+            let record_path = AbsoluteSystemPathBuf::try_from(entry.path())?;
+            let record: PendingUpload = serde_json::from_str(&record_path.read_to_string()?)
+                .map_err(|e| CacheError::InvalidMetadata(e, Backtrace::capture()))?;
+            pending.push(record);
+        }
+        pending.sort_by(|a, b| a.queued_at.cmp(&b.queued_at));
+
+        Ok(pending)
+    }
+
+    pub fn remove(&self, hash: &str) -> Result<(), CacheError> {
+        for path in [self.record_path(hash), self.archive_path(hash)] {
+            match path.remove_file() {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
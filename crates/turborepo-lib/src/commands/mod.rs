@@ -3,7 +3,7 @@ use std::cell::OnceCell;
 use dirs_next::config_dir;
 use sha2::{Digest, Sha256};
 use turbopath::{AbsoluteSystemPath, AbsoluteSystemPathBuf};
-use turborepo_api_client::{APIAuth, APIClient};
+use turborepo_api_client::{APIAuth, APIClient, NetworkOpts};
 use turborepo_ui::UI;
 
 use crate::{
@@ -121,6 +121,7 @@ impl CommandBase {
         let timeout = config.timeout();
 
         APIClient::new(api_url, timeout, self.version, args.preflight)
+            .and_then(|client| client.with_network_opts(&NetworkOpts::from_env()))
             .map_err(ConfigError::ApiClient)
     }
 
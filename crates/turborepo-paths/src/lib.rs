@@ -189,12 +189,33 @@ pub(crate) fn check_path(name: &str) -> PathValidation {
         windows_safe = false;
     }
 
+    // Name has a component that Windows treats as a device
+    if name.split('/').any(is_windows_reserved_name) {
+        windows_safe = false;
+    }
+
     PathValidation {
         well_formed,
         windows_safe,
     }
 }
 
+// Windows reserves these names for devices, regardless of case, extension, or
+// trailing spaces. Opening `nul.txt` opens the null device rather than a file.
+fn is_windows_reserved_name(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or_default();
+    let stem = stem.trim_end_matches(' ').to_ascii_uppercase();
+
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && stem.len() == 4
+                && stem.as_bytes()[3].is_ascii_digit()
+        }
+    }
+}
+
 pub enum UnknownPathType {
     Absolute(AbsoluteSystemPathBuf),
     Anchored(AnchoredSystemPathBuf),
@@ -266,6 +287,14 @@ mod tests {
     #[test_case("a/...", PathValidation { well_formed: true, windows_safe: true } ; "28")]
     #[test_case("a/.../a", PathValidation { well_formed: true, windows_safe: true } ; "29")]
     #[test_case(".../...", PathValidation { well_formed: true, windows_safe: true } ; "30")]
+    #[test_case("CON", PathValidation { well_formed: true, windows_safe: false } ; "31")]
+    #[test_case("a/nul.txt", PathValidation { well_formed: true, windows_safe: false } ; "32")]
+    #[test_case("Aux /a", PathValidation { well_formed: true, windows_safe: false } ; "33")]
+    #[test_case("a/com1.tar.gz", PathValidation { well_formed: true, windows_safe: false } ; "34")]
+    #[test_case("lpt9", PathValidation { well_formed: true, windows_safe: false } ; "35")]
+    #[test_case("console/com10", PathValidation { well_formed: true, windows_safe: true } ; "36")]
+    #[test_case("a/lpt.txt", PathValidation { well_formed: true, windows_safe: true } ; "37")]
+    #[test_case("nul-ish", PathValidation { well_formed: true, windows_safe: true } ; "38")]
     fn test_check_path(path: &'static str, expected_output: PathValidation) {
         let output = check_path(path);
         assert_eq!(output, expected_output);